@@ -0,0 +1,89 @@
+//! Parsing of the input lines, with serde_json and with simd-json
+//!
+//! The lines are generated, set `FORUM_RS_BENCH_JSONL` to the path of a `jsonl` file to measure
+//! the lines of a real dump instead.
+//!
+//! ```bash
+//! cargo bench --bench parse --features simd-json
+//! FORUM_RS_BENCH_JSONL=main_folder/sub1/posts.jsonl cargo bench --bench parse --features simd-json
+//! ```
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use forum_rs::forum_thread::{InputParser, JsonStruct, JsonlParser};
+use std::hint::black_box;
+
+/// Lines shaped like a forum dump, posts from a few words to a few paragraphs, some with escapes
+fn generated(count: usize) -> Vec<String> {
+    let sentences = [
+        "I do not think that is right, see the FAQ as it says otherwise. ",
+        "It's \\\"fine\\\" — until it isn't.\\nEdit: typo. ",
+    ];
+    (0..count)
+        .map(|i| {
+            let text = sentences[i % 2].repeat(1 + i % 40);
+            format!(
+                r#"{{"id":"t1_{i}","is_thread":"{}","pagetext":"{text}","parent_post_id":"t1_{}","root_post_id":"t3_{}","created_utc":{},"score":"{}","author":"user_{}"}}"#,
+                if i % 50 == 0 { "Y" } else { "N" },
+                i.saturating_sub(1 + i % 3),
+                i / 50,
+                1_600_000_000 + i,
+                i % 100,
+                i % 997,
+            )
+        })
+        .collect()
+}
+
+fn lines() -> Vec<String> {
+    match std::env::var("FORUM_RS_BENCH_JSONL") {
+        Ok(path) => std::fs::read_to_string(path)
+            .expect("Unable to read FORUM_RS_BENCH_JSONL")
+            .lines()
+            .filter(|line| serde_json::from_str::<JsonStruct>(line).is_ok())
+            .map(str::to_string)
+            .collect(),
+        Err(_) => generated(10_000),
+    }
+}
+
+fn parse(c: &mut Criterion) {
+    let lines = lines();
+    let bytes: usize = lines.iter().map(|line| line.len()).sum();
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Bytes(bytes as u64));
+    group.bench_function(BenchmarkId::new("serde_json", lines.len()), |b| {
+        b.iter(|| {
+            for line in &lines {
+                black_box(serde_json::from_str::<JsonStruct>(line).unwrap());
+            }
+        })
+    });
+    #[cfg(feature = "simd-json")]
+    group.bench_function(BenchmarkId::new("simd_json", lines.len()), |b| {
+        let mut buffers = simd_json::Buffers::default();
+        let mut bytes = Vec::new();
+        b.iter(|| {
+            for line in &lines {
+                bytes.clear();
+                bytes.extend_from_slice(line.as_bytes());
+                let json = simd_json::serde::from_slice_with_buffers::<JsonStruct>(
+                    &mut bytes,
+                    &mut buffers,
+                );
+                black_box(json.unwrap());
+            }
+        })
+    });
+    // What the pipeline uses, simd-json with the feature, down to the posts
+    group.bench_function(BenchmarkId::new("parser", lines.len()), |b| {
+        b.iter(|| {
+            for line in &lines {
+                black_box(JsonlParser.parse(line).unwrap());
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
@@ -0,0 +1,29 @@
+/* C bindings of the forum-rs thread reconstruction, see src/ffi.rs
+ *
+ * Build the library with `cargo build --release --features ffi` and link against
+ * target/release/libforum_rs.so (or .dylib, .dll).
+ */
+#ifndef FORUM_RS_H
+#define FORUM_RS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The threads of `len` bytes of JSONL at `jsonl`, one JSON line per thread:
+ * {"root_id": "...", "post_ids": [...]} with the posts in traversal order.
+ * Lines that cannot be parsed are skipped. Returns NULL if the buffer is not UTF-8.
+ * The string must be freed with forum_rs_free_string. */
+char *forum_rs_reconstruct_threads(const uint8_t *jsonl, size_t len);
+
+/* Free a string returned by this library, NULL is ignored. */
+void forum_rs_free_string(char *string);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* FORUM_RS_H */
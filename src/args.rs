@@ -1,13 +1,184 @@
-use clap::Parser;
+use clap::{CommandFactory, Parser};
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+use crate::config::{
+    BlocklistAction, C4Rule, Chunking, Dedup, DeepReplies, DuplicateIds, ExtraPosts, Extract,
+    FilterScope, GopherRule, GraphFormat, InvalidUtf8, LogFormat, Metadata, MissingParent,
+    OutputContent, OversizedPolicy, ProgressFormat, ProvenanceTarget, RepeatedLines, ReplyOrder,
+    RootSource, Schedule, Traversal,
+};
+use crate::utils::blocklist::{required_pattern, Blocklist};
+use crate::utils::histogram::DEFAULT_BUCKETS;
+use crate::utils::writer::{CHANNEL_BATCH, WRITE_BUFFER_BYTES};
+
+/// Name of the installed binary, the completion scripts complete this command
+pub const BIN_NAME: &str = "clean-reddit";
 
 #[derive(Parser, Debug)]
 #[clap(
     author,
     version,
     long_about = "This program reads a folder with subfolders of JSONL files and 
-outputs a folder of JSONL file with the threads and their posts."
+outputs a folder of JSONL file with the threads and their posts.
+Without a subcommand, the arguments are those of `process`."
 )]
 pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Command,
+    #[clap(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = LogFormat::Text,
+        help = "Format of the log events on stderr, the level is set with RUST_LOG"
+    )]
+    pub log_format: LogFormat,
+    #[clap(
+        long,
+        global = true,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Number of worker threads, default is RAYON_NUM_THREADS or one per core"
+    )]
+    pub threads: Option<usize>,
+    #[clap(
+        long,
+        global = true,
+        default_value_t = false,
+        help = "If true, only warnings and the summary at the end are logged and no progress is shown"
+    )]
+    pub quiet: std::primitive::bool,
+}
+
+/// Subcommands, `process` is used when none is given
+#[derive(clap::Subcommand, Debug)]
+pub enum Command {
+    #[clap(about = "Build the threads of the input folders and write them as JSONL")]
+    Process(Box<ProcessArgs>),
+    #[clap(
+        about = "Check the input folders and report unreadable lines, duplicate ids, reply cycles and root mismatches"
+    )]
+    Validate(ValidateArgs),
+    #[clap(
+        about = "Report document counts, token and word totals and length percentiles of an output folder"
+    )]
+    Stats(StatsArgs),
+    #[clap(
+        about = "Merge the output folders of several runs into shards of a target size, with a manifest"
+    )]
+    Merge(MergeArgs),
+    #[clap(about = "Write a uniform random sample of the documents of an output folder")]
+    Sample(SampleArgs),
+    #[clap(about = "Print the completions of clean-reddit for a shell")]
+    Completions(CompletionsArgs),
+}
+
+/// Names of the subcommands, anything else on the command line is passed to `process`
+const SUBCOMMANDS: [&str; 7] = [
+    "process",
+    "validate",
+    "stats",
+    "merge",
+    "sample",
+    "completions",
+    "help",
+];
+
+/// Arguments of the `completions` subcommand
+#[derive(clap::Args, Debug)]
+pub struct CompletionsArgs {
+    #[clap(value_enum, help = "Shell to print the completions for")]
+    pub shell: clap_complete::Shell,
+}
+
+/// Arguments of the `validate` subcommand
+#[derive(clap::Args, Debug)]
+pub struct ValidateArgs {
+    #[clap(short, long, help="Input to the root folder, internally must be in format main/subreddit/*.jsonl",
+    value_hint=clap::ValueHint::DirPath)]
+    pub input: String,
+}
+
+/// Arguments of the `stats` subcommand
+#[derive(clap::Args, Debug)]
+pub struct StatsArgs {
+    #[clap(short, long, help="Output folder of a previous run, every JSONL file in it is read",
+    value_hint=clap::ValueHint::DirPath)]
+    pub input: String,
+    #[clap(
+        long,
+        help = "Length percentiles to report",
+        value_delimiter = ',',
+        default_values_t = vec![50.0, 90.0, 99.0]
+    )]
+    pub percentiles: Vec<f64>,
+    #[clap(long, help = "Also save the report as JSON to this file",
+    value_hint=clap::ValueHint::FilePath)]
+    pub json: Option<String>,
+}
+
+/// Arguments of the `merge` subcommand
+#[derive(clap::Args, Debug)]
+pub struct MergeArgs {
+    #[clap(short, long, num_args = 1.., required = true, help="Output folders of previous runs, every JSONL file in them is read in the given order",
+    value_hint=clap::ValueHint::DirPath)]
+    pub input: Vec<String>,
+    #[clap(short, long, help = "Folder for the shards and manifest.json",
+    value_hint=clap::ValueHint::DirPath)]
+    pub output: String,
+    #[clap(
+        long,
+        default_value_t = 100,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Target size of a shard in MiB, a record is never split across shards"
+    )]
+    pub shard_size: usize,
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "Keep only the first record of every thread_id (and chunk)"
+    )]
+    pub dedup: std::primitive::bool,
+    #[clap(
+        long,
+        default_value_t = true,
+        help = "If true, will not overwrite existing files, default is true"
+    )]
+    pub safe: std::primitive::bool,
+}
+
+/// Arguments of the `sample` subcommand
+#[derive(clap::Args, Debug)]
+pub struct SampleArgs {
+    #[clap(short, long, help="Output folder of a previous run, every JSONL file in it is read",
+    value_hint=clap::ValueHint::DirPath)]
+    pub input: String,
+    #[clap(short, long, default_value = "sample.jsonl", help = "JSONL file for the sampled documents",
+    value_hint=clap::ValueHint::FilePath)]
+    pub output: String,
+    #[clap(
+        short = 'n',
+        long,
+        default_value_t = 1000,
+        help = "Number of documents to sample"
+    )]
+    pub count: usize,
+    #[clap(
+        long,
+        help = "Seed of the sample, the same seed on the same folder gives the same documents, random if not given"
+    )]
+    pub seed: Option<u64>,
+}
+
+/// Arguments of the `process` subcommand
+#[derive(clap::Args, Debug)]
+pub struct ProcessArgs {
+    #[clap(
+        long,
+        help = "TOML or YAML file with the options, keyed by their long name, options given on the command line take precedence",
+        value_hint=clap::ValueHint::FilePath
+    )]
+    pub config: Option<String>,
     #[clap(short, long, help="Input to the root folder, internally must be in format main/subreddit/*.jsonl",
     value_hint=clap::ValueHint::DirPath)]
     pub input: String,
@@ -17,21 +188,1071 @@ pub struct Cli {
     #[clap(
         short,
         long,
-        help = "Tokenizer name: Accepts huggingface <org>/<name> or a path to tokenizer.json\nIf not provided, will split and count words"
+        help = "Tokenizer name: Accepts huggingface <org>/<name>, a path to tokenizer.json or a SentencePiece .model file\nIf not provided, will split and count words\nCan be repeated to count with several tokenizers, written as `length_<label>`, use `label=name` to set the label"
+    )]
+    pub tokenizer: Vec<String>,
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "If true, tokenizers are only loaded from the huggingface cache and never downloaded"
+    )]
+    pub offline: std::primitive::bool,
+    #[clap(
+        long,
+        help = "Huggingface hub cache folder, defaults to $HF_HOME/hub",
+        value_hint=clap::ValueHint::DirPath
+    )]
+    pub hf_cache_dir: Option<String>,
+    #[clap(
+        long,
+        help = "If false, the tokenizer encodes each batch on a single thread, same as TOKENIZERS_PARALLELISM=false, which it follows when not set"
+    )]
+    pub tokenizer_parallelism: Option<std::primitive::bool>,
+    #[clap(
+        long,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Run batch tokenization on a dedicated pool of this many threads instead of the folder pool"
+    )]
+    pub tokenizer_threads: Option<usize>,
+    #[clap(
+        long,
+        default_value = "reddit",
+        help = "Source of the forum, `{folder}` is replaced by the name of the subfolder, as in reddit/{folder}"
     )]
-    pub tokenizer: Option<String>,
-    #[clap(long, default_value = "reddit", help = "Source of the forum")]
     pub source: String,
+    #[clap(
+        long,
+        value_parser = parse_source_map,
+        help = "TOML file, or YAML with a .yaml extension, of `subfolder = \"source\"`, the subfolders that are not in it use --source"
+    )]
+    pub source_map: Option<BTreeMap<String, String>>,
+    #[clap(
+        long,
+        help = "License of the data, written with the provenance of the run, as CC-BY-4.0"
+    )]
+    pub license: Option<String>,
+    #[clap(
+        long,
+        value_parser = |value: &str| parse_date(value).map(|_| value.to_string()),
+        help = "Date the data was crawled, written with the provenance of the run, as 2024-05-01"
+    )]
+    pub crawl_date: Option<String>,
+    #[clap(
+        long,
+        value_enum,
+        help = "Write the license, crawl date, version and config hash of the run to provenance.json, on every record or both, provenance.json by default with --license or --crawl-date"
+    )]
+    pub provenance: Option<ProvenanceTarget>,
     #[clap(
         long,
         default_value_t = true,
         help = "If true, will not overwrite existing files, default is true"
     )]
     pub safe: std::primitive::bool,
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "If true, the output folder is created when it does not exist with --safe, it is always created without"
+    )]
+    pub create_output: std::primitive::bool,
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "If true, folders completed by a previous run into the same output folder are skipped and the output is appended to, a folder that fails writes nothing (without it, the records it sent before failing are kept)"
+    )]
+    pub resume: std::primitive::bool,
     #[clap(
         long,
         default_value_t = false,
         help = "If true, will run each folder individually, reduces memory usage, default is false"
     )]
     pub low_memory: std::primitive::bool,
+    #[clap(
+        long,
+        help = "Path to a KenLM model in ARPA format, adds a perplexity score to each document",
+        value_hint=clap::ValueHint::FilePath
+    )]
+    pub kenlm_model: Option<String>,
+    #[clap(
+        long,
+        requires = "kenlm_model",
+        help = "Drop documents with a perplexity above this value"
+    )]
+    pub max_perplexity: Option<f64>,
+    #[clap(
+        long,
+        requires = "kenlm_model",
+        value_delimiter = ',',
+        value_parser = parse_perplexity,
+        help = "Write the documents to perplexity_<bucket>/ folders split at these perplexities, e.g. 100,500 for perplexity_0/ below 100, perplexity_1/ and perplexity_2/ from 500"
+    )]
+    pub perplexity_buckets: Vec<f64>,
+    #[clap(
+        long,
+        value_enum,
+        value_name = "RULES",
+        num_args = 0..,
+        value_delimiter = ',',
+        help = "Apply the C4 quality rules after cleaning, all of them without a value or the comma separated RULES"
+    )]
+    pub c4: Option<Vec<C4Rule>>,
+    #[clap(
+        long,
+        value_enum,
+        value_name = "RULES",
+        num_args = 0..,
+        value_delimiter = ',',
+        help = "Apply the Gopher quality rules after cleaning, all of them without a value or the comma separated RULES"
+    )]
+    pub gopher: Option<Vec<GopherRule>>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = RepeatedLines::None,
+        help = "Remove the lines of a document that repeat an earlier line of it, such as quotes and bot echoes"
+    )]
+    pub repeated_lines: RepeatedLines,
+    #[clap(
+        long,
+        default_value_t = 0.8,
+        value_parser = parse_fraction,
+        help = "Jaccard similarity of the word trigrams from which a line is a repeat with --repeated-lines near"
+    )]
+    pub repeated_line_similarity: f64,
+    #[clap(
+        long,
+        value_parser = parse_ratio,
+        help = "Drop the documents with more symbols (characters other than letters, digits, whitespace and common punctuation) per word than this, such as ASCII art and emoticon spam"
+    )]
+    pub max_symbol_ratio: Option<f64>,
+    #[clap(
+        long,
+        value_parser = parse_fraction,
+        help = "Drop the documents whose characters other than whitespace are more than this share neither letters nor digits, such as code dumps"
+    )]
+    pub max_non_alphanumeric: Option<f64>,
+    #[clap(
+        long,
+        value_name = "FILE",
+        value_parser = parse_blocklist,
+        help = "Drop the documents with a term of this file, one per line as a whole word or `re:` and a regular expression, case insensitive",
+        value_hint=clap::ValueHint::FilePath
+    )]
+    pub blocklist: Option<Blocklist>,
+    #[clap(
+        long,
+        value_name = "REGEX",
+        value_parser = required_pattern,
+        help = "Keep only the documents that match this case insensitive regular expression, or one of them when repeated, such as a keyword to extract a topic"
+    )]
+    pub require_pattern: Vec<regex::Regex>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = BlocklistAction::Drop,
+        requires = "blocklist",
+        help = "Drop the documents with a term of --blocklist, or flag them with the terms found in `blocklist`"
+    )]
+    pub blocklist_action: BlocklistAction,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = Dedup::None,
+        help = "Drop the documents whose cleaned text was already written in the run, across all folders"
+    )]
+    pub dedup: Dedup,
+    #[clap(
+        long,
+        default_value_t = 256,
+        value_parser = clap::builder::RangedU64ValueParser::<u64>::new().range(1..),
+        help = "Memory of the Bloom filter of --dedup bloom, in MiB"
+    )]
+    pub bloom_memory_mb: u64,
+    #[clap(
+        long,
+        default_value_t = 0.001,
+        value_parser = parse_fraction,
+        help = "Share of new documents the Bloom filter of --dedup bloom may take for copies once it holds as many as its memory allows"
+    )]
+    pub bloom_fp_rate: f64,
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Load the hashes of --dedup from this file at the start if it exists, and save them to it at the end, so the documents of previous runs are not written again"
+    )]
+    pub dedup_index: Option<String>,
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "If true, also writes the token (or word) count of every post as `post_lengths`"
+    )]
+    pub post_lengths: std::primitive::bool,
+    #[clap(
+        long,
+        default_value_t = 64,
+        help = "Number of documents to tokenize in a single batch"
+    )]
+    pub batch_size: usize,
+    #[clap(
+        long,
+        help = "Maximum number of tokens (or words without a tokenizer) per document"
+    )]
+    pub max_tokens: Option<usize>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = Chunking::Truncate,
+        requires = "max_tokens",
+        help = "How to handle documents longer than --max-tokens"
+    )]
+    pub chunking: Chunking,
+    #[clap(
+        long,
+        default_value_t = 0,
+        help = "Number of tokens of overlapping context between split chunks, less than --max-tokens"
+    )]
+    pub chunk_overlap: usize,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = OutputContent::Text,
+        requires = "tokenizer",
+        help = "Write the cleaned text, the token ids (`input_ids`) or both"
+    )]
+    pub output_content: OutputContent,
+    #[clap(
+        long,
+        help = "Documents with more tokens (or words) than this are routed or dropped, see --oversized"
+    )]
+    pub oversized_tokens: Option<usize>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = OversizedPolicy::Route,
+        requires = "oversized_tokens",
+        help = "Write oversized documents to oversized.jsonl or drop them"
+    )]
+    pub oversized: OversizedPolicy,
+    #[clap(
+        long,
+        value_delimiter = ',',
+        default_values_t = DEFAULT_BUCKETS,
+        help = "Comma separated upper bounds of the token length histogram printed and saved at the end when a tokenizer is used"
+    )]
+    pub histogram_buckets: Vec<usize>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = Traversal::Dfs,
+        help = "Order of the posts in a thread, bfs puts all direct replies first"
+    )]
+    pub traversal: Traversal,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = ReplyOrder::Insertion,
+        help = "Order of the replies to a post, time uses the created_utc or timestamp field and score the score or upvotes field"
+    )]
+    pub reply_order: ReplyOrder,
+    #[clap(
+        long,
+        help = "Deepest level of replies to keep in a thread, the first post is at depth 0"
+    )]
+    pub max_depth: Option<usize>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = DeepReplies::Drop,
+        requires = "max_depth",
+        help = "Drop the replies below --max-depth or replace them with a count"
+    )]
+    pub deep_replies: DeepReplies,
+    #[clap(
+        long,
+        help = "Maximum number of posts in a document, the first posts in traversal order are kept"
+    )]
+    pub max_posts_per_thread: Option<usize>,
+    #[clap(
+        long,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Threads per task when the threads of a folder are traversed in parallel, by default from the number of threads and their average size"
+    )]
+    pub roots_per_task: Option<usize>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = ExtraPosts::Drop,
+        requires = "max_posts_per_thread",
+        help = "Drop the posts after --max-posts-per-thread or write them as continuation documents"
+    )]
+    pub extra_posts: ExtraPosts,
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "Group posts by root_post_id without building the reply graph, faster and lighter for dumps with reliable root ids"
+    )]
+    pub group_by_root: std::primitive::bool,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = Extract::Tree,
+        help = "Write one document per thread, one per root to leaf conversation path, or one per reply with its ancestors"
+    )]
+    pub extract: Extract,
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "If true, also writes the depth, number of posts, branching factor and unique authors of each thread"
+    )]
+    pub thread_stats: std::primitive::bool,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = Metadata::None,
+        help = "Write the author, created_utc and score of the posts in a metadata object, as an array per field or aggregated over the posts of the record"
+    )]
+    pub metadata: Metadata,
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "If true, also writes the id, depth and byte range in raw_content of every post as a composition object"
+    )]
+    pub composition: std::primitive::bool,
+    #[clap(
+        long,
+        value_name = "KEY",
+        help = "Replace the post ids and authors in the output with their BLAKE3 hash keyed by KEY, the same id always gets the same hash"
+    )]
+    pub hash_ids: Option<String>,
+    #[clap(
+        long,
+        value_name = "WORDS",
+        help = "Leave out the threads whose root only shares a link, with at most WORDS other words, that a thread with more posts (then a higher score) of the same subfolder also shares, or of the whole input with --cross-folder"
+    )]
+    pub drop_link_reposts: Option<usize>,
+    #[clap(
+        long,
+        value_parser = parse_date,
+        help = "Leave out the posts created before this UTC date, as 2018-01-01, 2018-01-01T12:00:00 or seconds since the epoch"
+    )]
+    pub after: Option<i64>,
+    #[clap(
+        long,
+        value_parser = parse_date,
+        help = "Leave out the posts created at or after this UTC date, same formats as --after"
+    )]
+    pub before: Option<i64>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = FilterScope::Posts,
+        help = "Leave out every post outside --after and --before, or the whole thread when its root post is outside"
+    )]
+    pub date_scope: FilterScope,
+    #[clap(
+        long,
+        allow_negative_numbers = true,
+        help = "Leave out the posts with a score (or upvotes) below this, the posts without a score are kept"
+    )]
+    pub min_score: Option<i64>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = FilterScope::Posts,
+        help = "Leave out every post below --min-score, or the whole thread when its root post is below"
+    )]
+    pub score_scope: FilterScope,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = MissingParent::Empty,
+        help = "What is written for a parent post that is missing from the dump"
+    )]
+    pub missing_parent: MissingParent,
+    #[clap(
+        long,
+        requires = "thread_id",
+        help = "Folder to write the graphs of the threads selected with --thread-id, for debugging"
+    )]
+    pub export_graph: Option<String>,
+    #[clap(
+        long,
+        requires = "export_graph",
+        help = "Id of a post to export with all the replies below it, can be repeated"
+    )]
+    pub thread_id: Vec<String>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = GraphFormat::Dot,
+        requires = "export_graph",
+        help = "Format of the exported graphs"
+    )]
+    pub graph_format: GraphFormat,
+    #[clap(
+        long,
+        help = "Build the reply graph of each folder in this many shards routed by root_post_id, one thread per shard, for folders with a single huge graph"
+    )]
+    pub graph_shards: Option<usize>,
+    #[clap(
+        long,
+        value_enum,
+        help = "Report the posts whose root_post_id differs from the root of the reply graph, then keep the graph threads or regroup by the declared root"
+    )]
+    pub validate_roots: Option<RootSource>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = DuplicateIds::LastWins,
+        help = "Which copy of a post id read more than once is kept in the reply graph, the counts are reported per folder"
+    )]
+    pub duplicate_ids: DuplicateIds,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = InvalidUtf8::Lossy,
+        help = "What is done with an input line that is not valid UTF-8: replace the invalid bytes, reject the line, or skip the rest of the file and report it"
+    )]
+    pub invalid_utf8: InvalidUtf8,
+    #[clap(
+        long,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Skip and log the input lines longer than this many bytes instead of reading them into memory, for dumps with corrupted lines"
+    )]
+    pub max_line_bytes: Option<usize>,
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "If true, threads are built from the files of all the subfolders together, for dumps where a thread spans several folders"
+    )]
+    pub cross_folder: std::primitive::bool,
+    #[clap(
+        long,
+        help = "Partition each folder into this many on-disk buckets by root_post_id and build the threads one bucket at a time, bounds memory for huge folders"
+    )]
+    pub buckets: Option<usize>,
+    #[clap(
+        long,
+        requires = "buckets",
+        help = "Folder for the temporary buckets, default is .buckets in the output folder"
+    )]
+    pub bucket_dir: Option<String>,
+    #[clap(
+        long,
+        value_parser = parse_fraction,
+        help = "Write this share of the threads to val/ and the rest to train/, by the hash of the thread id so a thread is always in the same split"
+    )]
+    pub val_fraction: Option<f64>,
+    #[clap(
+        long,
+        default_value_t = WRITE_BUFFER_BYTES,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Size of the buffer of each output file in bytes"
+    )]
+    pub write_buffer_bytes: usize,
+    #[clap(
+        long,
+        value_parser = parse_seconds,
+        help = "Flush the output files at least every this many seconds, so they grow during a long run, by default only when a buffer is full"
+    )]
+    pub flush_interval: Option<f64>,
+    #[clap(
+        long,
+        default_value_t = CHANNEL_BATCH,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Number of records a worker sends to the writer at a time, larger batches synchronize less but the records wait longer"
+    )]
+    pub channel_batch: usize,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = ProgressFormat::Bar,
+        help = "Show the progress as a line on stdout, as JSON lines on stderr with the folders done, queue, bytes written and ETA, or not at all"
+    )]
+    pub progress: ProgressFormat,
+    #[clap(
+        long,
+        value_parser = parse_gib,
+        help = "Approximate memory budget in GiB, the next folder waits while the folders in progress (by file size) and the queued records are over it"
+    )]
+    pub max_memory_gb: Option<f64>,
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "If true, the lines that cannot be parsed and the documents dropped by a filter are written with the reason to rejects/<folder>.jsonl"
+    )]
+    pub rejects: std::primitive::bool,
+    #[clap(
+        long,
+        default_value_t = true,
+        help = "If true, the largest subfolders are started first so a large one does not run alone at the end, otherwise they are processed in name order"
+    )]
+    pub largest_first: std::primitive::bool,
+    #[clap(
+        long,
+        value_parser = parse_minutes,
+        help = "Abandon a subfolder that is still being processed after this many minutes, it is reported as failed and the run goes on"
+    )]
+    pub folder_timeout: Option<f64>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = Schedule::Files,
+        help = "Put the files of all the subfolders in one work queue, so the files and threads of a large subfolder are shared by all the workers, or process one subfolder per worker"
+    )]
+    pub schedule: Schedule,
+    #[clap(
+        long,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        help = "Read the input files on a dedicated pool of this many threads, so the next subfolders are read while the previous ones are built and cleaned, with --schedule files"
+    )]
+    pub read_threads: Option<usize>,
+}
+
+/// A number between 0 and 1
+fn parse_fraction(value: &str) -> Result<f64, String> {
+    let fraction: f64 = value.parse().map_err(|e| format!("{}", e))?;
+    match (0.0..=1.0).contains(&fraction) {
+        true => Ok(fraction),
+        false => Err(format!("{} is not between 0 and 1", fraction)),
+    }
+}
+
+/// A number of one thing per another, 0 or more
+fn parse_ratio(value: &str) -> Result<f64, String> {
+    let ratio: f64 = value.parse().map_err(|e| format!("{}", e))?;
+    match ratio.is_finite() && ratio >= 0.0 {
+        true => Ok(ratio),
+        false => Err(format!("{} is not a ratio of 0 or more", ratio)),
+    }
+}
+
+/// A positive perplexity
+fn parse_perplexity(value: &str) -> Result<f64, String> {
+    let perplexity: f64 = value.parse().map_err(|e| format!("{}", e))?;
+    match perplexity.is_finite() && perplexity > 0.0 {
+        true => Ok(perplexity),
+        false => Err(format!("{} is not a positive perplexity", perplexity)),
+    }
+}
+
+/// A positive number of minutes
+fn parse_minutes(value: &str) -> Result<f64, String> {
+    let minutes: f64 = value.parse().map_err(|e| format!("{}", e))?;
+    match minutes.is_finite() && minutes > 0.0 {
+        true => Ok(minutes),
+        false => Err(format!("{} is not a positive number of minutes", minutes)),
+    }
+}
+
+/// A positive number of GiB
+fn parse_gib(value: &str) -> Result<f64, String> {
+    let gib: f64 = value.parse().map_err(|e| format!("{}", e))?;
+    match gib.is_finite() && gib > 0.0 {
+        true => Ok(gib),
+        false => Err(format!("{} is not a positive number of GiB", gib)),
+    }
+}
+
+/// A positive number of seconds
+fn parse_seconds(value: &str) -> Result<f64, String> {
+    let seconds: f64 = value.parse().map_err(|e| format!("{}", e))?;
+    match seconds.is_finite() && seconds > 0.0 {
+        true => Ok(seconds),
+        false => Err(format!("{} is not a positive number of seconds", seconds)),
+    }
+}
+
+/// The sources of the subfolders, from a TOML file, or YAML with a `.yaml` or `.yml` extension
+fn parse_source_map(path: &str) -> Result<BTreeMap<String, String>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read the source map {}: {}", path, e))?;
+    let extension = Path::new(path).extension().and_then(|e| e.to_str());
+    match extension {
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&text).map_err(|e| e.to_string()),
+        _ => toml::from_str(&text).map_err(|e| e.to_string()),
+    }
+    .map_err(|e| format!("Invalid source map {}: {}", path, e))
+}
+
+fn parse_blocklist(path: &str) -> Result<Blocklist, String> {
+    Blocklist::from_file(Path::new(path))
+}
+
+/// A UTC date `YYYY-MM-DD`, with an optional time `THH:MM:SS`, or a number of seconds since the
+/// epoch, as seconds since the epoch
+fn parse_date(value: &str) -> Result<i64, String> {
+    if let Ok(seconds) = value.parse::<i64>() {
+        return Ok(seconds);
+    }
+    let invalid = || {
+        format!(
+            "{} is not a date like 2018-01-01 or 2018-01-01T12:00:00",
+            value
+        )
+    };
+    let numbers = |text: &str, separator: char| -> Result<Vec<i64>, String> {
+        text.split(separator)
+            .map(|number| number.parse().map_err(|_| invalid()))
+            .collect()
+    };
+    let value = value.strip_suffix('Z').unwrap_or(value);
+    let (date, time) = value.split_once('T').unwrap_or((value, "00:00:00"));
+    let (date, time) = (numbers(date, '-')?, numbers(time, ':')?);
+    let (&[year, month, day], &[hour, minute, second]) = (&date[..], &time[..]) else {
+        return Err(invalid());
+    };
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if !(1..=12).contains(&month)
+        || !(1..=month_days).contains(&day)
+        || !(0..24).contains(&hour)
+        || !(0..60).contains(&minute)
+        || !(0..60).contains(&second)
+    {
+        return Err(invalid());
+    }
+    // Days since 1970-01-01 of the proleptic Gregorian calendar, with the years starting in March
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    Ok(days * 86_400 + hour * 3_600 + minute * 60 + second)
+}
+
+impl Cli {
+    /// Parse the command line, with the options of the `--config` file as defaults
+    ///
+    /// Exits with a usage error if the file cannot be read, same as [parse](#method.parse).
+    pub fn parse_with_config() -> Self {
+        let args: Vec<String> = std::env::args().collect();
+        match args_with_config(with_subcommand(args)) {
+            Ok(args) => Self::parse_from(args),
+            Err(e) => Self::command().error(clap::error::ErrorKind::Io, e).exit(),
+        }
+    }
+}
+
+/// Insert `process` when the arguments do not start with a subcommand
+///
+/// Keeps the command lines from before the subcommands working, `--help` and `--version` are left
+/// to the top level.
+pub fn with_subcommand(mut args: Vec<String>) -> Vec<String> {
+    let keep = match args.get(1).map(String::as_str) {
+        None => true,
+        Some("-h" | "--help" | "-V" | "--version") => true,
+        Some(first) => SUBCOMMANDS.contains(&first),
+    };
+    if !keep {
+        args.insert(1, "process".to_string());
+    }
+    args
+}
+
+/// Value of `--config` in the raw arguments
+fn config_path(args: &[String]) -> Option<String> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().cloned();
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(path.to_string());
+        }
+    }
+    None
+}
+
+/// Long names of the `process` options given in the raw arguments, short flags are mapped to their
+/// long name
+fn given_options(args: &[String]) -> HashSet<String> {
+    let cli = Cli::command();
+    let command = cli.find_subcommand("process").unwrap();
+    let mut given = HashSet::new();
+    for arg in args.iter().skip(2) {
+        if let Some(name) = arg.strip_prefix("--") {
+            let name = name.split('=').next().unwrap_or_default();
+            given.insert(name.to_string());
+        } else if let Some(short) = arg.strip_prefix('-').and_then(|flag| flag.chars().next()) {
+            let long = command
+                .get_arguments()
+                .find(|option| option.get_short() == Some(short))
+                .and_then(|option| option.get_long());
+            if let Some(long) = long {
+                given.insert(long.to_string());
+            }
+        }
+    }
+    given
+}
+
+/// Turn the options of a config file into command line arguments
+///
+/// Keys are long option names, with `-` or `_`. Arrays repeat the option, booleans and numbers are
+/// passed as text and `null` leaves the option out.
+fn file_args(path: &str) -> Result<Vec<(String, Vec<String>)>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read the config file {}: {}", path, e))?;
+    let extension = Path::new(path).extension().and_then(|e| e.to_str());
+    let table: serde_json::Map<String, serde_json::Value> = match extension {
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&text).map_err(|e| e.to_string()),
+        _ => toml::from_str(&text).map_err(|e| e.to_string()),
+    }
+    .map_err(|e| format!("Invalid config file {}: {}", path, e))?;
+
+    let scalar = |key: &str, value: &serde_json::Value| match value {
+        serde_json::Value::String(text) => Ok(text.clone()),
+        serde_json::Value::Bool(_) | serde_json::Value::Number(_) => Ok(value.to_string()),
+        _ => Err(format!("Unsupported value for `{}` in {}", key, path)),
+    };
+    let mut options = Vec::with_capacity(table.len());
+    for (key, value) in table {
+        let name = key.replace('_', "-");
+        let values = match &value {
+            serde_json::Value::Null => continue,
+            serde_json::Value::Array(items) => items
+                .iter()
+                .map(|item| scalar(&key, item))
+                .collect::<Result<_, _>>()?,
+            value => vec![scalar(&key, value)?],
+        };
+        options.push((name, values));
+    }
+    Ok(options)
+}
+
+/// Insert the options of the `--config` file that are not on the command line
+///
+/// Only `process` reads a config file.
+///
+/// # Arguments
+///
+/// * `args` - `Vec<String>` - The raw arguments, starting with the program name and the subcommand
+///
+/// # Returns
+///
+/// * `Result<Vec<String>, String>` - The arguments to parse, or why the config file is not usable
+pub fn args_with_config(args: Vec<String>) -> Result<Vec<String>, String> {
+    if args.get(1).map(String::as_str) != Some("process") {
+        return Ok(args);
+    }
+    let Some(path) = config_path(&args) else {
+        return Ok(args);
+    };
+    let given = given_options(&args);
+    let mut merged: Vec<String> = args.iter().take(2).cloned().collect();
+    for (name, values) in file_args(&path)? {
+        if given.contains(&name) || name == "config" {
+            continue;
+        }
+        for value in values {
+            merged.push(format!("--{}", name));
+            merged.push(value);
+        }
+    }
+    merged.extend(args.into_iter().skip(2));
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, Models};
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    /// Parse the arguments of `process` the way `main` does
+    fn parse(args: Vec<String>) -> ProcessArgs {
+        let args = args_with_config(with_subcommand(args)).unwrap();
+        match Cli::parse_from(args).command {
+            Command::Process(process) => *process,
+            command => panic!("unexpected subcommand {:?}", command),
+        }
+    }
+
+    #[test]
+    fn test_config_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let toml_path = temp_dir.path().join("run.toml");
+        std::fs::write(
+            &toml_path,
+            r#"
+input = "data/"
+output = "out/"
+tokenizer = ["gpt2", "llama=meta-llama/Llama-2-7b-hf"]
+max_tokens = 2048
+group-by-root = true
+"#,
+        )
+        .unwrap();
+        let args: Vec<String> = ["clean-reddit", "--config", toml_path.to_str().unwrap()]
+            .into_iter()
+            .chain(["-o", "elsewhere/", "--max-tokens", "512"])
+            .map(String::from)
+            .collect();
+        let cli = parse(args);
+        assert_eq!(cli.input, "data/");
+        assert_eq!(cli.output, "elsewhere/");
+        assert_eq!(cli.tokenizer.len(), 2);
+        assert_eq!(cli.max_tokens, Some(512));
+        assert!(cli.group_by_root);
+
+        let yaml_path = temp_dir.path().join("run.yaml");
+        std::fs::write(&yaml_path, "input: data/\noutput: out/\nsafe: false\n").unwrap();
+        let args = vec![
+            "clean-reddit".to_string(),
+            format!("--config={}", yaml_path.display()),
+        ];
+        let cli = parse(args);
+        assert_eq!(cli.output, "out/");
+        assert!(!cli.safe);
+
+        let args = vec![
+            "clean-reddit".to_string(),
+            "--config".to_string(),
+            "missing.toml".to_string(),
+        ];
+        assert!(args_with_config(with_subcommand(args)).is_err());
+    }
+
+    #[test]
+    fn test_subcommands() {
+        let args = |args: &[&str]| {
+            let args = args.iter().map(|arg| arg.to_string()).collect();
+            Cli::parse_from(with_subcommand(args)).command
+        };
+        let Command::Process(process) = args(&["clean-reddit", "-i", "in", "-o", "out"]) else {
+            panic!("process is the default subcommand");
+        };
+        assert_eq!(process.input, "in");
+        let Command::Process(process) = args(&["clean-reddit", "process", "-i", "in", "-o", "out"])
+        else {
+            panic!("process subcommand");
+        };
+        assert_eq!(process.output, "out");
+        let Command::Validate(validate) = args(&["clean-reddit", "validate", "-i", "in"]) else {
+            panic!("validate subcommand");
+        };
+        assert_eq!(validate.input, "in");
+        let Command::Stats(stats) = args(&["clean-reddit", "stats", "-i", "out"]) else {
+            panic!("stats subcommand");
+        };
+        assert_eq!(stats.percentiles, vec![50.0, 90.0, 99.0]);
+        let Command::Merge(merge) = args(&["clean-reddit", "merge", "-i", "a", "b", "-o", "out"])
+        else {
+            panic!("merge subcommand");
+        };
+        assert_eq!(merge.input, vec!["a", "b"]);
+        assert_eq!(merge.shard_size, 100);
+        let shard_size = [
+            "clean-reddit",
+            "merge",
+            "-i",
+            "a",
+            "-o",
+            "out",
+            "--shard-size",
+            "0",
+        ];
+        assert!(Cli::try_parse_from(shard_size).is_err());
+        let Command::Sample(sample) = args(&["clean-reddit", "sample", "-i", "out", "-n", "5"])
+        else {
+            panic!("sample subcommand");
+        };
+        assert_eq!((sample.count, sample.seed), (5, None));
+        let args = [
+            "clean-reddit",
+            "--log-format",
+            "json",
+            "-i",
+            "in",
+            "-o",
+            "out",
+        ];
+        let cli = Cli::parse_from(with_subcommand(
+            args.iter().map(|arg| arg.to_string()).collect(),
+        ));
+        assert_eq!(cli.log_format, LogFormat::Json);
+        assert_eq!(cli.threads, None);
+        assert!(!cli.quiet);
+        assert!(matches!(cli.command, Command::Process(_)));
+        let args = ["clean-reddit", "stats", "-i", "out", "--quiet", "true"];
+        let cli = Cli::parse_from(args);
+        assert!(cli.quiet);
+        let args = [
+            "clean-reddit",
+            "-i",
+            "in",
+            "-o",
+            "out",
+            "--val-fraction",
+            "1.5",
+        ];
+        assert!(Cli::try_parse_from(with_subcommand(
+            args.iter().map(|arg| arg.to_string()).collect()
+        ))
+        .is_err());
+        let args = ["clean-reddit", "-i", "in", "-o", "out", "--folder-timeout"];
+        for minutes in ["0", "-1", "inf"] {
+            let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            args.push(minutes.to_string());
+            assert!(Cli::try_parse_from(with_subcommand(args)).is_err());
+        }
+        let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        args.push("0.5".to_string());
+        let config = Config::from(&parse(args));
+        assert_eq!(config.folder_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(config.write_buffer.bytes, WRITE_BUFFER_BYTES);
+        assert_eq!(config.write_buffer.flush_interval, None);
+        assert_eq!(config.channel_batch, CHANNEL_BATCH);
+        let args = [
+            "clean-reddit",
+            "-i",
+            "in",
+            "-o",
+            "out",
+            "--flush-interval",
+            "2.5",
+        ];
+        let args = args.iter().map(|arg| arg.to_string()).collect();
+        let config = Config::from(&parse(args));
+        assert_eq!(
+            config.write_buffer.flush_interval,
+            Some(Duration::from_millis(2500))
+        );
+        let args = ["clean-reddit", "-i", "in", "-o", "out", "--max-memory-gb"];
+        for gib in ["0", "-2", "NaN", "inf"] {
+            let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            args.push(gib.to_string());
+            assert!(Cli::try_parse_from(with_subcommand(args)).is_err());
+        }
+        let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        args.push("0.5".to_string());
+        assert_eq!(
+            Config::from(&parse(args)).max_memory,
+            Some(512 * 1024 * 1024)
+        );
+        let args = ["clean-reddit", "-i", "in", "-o", "out"];
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        // The tokenizer follows TOKENIZERS_PARALLELISM unless asked, as with the library default
+        let models = Config::from(&parse(args.clone())).models;
+        assert_eq!(
+            models.tokenizer_parallelism,
+            Models::default().tokenizer_parallelism
+        );
+        let mut threads = args.clone();
+        threads.extend(["--tokenizer-threads".to_string(), "0".to_string()]);
+        assert!(Cli::try_parse_from(with_subcommand(threads)).is_err());
+        let mut parallelism = args;
+        parallelism.extend(["--tokenizer-parallelism".to_string(), "false".to_string()]);
+        let models = Config::from(&parse(parallelism)).models;
+        assert_eq!(models.tokenizer_parallelism, Some(false));
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(parse_date("1970-01-01"), Ok(0));
+        assert_eq!(parse_date("2018-01-01"), Ok(1_514_764_800));
+        assert_eq!(parse_date("2023-06-30T12:30:15Z"), Ok(1_688_128_215));
+        assert_eq!(parse_date("2024-02-29"), Ok(1_709_164_800));
+        assert_eq!(parse_date("1969-12-31"), Ok(-86_400));
+        assert_eq!(parse_date("1514764800"), Ok(1_514_764_800));
+        for invalid in [
+            "2023-02-29",
+            "2023-13-01",
+            "2023-06",
+            "2023-06-30T24:00:00",
+            "yesterday",
+        ] {
+            assert!(parse_date(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_source_map() {
+        let temp_dir = TempDir::new().unwrap();
+        let map = temp_dir.path().join("sources.toml");
+        std::fs::write(&map, "askscience = \"reddit/science\"\n").unwrap();
+        let args = [
+            "clean-reddit",
+            "-i",
+            "in",
+            "-o",
+            "out",
+            "--source",
+            "reddit/{folder}",
+            "--source-map",
+            map.to_str().unwrap(),
+        ];
+        let config = Config::from(&parse(args.iter().map(|arg| arg.to_string()).collect()));
+        assert_eq!(
+            config.source_of(Path::new("in/askscience")),
+            "reddit/science"
+        );
+        assert_eq!(config.source_of(Path::new("in/rust")), "reddit/rust");
+        assert_eq!(Config::default().source_of(Path::new("in/rust")), "reddit");
+
+        std::fs::write(&map, "askscience = 1\n").unwrap();
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        assert!(Cli::try_parse_from(with_subcommand(args)).is_err());
+    }
+
+    #[test]
+    fn test_provenance() {
+        let config = |extra: &[&str]| {
+            let args = ["clean-reddit", "-i", "in", "-o", "out"]
+                .iter()
+                .chain(extra)
+                .map(|arg| arg.to_string())
+                .collect();
+            Config::from(&parse(args))
+        };
+        assert_eq!(config(&[]).provenance, None);
+
+        let stamped = config(&["--license", "CC-BY-4.0", "--crawl-date", "2024-05-01"]);
+        let provenance = stamped.provenance.clone().unwrap();
+        assert_eq!(provenance.license.as_deref(), Some("CC-BY-4.0"));
+        assert_eq!(provenance.crawl_date.as_deref(), Some("2024-05-01"));
+        assert_eq!(provenance.pipeline_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(stamped.provenance_target, ProvenanceTarget::Manifest);
+        // The hash does not depend on the folders or the provenance, only on the options
+        assert_eq!(provenance.config_hash, config(&[]).config_hash());
+        let elsewhere = Config {
+            output: "elsewhere".into(),
+            ..config(&[])
+        };
+        assert_eq!(elsewhere.config_hash(), provenance.config_hash);
+        assert_ne!(
+            config(&["--max-tokens", "512"]).config_hash(),
+            provenance.config_hash
+        );
+        // A thread may be split across graph shards
+        assert_ne!(
+            config(&["--graph-shards", "2"]).config_hash(),
+            provenance.config_hash
+        );
+
+        let records = config(&["--provenance", "records"]);
+        assert_eq!(records.provenance_target, ProvenanceTarget::Records);
+        assert_eq!(records.provenance.unwrap().license, None);
+        let args = [
+            "clean-reddit",
+            "-i",
+            "in",
+            "-o",
+            "out",
+            "--crawl-date",
+            "May",
+        ];
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        assert!(Cli::try_parse_from(with_subcommand(args)).is_err());
+    }
 }
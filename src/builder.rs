@@ -0,0 +1,200 @@
+//! Assemble a custom variant of the `process` pipeline
+//!
+//! The folders are read, turned into threads, cleaned, tokenized and written the same way as
+//! [run](../pipeline/fn.run.html), with the progress, the memory limit and the report of the
+//! failed folders, but any of the stages can be swapped for a closure, and the output files for
+//! an [OutputSink](../utils/writer/trait.OutputSink.html).
+//!
+//! ```no_run
+//! # use forum_rs::Pipeline;
+//! let exit = Pipeline::new()
+//!     .input("main_folder")
+//!     .cleaner(|text| text.trim().to_lowercase())
+//!     .tokenizer(|texts| texts.iter().map(|text| text.bytes().map(u32::from).collect()).collect())
+//!     .thread_hook(|_, posts| (posts[0].author.as_deref() != Some("AutoModerator")).then_some(posts))
+//!     .writer(|route, record| {
+//!         println!("{}: {}", route.path().display(), record);
+//!         Ok(())
+//!     })
+//!     .build()
+//!     .run()?;
+//! # Ok::<(), forum_rs::Error>(())
+//! ```
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::error;
+use crate::forum_thread::{InputParser, Post};
+use crate::pipeline;
+use crate::utils::writer::{OutputSink, Route, ThreadPost};
+
+/// Receives the route and the JSON line of every record, in place of the output files
+pub type Writer = dyn FnMut(&Route, &str) -> std::io::Result<()> + Send;
+
+/// The sink of [PipelineBuilder::writer](struct.PipelineBuilder.html#method.writer), the rejects
+/// go to the writer as well
+struct WriterSink(Box<Writer>);
+
+impl OutputSink for WriterSink {
+    fn write(&mut self, route: &Route, threadpost: ThreadPost) -> std::io::Result<()> {
+        let record = serde_json::to_string(&threadpost)?;
+        (self.0)(route, &record)
+    }
+
+    fn reject(&mut self, route: &Route, record: &str) -> std::io::Result<()> {
+        (self.0)(route, record)
+    }
+}
+
+/// A `process` run with its stages, built by a [PipelineBuilder](struct.PipelineBuilder.html)
+pub struct Pipeline {
+    config: Config,
+    sink: Option<Box<dyn OutputSink>>,
+}
+
+impl Pipeline {
+    /// Start a pipeline with the default [Config](../config/struct.Config.html) and the built-in
+    /// stages
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> PipelineBuilder {
+        PipelineBuilder::default()
+    }
+
+    /// The options of the run, with the custom cleaner and tokenizer in `stages`
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Process every folder of the input, see [run](../pipeline/fn.run.html)
+    ///
+    /// # Errors
+    ///
+    /// Same as [run](../pipeline/fn.run.html), and `Error::Usage` when a custom sink is asked to
+    /// resume. An error of the sink stops the writing and fails the run.
+    pub fn run(self) -> error::Result<error::Exit> {
+        pipeline::run_with(self.config, self.sink)
+    }
+}
+
+/// Builder of a [Pipeline](struct.Pipeline.html), every stage left out is the built-in one
+#[derive(Default)]
+pub struct PipelineBuilder {
+    config: Config,
+    sink: Option<Box<dyn OutputSink>>,
+}
+
+impl PipelineBuilder {
+    /// Start from `config` instead of the default options, the stages already set are kept
+    pub fn config(mut self, config: Config) -> Self {
+        let stages = std::mem::take(&mut self.config.stages);
+        self.config = Config { stages, ..config };
+        self
+    }
+
+    /// The root folder, with a subfolder of `jsonl` files per forum
+    pub fn input<P: Into<PathBuf>>(mut self, input: P) -> Self {
+        self.config.input = input.into();
+        self
+    }
+
+    /// The folder of the output files, only needed for the histogram with a custom writer
+    pub fn output<P: Into<PathBuf>>(mut self, output: P) -> Self {
+        self.config.output = output.into();
+        self
+    }
+
+    /// Clean the text of every post with `cleaner` instead of the built-in regexes
+    pub fn cleaner<F>(mut self, cleaner: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.config.stages.cleaner = Some(Arc::new(cleaner));
+        self
+    }
+
+    /// Count the lengths with `tokenizer`, which returns the token ids of every document of a batch
+    ///
+    /// The ids are written in `input_ids` when the output content asks for them.
+    pub fn tokenizer<F>(mut self, tokenizer: F) -> Self
+    where
+        F: Fn(&[String]) -> Vec<Vec<u32>> + Send + Sync + 'static,
+    {
+        self.config.stages.tokenizer = Some(Arc::new(tokenizer));
+        self
+    }
+
+    /// Call `hook` with the root id and the posts of every thread before it is cleaned
+    ///
+    /// The hook returns the posts to keep, which it may change, or `None` to drop the thread.
+    pub fn thread_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&str, Vec<Post>) -> Option<Vec<Post>> + Send + Sync + 'static,
+    {
+        self.config.stages.thread_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Read the lines of the input files with `parser` instead of the JSONL of the dumps
+    pub fn parser<P: InputParser + 'static>(mut self, parser: P) -> Self {
+        self.config.stages.parser = Some(Arc::new(parser));
+        self
+    }
+
+    /// Send the JSON line of every record to `writer` instead of the output files, along with the
+    /// rejects of `--rejects`
+    ///
+    /// `writer` runs on a thread of its own, an error stops the writing and fails the run. Replaces
+    /// the sink.
+    pub fn writer<F>(mut self, writer: F) -> Self
+    where
+        F: FnMut(&Route, &str) -> std::io::Result<()> + Send + 'static,
+    {
+        self.sink = Some(Box::new(WriterSink(Box::new(writer))));
+        self
+    }
+
+    /// Send every record to `sink` instead of the output files, replaces the writer
+    ///
+    /// The sink runs on a thread of its own and is closed after the last record.
+    pub fn sink<S: OutputSink + 'static>(mut self, sink: S) -> Self {
+        self.sink = Some(Box::new(sink));
+        self
+    }
+
+    pub fn build(self) -> Pipeline {
+        Pipeline {
+            config: self.config,
+            sink: self.sink,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_builder() {
+        let config = Config {
+            batch_size: 7,
+            ..Default::default()
+        };
+        let pipeline = Pipeline::new()
+            .cleaner(|text| text.to_uppercase())
+            .config(config)
+            .input("main_folder")
+            .writer(|_, _| Ok(()))
+            .build();
+        assert_eq!(pipeline.config().input, PathBuf::from("main_folder"));
+        assert_eq!(pipeline.config().batch_size, 7);
+        let cleaner = pipeline.config().stages.cleaner.as_ref().unwrap();
+        assert_eq!(cleaner("a"), "A");
+        assert!(pipeline.config().stages.tokenizer.is_none());
+        assert!(pipeline.config().stages.thread_hook.is_none());
+        assert!(pipeline.config().stages.parser.is_none());
+        assert!(pipeline.sink.is_some());
+    }
+}
@@ -0,0 +1,30 @@
+use clap::CommandFactory;
+use std::io;
+
+use crate::args::{Cli, CompletionsArgs, BIN_NAME};
+
+/// Run the `completions` subcommand, the script is printed to stdout
+pub fn run(args: &CompletionsArgs) -> io::Result<()> {
+    clap_complete::generate(args.shell, &mut Cli::command(), BIN_NAME, &mut io::stdout());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_completions() {
+        let mut command = Cli::command();
+        let mut script = Vec::new();
+        clap_complete::generate(
+            clap_complete::Shell::Bash,
+            &mut command,
+            "clean-reddit",
+            &mut script,
+        );
+        let script = String::from_utf8(script).unwrap();
+        assert!(script.contains("--max-memory-gb"));
+        assert!(script.contains("completions"));
+    }
+}
@@ -0,0 +1,264 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::args::MergeArgs;
+use crate::utils;
+use crate::utils::writer::{Provenance, PROVENANCE_FILE};
+
+/// Name of the manifest written next to the shards
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Fields of an output record that identify it
+#[derive(Deserialize)]
+struct RecordKey {
+    thread_id: String,
+    #[serde(default)]
+    chunk: Option<usize>,
+}
+
+/// One shard of the merged output
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Shard {
+    /// File name, relative to the output folder
+    pub file: String,
+    pub records: u64,
+    pub bytes: u64,
+}
+
+/// What was merged and where it went, saved as `manifest.json`
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct Manifest {
+    pub inputs: Vec<String>,
+    pub records: u64,
+    /// Records dropped because their `thread_id` and chunk were already written
+    pub duplicates: u64,
+    /// Lines that are not an output record, they are not written
+    pub invalid: u64,
+    pub shards: Vec<Shard>,
+    /// The `provenance.json` of the inputs that have one, in the order of the inputs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub provenance: Vec<Provenance>,
+}
+
+/// Writes lines into numbered shards, starting a new one when the next line would go over `limit`
+struct ShardWriter {
+    dir: PathBuf,
+    limit: u64,
+    writer: Option<BufWriter<File>>,
+    shards: Vec<Shard>,
+}
+
+impl ShardWriter {
+    fn new(dir: &Path, limit: u64) -> Self {
+        ShardWriter {
+            dir: dir.to_path_buf(),
+            limit,
+            writer: None,
+            shards: Vec::new(),
+        }
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        let bytes = line.len() as u64 + 1;
+        let full = self
+            .shards
+            .last()
+            .is_none_or(|shard| shard.records > 0 && shard.bytes + bytes > self.limit);
+        if full {
+            if let Some(mut writer) = self.writer.take() {
+                writer.flush()?;
+            }
+            let file = format!("shard_{:05}.jsonl", self.shards.len());
+            self.writer = Some(BufWriter::new(File::create(self.dir.join(&file))?));
+            self.shards.push(Shard {
+                file,
+                records: 0,
+                bytes: 0,
+            });
+        }
+        writeln!(self.writer.as_mut().unwrap(), "{}", line)?;
+        let shard = self.shards.last_mut().unwrap();
+        shard.records += 1;
+        shard.bytes += bytes;
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<Vec<Shard>> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.flush()?;
+        }
+        Ok(self.shards)
+    }
+}
+
+/// Merge the output folders `inputs` into shards of at most `shard_bytes` in `output`
+///
+/// The folders are read in the given order and their files in sorted order, so the shards are the
+/// same on every run. A record larger than `shard_bytes` gets a shard of its own.
+///
+/// # Arguments
+///
+/// * `inputs` - `&[String]` - The output folders of previous runs
+/// * `output` - `&Path` - The folder for the shards and the manifest, must exist
+/// * `shard_bytes` - `u64` - Target size of a shard
+/// * `dedup` - `bool` - Drop the records whose `thread_id` and chunk were already written
+///
+/// # Returns
+///
+/// * `io::Result<Manifest>` - The manifest, also saved as `manifest.json` in `output`
+pub fn merge(
+    inputs: &[String],
+    output: &Path,
+    shard_bytes: u64,
+    dedup: bool,
+) -> io::Result<Manifest> {
+    let mut manifest = Manifest {
+        inputs: inputs.to_vec(),
+        ..Default::default()
+    };
+    let mut seen: HashSet<(String, Option<usize>)> = HashSet::new();
+    let mut shards = ShardWriter::new(output, shard_bytes);
+    for input in inputs {
+        let provenance = Path::new(input).join(PROVENANCE_FILE);
+        if provenance.is_file() {
+            let provenance = serde_json::from_reader(BufReader::new(File::open(provenance)?))?;
+            manifest.provenance.push(provenance);
+        }
+        for path in utils::file::output_files(Path::new(input))? {
+            let reader = BufReader::new(File::open(path)?);
+            for line in reader.lines() {
+                let line = line?;
+                let Ok(key) = serde_json::from_str::<RecordKey>(&line) else {
+                    manifest.invalid += 1;
+                    continue;
+                };
+                if dedup && !seen.insert((key.thread_id, key.chunk)) {
+                    manifest.duplicates += 1;
+                    continue;
+                }
+                shards.write(&line)?;
+                manifest.records += 1;
+            }
+        }
+    }
+    manifest.shards = shards.finish()?;
+
+    let mut writer = BufWriter::new(File::create(output.join(MANIFEST_FILE))?);
+    serde_json::to_writer_pretty(&mut writer, &manifest)?;
+    writeln!(writer)?;
+    writer.flush()?;
+    Ok(manifest)
+}
+
+/// Run the `merge` subcommand
+///
+/// With `--safe` the output folder must be empty, otherwise the shards and manifest of a previous
+/// merge are replaced.
+pub fn run(args: &MergeArgs) -> io::Result<()> {
+    let output = Path::new(&args.output);
+    fs::create_dir_all(output)?;
+    let canonical = fs::canonicalize(output)?;
+    for input in &args.input {
+        if fs::canonicalize(input)? == canonical {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("The output folder `{}` is also an input", args.output),
+            ));
+        }
+    }
+    let existing: Vec<PathBuf> = fs::read_dir(output)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<_>>()?;
+    if args.safe && !existing.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "Output folder is not empty, you can run with `--safe false` to overwrite the files.",
+        ));
+    }
+    for path in existing {
+        let name = path.file_name().unwrap().to_string_lossy();
+        if name.starts_with("shard_") && name.ends_with(".jsonl") || name == MANIFEST_FILE {
+            fs::remove_file(&path)?;
+        }
+    }
+
+    let shard_bytes = args.shard_size as u64 * 1024_u64.pow(2);
+    let manifest = merge(&args.input, output, shard_bytes, args.dedup)?;
+    println!(
+        "Merged {} records into {} shards at `{}`",
+        manifest.records,
+        manifest.shards.len(),
+        args.output
+    );
+    if args.dedup {
+        println!("Duplicate records dropped: {}", manifest.duplicates);
+    }
+    if manifest.invalid > 0 {
+        println!("Lines that are not records: {}", manifest.invalid);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_merge() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        let record = |id: &str, chunk: Option<usize>| match chunk {
+            Some(chunk) => {
+                format!(r#"{{"raw_content":"text","thread_id":"{id}","chunk":{chunk}}}"#)
+            }
+            None => format!(r#"{{"raw_content":"text","thread_id":"{id}"}}"#),
+        };
+        let first = dir.join("first");
+        let second = dir.join("second");
+        let output = dir.join("merged");
+        for folder in [&first, &second, &output] {
+            fs::create_dir(folder).unwrap();
+        }
+        let lines = [
+            record("1", None),
+            record("2", Some(0)),
+            record("2", Some(1)),
+        ];
+        fs::write(first.join("all.jsonl"), lines.join("\n")).unwrap();
+        let lines = [record("1", None), record("3", None), "cut".to_string()];
+        fs::write(second.join("all.jsonl"), lines.join("\n")).unwrap();
+        let provenance = Provenance {
+            license: Some("CC-BY-4.0".to_string()),
+            ..Default::default()
+        };
+        provenance.save(&second).unwrap();
+
+        let inputs = vec![
+            first.to_string_lossy().into_owned(),
+            second.to_string_lossy().into_owned(),
+        ];
+        // Two records fit in a shard
+        let limit = 2 * (record("1", None).len() as u64 + 1) + 20;
+        let manifest = merge(&inputs, &output, limit, true).unwrap();
+        assert_eq!(manifest.records, 4);
+        assert_eq!(manifest.duplicates, 1);
+        assert_eq!(manifest.invalid, 1);
+        assert_eq!(manifest.shards.len(), 2);
+        assert_eq!(manifest.shards[0].records, 2);
+        let shard = fs::read_to_string(output.join(&manifest.shards[1].file)).unwrap();
+        assert_eq!(shard.lines().last().unwrap(), record("3", None));
+        assert_eq!(shard.len() as u64, manifest.shards[1].bytes);
+        assert_eq!(manifest.provenance, vec![provenance]);
+        let saved: Manifest =
+            serde_json::from_str(&fs::read_to_string(output.join(MANIFEST_FILE)).unwrap()).unwrap();
+        assert_eq!(saved, manifest);
+
+        let manifest = merge(&inputs, &output, limit, false).unwrap();
+        assert_eq!(manifest.records, 5);
+    }
+}
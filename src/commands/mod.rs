@@ -0,0 +1,5 @@
+pub mod completions;
+pub mod merge;
+pub mod sample;
+pub mod stats;
+pub mod validate;
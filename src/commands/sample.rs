@@ -0,0 +1,102 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::args::SampleArgs;
+use crate::utils;
+
+/// Pick `count` records of the output folder uniformly at random, in one pass
+///
+/// Reservoir sampling, only `count` records are kept in memory whatever the size of the folder.
+/// Lines that are not JSON are never picked. The files are read in sorted order, so the same
+/// `seed` on the same folder always picks the same records.
+///
+/// # Arguments
+///
+/// * `folder` - `&Path` - The output folder of a previous run
+/// * `count` - `usize` - Number of records to keep, all of them if the folder has fewer
+/// * `seed` - `u64` - Seed of the random generator
+///
+/// # Returns
+///
+/// * `io::Result<Vec<String>>` - The sampled lines, in the order they are in the folder
+pub fn sample(folder: &Path, count: usize, seed: u64) -> io::Result<Vec<String>> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut reservoir: Vec<(u64, String)> = Vec::with_capacity(count);
+    let mut seen: u64 = 0;
+    for path in utils::file::output_files(folder)? {
+        let reader = BufReader::new(File::open(path)?);
+        for line in reader.lines() {
+            let line = line?;
+            if serde_json::from_str::<serde::de::IgnoredAny>(&line).is_err() {
+                continue;
+            }
+            if reservoir.len() < count {
+                reservoir.push((seen, line));
+            } else {
+                let slot = rng.gen_range(0..=seen);
+                if slot < count as u64 {
+                    reservoir[slot as usize] = (seen, line);
+                }
+            }
+            seen += 1;
+        }
+    }
+    reservoir.sort_unstable_by_key(|(index, _)| *index);
+    Ok(reservoir.into_iter().map(|(_, line)| line).collect())
+}
+
+/// Run the `sample` subcommand
+pub fn run(args: &SampleArgs) -> io::Result<()> {
+    let seed = args.seed.unwrap_or_else(rand::random);
+    let lines = sample(Path::new(&args.input), args.count, seed)?;
+
+    let mut writer = BufWriter::new(File::create(&args.output)?);
+    for line in &lines {
+        writeln!(writer, "{}", line)?;
+    }
+    writer.flush()?;
+    println!(
+        "Sampled {} documents to `{}` with seed {}",
+        lines.len(),
+        args.output,
+        seed
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_sample() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        let lines: Vec<String> = (0..100)
+            .map(|i| format!(r#"{{"thread_id":"{i}"}}"#))
+            .collect();
+        fs::write(dir.join("all.jsonl"), lines[..50].join("\n")).unwrap();
+        fs::write(
+            dir.join("oversized.jsonl"),
+            lines[50..].join("\n") + "\ncut",
+        )
+        .unwrap();
+
+        let picked = sample(dir, 10, 42).unwrap();
+        assert_eq!(picked.len(), 10);
+        assert!(picked.iter().all(|line| lines.contains(line)));
+        assert_eq!(picked, sample(dir, 10, 42).unwrap());
+        assert_ne!(picked, sample(dir, 10, 7).unwrap());
+
+        // Fewer records than asked for, every record is kept in order
+        let all = sample(dir, 1000, 42).unwrap();
+        assert_eq!(all.len(), 100);
+        assert_eq!(all[..50], lines[..50]);
+    }
+}
@@ -0,0 +1,290 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::args::StatsArgs;
+use crate::utils;
+
+/// Fields of an output record that are counted
+#[derive(Deserialize)]
+struct Record {
+    #[serde(default)]
+    length: usize,
+    #[serde(default)]
+    raw_content: String,
+    #[serde(default)]
+    source: String,
+    /// `length_<label>` of every tokenizer, and the fields that are not counted
+    #[serde(flatten)]
+    extra: HashMap<String, serde_json::Value>,
+}
+
+/// Counts of the records of one source
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Totals {
+    pub documents: u64,
+    /// Sum of `length`, tokens of the first tokenizer or words without a tokenizer
+    pub tokens: u64,
+    /// Whitespace separated words of `raw_content`
+    pub words: u64,
+    /// Sum of every `length_<label>` field
+    pub tokenizer_tokens: BTreeMap<String, u64>,
+    /// `length` of every record, sorted by [finish](#method.finish)
+    pub lengths: Vec<usize>,
+}
+
+impl Totals {
+    fn add(&mut self, record: Record) {
+        self.documents += 1;
+        self.tokens += record.length as u64;
+        self.words += record.raw_content.split_whitespace().count() as u64;
+        self.lengths.push(record.length);
+        for (key, value) in record.extra {
+            if let (Some(label), Some(length)) = (key.strip_prefix("length_"), value.as_u64()) {
+                *self.tokenizer_tokens.entry(label.to_string()).or_default() += length;
+            }
+        }
+    }
+
+    fn merge(&mut self, other: Totals) {
+        self.documents += other.documents;
+        self.tokens += other.tokens;
+        self.words += other.words;
+        self.lengths.extend(other.lengths);
+        for (label, tokens) in other.tokenizer_tokens {
+            *self.tokenizer_tokens.entry(label).or_default() += tokens;
+        }
+    }
+
+    /// Sort the lengths, needed before [percentile](#method.percentile)
+    fn finish(&mut self) {
+        self.lengths.sort_unstable();
+    }
+
+    /// Length below or at which `percentile` percent of the documents are, by nearest rank
+    pub fn percentile(&self, percentile: f64) -> usize {
+        if self.lengths.is_empty() {
+            return 0;
+        }
+        let rank = (percentile / 100.0 * self.lengths.len() as f64).ceil() as usize;
+        self.lengths[rank.clamp(1, self.lengths.len()) - 1]
+    }
+
+    fn report(&self, percentiles: &[f64]) -> SourceReport {
+        SourceReport {
+            documents: self.documents,
+            tokens: self.tokens,
+            words: self.words,
+            tokenizer_tokens: self.tokenizer_tokens.clone(),
+            mean_length: match self.documents {
+                0 => 0.0,
+                documents => self.tokens as f64 / documents as f64,
+            },
+            min_length: self.lengths.first().copied().unwrap_or(0),
+            max_length: self.lengths.last().copied().unwrap_or(0),
+            percentiles: percentiles
+                .iter()
+                .map(|&p| (format!("p{}", p), self.percentile(p)))
+                .collect(),
+        }
+    }
+}
+
+/// Counts of one source as they are printed and saved
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SourceReport {
+    pub documents: u64,
+    pub tokens: u64,
+    pub words: u64,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub tokenizer_tokens: BTreeMap<String, u64>,
+    pub mean_length: f64,
+    pub min_length: usize,
+    pub max_length: usize,
+    pub percentiles: BTreeMap<String, usize>,
+}
+
+/// The report of an output folder
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Report {
+    pub files: usize,
+    /// Lines that are not an output record
+    pub invalid: u64,
+    pub sources: BTreeMap<String, SourceReport>,
+    pub total: SourceReport,
+}
+
+/// Count the records of a file per source, and the lines that are not records
+fn file_totals(path: &Path) -> io::Result<(BTreeMap<String, Totals>, u64)> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut sources: BTreeMap<String, Totals> = BTreeMap::new();
+    let mut invalid = 0;
+    for line in reader.lines() {
+        let line = line?;
+        match serde_json::from_str::<Record>(&line) {
+            Ok(record) => sources
+                .entry(record.source.clone())
+                .or_default()
+                .add(record),
+            Err(_) => invalid += 1,
+        }
+    }
+    Ok((sources, invalid))
+}
+
+/// Read every output file of `folder` and compute the report
+///
+/// # Arguments
+///
+/// * `folder` - `&Path` - The output folder of a previous run
+/// * `percentiles` - `&[f64]` - The length percentiles to report
+///
+/// # Returns
+///
+/// * `io::Result<Report>` - The counts per source and in total
+pub fn folder_report(folder: &Path, percentiles: &[f64]) -> io::Result<Report> {
+    let files = utils::file::output_files(folder)?;
+    let counted: Vec<(BTreeMap<String, Totals>, u64)> = files
+        .par_iter()
+        .map(|path| file_totals(path))
+        .collect::<io::Result<_>>()?;
+
+    let mut sources: BTreeMap<String, Totals> = BTreeMap::new();
+    let mut invalid = 0;
+    for (file_sources, file_invalid) in counted {
+        invalid += file_invalid;
+        for (source, totals) in file_sources {
+            sources.entry(source).or_default().merge(totals);
+        }
+    }
+    let mut total = Totals::default();
+    for totals in sources.values_mut() {
+        totals.finish();
+        total.merge(totals.clone());
+    }
+    total.finish();
+
+    Ok(Report {
+        files: files.len(),
+        invalid,
+        sources: sources
+            .iter()
+            .map(|(source, totals)| (source.clone(), totals.report(percentiles)))
+            .collect(),
+        total: total.report(percentiles),
+    })
+}
+
+/// Run the `stats` subcommand, print one line per source and a total
+pub fn run(args: &StatsArgs) -> io::Result<()> {
+    let report = folder_report(Path::new(&args.input), &args.percentiles)?;
+    println!(
+        "Read {} files, {} lines are not records",
+        report.files, report.invalid
+    );
+
+    let header: Vec<String> = args.percentiles.iter().map(|p| format!("p{}", p)).collect();
+    println!(
+        "{:>10} {:>14} {:>14} {:>10} {:>8} {:>8} {}  source",
+        "documents",
+        "tokens",
+        "words",
+        "mean",
+        "min",
+        "max",
+        header
+            .iter()
+            .map(|p| format!("{:>8}", p))
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+    let rows = report
+        .sources
+        .iter()
+        .map(|(source, row)| (source.as_str(), row))
+        .chain(std::iter::once(("total", &report.total)));
+    for (source, row) in rows {
+        println!(
+            "{:>10} {:>14} {:>14} {:>10.1} {:>8} {:>8} {}  {}",
+            row.documents,
+            row.tokens,
+            row.words,
+            row.mean_length,
+            row.min_length,
+            row.max_length,
+            header
+                .iter()
+                .map(|p| format!("{:>8}", row.percentiles[p]))
+                .collect::<Vec<_>>()
+                .join(" "),
+            source
+        );
+    }
+    for (label, tokens) in &report.total.tokenizer_tokens {
+        println!("Tokens of {}: {}", label, tokens);
+    }
+
+    if let Some(path) = &args.json {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, &report)?;
+        writeln!(writer)?;
+        writer.flush()?;
+        println!("Saved the report to {}", path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::resume;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_folder_report() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        let record = |length: usize, text: &str, source: &str| {
+            format!(
+                r#"{{"length":{length},"raw_content":"{text}","thread_id":"1","source":"{source}","length_b":{}}}"#,
+                length * 2
+            )
+        };
+        let all = [
+            record(1, "one", "reddit"),
+            record(2, "one two", "reddit"),
+            record(10, "a b c", "forum"),
+            "not json".to_string(),
+        ];
+        fs::write(dir.join("all.jsonl"), all.join("\n")).unwrap();
+        fs::create_dir(dir.join("split")).unwrap();
+        fs::write(
+            dir.join("split").join("part.jsonl"),
+            record(3, "x y z", "reddit"),
+        )
+        .unwrap();
+        fs::write(dir.join(resume::STATE_FILE), "{}").unwrap();
+        fs::write(dir.join("token_lengths.json"), "[]").unwrap();
+
+        let report = folder_report(dir, &[50.0, 100.0]).unwrap();
+        assert_eq!(report.files, 2);
+        assert_eq!(report.invalid, 1);
+        let reddit = &report.sources["reddit"];
+        assert_eq!(reddit.documents, 3);
+        assert_eq!(reddit.tokens, 6);
+        assert_eq!(reddit.words, 6);
+        assert_eq!(reddit.tokenizer_tokens["b"], 12);
+        assert_eq!(reddit.percentiles["p50"], 2);
+        assert_eq!(report.sources["forum"].documents, 1);
+        assert_eq!(report.total.documents, 4);
+        assert_eq!(report.total.min_length, 1);
+        assert_eq!(report.total.max_length, 10);
+        assert_eq!(report.total.percentiles["p100"], 10);
+        assert_eq!(report.total.mean_length, 4.0);
+    }
+}
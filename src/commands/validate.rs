@@ -0,0 +1,201 @@
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use crate::args::ValidateArgs;
+use crate::error::{self, Error};
+use crate::experimental::graph::ThreadGraph;
+use crate::forum_thread::{CompactString, InputParser, JsonlParser};
+use crate::utils;
+
+/// What `validate` found in a folder
+#[derive(Debug, Default, PartialEq)]
+pub struct FolderReport {
+    pub folder: String,
+    pub files: usize,
+    pub lines: usize,
+    /// Lines that are not a post, they are skipped by `process`
+    pub invalid: usize,
+    pub posts: usize,
+    /// Posts whose id was already read, only the last one is kept by `process`
+    pub duplicates: usize,
+    /// Replies whose parent is not in the folder
+    pub missing_parents: usize,
+    pub cycles: usize,
+    /// Posts whose `root_post_id` is not the root of the thread they are in
+    pub root_mismatches: usize,
+}
+
+impl FolderReport {
+    /// Whether `process` would have to work around anything in the folder
+    pub fn is_clean(&self) -> bool {
+        self.invalid == 0
+            && self.duplicates == 0
+            && self.missing_parents == 0
+            && self.cycles == 0
+            && self.root_mismatches == 0
+    }
+}
+
+/// Read the `jsonl` files of a folder and check the posts without writing anything
+///
+/// # Arguments
+///
+/// * `folder` - `&Path` - The folder containing `jsonl` files
+///
+/// # Returns
+///
+/// * `error::Result<FolderReport>` - The counts of the folder, or why a file cannot be read
+pub fn validate_folder(folder: &Path) -> error::Result<FolderReport> {
+    let mut entries: Vec<PathBuf> = utils::file::folder_files(folder)?;
+    entries.sort();
+    let mut report = FolderReport {
+        folder: folder.display().to_string(),
+        files: entries.len(),
+        ..Default::default()
+    };
+
+    let mut ids: HashSet<CompactString> = HashSet::new();
+    let mut parents: Vec<CompactString> = Vec::new();
+    let mut threadgraph: ThreadGraph = ThreadGraph::new();
+    for entry in entries {
+        let read_file = |source| Error::ReadFile {
+            path: entry.clone(),
+            source,
+        };
+        let mut reader = BufReader::new(File::open(&entry).map_err(read_file)?);
+        let compression = utils::file::Compression::detect(reader.fill_buf().map_err(read_file)?);
+        let reader = compression.decoder(Box::new(reader)).map_err(read_file)?;
+        for line in reader.lines() {
+            let line = line.map_err(read_file)?;
+            report.lines += 1;
+            let Ok(Some(post)) = JsonlParser.parse(&line) else {
+                report.invalid += 1;
+                continue;
+            };
+            report.posts += 1;
+            if !ids.insert(post.id.clone()) {
+                report.duplicates += 1;
+            }
+            if post.parent_post_id != post.id {
+                parents.push(post.parent_post_id.clone());
+            }
+            threadgraph.add_post(post);
+        }
+    }
+
+    report.missing_parents = parents
+        .iter()
+        .filter(|parent| !ids.contains(*parent))
+        .count();
+    report.cycles = threadgraph.break_cycles();
+    report.root_mismatches = threadgraph.root_mismatches();
+    Ok(report)
+}
+
+/// Run the `validate` subcommand, print one line per folder and a total
+///
+/// A folder with a file that cannot be read is reported and left out of the table.
+pub fn run(args: &ValidateArgs) -> io::Result<()> {
+    let mut folders = utils::file::all_folders(&args.input)?;
+    folders.sort();
+    let reports: Vec<FolderReport> = folders
+        .par_iter()
+        .map(|folder| validate_folder(folder))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .filter_map(|report| report.map_err(error::report).ok())
+        .collect();
+
+    println!(
+        "{:>8} {:>10} {:>8} {:>10} {:>10} {:>8} {:>7} {:>10}  folder",
+        "files", "lines", "invalid", "posts", "duplicate", "orphan", "cycles", "mismatch"
+    );
+    let mut total = FolderReport {
+        folder: "total".to_string(),
+        ..Default::default()
+    };
+    for report in &reports {
+        print_report(report);
+        total.files += report.files;
+        total.lines += report.lines;
+        total.invalid += report.invalid;
+        total.posts += report.posts;
+        total.duplicates += report.duplicates;
+        total.missing_parents += report.missing_parents;
+        total.cycles += report.cycles;
+        total.root_mismatches += report.root_mismatches;
+    }
+    print_report(&total);
+    let unclean = reports.iter().filter(|report| !report.is_clean()).count();
+    println!("{} of {} folders need attention", unclean, reports.len());
+    Ok(())
+}
+
+fn print_report(report: &FolderReport) {
+    println!(
+        "{:>8} {:>10} {:>8} {:>10} {:>10} {:>8} {:>7} {:>10}  {}",
+        report.files,
+        report.lines,
+        report.invalid,
+        report.posts,
+        report.duplicates,
+        report.missing_parents,
+        report.cycles,
+        report.root_mismatches,
+        report.folder
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forum_thread::input_line;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_validate_folder() {
+        let temp_dir = TempDir::new().unwrap();
+        let folder = temp_dir.path().join("sub");
+        fs::create_dir_all(&folder).unwrap();
+        let line = |id: &str, parent: &str, root: &str| input_line(id, parent, root, id);
+        let first = [
+            line("1", "1", "1"),
+            line("2", "1", "1"),
+            // root_post_id does not match the thread it is in
+            line("3", "2", "9"),
+        ];
+        let second = [
+            line("2", "1", "1"),
+            // parent was never read
+            line("4", "5", "5"),
+            // cycle
+            line("7", "8", "7"),
+            line("8", "7", "7"),
+            "not json".to_string(),
+        ];
+        fs::write(folder.join("a.jsonl"), first.join("\n")).unwrap();
+        fs::write(folder.join("b.jsonl"), second.join("\n")).unwrap();
+
+        let report = validate_folder(&folder).unwrap();
+        assert_eq!(
+            report,
+            FolderReport {
+                folder: folder.display().to_string(),
+                files: 2,
+                lines: 8,
+                invalid: 1,
+                posts: 7,
+                duplicates: 1,
+                missing_parents: 1,
+                cycles: 1,
+                root_mismatches: 1,
+            }
+        );
+        assert!(!report.is_clean());
+    }
+}
@@ -0,0 +1,996 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::args;
+use crate::forum_thread::{InputParser, JsonlParser, Post};
+use crate::globals::HubOptions;
+use crate::utils::blocklist::{Allowlist, Blocklist};
+use crate::utils::histogram::DEFAULT_BUCKETS;
+use crate::utils::writer::{Provenance, CHANNEL_BATCH, WRITE_BUFFER_BYTES};
+
+/// What to do with documents longer than `max_tokens`
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Chunking {
+    /// Keep only the first `max_tokens` tokens
+    #[default]
+    Truncate,
+    /// Split into several records of at most `max_tokens` tokens
+    Split,
+}
+
+/// Order in which the posts of a thread are flattened into a document
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Traversal {
+    /// Depth first, each reply is followed by its own replies
+    #[default]
+    Dfs,
+    /// Breadth first, all direct replies come before the replies to them
+    Bfs,
+}
+
+/// Order of the replies to the same post
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReplyOrder {
+    /// The order in which the posts were read
+    #[default]
+    Insertion,
+    /// Oldest first by `created_utc` or `timestamp`, posts without one come last
+    Time,
+    /// Highest `score` or `upvotes` first, like "top comments", posts without one come last
+    Score,
+}
+
+/// What to do with the replies below `max_depth`
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DeepReplies {
+    /// Leave them out of the document
+    #[default]
+    Drop,
+    /// Replace them with a `[N more replies]` line after the deepest post that is kept
+    Count,
+}
+
+/// What to do with the posts after `max_posts`
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExtraPosts {
+    /// Leave them out
+    #[default]
+    Drop,
+    /// Write them as continuation documents of at most `max_posts` posts, with the id `<root>-<part>`
+    Continue,
+}
+
+/// What a document is made of
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Extract {
+    /// One document per thread with all its posts
+    #[default]
+    Tree,
+    /// One document per path from the root to a post without replies, for dialogue data
+    Paths,
+    /// One document per reply with its ancestors, from the root down to the reply, for reply generation
+    Context,
+}
+
+/// What stands in for a parent post that is missing from the dump
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissingParent {
+    /// An empty post, written as an empty first line
+    #[default]
+    Empty,
+    /// Nothing, the document starts with the replies to the missing post
+    Skip,
+    /// An empty post with the time, score, author and root of its first reply, so the thread root has metadata
+    CopyChild,
+    /// A post with the text `<missing parent>`
+    Marker,
+}
+
+/// Author, creation time and score of the posts, written in the `metadata` object of every record
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Metadata {
+    /// No `metadata` object
+    #[default]
+    None,
+    /// An `author`, `created_utc` and `score` array with a value, or null, for every post of the
+    /// record, see [PostsMetadata](../utils/writer/struct.PostsMetadata.html)
+    Posts,
+    /// The range of creation times, the total and highest score and the authors of the posts of the
+    /// record, see [ThreadMetadata](../utils/writer/struct.ThreadMetadata.html)
+    Thread,
+}
+
+/// What the filters on the posts, like `--after` and `--min-score`, leave out
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FilterScope {
+    /// Every post that does not pass, its replies are still written
+    #[default]
+    Posts,
+    /// The whole thread when its root post does not pass
+    Threads,
+}
+
+/// The creation times of the posts that are written, in seconds since the epoch
+///
+/// A post without a creation time is always kept.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DateRange {
+    /// Oldest time kept
+    pub after: Option<i64>,
+    /// First time left out
+    pub before: Option<i64>,
+    pub scope: FilterScope,
+}
+
+impl DateRange {
+    /// Whether the range leaves anything out
+    pub fn is_set(&self) -> bool {
+        self.after.is_some() || self.before.is_some()
+    }
+
+    /// Whether a post created at `created_utc` is in the range
+    pub fn contains(&self, created_utc: Option<f64>) -> bool {
+        let Some(created_utc) = created_utc else {
+            return true;
+        };
+        self.after.is_none_or(|after| created_utc >= after as f64)
+            && self.before.is_none_or(|before| created_utc < before as f64)
+    }
+}
+
+/// The score below which the posts are left out
+///
+/// A post without a score is always kept.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ScoreFilter {
+    pub min: Option<i64>,
+    pub scope: FilterScope,
+}
+
+impl ScoreFilter {
+    /// Whether a post with `score` is kept
+    pub fn passes(&self, score: Option<f64>) -> bool {
+        match (self.min, score) {
+            (Some(min), Some(score)) => score >= min as f64,
+            _ => true,
+        }
+    }
+}
+
+/// Secret key of the hashes that replace the post ids and authors in the output
+///
+/// The key is derived from the key given by the user, the same id always gets the same hash under
+/// the same key, so the records can still be joined, but the ids cannot be looked up without it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct HashKey([u8; blake3::KEY_LEN]);
+
+impl HashKey {
+    pub fn new(key: &str) -> Self {
+        HashKey(blake3::derive_key("forum-rs hash-ids", key.as_bytes()))
+    }
+
+    /// The keyed BLAKE3 hash of `id`, in hex
+    pub fn hash(&self, id: &str) -> String {
+        blake3::keyed_hash(&self.0, id.as_bytes())
+            .to_hex()
+            .to_string()
+    }
+}
+
+/// The key is not printed
+impl std::fmt::Debug for HashKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("HashKey(..)")
+    }
+}
+
+/// Which copy of a post is kept when its id is read more than once, from scraper retries or files
+/// that overlap
+///
+/// First and last are in reading order, by file name then line, whichever worker reads them. The
+/// copy that is not kept is dropped with its reply edge.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DuplicateIds {
+    FirstWins,
+    #[default]
+    LastWins,
+    /// Fail the folder, see [Error::DuplicateIds](../error/enum.Error.html)
+    Error,
+    /// The copy with the longest text, the first one on a tie
+    MergeLongestText,
+}
+
+/// What is done with an input line that is not valid UTF-8
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InvalidUtf8 {
+    /// The line is [rejected](../utils/rejects/fn.invalid_utf8.html) and the file goes on
+    SkipLine,
+    /// The invalid bytes are replaced with U+FFFD and the line is parsed
+    #[default]
+    Lossy,
+    /// The rest of the file is skipped and the file is reported, see
+    /// [Error::ReadFile](../error/enum.Error.html)
+    Error,
+}
+
+/// How the lines of the input files are read, see
+/// [read_lines](../utils/file/fn.read_lines.html)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReadOptions {
+    pub invalid_utf8: InvalidUtf8,
+    /// Lines longer than this many bytes, without the newline, are skipped without being kept in
+    /// memory
+    pub max_line_bytes: Option<usize>,
+}
+
+/// How a thread graph is flattened into a list of posts
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TraversalOptions {
+    pub strategy: Traversal,
+    pub reply_order: ReplyOrder,
+    /// Deepest reply level that is kept, the root post is at depth 0
+    pub max_depth: Option<usize>,
+    pub deep_replies: DeepReplies,
+    /// Maximum number of posts in a document, in traversal order
+    pub max_posts: Option<usize>,
+    pub extra_posts: ExtraPosts,
+    pub extract: Extract,
+    /// Compute the [ThreadStats](../utils/writer/struct.ThreadStats.html) of every thread
+    pub stats: bool,
+    /// Collect the author, time and score of the posts of every document
+    pub metadata: Metadata,
+    /// Collect the id and depth of the posts of every document, for the `composition` of the records
+    pub composition: bool,
+    pub dates: DateRange,
+    pub scores: ScoreFilter,
+    /// Hash the ids and authors of the documents, see [HashKey](struct.HashKey.html)
+    pub hash_ids: Option<HashKey>,
+    /// Leave out the threads whose root has at most this many words besides a link that a more
+    /// engaged thread shares, see [links](../utils/links/index.html)
+    pub link_reposts: Option<usize>,
+    pub missing_parent: MissingParent,
+    /// Roots per task of the parallel traversal, see
+    /// [roots_per_task](../experimental/traversal/fn.roots_per_task.html), adaptive by default
+    pub roots_per_task: Option<usize>,
+}
+
+/// File format of the exported thread graphs
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GraphFormat {
+    /// Graphviz DOT, `.dot`
+    #[default]
+    Dot,
+    /// GraphML, `.graphml`
+    Graphml,
+}
+
+/// Which thread graphs to write for inspection and where
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GraphExport {
+    pub dir: PathBuf,
+    /// Ids of the posts to export, with everything below them
+    pub thread_ids: Vec<String>,
+    pub format: GraphFormat,
+}
+
+/// On-disk partitioning of each folder to bound memory, see
+/// [process_buckets](../experimental/bucketed/fn.process_buckets.html)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Buckets {
+    pub count: usize,
+    /// Where the buckets are written, one subfolder per input folder
+    pub dir: PathBuf,
+}
+
+/// Buffering of the output files, see
+/// [write_routed_receiver](../utils/writer/fn.write_routed_receiver.html)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WriteBuffer {
+    /// Capacity of the buffer of each output file
+    pub bytes: usize,
+    /// Flush the output files at least this often, so they grow during a long run
+    pub flush_interval: Option<Duration>,
+}
+
+impl Default for WriteBuffer {
+    fn default() -> Self {
+        WriteBuffer {
+            bytes: WRITE_BUFFER_BYTES,
+            flush_interval: None,
+        }
+    }
+}
+
+/// Which root wins when a post's `root_post_id` disagrees with the reply graph
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RootSource {
+    /// Keep the threads of the reply graph, the mismatches are only reported
+    #[default]
+    Graph,
+    /// Group the posts by their declared `root_post_id`, as with `--group-by-root`
+    Declared,
+}
+
+/// How the copies of a document are found, see [dedup](../utils/dedup/index.html)
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Dedup {
+    /// Every document is written
+    #[default]
+    None,
+    /// Keep a hash of every document written, the copies are dropped
+    Exact,
+    /// Keep the hashes in a Bloom filter of a fixed size, see [BloomOptions](struct.BloomOptions.html)
+    Bloom,
+}
+
+/// A rule of the C4 filters, see [quality](../utils/quality/index.html)
+///
+/// The first four remove lines from the documents, the others drop whole documents.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum C4Rule {
+    /// Remove the lines that do not end with `.`, `!`, `?` or `"`, or that end with an ellipsis
+    TerminalPunctuation,
+    /// Remove the lines of fewer than 5 words
+    LineWords,
+    /// Remove the lines that mention javascript
+    Javascript,
+    /// Remove the lines about cookies, the privacy policy or the terms of use
+    Policy,
+    /// Drop the documents with "lorem ipsum"
+    LoremIpsum,
+    /// Drop the documents with a curly brace, mostly code
+    CurlyBrace,
+    /// Drop the documents of fewer than 3 sentences
+    Sentences,
+}
+
+impl C4Rule {
+    pub const ALL: [C4Rule; 7] = [
+        C4Rule::TerminalPunctuation,
+        C4Rule::LineWords,
+        C4Rule::Javascript,
+        C4Rule::Policy,
+        C4Rule::LoremIpsum,
+        C4Rule::CurlyBrace,
+        C4Rule::Sentences,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            C4Rule::TerminalPunctuation => "terminal_punctuation",
+            C4Rule::LineWords => "line_words",
+            C4Rule::Javascript => "javascript",
+            C4Rule::Policy => "policy",
+            C4Rule::LoremIpsum => "lorem_ipsum",
+            C4Rule::CurlyBrace => "curly_brace",
+            C4Rule::Sentences => "sentences",
+        }
+    }
+
+    /// Whether the rule removes lines rather than documents
+    pub fn removes_lines(self) -> bool {
+        matches!(
+            self,
+            C4Rule::TerminalPunctuation | C4Rule::LineWords | C4Rule::Javascript | C4Rule::Policy
+        )
+    }
+}
+
+/// A rule of the Gopher filters, see [quality](../utils/quality/index.html)
+///
+/// Every rule drops whole documents.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GopherRule {
+    /// Drop the documents of fewer than 50 or more than 100,000 words
+    WordCount,
+    /// Drop the documents whose words are shorter than 3 or longer than 10 characters on average
+    MeanWordLength,
+    /// Drop the documents with more than one `#` or ellipsis for ten words
+    SymbolRatio,
+    /// Drop the documents with more than 90% of lines starting with a bullet
+    BulletLines,
+    /// Drop the documents with more than 30% of lines ending with an ellipsis
+    EllipsisLines,
+    /// Drop the documents with less than 80% of words that have a letter
+    AlphabeticWords,
+    /// Drop the documents with fewer than 2 English stop words
+    StopWords,
+}
+
+impl GopherRule {
+    pub const ALL: [GopherRule; 7] = [
+        GopherRule::WordCount,
+        GopherRule::MeanWordLength,
+        GopherRule::SymbolRatio,
+        GopherRule::BulletLines,
+        GopherRule::EllipsisLines,
+        GopherRule::AlphabeticWords,
+        GopherRule::StopWords,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            GopherRule::WordCount => "word_count",
+            GopherRule::MeanWordLength => "mean_word_length",
+            GopherRule::SymbolRatio => "symbol_ratio",
+            GopherRule::BulletLines => "bullet_lines",
+            GopherRule::EllipsisLines => "ellipsis_lines",
+            GopherRule::AlphabeticWords => "alphabetic_words",
+            GopherRule::StopWords => "stop_words",
+        }
+    }
+}
+
+/// Which lines repeated within a document are removed, see [quality](../utils/quality/index.html)
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RepeatedLines {
+    /// Every line is kept
+    #[default]
+    None,
+    /// The lines equal to an earlier line, apart from the surrounding whitespace
+    Exact,
+    /// Also the lines whose word trigrams are close enough to those of an earlier line
+    Near,
+}
+
+/// What is done with the documents that have a term of the blocklist
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BlocklistAction {
+    /// Do not write them
+    #[default]
+    Drop,
+    /// Write them with the terms found in `blocklist`
+    Flag,
+}
+
+/// Heuristic filters on the cleaned text, see [quality](../utils/quality/index.html)
+#[derive(Clone, Debug, PartialEq)]
+pub struct QualityOptions {
+    /// The C4 rules that are applied, none by default
+    pub c4: Vec<C4Rule>,
+    /// The Gopher rules that are applied, after the C4 rules, none by default
+    pub gopher: Vec<GopherRule>,
+    pub repeated_lines: RepeatedLines,
+    /// Jaccard similarity of the word trigrams from which a line is a near repeat
+    pub repeated_line_similarity: f64,
+    /// Documents with more symbols per word are dropped
+    pub max_symbol_ratio: Option<f64>,
+    /// Documents with a larger share of characters that are not letters, digits or whitespace
+    /// are dropped
+    pub max_non_alphanumeric: Option<f64>,
+    /// Patterns the documents must have one of, see [blocklist](../utils/blocklist/index.html)
+    pub required: Option<Allowlist>,
+    /// Terms the documents must not have
+    pub blocklist: Option<Blocklist>,
+    pub blocklist_action: BlocklistAction,
+}
+
+impl Default for QualityOptions {
+    fn default() -> Self {
+        QualityOptions {
+            c4: Vec::new(),
+            gopher: Vec::new(),
+            repeated_lines: RepeatedLines::None,
+            repeated_line_similarity: 0.8,
+            max_symbol_ratio: None,
+            max_non_alphanumeric: None,
+            required: None,
+            blocklist: None,
+            blocklist_action: BlocklistAction::Drop,
+        }
+    }
+}
+
+/// Size of the Bloom filter of `Dedup::Bloom`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BloomOptions {
+    pub bytes: u64,
+    /// Share of the new documents taken for copies once the filter holds as many documents as its
+    /// size allows, it grows past that
+    pub false_positive_rate: f64,
+}
+
+impl Default for BloomOptions {
+    fn default() -> Self {
+        BloomOptions {
+            bytes: 256 * 1024_u64.pow(2),
+            false_positive_rate: 0.001,
+        }
+    }
+}
+
+/// What to do with documents over `oversized_tokens`
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OversizedPolicy {
+    /// Write them to `oversized.jsonl` instead of `all.jsonl`
+    #[default]
+    Route,
+    /// Do not write them at all
+    Drop,
+}
+
+/// Where the [Provenance](../utils/writer/struct.Provenance.html) of a run is written
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProvenanceTarget {
+    /// `provenance.json` in the output folder
+    #[default]
+    Manifest,
+    /// A `provenance` object on every record
+    Records,
+    /// Both
+    Both,
+}
+
+/// Format of the log events written to stderr
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One human readable line per event
+    #[default]
+    Text,
+    /// One JSON object per event, with the folder span and fields of the event
+    Json,
+}
+
+/// How the progress of a run is shown
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProgressFormat {
+    /// A line on stdout updated in place
+    #[default]
+    Bar,
+    /// One JSON object per update on stderr, for scripts and schedulers
+    Json,
+    /// Nothing
+    None,
+}
+
+/// What to write as the content of each record
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputContent {
+    /// The cleaned text in `raw_content`
+    #[default]
+    Text,
+    /// The token ids in `input_ids`, `raw_content` is left empty
+    Ids,
+    /// Both the text and the token ids
+    Both,
+}
+
+/// How the work of a run is spread over the workers
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Schedule {
+    /// The files of every folder in one queue, each folder is built in shards by `root_post_id`
+    #[default]
+    Files,
+    /// One folder per worker, the files of a folder are read in parallel
+    Folders,
+}
+
+/// Models loaded once at the start of a run, see [globals](../globals/index.html)
+#[derive(Clone, Debug)]
+pub struct Models {
+    /// Tokenizer names or `tokenizer.json` paths, optionally `label=name`
+    pub tokenizers: Vec<String>,
+    pub hub: HubOptions,
+    /// Let the tokenizer parallelize a batch itself, `None` to follow `TOKENIZERS_PARALLELISM`
+    pub tokenizer_parallelism: Option<bool>,
+    /// Size of the dedicated pool for batch tokenization, `None` to use the folder pool
+    pub tokenizer_threads: Option<usize>,
+    /// Upper bounds of the token length histogram
+    pub histogram_buckets: Vec<usize>,
+    pub kenlm_model: Option<String>,
+}
+
+impl Default for Models {
+    fn default() -> Self {
+        Models {
+            tokenizers: Vec::new(),
+            hub: HubOptions::default(),
+            tokenizer_parallelism: None,
+            tokenizer_threads: None,
+            histogram_buckets: DEFAULT_BUCKETS.to_vec(),
+            kenlm_model: None,
+        }
+    }
+}
+
+/// Cleans the text of a post, in place of the built-in regexes
+pub type Cleaner = dyn Fn(&str) -> String + Send + Sync;
+
+/// Token ids of every document of a batch, in the same order, in place of the tokenizers of
+/// [Models](struct.Models.html)
+pub type Tokenizer = dyn Fn(&[String]) -> Vec<Vec<u32>> + Send + Sync;
+
+/// Called with the root id and the posts of every thread before they are cleaned, in traversal
+/// order, returns the posts to keep or `None` to drop the thread
+///
+/// The posts can be changed, removed or added, they are linked again through `parent_post_id`.
+pub type ThreadHook = dyn Fn(&str, Vec<Post>) -> Option<Vec<Post>> + Send + Sync;
+
+/// Stages of a [Pipeline](../builder/struct.PipelineBuilder.html) that replace the built-in ones,
+/// `None` keeps the built-in stage
+#[derive(Clone, Default)]
+pub struct Stages {
+    pub cleaner: Option<Arc<Cleaner>>,
+    /// Chunking with `max_tokens` still cuts on the built-in tokenizer, or on words without one
+    pub tokenizer: Option<Arc<Tokenizer>>,
+    pub thread_hook: Option<Arc<ThreadHook>>,
+    /// Reads the lines of the input files, [JsonlParser](../forum_thread/struct.JsonlParser.html)
+    /// when `None`
+    pub parser: Option<Arc<dyn InputParser>>,
+}
+
+impl Stages {
+    /// The parser of the input lines, the built-in one unless replaced
+    pub fn parser(&self) -> &dyn InputParser {
+        self.parser.as_deref().unwrap_or(&JsonlParser)
+    }
+}
+
+impl fmt::Debug for Stages {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Stages")
+            .field("cleaner", &self.cleaner.is_some())
+            .field("tokenizer", &self.tokenizer.is_some())
+            .field("thread_hook", &self.thread_hook.is_some())
+            .field("parser", &self.parser.is_some())
+            .finish()
+    }
+}
+
+/// Options of a run, from the input and output folders to how reconstructed threads are turned
+/// into output records
+///
+/// Built once from the [ProcessArgs](../args/struct.ProcessArgs.html) by the command line, or
+/// directly by a library user, and passed down by reference, so the processing functions do not
+/// need to know about the command line.
+///
+/// # Example
+///
+/// ```no_run
+/// # use std::path::PathBuf;
+/// # use forum_rs::Config;
+/// let config = Config {
+///     input: PathBuf::from("main_folder"),
+///     output: PathBuf::from("output"),
+///     use_sentencepiece: true,
+///     ..Default::default()
+/// };
+/// forum_rs::run(config)?;
+/// # Ok::<(), forum_rs::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The root folder, with a subfolder of `jsonl` files per forum
+    pub input: PathBuf,
+    /// Where the output files are written
+    pub output: PathBuf,
+    /// Refuse an output folder that is not empty
+    pub safe: bool,
+    /// Create the output folder with `safe` when it does not exist, it is always created without
+    pub create_output: bool,
+    pub progress: ProgressFormat,
+    pub models: Models,
+    /// Whether to count tokens with the tokenizer in `globals`, the name does not mean that it
+    /// will use sentencepiece. If false, words split by whitespace are counted instead.
+    pub use_sentencepiece: bool,
+    /// The source of the data. This is just for labelling. `{folder}` is replaced by the name of
+    /// the subfolder, see [source_of](#method.source_of)
+    pub source: String,
+    /// The source of the subfolders by name, the others use `source`
+    pub source_map: BTreeMap<String, String>,
+    /// License, crawl date, version and config hash of the run, nothing is written without it
+    pub provenance: Option<Provenance>,
+    pub provenance_target: ProvenanceTarget,
+    /// Documents with a KenLM perplexity above this are dropped
+    pub max_perplexity: Option<f64>,
+    /// Positive, strictly increasing perplexities that split the documents into buckets, each written to a folder of
+    /// its own, see [route](../utils/processing/fn.route.html)
+    pub perplexity_buckets: Vec<f64>,
+    pub quality: QualityOptions,
+    /// Drop the copies of a document already written, see [dedup](../utils/dedup/index.html)
+    pub dedup: Dedup,
+    pub bloom: BloomOptions,
+    /// Where the hashes of `dedup` are loaded from and saved to, see
+    /// [load](../utils/dedup/fn.load.html)
+    pub dedup_index: Option<PathBuf>,
+    /// Whether to record the length of every post in addition to the document length
+    pub post_lengths: bool,
+    /// Number of documents tokenized together with `encode_batch`
+    pub batch_size: usize,
+    /// Token budget per document, longer documents are handled according to `chunking`
+    pub max_tokens: Option<usize>,
+    pub chunking: Chunking,
+    /// Number of tokens repeated at the start of each split chunk from the end of the previous one
+    pub chunk_overlap: usize,
+    /// Whether to write text, token ids or both, ids require a tokenizer
+    pub output_content: OutputContent,
+    /// Documents longer than this, after chunking, are handled according to `oversized`
+    pub oversized_tokens: Option<usize>,
+    pub oversized: OversizedPolicy,
+    /// How the lines of the input files are read
+    pub read: ReadOptions,
+    /// Order of the posts when a thread is flattened
+    pub traversal: TraversalOptions,
+    /// Group the posts by `root_post_id` instead of building the reply graph
+    pub group_by_root: bool,
+    /// Thread graphs to export, only used when the reply graph is built
+    pub export_graph: Option<GraphExport>,
+    /// Build the reply graph of a folder in this many shards by `root_post_id`, without a shared lock
+    pub graph_shards: Option<usize>,
+    /// Report the posts whose `root_post_id` is not the root found in the reply graph
+    pub validate_roots: Option<RootSource>,
+    /// Which copy of a post id read more than once is kept in the reply graph
+    pub duplicate_ids: DuplicateIds,
+    /// Send the records of each folder at once and record the completed folders, see
+    /// [resume](../utils/resume/index.html)
+    pub resume: bool,
+    /// Build the threads from the files of every folder at once, for threads split across folders
+    pub cross_folder: bool,
+    /// Build the threads one on-disk bucket at a time instead of the whole folder at once
+    pub buckets: Option<Buckets>,
+    /// Share of the threads written to `val/` instead of `train/`, by the hash of the thread id
+    pub val_fraction: Option<f64>,
+    pub write_buffer: WriteBuffer,
+    /// Records sent to the writer in one message, see [Batcher](../utils/writer/struct.Batcher.html)
+    pub channel_batch: usize,
+    /// Approximate bytes the folders in progress and the queued records may hold before the next
+    /// folder waits, see [memory](../utils/memory/index.html)
+    pub max_memory: Option<u64>,
+    /// Write the unparsable lines and the filtered documents to `rejects/<folder>.jsonl`, see
+    /// [rejects](../utils/rejects/index.html)
+    pub rejects: bool,
+    /// Start the largest folders first, otherwise in name order
+    pub largest_first: bool,
+    /// Abandon a folder that takes longer, see
+    /// [process_folder_within](../pipeline/fn.process_folder_within.html)
+    pub folder_timeout: Option<Duration>,
+    /// Ignored with `buckets`, `group_by_root`, `resume`, `cross_folder` or `folder_timeout`,
+    /// which need a folder at a time
+    pub schedule: Schedule,
+    /// Read the files on a pool of this many threads while the global pool builds the folders
+    /// already read, with `Schedule::Files` only
+    pub read_threads: Option<usize>,
+    pub stages: Stages,
+}
+
+impl Config {
+    /// The `source` of the records of `folder`
+    ///
+    /// Looked up in `source_map` by the name of the folder, or `source` with `{folder}` replaced by
+    /// that name.
+    pub fn source_of(&self, folder: &Path) -> String {
+        let name = folder
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        match self.source_map.get(name.as_ref()) {
+            Some(source) => source.clone(),
+            None => self.source.replace("{folder}", &name),
+        }
+    }
+
+    /// BLAKE3 hash, in hex, of the options that decide the content of the records
+    ///
+    /// The folders, the performance options and the `provenance` itself are left out, two runs
+    /// with the same hash and version write the same records from the same input and
+    /// `dedup_index`. The options that change how the threads are split, the buckets, the graph
+    /// shards and the schedule, are part of it. The key of `hash_ids` is not.
+    pub fn config_hash(&self) -> String {
+        let options = format!(
+            "{:?}",
+            (
+                (&self.source, &self.source_map, self.use_sentencepiece),
+                (&self.models.tokenizers, &self.models.kenlm_model),
+                (self.max_perplexity, &self.perplexity_buckets, &self.quality),
+                (self.dedup, self.bloom),
+                (self.post_lengths, self.output_content),
+                (self.max_tokens, self.chunking, self.chunk_overlap),
+                (self.oversized_tokens, self.oversized),
+                (self.read, self.traversal, self.group_by_root),
+                (self.duplicate_ids, self.cross_folder, self.val_fraction),
+                (self.validate_roots, &self.dedup_index),
+                (
+                    self.buckets.as_ref().map(|buckets| buckets.count),
+                    self.graph_shards,
+                    self.schedule,
+                ),
+            )
+        );
+        blake3::hash(options.as_bytes()).to_hex().to_string()
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            input: PathBuf::new(),
+            output: PathBuf::new(),
+            safe: true,
+            create_output: false,
+            progress: ProgressFormat::Bar,
+            models: Models::default(),
+            use_sentencepiece: false,
+            source: "reddit".to_string(),
+            source_map: BTreeMap::new(),
+            provenance: None,
+            provenance_target: ProvenanceTarget::Manifest,
+            max_perplexity: None,
+            perplexity_buckets: Vec::new(),
+            quality: QualityOptions::default(),
+            dedup: Dedup::None,
+            bloom: BloomOptions::default(),
+            dedup_index: None,
+            post_lengths: false,
+            batch_size: 64,
+            max_tokens: None,
+            chunking: Chunking::Truncate,
+            chunk_overlap: 0,
+            output_content: OutputContent::Text,
+            oversized_tokens: None,
+            oversized: OversizedPolicy::Route,
+            read: ReadOptions::default(),
+            traversal: TraversalOptions::default(),
+            group_by_root: false,
+            export_graph: None,
+            graph_shards: None,
+            validate_roots: None,
+            duplicate_ids: DuplicateIds::LastWins,
+            resume: false,
+            cross_folder: false,
+            buckets: None,
+            val_fraction: None,
+            write_buffer: WriteBuffer::default(),
+            channel_batch: CHANNEL_BATCH,
+            max_memory: None,
+            rejects: false,
+            largest_first: true,
+            folder_timeout: None,
+            schedule: Schedule::Files,
+            read_threads: None,
+            stages: Stages::default(),
+        }
+    }
+}
+
+impl From<&args::ProcessArgs> for Config {
+    fn from(args: &args::ProcessArgs) -> Self {
+        let config = Config {
+            input: PathBuf::from(&args.input),
+            output: PathBuf::from(&args.output),
+            safe: args.safe,
+            create_output: args.create_output,
+            progress: args.progress,
+            models: Models {
+                tokenizers: args.tokenizer.clone(),
+                hub: HubOptions {
+                    offline: args.offline,
+                    cache_dir: args.hf_cache_dir.as_ref().map(PathBuf::from),
+                },
+                tokenizer_parallelism: args.tokenizer_parallelism,
+                tokenizer_threads: args.tokenizer_threads,
+                histogram_buckets: args.histogram_buckets.clone(),
+                kenlm_model: args.kenlm_model.clone(),
+            },
+            use_sentencepiece: !args.tokenizer.is_empty(),
+            source: args.source.clone(),
+            source_map: args.source_map.clone().unwrap_or_default(),
+            provenance: None,
+            provenance_target: ProvenanceTarget::Manifest,
+            max_perplexity: args.max_perplexity,
+            perplexity_buckets: {
+                let mut buckets = args.perplexity_buckets.clone();
+                buckets.sort_by(f64::total_cmp);
+                buckets.dedup();
+                buckets
+            },
+            quality: QualityOptions {
+                c4: match &args.c4 {
+                    Some(rules) if rules.is_empty() => C4Rule::ALL.to_vec(),
+                    Some(rules) => rules.clone(),
+                    None => Vec::new(),
+                },
+                gopher: match &args.gopher {
+                    Some(rules) if rules.is_empty() => GopherRule::ALL.to_vec(),
+                    Some(rules) => rules.clone(),
+                    None => Vec::new(),
+                },
+                repeated_lines: args.repeated_lines,
+                repeated_line_similarity: args.repeated_line_similarity,
+                max_symbol_ratio: args.max_symbol_ratio,
+                max_non_alphanumeric: args.max_non_alphanumeric,
+                // The patterns are checked by the parser of the arguments
+                required: (!args.require_pattern.is_empty())
+                    .then(|| Allowlist::from(args.require_pattern.clone())),
+                blocklist: args.blocklist.clone(),
+                blocklist_action: args.blocklist_action,
+            },
+            dedup: args.dedup,
+            bloom: BloomOptions {
+                bytes: args.bloom_memory_mb * 1024_u64.pow(2),
+                false_positive_rate: args.bloom_fp_rate,
+            },
+            dedup_index: args.dedup_index.as_ref().map(PathBuf::from),
+            post_lengths: args.post_lengths,
+            batch_size: args.batch_size,
+            max_tokens: args.max_tokens,
+            chunking: args.chunking,
+            chunk_overlap: args.chunk_overlap,
+            output_content: args.output_content,
+            oversized_tokens: args.oversized_tokens,
+            oversized: args.oversized,
+            read: ReadOptions {
+                invalid_utf8: args.invalid_utf8,
+                max_line_bytes: args.max_line_bytes,
+            },
+            traversal: TraversalOptions {
+                strategy: args.traversal,
+                reply_order: args.reply_order,
+                max_depth: args.max_depth,
+                deep_replies: args.deep_replies,
+                max_posts: args.max_posts_per_thread,
+                extra_posts: args.extra_posts,
+                extract: args.extract,
+                stats: args.thread_stats,
+                metadata: args.metadata,
+                composition: args.composition,
+                dates: DateRange {
+                    after: args.after,
+                    before: args.before,
+                    scope: args.date_scope,
+                },
+                scores: ScoreFilter {
+                    min: args.min_score,
+                    scope: args.score_scope,
+                },
+                hash_ids: args.hash_ids.as_deref().map(HashKey::new),
+                link_reposts: args.drop_link_reposts,
+                missing_parent: args.missing_parent,
+                roots_per_task: args.roots_per_task,
+            },
+            group_by_root: args.group_by_root,
+            export_graph: args.export_graph.as_ref().map(|dir| GraphExport {
+                dir: PathBuf::from(dir),
+                thread_ids: args.thread_id.clone(),
+                format: args.graph_format,
+            }),
+            graph_shards: args.graph_shards,
+            validate_roots: args.validate_roots,
+            duplicate_ids: args.duplicate_ids,
+            resume: args.resume,
+            cross_folder: args.cross_folder,
+            buckets: args.buckets.map(|count| Buckets {
+                count,
+                dir: match &args.bucket_dir {
+                    Some(dir) => PathBuf::from(dir),
+                    None => Path::new(&args.output).join(".buckets"),
+                },
+            }),
+            val_fraction: args.val_fraction,
+            write_buffer: WriteBuffer {
+                bytes: args.write_buffer_bytes,
+                flush_interval: args.flush_interval.map(Duration::from_secs_f64),
+            },
+            channel_batch: args.channel_batch,
+            max_memory: args.max_memory_gb.map(|gb| (gb * 1024_f64.powi(3)) as u64),
+            rejects: args.rejects,
+            largest_first: args.largest_first,
+            folder_timeout: args
+                .folder_timeout
+                .map(|minutes| Duration::from_secs_f64(minutes * 60.0)),
+            schedule: args.schedule,
+            read_threads: args.read_threads,
+            stages: Stages::default(),
+        };
+        let wanted =
+            args.provenance.is_some() || args.license.is_some() || args.crawl_date.is_some();
+        Config {
+            provenance: wanted
+                .then(|| Provenance::new(&config, args.license.clone(), args.crawl_date.clone())),
+            provenance_target: args.provenance.unwrap_or_default(),
+            ..config
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+/// Exit status of the process
+///
+/// Usage errors use the same code as the command line errors reported by clap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// Everything was processed
+    Success = 0,
+    /// The run stopped, or its output is incomplete because the writer failed
+    Fatal = 1,
+    /// The options or the output folder do not allow the run to start
+    Usage = 2,
+    /// The run finished, but some files or folders were skipped
+    Partial = 3,
+    /// The run was stopped by Ctrl-C or SIGTERM, as a shell reports a process killed by SIGINT
+    Interrupted = 130,
+}
+
+impl From<Exit> for ExitCode {
+    fn from(exit: Exit) -> Self {
+        ExitCode::from(exit as u8)
+    }
+}
+
+/// Errors of the processing that do not stop the run
+///
+/// A file or folder that fails is [reported](fn.report.html) and skipped, the failures are listed
+/// at the end of the run.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("unable to read the folder `{}`: {source}", path.display())]
+    ReadFolder { path: PathBuf, source: io::Error },
+    #[error("unable to read the file `{}`: {source}", path.display())]
+    ReadFile { path: PathBuf, source: io::Error },
+    #[error("unable to read the dedup index `{}`: {source}", path.display())]
+    ReadIndex { path: PathBuf, source: io::Error },
+    #[error("unable to read the model `{}`: {source}", path.display())]
+    ReadModel { path: PathBuf, source: io::Error },
+    #[error("unable to write the buckets of `{}`: {source}", path.display())]
+    Buckets { path: PathBuf, source: io::Error },
+    #[error("processing `{}` panicked: {message}", path.display())]
+    Panic { path: PathBuf, message: String },
+    #[error("{count} duplicate post ids in `{}`", path.display())]
+    DuplicateIds { path: PathBuf, count: usize },
+    #[error("abandoned `{}` after {:?}", path.display(), timeout)]
+    Timeout { path: PathBuf, timeout: Duration },
+    #[error("unable to write the output to `{}`: {source}", path.display())]
+    Write { path: PathBuf, source: io::Error },
+    #[error("{0}")]
+    Usage(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Exit status of a run stopped by this error
+    pub fn exit(&self) -> Exit {
+        match self {
+            Error::Usage(_) => Exit::Usage,
+            _ => Exit::Fatal,
+        }
+    }
+}
+
+/// Failures reported so far, in the order they happened
+///
+/// Taken even when poisoned, a folder that panics must not fail the folders after it.
+static FAILURES: Mutex<Vec<Error>> = Mutex::new(Vec::new());
+
+/// Log a failure and keep it for the summary at the end of the run
+pub fn report(error: Error) {
+    tracing::warn!(%error, "Skipped");
+    FAILURES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(error);
+}
+
+/// Run `f` and turn a panic into an [Error::Panic](enum.Error.html#variant.Panic) for `path`
+///
+/// The panic message is still printed by the panic hook when it happens, with its location.
+///
+/// # Example
+///
+/// ```ignore
+/// if let Err(e) = catch_panic(folder, || process_folder(folder, &config, data_tx.clone())) {
+///     report(e);
+/// }
+/// ```
+pub fn catch_panic<T, F: FnOnce() -> Result<T>>(path: &Path, f: F) -> Result<T> {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let message = match (
+            payload.downcast_ref::<&str>(),
+            payload.downcast_ref::<String>(),
+        ) {
+            (Some(message), _) => message.to_string(),
+            (_, Some(message)) => message.clone(),
+            _ => "unknown panic".to_string(),
+        };
+        Err(Error::Panic {
+            path: path.to_path_buf(),
+            message,
+        })
+    })
+}
+
+/// The failures reported so far, the list is emptied
+pub fn take_failures() -> Vec<Error> {
+    std::mem::take(&mut *FAILURES.lock().unwrap_or_else(PoisonError::into_inner))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_report() {
+        let error = Error::ReadFile {
+            path: PathBuf::from("missing/file.jsonl"),
+            source: io::Error::new(io::ErrorKind::NotFound, "not found"),
+        };
+        assert_eq!(
+            error.to_string(),
+            "unable to read the file `missing/file.jsonl`: not found"
+        );
+        report(error);
+        let panicked = catch_panic(Path::new("poisoned"), || -> Result<()> {
+            panic!("bad line {}", 3);
+        });
+        let panicked = panicked.unwrap_err();
+        assert_eq!(
+            panicked.to_string(),
+            "processing `poisoned` panicked: bad line 3"
+        );
+        assert_eq!(panicked.exit(), Exit::Fatal);
+        assert_eq!(catch_panic(Path::new("fine"), || Ok(1)).unwrap(), 1);
+        assert_eq!(Error::Usage("bad option".to_string()).exit(), Exit::Usage);
+        // A panic with the list locked does not stop the next folders from reporting
+        let _ = catch_panic(Path::new("locked"), || -> Result<()> {
+            let _failures = FAILURES.lock().unwrap();
+            panic!("while reporting");
+        });
+        assert!(FAILURES.is_poisoned());
+        report(Error::Usage("after the panic".to_string()));
+        let failures = take_failures();
+        assert!(failures
+            .iter()
+            .any(|failure| failure.to_string().contains("missing/file.jsonl")));
+        assert!(failures
+            .iter()
+            .any(|failure| failure.to_string().contains("after the panic")));
+    }
+}
@@ -0,0 +1,253 @@
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::{Buckets, Config, ReadOptions};
+use crate::error;
+use crate::experimental;
+use crate::forum_thread::{InputParser, Thread};
+use crate::utils;
+
+/// Only the field needed to pick the bucket of a line
+#[derive(Deserialize)]
+struct RootId<'a> {
+    #[serde(borrow)]
+    root_post_id: Cow<'a, str>,
+}
+
+/// The `root_post_id` of a JSON line, borrowed from the line when it can be
+fn root_post_id(line: &str) -> serde_json::Result<Cow<'_, str>> {
+    #[cfg(feature = "simd-json")]
+    if let Some(root) = utils::json::simd_line(line, |bytes, buffers| {
+        let root = simd_json::serde::from_slice_with_buffers::<RootId>(bytes, buffers).ok()?;
+        Some(root.root_post_id.into_owned())
+    }) {
+        return Ok(Cow::Owned(root));
+    }
+    utils::json::from_line::<RootId>(line).map(|root| root.root_post_id)
+}
+
+/// Bucket of a thread, the same root always goes to the same bucket
+fn bucket_of(root_post_id: &str, count: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    root_post_id.hash(&mut hasher);
+    (hasher.finish() % count as u64) as usize
+}
+
+/// Split the lines of `entries` into `count` files in `dir` by the hash of `root_post_id`
+///
+/// The root is read with `parser` when there is one, otherwise only the `root_post_id` field of
+/// the JSON is parsed. Lines that cannot be parsed and files that cannot be opened are skipped, the same way they are
+/// skipped when building threads, the lines are read with `read`.
+/// The files are read one after the other in sorted order, so every bucket keeps the reading order
+/// and the replies are visited in the same order on every run.
+pub(crate) fn write_buckets(
+    entries: &[PathBuf],
+    dir: &Path,
+    count: usize,
+    parser: Option<&dyn InputParser>,
+    read: &ReadOptions,
+) -> io::Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)?;
+    let paths: Vec<PathBuf> = (0..count).map(|i| bucket_path(dir, i)).collect();
+    let mut writers: Vec<BufWriter<File>> = paths
+        .iter()
+        .map(|path| File::create(path).map(BufWriter::new))
+        .collect::<io::Result<_>>()?;
+
+    let mut entries = entries.to_vec();
+    entries.sort();
+    for entry in entries {
+        let _timer = utils::timing::start(utils::timing::Stage::Read);
+        let Some(lines) = utils::file::read_lines(&entry, read) else {
+            continue;
+        };
+        for line in lines {
+            let root_post_id = match parser {
+                Some(parser) => match parser.parse(&line) {
+                    Ok(Some(post)) => Cow::Owned(post.root_post_id.into_string()),
+                    Ok(None) => continue,
+                    Err(e) => {
+                        utils::rejects::invalid_line(&entry, &line, e.as_ref());
+                        continue;
+                    }
+                },
+                None => match root_post_id(&line) {
+                    Ok(root) => root,
+                    Err(e) => {
+                        utils::rejects::invalid_line(&entry, &line, &e);
+                        continue;
+                    }
+                },
+            };
+            writeln!(writers[bucket_of(&root_post_id, count)], "{}", line)?;
+        }
+    }
+
+    for mut writer in writers {
+        writer.flush()?;
+    }
+    Ok(paths)
+}
+
+/// File of the bucket `i` in `dir`
+fn bucket_path(dir: &Path, i: usize) -> PathBuf {
+    dir.join(format!("bucket_{}.jsonl", i))
+}
+
+/// Removes the buckets left in their folder when dropped, so a folder that fails or panics does
+/// not leave them on disk
+struct BucketCleanup<'a> {
+    dir: &'a Path,
+    count: usize,
+}
+
+impl Drop for BucketCleanup<'_> {
+    fn drop(&mut self) {
+        for i in 0..self.count {
+            let _ = fs::remove_file(bucket_path(self.dir, i));
+        }
+        let _ = fs::remove_dir(self.dir);
+    }
+}
+
+/// Folder of the buckets of the input folder `path`
+pub(crate) fn bucket_dir(path: &Path, buckets: &Buckets) -> PathBuf {
+    let folder = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    buckets.dir.join(folder)
+}
+
+/// Build and hand over the threads of a folder one bucket at a time
+///
+/// The posts are first partitioned into `buckets.count` files on disk by the hash of `root_post_id`,
+/// then each bucket is turned into threads and passed to `f` before the next one is read. Peak
+/// memory is bounded by the largest bucket instead of the whole folder. Threads whose posts have
+/// different `root_post_id` values may end up split across buckets.
+///
+/// # Arguments
+///
+/// * `entries` - `&[PathBuf]` - The `jsonl` files to read
+/// * `path` - `&Path` - The folder they belong to, names the bucket folder and the exported graphs
+/// * `config` - `&Config` - The processing options, `group_by_root` picks how threads are built
+/// * `buckets` - `&Buckets` - Number of buckets and where to write them
+/// * `f` - `FnMut(Vec<Thread>)` - Called with the threads of every bucket
+///
+/// # Errors
+///
+/// Returns [Error::Buckets](../../error/enum.Error.html) if the buckets cannot be written or
+/// removed, and the error of a bucket whose threads cannot be built. The buckets are removed on
+/// an error too.
+///
+/// # Example
+///
+/// ```ignore
+/// process_buckets(&entries, folder, &config, &buckets, |threads| {
+///     forum_thread::sender_thread_posts(threads, folder, &config, post_tx.clone());
+/// })?;
+/// ```
+pub fn process_buckets<F>(
+    entries: &[PathBuf],
+    path: &Path,
+    config: &Config,
+    buckets: &Buckets,
+    mut f: F,
+) -> error::Result<()>
+where
+    F: FnMut(Vec<Thread>),
+{
+    let buckets_error = |source| error::Error::Buckets {
+        path: path.to_path_buf(),
+        source,
+    };
+    let dir = bucket_dir(path, buckets);
+    let count = buckets.count.max(1);
+    let _cleanup = BucketCleanup { dir: &dir, count };
+    let parser = config.stages.parser.as_deref();
+    let bucket_paths =
+        write_buckets(entries, &dir, count, parser, &config.read).map_err(buckets_error)?;
+
+    for bucket in bucket_paths {
+        let files = [bucket.clone()];
+        let threads = match config.group_by_root {
+            true => experimental::grouped::threads_from_files(&files, config),
+            false => experimental::sender::threads_from_files(&files, path, config)?,
+        };
+        fs::remove_file(&bucket).map_err(buckets_error)?;
+        f(threads);
+    }
+    fs::remove_dir(&dir).map_err(buckets_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DuplicateIds;
+    use crate::forum_thread::input_line;
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_process_buckets() {
+        let temp_dir = TempDir::new().unwrap();
+        let folder = temp_dir.path().join("input").join("sub");
+        fs::create_dir_all(&folder).unwrap();
+        let line = |id: &str, parent: &str, root: &str| input_line(id, parent, root, id);
+        let first = [
+            line("1", "1", "1"),
+            line("2", "1", "1"),
+            line("10", "10", "10"),
+        ];
+        let second = [
+            line("3", "2", "1"),
+            line("11", "10", "10"),
+            line("20", "20", "20"),
+        ];
+        fs::write(folder.join("a.jsonl"), first.join("\n")).unwrap();
+        fs::write(folder.join("b.jsonl"), second.join("\n") + "\nnot json").unwrap();
+
+        let buckets = Buckets {
+            count: 3,
+            dir: temp_dir.path().join("buckets"),
+        };
+        let entries = crate::utils::file::single_folder(&folder);
+        for group_by_root in [false, true] {
+            let config = Config {
+                group_by_root,
+                ..Default::default()
+            };
+            let mut threads: Vec<Thread> = Vec::new();
+            let mut calls = 0;
+            process_buckets(&entries, &folder, &config, &buckets, |bucket| {
+                calls += 1;
+                threads.extend(bucket);
+            })
+            .unwrap();
+            threads.sort_by(|a, b| a.id.cmp(&b.id));
+
+            assert_eq!(calls, 3);
+            let ids: Vec<&str> = threads.iter().map(|thread| thread.id.as_str()).collect();
+            assert_eq!(ids, vec!["1", "10", "20"]);
+            assert_eq!(threads[0].posts, vec!["1", "2", "3"]);
+            assert_eq!(threads[1].posts, vec!["10", "11"]);
+            assert!(!buckets.dir.join("sub").exists());
+        }
+
+        // A bucket that fails does not leave the others on disk
+        let config = Config {
+            duplicate_ids: DuplicateIds::Error,
+            ..Default::default()
+        };
+        fs::write(folder.join("c.jsonl"), line("1", "1", "1")).unwrap();
+        let entries = crate::utils::file::single_folder(&folder);
+        let result = process_buckets(&entries, &folder, &config, &buckets, |_| {});
+        assert!(matches!(result, Err(error::Error::DuplicateIds { .. })));
+        assert!(!buckets.dir.join("sub").exists());
+    }
+}
@@ -1,21 +1,48 @@
-use crate::forum_thread::Post;
+use crate::config::{DuplicateIds, MissingParent, ReplyOrder, TraversalOptions};
+use crate::experimental::traversal;
+use crate::forum_thread::{CompactString, Payload, Post, Thread};
+use petgraph::algo::kosaraju_scc;
 use petgraph::graph::NodeIndex;
-use petgraph::visit::Dfs;
+use petgraph::visit::{Bfs, EdgeRef};
 use petgraph::Graph;
 use rayon::prelude::*;
-use std::collections::HashMap;
+use rustc_hash::FxHashMap;
+use std::collections::HashSet;
+
+/// Number of characters of the text kept in the labels of exported graphs
+const EXPORT_LABEL_CHARS: usize = 40;
+
+/// Text of the post that stands in for a missing parent with [MissingParent::Marker](../../config/enum.MissingParent.html)
+pub const MISSING_PARENT_MARKER: &str = "<missing parent>";
 
 ///
 /// ThreadGraph is a struct that represents a graph of threads and comments
 ///
+/// The posts can be any [Payload](../../forum_thread/trait.Payload.html), [Post](../../forum_thread/struct.Post.html)
+/// by default.
+///
 #[derive(Default)]
-pub struct ThreadGraph {
-    graph: Graph<String, (), petgraph::Directed>,
-    node_map: HashMap<String, NodeIndex>,
+pub struct ThreadGraph<P = Post> {
+    /// Each reply edge carries the reading position of the reply, see [add_post_at](#method.add_post_at)
+    ///
+    /// The nodes carry no weight, a node index is also the index of its post in `allthreads`.
+    graph: Graph<(), u64, petgraph::Directed>,
+    /// The ids are stored inline up to 24 bytes, a lookup does not follow a pointer. Hit twice
+    /// per post, it uses FxHash rather than the slower SipHash of the standard library
+    node_map: FxHashMap<CompactString, NodeIndex>,
     threads: Vec<NodeIndex>,
-    allthreads: Vec<Post>,
+    allthreads: Vec<P>,
+    /// Reading position of every post, to pick the copy of a duplicate id
+    positions: Vec<u64>,
+    /// Ids of the parents that were referenced by a reply but never read
+    missing: FxHashMap<usize, CompactString>,
+    /// Reading position given to the next post added with [add_post](#method.add_post)
+    sequence: u64,
+    duplicate_ids: DuplicateIds,
+    /// Posts read with an id that was already read
+    duplicates: usize,
 }
-impl ThreadGraph {
+impl<P: Payload> ThreadGraph<P> {
     /// Constructs a new `ThreadGraph` with a `DiGraph` and a `HashMap`
     ///
     /// # Returns
@@ -24,26 +51,61 @@ impl ThreadGraph {
     ///
     /// # Example
     ///
-    /// ```
+    /// ```ignore
     /// let threadgraph = ThreadGraph::new();
     /// ```
     pub fn new() -> Self {
+        ThreadGraph::with_capacity(10000)
+    }
+
+    /// Same as [new](#method.new) with room for `posts` posts, so the map of the ids is not
+    /// rehashed while a large folder is read
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let threadgraph = ThreadGraph::with_capacity(utils::file::estimate_lines(&entries));
+    /// ```
+    pub fn with_capacity(posts: usize) -> Self {
         ThreadGraph {
-            graph: Graph::new(),
-            node_map: HashMap::with_capacity(10000),
+            graph: Graph::with_capacity(posts, posts),
+            node_map: FxHashMap::with_capacity_and_hasher(posts, Default::default()),
             threads: Vec::with_capacity(10000),
-            allthreads: Vec::with_capacity(10000),
+            allthreads: Vec::with_capacity(posts),
+            positions: Vec::with_capacity(posts),
+            missing: FxHashMap::default(),
+            sequence: 0,
+            duplicate_ids: DuplicateIds::default(),
+            duplicates: 0,
         }
     }
+
+    /// Keep the copy of a duplicate id chosen by `policy`, the last one read by default
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let threadgraph = ThreadGraph::new().with_duplicate_ids(DuplicateIds::FirstWins);
+    /// ```
+    pub fn with_duplicate_ids(mut self, policy: DuplicateIds) -> Self {
+        self.duplicate_ids = policy;
+        self
+    }
+
+    /// Number of posts read with an id that was already read
+    pub fn duplicates(&self) -> usize {
+        self.duplicates
+    }
     /// Adds a node to the graph and returns the index
     ///
     /// If the node already exists, it will return the index of the existing node,
     /// otherwise it will add the node to the graph and return the index. At the same time,
     /// it adds the node into the `allthreads` vector and the `node_map` hashmap.
+    /// The placeholder post has an empty id until the post itself is added.
     ///
     /// # Arguments
     ///
-    /// * `id` - `&String` - The id of the post
+    /// * `id` - `&str` - The id of the post
     ///
     /// # Returns
     ///
@@ -51,19 +113,22 @@ impl ThreadGraph {
     ///
     /// # Example
     ///
-    /// ```rust
+    /// ```ignore
     /// let post = Post::default();
     /// let idx = threadgraph.add_node(&post.id);
     ///
     /// assert_eq!(idx.index(), 0);
     /// ```
-    fn add_node(&mut self, id: &String) -> NodeIndex {
+    fn add_node(&mut self, id: &str) -> NodeIndex {
         if let Some(&idx) = self.node_map.get(id) {
             idx
         } else {
-            let idx = self.graph.add_node(id.clone());
-            self.allthreads.push(Post::default());
-            self.node_map.insert(id.to_string(), idx);
+            let id = CompactString::new(id);
+            let idx = self.graph.add_node(());
+            self.allthreads.push(P::default());
+            self.positions.push(0);
+            self.missing.insert(idx.index(), id.clone());
+            self.node_map.insert(id, idx);
             idx
         }
     }
@@ -73,15 +138,108 @@ impl ThreadGraph {
     /// If it doesn't exist, it will create a placeholder post and add it to the graph.
     ///
     /// The reason for the above implementation is due to some of the threads being detached from the main thread.
-    pub fn add_post(&mut self, post: Post) {
-        // Every id should be unique, use this to update the node_map
-        let from_idx = self.add_node(&post.parent_post_id);
-        let to_id = self.add_node(&post.id);
+    ///
+    /// The replies to a post are visited in the order they were added, use
+    /// [add_post_at](#method.add_post_at) when the posts are added in a nondeterministic order.
+    pub fn add_post(&mut self, post: P) {
+        let position = self.sequence;
+        self.add_post_at(post, position);
+    }
+
+    /// Same as [add_post](#method.add_post) with an explicit reading position
+    ///
+    /// Replies to the same post are visited by increasing `position`, so the output does not
+    /// depend on the order the posts are added in. The sender uses the file and line of each post.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// threadgraph.add_post_at(reply, 1);
+    /// threadgraph.add_post_at(earlier_reply, 0);
+    /// ```
+    ///
+    /// A post whose id was already read is counted in [duplicates](#method.duplicates), only one
+    /// copy is kept with its reply edge, see [with_duplicate_ids](#method.with_duplicate_ids).
+    pub fn add_post_at(&mut self, post: P, position: u64) {
+        self.sequence = self.sequence.max(position + 1);
+        if let Some(&node) = self.node_map.get(post.id()) {
+            if !self.missing.contains_key(&node.index()) {
+                self.duplicates += 1;
+                if !self.replaces(node.index(), &post, position) {
+                    return;
+                }
+                if let Some(edge) = self.graph.first_edge(node, petgraph::Direction::Incoming) {
+                    self.graph.remove_edge(edge);
+                }
+            }
+        }
+        let from_idx = self.add_node(post.parent_id());
+        let to_id = self.add_node(post.id());
+        self.missing.remove(&to_id.index());
         self.allthreads[to_id.index()] = post;
+        self.positions[to_id.index()] = position;
         if from_idx == to_id {
             return;
         }
-        self.graph.add_edge(from_idx, to_id, ());
+        self.graph.add_edge(from_idx, to_id, position);
+    }
+
+    /// Whether `post` read at `position` is kept instead of the copy of its id in `node`
+    fn replaces(&self, node: usize, post: &P, position: u64) -> bool {
+        let first = position < self.positions[node];
+        match self.duplicate_ids {
+            DuplicateIds::FirstWins | DuplicateIds::Error => first,
+            DuplicateIds::LastWins => !first,
+            DuplicateIds::MergeLongestText => {
+                let (length, kept) = (post.text().len(), self.allthreads[node].text().len());
+                length > kept || (length == kept && first)
+            }
+        }
+    }
+
+    /// Whether `node` is a parent that was referenced by a reply but never read
+    fn is_placeholder(&self, node: usize) -> bool {
+        self.allthreads[node].id().is_empty()
+    }
+
+    /// The id of `node`, also for a placeholder
+    fn id(&self, node: usize) -> &str {
+        match self.is_placeholder(node) {
+            true => self.missing.get(&node).map_or("", |id| id),
+            false => self.allthreads[node].id(),
+        }
+    }
+
+    /// Number of posts whose `root_post_id` is not the id of the root they are reached from
+    ///
+    /// Placeholders are not counted. Call this after [break_cycles](#method.break_cycles), posts
+    /// in a cycle have no root and are not checked.
+    pub fn root_mismatches(&self) -> usize {
+        self.show_roots()
+            .par_iter()
+            .map(|&root| {
+                let root_id = self.id(root.index());
+                let mut bfs = Bfs::new(&self.graph, root);
+                let mut mismatches = 0;
+                while let Some(node) = bfs.next(&self.graph) {
+                    let declared = self.allthreads[node.index()].root_id();
+                    if !self.is_placeholder(node.index())
+                        && declared.is_some_and(|declared| declared != root_id)
+                    {
+                        mismatches += 1;
+                    }
+                }
+                mismatches
+            })
+            .sum()
+    }
+
+    /// The posts that were read, without the placeholders
+    pub fn into_posts(self) -> Vec<P> {
+        self.allthreads
+            .into_iter()
+            .filter(|post| !post.id().is_empty())
+            .collect()
     }
 
     // #[allow(dead_code)]
@@ -93,6 +251,53 @@ impl ThreadGraph {
     //     set.len() == self.graph.node_count()
     // }
 
+    /// Break the reply cycles in the graph and return how many were found
+    ///
+    /// Posts in a cycle (A replies to B, B replies to A) have no root and would never be traversed.
+    /// In every cycle the post with the smallest id loses its reply edges from inside the cycle,
+    /// so it becomes the root of the thread and the result does not depend on the reading order.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let cycles = threadgraph.break_cycles();
+    /// let threads = threadgraph.traverse();
+    /// ```
+    pub fn break_cycles(&mut self) -> usize {
+        let mut cycles = 0;
+        loop {
+            let components: Vec<Vec<NodeIndex>> = kosaraju_scc(&self.graph)
+                .into_iter()
+                .filter(|component| component.len() > 1)
+                .collect();
+            if components.is_empty() {
+                return cycles;
+            }
+            cycles += components.len();
+            for component in components {
+                let members: HashSet<NodeIndex> = component.iter().copied().collect();
+                let new_root = *component
+                    .iter()
+                    .min_by_key(|node| self.id(node.index()))
+                    .unwrap();
+                let mut edges: Vec<_> = self
+                    .graph
+                    .edges_directed(new_root, petgraph::Direction::Incoming)
+                    .filter(|edge| members.contains(&edge.source()))
+                    .map(|edge| edge.id())
+                    .collect();
+                // Removing an edge moves the last edge into its index, so remove the highest
+                // indices first, the edges still to remove keep theirs
+                edges.sort_unstable_by(|a, b| b.cmp(a));
+                for edge in edges {
+                    self.graph.remove_edge(edge);
+                }
+            }
+        }
+    }
+
+    /// The nodes without a parent, a placeholder is left out once it has no reply either, when its
+    /// only reply was a duplicate that was dropped
     #[allow(dead_code)]
     pub fn show_roots(&self) -> Vec<NodeIndex> {
         let mut roots_idx: Vec<NodeIndex> = Vec::new();
@@ -101,7 +306,13 @@ impl ThreadGraph {
                 .graph
                 .neighbors_directed(node, petgraph::Direction::Incoming)
                 .count();
-            if incoming_count == 0 {
+            let orphan = self.is_placeholder(node.index())
+                && self
+                    .graph
+                    .neighbors_directed(node, petgraph::Direction::Outgoing)
+                    .next()
+                    .is_none();
+            if incoming_count == 0 && !orphan {
                 roots_idx.push(node);
             }
         }
@@ -115,12 +326,52 @@ impl ThreadGraph {
     ///
     /// # Example
     ///
-    /// ```
+    /// ```ignore
     /// let threads = threadgraph.traverse();
     /// threads[0].0 // root post id
     /// threads[0].1 // vector of pagetext
     /// ```
     pub fn traverse(&self) -> Vec<(String, Vec<String>)> {
+        self.traverse_with(&TraversalOptions::default())
+            .into_iter()
+            .map(Into::into)
+            .collect()
+    }
+
+    /// Replies to `node` in the requested order
+    ///
+    /// The replies are first sorted by their reading position, which is also the order of
+    /// `ReplyOrder::Insertion` and breaks the ties of the other orders.
+    fn children(&self, node: usize, order: ReplyOrder) -> Vec<usize> {
+        let mut edges: Vec<(u64, usize)> = self
+            .graph
+            .edges(NodeIndex::new(node))
+            .map(|edge| (*edge.weight(), edge.target().index()))
+            .collect();
+        edges.sort_unstable();
+        let mut children: Vec<usize> = edges.into_iter().map(|(_, child)| child).collect();
+        traversal::sort_replies(&mut children, |i| &self.allthreads[i], order);
+        children
+    }
+
+    /// Same as [traverse](#method.traverse) with the order of the posts in each thread
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - `&TraversalOptions` - Depth first or breadth first and the order of the replies,
+    ///   see [TraversalOptions](../../config/struct.TraversalOptions.html)
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let options = TraversalOptions {
+    ///     strategy: Traversal::Bfs,
+    ///     reply_order: ReplyOrder::Time,
+    ///     ..Default::default()
+    /// };
+    /// let threads = threadgraph.traverse_with(&options);
+    /// ```
+    pub fn traverse_with(&self, options: &TraversalOptions) -> Vec<Thread> {
         let roots = self.show_roots();
         // check for duplicates
         // self.show_roots();
@@ -128,54 +379,363 @@ impl ThreadGraph {
         // print number of nodes
         //dbg!(self.graph.node_count());
 
-        let mut final_threads: Vec<(String, Vec<String>)> = Vec::with_capacity(self.threads.len());
-        roots
-            .par_iter()
-            .with_min_len(100)
-            .map(|start| {
-                // skip if not root
-                let mut dfs = Dfs::new(&self.graph, *start);
-                let mut threads: Vec<usize> = Vec::new();
-
-                while let Some(visited) = dfs.next(&self.graph) {
-                    threads.push(visited.index());
-                }
-                let root_id = self.graph[*start].clone();
-                let vec_string: Vec<String> = threads
-                    .iter()
-                    // .with_min_len(100)
-                    .map(|thread| {
-                        // print!("{} ", thread);
-                        self.allthreads[*thread].pagetext.clone()
-                    })
-                    .collect();
-                // dbg!(vec_string.len());
-                // println!();
-                (root_id, vec_string)
-            })
-            .collect_into_vec(&mut final_threads);
+        let min_len = traversal::roots_per_task(roots.len(), self.allthreads.len(), options);
+        let mut final_threads: Vec<Thread> = Vec::with_capacity(self.threads.len());
+        final_threads.par_extend(
+            roots
+                .par_iter()
+                .with_min_len(min_len)
+                .flat_map_iter(|start| {
+                    // skip if not root
+                    let root_id = self.id(start.index()).to_string();
+                    traversal::documents(
+                        root_id,
+                        self.starts(*start, options),
+                        |node| self.children(node, options.reply_order),
+                        |thread| &self.allthreads[thread],
+                        |thread| self.id(thread).to_string(),
+                        options,
+                    )
+                }),
+        );
         // println!("Longest thread: {}", long_string);
         // println!("Longest thread: {}", longest_thread);
         // dbg!(roots);
         final_threads
     }
 
+    /// Same as [traverse_with](#method.traverse_with), the texts are moved out of the graph
+    ///
+    /// With `Extract::Tree` every post is written once, so the documents take the texts instead of
+    /// copying them and the posts are not held twice in memory. A post reached from several roots,
+    /// and the posts repeated by `Extract::Paths` and `Extract::Context`, are still copied.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// threadgraph.break_cycles();
+    /// let threads = threadgraph.into_documents(&TraversalOptions::default());
+    /// ```
+    pub fn into_documents(mut self, options: &TraversalOptions) -> Vec<Thread> {
+        let roots = self.show_roots();
+        let min_len = traversal::roots_per_task(roots.len(), self.allthreads.len(), options);
+        // The posts below each root, sorted to look up their text
+        let reached: Vec<Vec<usize>> = roots
+            .par_iter()
+            .with_min_len(min_len)
+            .map(|&root| {
+                let mut nodes: Vec<usize> = self.below(root).map(NodeIndex::index).collect();
+                nodes.sort_unstable();
+                nodes
+            })
+            .collect();
+        let mut uses: Vec<u32> = vec![0; self.allthreads.len()];
+        for &node in reached.iter().flatten() {
+            uses[node] += 1;
+        }
+        // Only the last root that reaches a post takes its text
+        let texts: Vec<Vec<(usize, String)>> = reached
+            .into_iter()
+            .map(|nodes| {
+                nodes
+                    .into_iter()
+                    .map(|node| {
+                        uses[node] -= 1;
+                        let post = &mut self.allthreads[node];
+                        let text = match uses[node] {
+                            0 => post.take_text(),
+                            _ => post.text().to_string(),
+                        };
+                        (node, text)
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let threadgraph = &self;
+        let mut final_threads: Vec<Thread> = Vec::with_capacity(roots.len());
+        final_threads.par_extend(
+            roots
+                .into_par_iter()
+                .zip(texts)
+                .with_min_len(min_len)
+                .flat_map_iter(|(start, mut texts)| {
+                    let pagetext = move |node: usize| {
+                        let i = texts
+                            .binary_search_by_key(&node, |(node, _)| *node)
+                            .unwrap();
+                        traversal::take_text(&mut texts[i].1, options.extract)
+                    };
+                    traversal::documents_with(
+                        threadgraph.id(start.index()).to_string(),
+                        threadgraph.starts(start, options),
+                        |node| threadgraph.children(node, options.reply_order),
+                        |thread| &threadgraph.allthreads[thread],
+                        |thread| threadgraph.id(thread).to_string(),
+                        pagetext,
+                        options,
+                    )
+                }),
+        );
+        final_threads
+    }
+
+    /// Where the documents of the thread at `root` start, its replies for a placeholder that is
+    /// skipped
+    fn starts(&self, root: NodeIndex, options: &TraversalOptions) -> Vec<usize> {
+        match options.missing_parent {
+            MissingParent::Skip if self.is_placeholder(root.index()) => {
+                self.children(root.index(), options.reply_order)
+            }
+            _ => vec![root.index()],
+        }
+    }
+
+    /// Posts reachable from `start`, in breadth first order with `start` first
+    fn below(&self, start: NodeIndex) -> impl Iterator<Item = NodeIndex> + '_ {
+        let mut bfs = Bfs::new(&self.graph, start);
+        std::iter::from_fn(move || bfs.next(&self.graph))
+    }
+
+    /// Posts reachable from the post `id`, in breadth first order with `id` first
+    fn reachable(&self, id: &str) -> Option<Vec<NodeIndex>> {
+        let start = *self.node_map.get(id)?;
+        Some(self.below(start).collect())
+    }
+
+    /// Short label of a post for the exported graphs
+    fn label(&self, node: NodeIndex) -> String {
+        let pagetext = self.allthreads[node.index()].text();
+        let mut text: String = pagetext.chars().take(EXPORT_LABEL_CHARS).collect();
+        if text.len() < pagetext.len() {
+            text.push_str("...");
+        }
+        format!("{}: {}", self.id(node.index()), text)
+    }
+
+    /// Export the thread below the post `id` in the DOT format of Graphviz
+    ///
+    /// Every post reachable from `id` is included with the edges between them, so reply cycles
+    /// are visible if this is called before [break_cycles](#method.break_cycles).
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - The DOT graph, `None` if the post is not in the graph
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let dot = threadgraph.to_dot("1").unwrap();
+    /// std::fs::write("1.dot", dot).unwrap();
+    /// ```
+    pub fn to_dot(&self, id: &str) -> Option<String> {
+        let nodes = self.reachable(id)?;
+        let escape = |text: &str| {
+            text.replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n")
+        };
+        let mut dot = format!("digraph \"{}\" {{\n", escape(id));
+        for &node in &nodes {
+            dot.push_str(&format!(
+                "    \"{}\" [label=\"{}\"];\n",
+                escape(self.id(node.index())),
+                escape(&self.label(node))
+            ));
+        }
+        for &node in &nodes {
+            for child in self.graph.neighbors(node) {
+                dot.push_str(&format!(
+                    "    \"{}\" -> \"{}\";\n",
+                    escape(self.id(node.index())),
+                    escape(self.id(child.index()))
+                ));
+            }
+        }
+        dot.push_str("}\n");
+        Some(dot)
+    }
+
+    /// Export the thread below the post `id` as GraphML
+    ///
+    /// Same as [to_dot](#method.to_dot), the text, timestamp and score of each post are kept as node data.
+    pub fn to_graphml(&self, id: &str) -> Option<String> {
+        let nodes = self.reachable(id)?;
+        let escape = |text: &str| {
+            text.replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;")
+        };
+        let mut xml = String::from(concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n",
+            "  <key id=\"pagetext\" for=\"node\" attr.name=\"pagetext\" attr.type=\"string\"/>\n",
+            "  <key id=\"created_utc\" for=\"node\" attr.name=\"created_utc\" attr.type=\"double\"/>\n",
+            "  <key id=\"score\" for=\"node\" attr.name=\"score\" attr.type=\"double\"/>\n",
+        ));
+        xml.push_str(&format!(
+            "  <graph id=\"{}\" edgedefault=\"directed\">\n",
+            escape(id)
+        ));
+        for &node in &nodes {
+            let post = &self.allthreads[node.index()];
+            xml.push_str(&format!(
+                "    <node id=\"{}\">\n",
+                escape(self.id(node.index()))
+            ));
+            xml.push_str(&format!(
+                "      <data key=\"pagetext\">{}</data>\n",
+                escape(post.text())
+            ));
+            if let Some(created_utc) = post.created_utc() {
+                xml.push_str(&format!(
+                    "      <data key=\"created_utc\">{}</data>\n",
+                    created_utc
+                ));
+            }
+            if let Some(score) = post.score() {
+                xml.push_str(&format!("      <data key=\"score\">{}</data>\n", score));
+            }
+            xml.push_str("    </node>\n");
+        }
+        for &node in &nodes {
+            for child in self.graph.neighbors(node) {
+                xml.push_str(&format!(
+                    "    <edge source=\"{}\" target=\"{}\"/>\n",
+                    escape(self.id(node.index())),
+                    escape(self.id(child.index()))
+                ));
+            }
+        }
+        xml.push_str("  </graph>\n</graphml>\n");
+        Some(xml)
+    }
+
+    /// Same as [traverse_with](#method.traverse_with) without flattening, the payloads of each thread
+    /// are borrowed in visiting order
+    ///
+    /// Only the order of the posts and `max_depth` are used from `options`, the replies below
+    /// `max_depth` are left out.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// for (root_id, posts) in threadgraph.threads(&TraversalOptions::default()) {
+    ///     let ids: Vec<&str> = posts.iter().map(|post| post.id()).collect();
+    /// }
+    /// ```
+    pub fn threads(&self, options: &TraversalOptions) -> Vec<(String, Vec<&P>)> {
+        self.thread_nodes(options)
+            .into_iter()
+            .map(|(root_id, nodes)| {
+                let posts = nodes
+                    .into_iter()
+                    .map(|node| &self.allthreads[node])
+                    .collect();
+                (root_id, posts)
+            })
+            .collect()
+    }
+
+    /// Same as [threads](#method.threads), the payloads are moved out of the graph
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// for (root_id, posts) in threadgraph.into_threads(&TraversalOptions::default()) {
+    ///     let texts: Vec<String> = posts.into_iter().map(|post| post.pagetext).collect();
+    /// }
+    /// ```
+    pub fn into_threads(self, options: &TraversalOptions) -> Vec<(String, Vec<P>)> {
+        let threads = self.thread_nodes(options);
+        let mut posts: Vec<Option<P>> = self.allthreads.into_iter().map(Some).collect();
+        threads
+            .into_iter()
+            .map(|(root_id, nodes)| {
+                // Every post is visited once, so each one is taken once
+                let posts = nodes
+                    .into_iter()
+                    .filter_map(|node| posts[node].take())
+                    .collect();
+                (root_id, posts)
+            })
+            .collect()
+    }
+
+    /// The root id and the nodes of every thread in visiting order
+    fn thread_nodes(&self, options: &TraversalOptions) -> Vec<(String, Vec<usize>)> {
+        let mut visited: HashSet<usize> = HashSet::new();
+        self.show_roots()
+            .into_iter()
+            .map(|root| {
+                let visits = traversal::walk(
+                    root.index(),
+                    |node| self.children(node, options.reply_order),
+                    options,
+                    &mut visited,
+                );
+                let nodes = visits.into_iter().map(|(node, _)| node).collect();
+                (self.id(root.index()).to_string(), nodes)
+            })
+            .collect()
+    }
+
     pub fn show_threads(&self) {
         for node in self.graph.node_indices() {
-            println!("{:?}", self.graph[node]);
+            println!("{:?}", self.id(node.index()));
         }
     }
     pub fn add_threads(&mut self, idx: NodeIndex) {
         self.threads.push(idx);
     }
-    pub fn is_in_map(&self, id: &String) -> bool {
+    pub fn is_in_map(&self, id: &str) -> bool {
         self.node_map.contains_key(id)
     }
 }
 
+impl ThreadGraph<Post> {
+    /// Fill the placeholders of the parents missing from the dump according to `policy`
+    ///
+    /// With [MissingParent::CopyChild](../../config/enum.MissingParent.html) the placeholder gets the
+    /// metadata of its first reply, with `Marker` the text [MISSING_PARENT_MARKER](constant.MISSING_PARENT_MARKER.html).
+    /// `Empty` and `Skip` leave it as is, `Skip` is applied by [traverse_with](#method.traverse_with).
+    pub fn fill_missing_parents(&mut self, policy: MissingParent) {
+        if matches!(policy, MissingParent::Empty | MissingParent::Skip) {
+            return;
+        }
+        for node in 0..self.allthreads.len() {
+            if !self.is_placeholder(node) {
+                continue;
+            }
+            match policy {
+                MissingParent::CopyChild => {
+                    let Some(&child) = self.children(node, ReplyOrder::Insertion).first() else {
+                        continue;
+                    };
+                    let reply = &self.allthreads[child];
+                    let filled = Post {
+                        created_utc: reply.created_utc,
+                        score: reply.score,
+                        author: reply.author.clone(),
+                        root_post_id: reply.root_post_id.clone(),
+                        ..Post::default()
+                    };
+                    self.allthreads[node] = filled;
+                }
+                MissingParent::Marker => {
+                    self.allthreads[node].pagetext = MISSING_PARENT_MARKER.to_string();
+                }
+                MissingParent::Empty | MissingParent::Skip => {}
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::{DeepReplies, ExtraPosts, Extract, Traversal};
     use itertools::izip;
     use pretty_assertions::assert_eq;
     use rand::prelude::*;
@@ -214,25 +774,20 @@ mod tests {
         // TODO: There should be a more idiomatic way to do this
         // assumes dfs
         let mut target: Vec<(&str, Vec<&str>)> = vec![
-            ("2", vec!["2", "7", "9", "8", "10"]),
-            ("1", vec!["1", "3", "5", "4", "6"]),
-            ("12", vec!["", "11"]),
-        ];
-        let mut alternative_target: Vec<(&str, Vec<&str>)> = vec![
             ("2", vec!["2", "7", "8", "10", "9"]),
             ("1", vec!["1", "3", "4", "6", "5"]),
-            ("12", vec!["11", ""]),
+            ("12", vec!["", "11"]),
         ];
         // sort target
         target.sort_by(|a, b| a.0.cmp(b.0));
-        alternative_target.sort_by(|a, b| a.0.cmp(b.0));
 
-        // run a loop for better determinism
+        // the posts are added in a random order, the reading position keeps the output stable
         for _ in 0..10 {
-            let (mut graph, mut posts) = setup();
+            let (mut graph, posts) = setup();
+            let mut posts: Vec<(usize, Post)> = posts.into_iter().enumerate().collect();
             posts.shuffle(&mut thread_rng());
-            for post in posts.iter() {
-                graph.add_post(post.clone());
+            for (position, post) in posts {
+                graph.add_post_at(post, position as u64);
             }
 
             assert_eq!(graph.graph.node_count(), 12);
@@ -243,11 +798,397 @@ mod tests {
 
             assert_eq!(threads.len(), target.len());
 
-            // check against target and alternative target
-            for (result, x, y) in izip!(threads, &target, &alternative_target) {
+            for (result, x) in threads.iter().zip(&target) {
                 assert_eq!(result.0, x.0);
-                assert!(result.1 == x.1 || result.1 == y.1);
+                assert_eq!(result.1, x.1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bfs_graph() {
+        let mut target: Vec<(&str, Vec<&str>)> = vec![
+            ("2", vec!["2", "7", "9", "8", "10"]),
+            ("1", vec!["1", "3", "5", "4", "6"]),
+            ("12", vec!["", "11"]),
+        ];
+        let mut alternative_target: Vec<(&str, Vec<&str>)> = vec![
+            ("2", vec!["2", "7", "8", "9", "10"]),
+            ("1", vec!["1", "3", "4", "5", "6"]),
+            ("12", vec!["", "11"]),
+        ];
+        target.sort_by(|a, b| a.0.cmp(b.0));
+        alternative_target.sort_by(|a, b| a.0.cmp(b.0));
+
+        for _ in 0..10 {
+            let (mut graph, mut posts) = setup();
+            posts.shuffle(&mut thread_rng());
+            for post in posts.iter() {
+                graph.add_post(post.clone());
+            }
+
+            let mut threads = graph.traverse_with(&TraversalOptions {
+                strategy: Traversal::Bfs,
+                ..Default::default()
+            });
+            threads.sort_by(|a, b| a.id.cmp(&b.id));
+
+            assert_eq!(threads.len(), target.len());
+            for (result, x, y) in izip!(threads, &target, &alternative_target) {
+                assert_eq!(result.id, x.0);
+                assert!(result.posts == x.1 || result.posts == y.1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_time_order() {
+        // replies are inserted newest first
+        let posts = vec![
+            Post::new("1", true, "1", "1", "1").with_created_utc(0.0),
+            Post::new("2", false, "2", "1", "1").with_created_utc(30.0),
+            Post::new("3", false, "3", "1", "1"),
+            Post::new("4", false, "4", "1", "1").with_created_utc(10.0),
+            Post::new("5", false, "5", "4", "1").with_created_utc(20.0),
+        ];
+        let mut graph = ThreadGraph::new();
+        for post in posts {
+            graph.add_post(post);
+        }
+
+        let dfs = TraversalOptions {
+            strategy: Traversal::Dfs,
+            reply_order: ReplyOrder::Time,
+            ..Default::default()
+        };
+        assert_eq!(
+            graph.traverse_with(&dfs)[0].posts,
+            vec!["1", "4", "5", "2", "3"]
+        );
+        let bfs = TraversalOptions {
+            strategy: Traversal::Bfs,
+            reply_order: ReplyOrder::Time,
+            ..Default::default()
+        };
+        assert_eq!(
+            graph.traverse_with(&bfs)[0].posts,
+            vec!["1", "4", "2", "3", "5"]
+        );
+        assert_eq!(
+            graph.traverse_with(&TraversalOptions::default())[0].posts,
+            vec!["1", "2", "3", "4", "5"]
+        );
+    }
+
+    #[test]
+    fn test_score_order() {
+        let posts = vec![
+            Post::new("1", true, "1", "1", "1"),
+            Post::new("2", false, "2", "1", "1").with_score(1.0),
+            Post::new("3", false, "3", "1", "1"),
+            Post::new("4", false, "4", "1", "1").with_score(50.0),
+            Post::new("5", false, "5", "4", "1").with_score(-2.0),
+            Post::new("6", false, "6", "4", "1").with_score(7.0),
+        ];
+        let mut graph = ThreadGraph::new();
+        for post in posts {
+            graph.add_post(post);
+        }
+
+        let options = TraversalOptions {
+            strategy: Traversal::Dfs,
+            reply_order: ReplyOrder::Score,
+            ..Default::default()
+        };
+        assert_eq!(
+            graph.traverse_with(&options)[0].posts,
+            vec!["1", "4", "6", "5", "2", "3"]
+        );
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let (mut graph, posts) = setup();
+        for post in posts {
+            graph.add_post(post);
+        }
+        for strategy in [Traversal::Dfs, Traversal::Bfs] {
+            let mut options = TraversalOptions {
+                strategy,
+                max_depth: Some(1),
+                ..Default::default()
+            };
+            let mut threads = graph.traverse_with(&options);
+            threads.sort_by(|a, b| a.id.cmp(&b.id));
+            assert_eq!(threads[0].posts, vec!["1", "3"]);
+            assert_eq!(threads[1].posts, vec!["", "11"]);
+            assert_eq!(threads[2].posts, vec!["2", "7"]);
+
+            options.deep_replies = DeepReplies::Count;
+            let mut threads = graph.traverse_with(&options);
+            threads.sort_by(|a, b| a.id.cmp(&b.id));
+            assert_eq!(threads[0].posts, vec!["1", "3", "[3 more replies]"]);
+            assert_eq!(threads[2].posts, vec!["2", "7", "[3 more replies]"]);
+        }
+    }
+
+    #[test]
+    fn test_max_posts() {
+        let (mut graph, posts) = setup();
+        for post in posts {
+            graph.add_post(post);
+        }
+        let mut options = TraversalOptions {
+            strategy: Traversal::Bfs,
+            max_posts: Some(2),
+            ..Default::default()
+        };
+        let mut threads = graph.traverse_with(&options);
+        threads.sort_by(|a, b| a.id.cmp(&b.id));
+        assert_eq!(threads.len(), 3);
+        assert_eq!(threads[0].posts, vec!["1", "3"]);
+
+        options.extra_posts = ExtraPosts::Continue;
+        let mut threads = graph.traverse_with(&options);
+        threads.sort_by(|a, b| a.id.cmp(&b.id));
+        let ids: Vec<&str> = threads.iter().map(|thread| thread.id.as_str()).collect();
+        assert_eq!(ids, vec!["1", "1-1", "1-2", "12", "2", "2-1", "2-2"]);
+        assert_eq!(threads[2].posts, vec!["6"]);
+        assert_eq!(threads[4].posts, vec!["2", "7"]);
+    }
+
+    #[test]
+    fn test_break_cycles() {
+        let (mut graph, posts) = setup();
+        for post in posts {
+            graph.add_post(post);
+        }
+        assert_eq!(graph.break_cycles(), 0);
+
+        // 20 -> 21 -> 22 -> 20, with 23 replying to 21
+        for (id, parent) in [("21", "20"), ("22", "21"), ("20", "22"), ("23", "21")] {
+            graph.add_post(Post::new(id, false, id, parent, "20"));
+        }
+        assert_eq!(graph.break_cycles(), 1);
+        assert_eq!(graph.break_cycles(), 0);
+
+        let mut threads = graph.traverse();
+        threads.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(threads.len(), 4);
+        assert_eq!(threads[3].0, "20");
+        let mut posts = threads[3].1.clone();
+        posts.sort();
+        assert_eq!(posts, vec!["20", "21", "22", "23"]);
+        assert_eq!(threads[3].1[0], "20");
+    }
+
+    #[test]
+    fn test_break_cycles_shared_node() {
+        // 1 <-> 2 and 1 <-> 3, both cycles go through 1
+        let mut graph: ThreadGraph = ThreadGraph::new();
+        for (id, parent) in [("1", "2"), ("2", "1"), ("3", "1"), ("4", "3")] {
+            graph.add_post(Post::new(id, false, id, parent, "1"));
+        }
+        // A post has a single parent, the second edge into 1 can only come from the graph itself
+        let node = |id: &str| graph.node_map[id];
+        let (one, three) = (node("1"), node("3"));
+        graph.graph.add_edge(three, one, 4);
+        assert_eq!(graph.break_cycles(), 1);
+        assert_eq!(graph.break_cycles(), 0);
+        assert_eq!(graph.graph.edge_count(), 3);
+        assert_eq!(graph.show_roots(), vec![one]);
+
+        let threads = graph.traverse();
+        assert_eq!(threads.len(), 1);
+        let mut posts = threads[0].1.clone();
+        posts.sort();
+        assert_eq!(posts, vec!["1", "2", "3", "4"]);
+    }
+
+    #[test]
+    fn test_export() {
+        let (mut graph, posts) = setup();
+        for post in posts {
+            graph.add_post(post);
+        }
+        let dot = graph.to_dot("3").unwrap();
+        assert!(dot.starts_with("digraph \"3\" {"));
+        assert!(dot.contains("\"3\" -> \"4\";"));
+        assert!(dot.contains("\"4\" -> \"6\";"));
+        assert!(!dot.contains("\"1\""));
+
+        let graphml = graph.to_graphml("7").unwrap();
+        assert!(graphml.contains("<edge source=\"8\" target=\"10\"/>"));
+        assert_eq!(graphml.matches("<node ").count(), 4);
+        assert!(graph.to_dot("missing").is_none());
+    }
+
+    #[test]
+    fn test_missing_parent() {
+        let thread_12 = |policy: MissingParent| {
+            let (mut graph, posts) = setup();
+            for post in posts {
+                graph.add_post(post.with_created_utc(5.0));
+            }
+            graph.fill_missing_parents(policy);
+            let options = TraversalOptions {
+                missing_parent: policy,
+                ..Default::default()
+            };
+            let threads = graph.traverse_with(&options);
+            let thread = threads
+                .into_iter()
+                .find(|thread| thread.id == "12")
+                .unwrap();
+            (graph, thread.posts)
+        };
+        assert_eq!(thread_12(MissingParent::Empty).1, vec!["", "11"]);
+        assert_eq!(thread_12(MissingParent::Skip).1, vec!["11"]);
+        assert_eq!(
+            thread_12(MissingParent::Marker).1,
+            vec![MISSING_PARENT_MARKER, "11"]
+        );
+        let (graph, posts) = thread_12(MissingParent::CopyChild);
+        assert_eq!(posts, vec!["", "11"]);
+        let placeholder = &graph.allthreads[graph.node_map["12"].index()];
+        assert_eq!(placeholder.created_utc, Some(5.0));
+        assert_eq!(placeholder.root_post_id, "12");
+        assert!(graph.is_placeholder(graph.node_map["12"].index()));
+    }
+
+    #[test]
+    fn test_node_ids() {
+        let (mut graph, posts) = setup();
+        for post in posts {
+            graph.add_post(post);
+        }
+        // Every node finds its id from its index, 12 is only known as a parent
+        for (id, &node) in graph.node_map.iter() {
+            assert_eq!(graph.id(node.index()), &**id);
+        }
+        assert_eq!(graph.node_map.len(), graph.graph.node_count());
+        assert_eq!(graph.missing.len(), 1);
+        assert_eq!(graph.missing[&graph.node_map["12"].index()], "12");
+    }
+
+    #[test]
+    fn test_into_documents() {
+        let build = || {
+            let (mut graph, posts) = setup();
+            for post in posts {
+                graph.add_post(post);
             }
+            // The same id under both roots, the last copy is kept under root 2 only
+            graph.add_post(Post::new("x", false, "x1", "1", "1"));
+            graph.add_post(Post::new("x", false, "x2", "2", "2"));
+            graph
+        };
+        for extract in [Extract::Tree, Extract::Paths, Extract::Context] {
+            let options = TraversalOptions {
+                extract,
+                ..Default::default()
+            };
+            let threads = |threads: Vec<Thread>| -> Vec<(String, Vec<String>)> {
+                threads.into_iter().map(Into::into).collect()
+            };
+            let borrowed = threads(build().traverse_with(&options));
+            assert_eq!(
+                borrowed
+                    .iter()
+                    .filter(|(_, posts)| posts.contains(&"x2".to_string()))
+                    .count(),
+                1
+            );
+            assert!(!borrowed
+                .iter()
+                .any(|(_, posts)| posts.contains(&"x1".to_string())));
+            assert_eq!(threads(build().into_documents(&options)), borrowed);
+        }
+    }
+
+    #[test]
+    fn test_duplicate_ids() {
+        // `x` is read twice under different parents, the positions are not in reading order
+        let posts = [
+            (Post::new("1", true, "root", "1", "1"), 0),
+            (Post::new("2", false, "reply", "1", "1"), 1),
+            (Post::new("x", false, "long copy", "1", "1"), 3),
+            (Post::new("x", false, "short", "2", "1"), 2),
+            // The parent of this copy is only known from it
+            (Post::new("x", false, "other", "9", "9"), 4),
+        ];
+        // The placeholder of 9 is left without a reply unless the last copy is kept
+        for (policy, text, thread_count) in [
+            (DuplicateIds::FirstWins, "short", 1),
+            (DuplicateIds::Error, "short", 1),
+            (DuplicateIds::LastWins, "other", 2),
+            (DuplicateIds::MergeLongestText, "long copy", 1),
+        ] {
+            let mut graph = ThreadGraph::new().with_duplicate_ids(policy);
+            for (post, position) in posts.clone() {
+                graph.add_post_at(post, position);
+            }
+            assert_eq!(graph.duplicates(), 2);
+            let threads: Vec<(String, Vec<String>)> = graph
+                .traverse_with(&TraversalOptions::default())
+                .into_iter()
+                .map(Into::into)
+                .collect();
+            let with_x: Vec<_> = threads
+                .iter()
+                .filter(|(_, posts)| posts.contains(&text.to_string()))
+                .collect();
+            assert_eq!(with_x.len(), 1, "{:?}", policy);
+            assert_eq!(threads.len(), thread_count, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn test_root_mismatches() {
+        let (mut graph, posts) = setup();
+        for post in posts {
+            graph.add_post(post);
+        }
+        // 4, 5, 6, 8, 9 and 10 declare their parent or grandparent as root
+        assert_eq!(graph.root_mismatches(), 6);
+        let posts = graph.into_posts();
+        assert_eq!(posts.len(), 11);
+        assert!(posts.iter().all(|post| !post.id.is_empty()));
+    }
+
+    #[test]
+    fn test_generic_payload() {
+        /// A post reduced to its ids and its line in the dump
+        #[derive(Default)]
+        struct Line {
+            id: String,
+            parent: String,
+            line: usize,
+        }
+        impl Payload for Line {
+            fn id(&self) -> &str {
+                &self.id
+            }
+            fn parent_id(&self) -> &str {
+                &self.parent
+            }
+        }
+
+        let mut graph: ThreadGraph<Line> = ThreadGraph::new();
+        for (line, (id, parent)) in [("1", "1"), ("2", "1"), ("3", "2"), ("4", "1")]
+            .into_iter()
+            .enumerate()
+        {
+            let (id, parent) = (id.to_string(), parent.to_string());
+            graph.add_post(Line { id, parent, line });
         }
+        let threads = graph.threads(&TraversalOptions::default());
+        assert_eq!(threads.len(), 1);
+        assert_eq!(threads[0].0, "1");
+        let lines: Vec<usize> = threads[0].1.iter().map(|post| post.line).collect();
+        assert_eq!(lines, vec![0, 1, 2, 3]);
+        // no text, the documents are empty lines
+        assert_eq!(graph.traverse()[0].1, vec!["", "", "", ""]);
+        assert_eq!(graph.root_mismatches(), 0);
     }
 }
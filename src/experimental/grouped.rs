@@ -0,0 +1,207 @@
+use rayon::prelude::*;
+use rustc_hash::FxHashMap;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Config, ThreadHook, TraversalOptions};
+use crate::experimental::traversal;
+use crate::forum_thread::{CompactString, Payload, Post, Thread};
+use crate::utils;
+
+/// Flatten the posts of a single thread
+///
+/// The posts are linked through `parent_post_id` inside the group only. The walk starts from the
+/// root post, then from the posts whose parent is not in the group, and finally from any post left
+/// over in a reply cycle, so every post of the group is written once.
+///
+/// # Arguments
+///
+/// * `root_id` - `String` - The `root_post_id` shared by the posts
+/// * `posts` - `Vec<Post>` - The posts in reading order
+/// * `options` - `&TraversalOptions` - The traversal options
+///
+/// # Returns
+///
+/// * `Vec<Thread>` - The documents of the thread, more than one with continuation documents
+fn thread_parts(root_id: String, mut posts: Vec<Post>, options: &TraversalOptions) -> Vec<Thread> {
+    let mut texts: Vec<String> = posts.iter_mut().map(Payload::take_text).collect();
+    let mut index: FxHashMap<&str, usize> =
+        FxHashMap::with_capacity_and_hasher(posts.len(), Default::default());
+    for (i, post) in posts.iter().enumerate() {
+        index.entry(post.id.as_str()).or_insert(i);
+    }
+
+    let mut children: Vec<Vec<usize>> = vec![Vec::new(); posts.len()];
+    let mut starts: Vec<usize> = Vec::new();
+    for (i, post) in posts.iter().enumerate() {
+        match index.get(post.parent_post_id.as_str()) {
+            Some(&parent) if post.parent_post_id != post.id => children[parent].push(i),
+            _ => starts.push(i),
+        }
+    }
+    for replies in children.iter_mut() {
+        traversal::sort_replies(replies, |i| &posts[i], options.reply_order);
+    }
+    traversal::sort_replies(&mut starts, |i| &posts[i], options.reply_order);
+    if let Some(&root) = index.get(root_id.as_str()) {
+        starts.retain(|&start| start != root);
+        starts.insert(0, root);
+    }
+    // Posts in a cycle are not reachable from any start, they are walked last in reading order
+    starts.extend(0..posts.len());
+
+    traversal::documents_with(
+        root_id,
+        starts,
+        |node| children[node].clone(),
+        |i| &posts[i],
+        |i| posts[i].id.to_string(),
+        |i| traversal::take_text(&mut texts[i], options.extract),
+        options,
+    )
+}
+
+/// Get the threads of a folder by grouping the posts on `root_post_id`
+///
+/// This skips the graph construction of [sender](../sender/fn.get_threads.html), which is much faster
+/// and lighter on memory, but relies on the `root_post_id` of the dump being correct.
+///
+/// # Arguments
+///
+/// * `path` - `P: AsRef<Path>` - The folder containing `jsonl` files
+/// * `config` - `&Config` - The processing options, only the traversal options are used
+///
+/// # Returns
+///
+/// * `Vec<Thread>` - The root post id and the texts of every thread
+pub fn get_threads<P: AsRef<Path>>(path: P, config: &Config) -> Vec<Thread> {
+    threads_from_files(&utils::file::single_folder(path), config)
+}
+
+/// Same as [get_threads](fn.get_threads.html) for a given list of `jsonl` files
+pub fn threads_from_files(entries: &[PathBuf], config: &Config) -> Vec<Thread> {
+    // Sorted so the posts of a group are always in the same order
+    let mut entries = entries.to_vec();
+    entries.sort();
+    let parser = config.stages.parser();
+    let files: Vec<Vec<Post>> = entries
+        .par_iter()
+        .map(|entry| {
+            let _timer = utils::timing::start(utils::timing::Stage::Read);
+            let Some(lines) = utils::file::read_lines(entry, &config.read) else {
+                return Vec::new();
+            };
+            let mut bytes = 0;
+            let posts: Vec<Post> = lines
+                .filter_map(|line| {
+                    bytes += line.len() as u64 + 1;
+                    parser
+                        .parse(&line)
+                        .inspect_err(|e| utils::rejects::invalid_line(entry, &line, e.as_ref()))
+                        .ok()
+                        .flatten()
+                })
+                .collect();
+            utils::file::record_read(posts.len() as u64, bytes);
+            posts
+        })
+        .collect();
+
+    let _timer = utils::timing::start(utils::timing::Stage::GetThreads);
+    let groups = group(files.into_iter().flatten());
+    documents(
+        groups,
+        &config.traversal,
+        config.stages.thread_hook.as_deref(),
+    )
+}
+
+/// Group posts by `root_post_id` and flatten every group into documents
+///
+/// # Arguments
+///
+/// * `posts` - `IntoIterator<Item = Post>` - The posts in reading order
+/// * `options` - `&TraversalOptions` - The traversal options
+pub fn group_posts<I>(posts: I, options: &TraversalOptions) -> Vec<Thread>
+where
+    I: IntoIterator<Item = Post>,
+{
+    documents(group(posts), options, None)
+}
+
+/// The posts of every `root_post_id`, in reading order
+pub(crate) fn group<I>(posts: I) -> Vec<(String, Vec<Post>)>
+where
+    I: IntoIterator<Item = Post>,
+{
+    let mut groups: FxHashMap<CompactString, Vec<Post>> = FxHashMap::default();
+    for post in posts {
+        groups
+            .entry(post.root_post_id.clone())
+            .or_default()
+            .push(post);
+    }
+    groups
+        .into_iter()
+        .map(|(root_id, posts)| (root_id.into_string(), posts))
+        .collect()
+}
+
+/// Flatten every thread into documents, after `hook` when there is one
+///
+/// The posts returned by the hook are linked again through `parent_post_id`, a thread it rejects
+/// has no documents.
+pub(crate) fn documents(
+    threads: Vec<(String, Vec<Post>)>,
+    options: &TraversalOptions,
+    hook: Option<&ThreadHook>,
+) -> Vec<Thread> {
+    let posts = threads.iter().map(|(_, posts)| posts.len()).sum();
+    let min_len = traversal::roots_per_task(threads.len(), posts, options);
+    threads
+        .into_par_iter()
+        .with_min_len(min_len)
+        .flat_map_iter(|(root_id, posts)| {
+            let posts = match hook {
+                Some(hook) => hook(&root_id, posts).unwrap_or_default(),
+                None => posts,
+            };
+            if posts.is_empty() {
+                return Vec::new();
+            }
+            thread_parts(root_id, posts, options)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ReplyOrder;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_thread_parts() {
+        let posts = vec![
+            Post::new("3", false, "3", "2", "1").with_created_utc(3.0),
+            Post::new("2", false, "2", "1", "1").with_created_utc(2.0),
+            Post::new("1", true, "1", "1", "1").with_created_utc(1.0),
+            // parent is missing from the dump
+            Post::new("5", false, "5", "4", "1").with_created_utc(5.0),
+            Post::new("6", false, "6", "1", "1").with_created_utc(1.5),
+            // cycle
+            Post::new("7", false, "7", "8", "1"),
+            Post::new("8", false, "8", "7", "1"),
+        ];
+        let parts = thread_parts("1".to_string(), posts.clone(), &TraversalOptions::default());
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].id, "1");
+        assert_eq!(parts[0].posts, vec!["1", "2", "3", "6", "5", "7", "8"]);
+
+        let options = TraversalOptions {
+            reply_order: ReplyOrder::Time,
+            ..Default::default()
+        };
+        let parts = thread_parts("1".to_string(), posts, &options);
+        assert_eq!(parts[0].posts[..5], ["1", "6", "2", "3", "5"]);
+    }
+}
@@ -0,0 +1,237 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::config::{Config, DuplicateIds, MissingParent, ReadOptions, TraversalOptions};
+use crate::error;
+use crate::experimental;
+use crate::experimental::graph::ThreadGraph;
+use crate::forum_thread::{InputParser, JsonlParser, Post};
+use crate::utils;
+
+/// The reconstructed threads of a folder, one `(root_id, posts)` at a time
+///
+/// The posts of a thread are in traversal order, with the order and `max_depth` of
+/// `config.traversal`, the parents missing from the dump are filled according to `missing_parent`.
+///
+/// Without `config.buckets` the graph of the whole folder is built on the first call to `next`
+/// and the threads are moved out of it one by one. With buckets the posts are first partitioned
+/// on disk by `root_post_id`, as with `--buckets`, and only the graph of the current bucket is in
+/// memory. The bucket files are removed as they are read, or when the iterator is dropped.
+///
+/// A post id read more than once keeps the copy chosen by `config.duplicate_ids`, there is no
+/// error to return with `DuplicateIds::Error`, the first copy is kept.
+///
+/// # Example
+///
+/// ```no_run
+/// # use std::path::Path;
+/// # use forum_rs::{Config, ThreadIter};
+/// let threads = ThreadIter::new(Path::new("main_folder/sub1"), &Config::default())?;
+/// let longest = threads.map(|(_, posts)| posts.len()).max();
+/// # Ok::<(), forum_rs::Error>(())
+/// ```
+pub struct ThreadIter {
+    options: TraversalOptions,
+    read: ReadOptions,
+    duplicate_ids: DuplicateIds,
+    parser: Arc<dyn InputParser>,
+    /// The files of every graph that is still to be built
+    pending: std::vec::IntoIter<Vec<PathBuf>>,
+    /// Folder of the buckets, removed at the end
+    bucket_dir: Option<PathBuf>,
+    current: std::vec::IntoIter<(String, Vec<Post>)>,
+}
+
+impl ThreadIter {
+    /// Read the threads of `folder`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the folder cannot be read or its buckets cannot be written. Files that
+    /// cannot be opened are reported and skipped.
+    pub fn new(folder: &Path, config: &Config) -> error::Result<ThreadIter> {
+        let entries = utils::file::folder_files(folder)?;
+        let parser = config.stages.parser.as_deref();
+        let (pending, bucket_dir) = match &config.buckets {
+            Some(buckets) => {
+                let dir = experimental::bucketed::bucket_dir(folder, buckets);
+                let paths = experimental::bucketed::write_buckets(
+                    &entries,
+                    &dir,
+                    buckets.count.max(1),
+                    parser,
+                    &config.read,
+                )
+                .map_err(|source| error::Error::Buckets {
+                    path: folder.to_path_buf(),
+                    source,
+                })?;
+                let pending = paths.into_iter().map(|path| vec![path]).collect();
+                (pending, Some(dir))
+            }
+            None => (vec![entries], None),
+        };
+        Ok(ThreadIter {
+            options: config.traversal,
+            read: config.read,
+            duplicate_ids: config.duplicate_ids,
+            parser: config
+                .stages
+                .parser
+                .clone()
+                .unwrap_or_else(|| Arc::new(JsonlParser)),
+            pending: pending.into_iter(),
+            bucket_dir,
+            current: Vec::new().into_iter(),
+        })
+    }
+
+    /// Build the graph of the next files and keep its threads
+    fn load(&mut self, files: Vec<PathBuf>) {
+        let threadgraph = experimental::sender::build_graph(
+            &files,
+            self.parser.as_ref(),
+            &self.read,
+            self.duplicate_ids,
+        );
+        if self.bucket_dir.is_some() {
+            for file in &files {
+                let _ = fs::remove_file(file);
+            }
+        }
+        self.current = into_threads(threadgraph, &self.options).into_iter();
+    }
+}
+
+/// The threads of a graph as read, with the missing parents filled and the cycles broken
+pub(crate) fn into_threads(
+    mut threadgraph: ThreadGraph,
+    options: &TraversalOptions,
+) -> Vec<(String, Vec<Post>)> {
+    threadgraph.fill_missing_parents(options.missing_parent);
+    threadgraph.break_cycles();
+    thread_posts(threadgraph, options)
+}
+
+/// The threads of a graph whose missing parents are filled and cycles broken, the placeholders
+/// are left out with `MissingParent::Skip`
+pub(crate) fn thread_posts(
+    threadgraph: ThreadGraph,
+    options: &TraversalOptions,
+) -> Vec<(String, Vec<Post>)> {
+    let mut threads = threadgraph.into_threads(options);
+    if options.missing_parent == MissingParent::Skip {
+        for (_, posts) in threads.iter_mut() {
+            posts.retain(|post| !post.id.is_empty());
+        }
+        threads.retain(|(_, posts)| !posts.is_empty());
+    }
+    threads
+}
+
+impl Iterator for ThreadIter {
+    type Item = (String, Vec<Post>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(thread) = self.current.next() {
+                return Some(thread);
+            }
+            let files = self.pending.next()?;
+            self.load(files);
+        }
+    }
+}
+
+impl Drop for ThreadIter {
+    fn drop(&mut self) {
+        let Some(dir) = &self.bucket_dir else {
+            return;
+        };
+        for files in self.pending.by_ref() {
+            for file in files {
+                let _ = fs::remove_file(file);
+            }
+        }
+        let _ = fs::remove_dir(dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Buckets;
+    use crate::forum_thread::input_line;
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_thread_iter() {
+        let temp_dir = TempDir::new().unwrap();
+        let folder = temp_dir.path().join("input").join("sub");
+        fs::create_dir_all(&folder).unwrap();
+        let line = |id: &str, parent: &str, root: &str| input_line(id, parent, root, id);
+        let lines = [
+            line("1", "1", "1"),
+            line("2", "1", "1"),
+            line("10", "10", "10"),
+            line("3", "2", "1"),
+            line("11", "10", "10"),
+            // parent is missing from the dump
+            line("21", "20", "20"),
+        ];
+        fs::write(folder.join("a.jsonl"), lines.join("\n")).unwrap();
+
+        let buckets = Buckets {
+            count: 3,
+            dir: temp_dir.path().join("buckets"),
+        };
+        for buckets in [None, Some(buckets.clone())] {
+            let config = Config {
+                buckets,
+                ..Default::default()
+            };
+            let mut threads: Vec<(String, Vec<String>)> = ThreadIter::new(&folder, &config)
+                .unwrap()
+                .map(|(root_id, posts)| {
+                    (
+                        root_id,
+                        posts
+                            .into_iter()
+                            .map(|post| post.id.into_string())
+                            .collect(),
+                    )
+                })
+                .collect();
+            threads.sort();
+            let thread = |root_id: &str, ids: &[&str]| {
+                let ids = ids.iter().map(|id| id.to_string()).collect();
+                (root_id.to_string(), ids)
+            };
+            assert_eq!(
+                threads,
+                vec![
+                    thread("1", &["1", "2", "3"]),
+                    thread("10", &["10", "11"]),
+                    thread("20", &["", "21"]),
+                ]
+            );
+        }
+        assert!(!buckets.dir.join("sub").exists());
+
+        // Dropped before the end, the buckets are removed all the same
+        let config = Config {
+            buckets: Some(buckets.clone()),
+            traversal: TraversalOptions {
+                missing_parent: MissingParent::Skip,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut threads = ThreadIter::new(&folder, &config).unwrap();
+        assert!(threads.next().is_some());
+        drop(threads);
+        assert!(!buckets.dir.join("sub").exists());
+    }
+}
@@ -17,3 +17,38 @@ Contains the function `process_graph` that processes objects sent by the iterato
 pub mod sender;
 
 pub mod graph;
+
+/**
+
+# Grouped module
+
+Groups the posts by `root_post_id` instead of building a graph, for dumps where the root ids can be trusted.
+*/
+pub mod grouped;
+
+/**
+
+# Bucketed module
+
+Partitions the posts of a folder into on-disk buckets by `root_post_id` and builds the threads one bucket at a time.
+*/
+pub mod bucketed;
+
+/**
+
+# Iter module
+
+Hands over the reconstructed threads of a folder one at a time, for library users.
+*/
+pub mod iter;
+
+/// Shared traversal of a thread, used by the graph and the grouped modes
+pub mod traversal;
+
+/**
+
+# Scheduled module
+
+Puts the files of every folder in one work queue and builds each folder in shards by `root_post_id`.
+*/
+pub mod scheduled;
@@ -1,14 +1,19 @@
+use crossbeam_channel::unbounded;
 use rayon::prelude::*;
+use std::collections::hash_map::DefaultHasher;
 use std::fs::File;
+use std::hash::{Hash, Hasher};
 use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
+use crate::config::{DuplicateIds, ReadOptions, TraversalOptions};
 use crate::experimental;
 use crate::forum_thread;
 use crate::graph;
 use crate::utils;
 
-pub fn _get_threads(path: &str) -> Vec<(String, Vec<String>)> {
+pub fn _get_threads<P: AsRef<Path>>(path: P) -> Vec<(String, Vec<String>)> {
     let entries = utils::file::single_folder(path);
     let threadgraph = Arc::new(Mutex::new(graph::ThreadGraph::new()));
     let comments = Arc::new(Mutex::new(Vec::with_capacity(10000)));
@@ -29,12 +34,16 @@ pub fn _get_threads(path: &str) -> Vec<(String, Vec<String>)> {
                 if let Some(thread) = forum_thread::Post::from_json_struct(json) {
                     // Lock the mutex only when needed
                     let mut graph = threadgraph.lock().unwrap();
-                    let thread_node = graph.add_node(thread.clone());
-                    if thread.is_thread {
-                        graph.add_threads(thread_node);
-                    } else {
-                        drop(graph);
-                        comments.lock().unwrap().push(thread);
+                    // Only the ids of a comment are needed for its edge
+                    let edge = (!thread.is_thread)
+                        .then(|| (thread.parent_post_id.clone(), thread.id.clone()));
+                    let thread_node = graph.add_node(thread);
+                    match edge {
+                        None => graph.add_threads(thread_node),
+                        Some(edge) => {
+                            drop(graph);
+                            comments.lock().unwrap().push(edge);
+                        }
                     };
                 }
             }
@@ -45,17 +54,17 @@ pub fn _get_threads(path: &str) -> Vec<(String, Vec<String>)> {
     let mut threadgraph = threadgraph.lock().unwrap();
     // let comment_time = std::time::Instant::now();
     let comments = comments.lock().unwrap();
-    for comment in comments.iter() {
-        threadgraph.add_edge(&comment.parent_post_id, &comment.id);
+    for (parent_post_id, id) in comments.iter() {
+        threadgraph.add_edge(parent_post_id, id);
     }
     // println!("Time taken for comments: {:.2?}", comment_time.elapsed());
     // let traverse_time = std::time::Instant::now();
-    threadgraph.traverse()
+    std::mem::take(&mut *threadgraph).into_traverse()
     // println!("Time taken for traverse: {:.2?}", traverse_time.elapsed());
     // threads
 }
 
-pub fn get_threads(path: &str) -> Vec<(String, Vec<String>)> {
+pub fn get_threads<P: AsRef<Path>>(path: P) -> Vec<(String, Vec<String>)> {
     let entries = utils::file::single_folder(path);
     let threadgraph = Arc::new(Mutex::new(experimental::graph::ThreadGraph::new()));
 
@@ -74,18 +83,144 @@ pub fn get_threads(path: &str) -> Vec<(String, Vec<String>)> {
                 if let Some(thread) = forum_thread::Post::from_json_struct(json) {
                     // Lock the mutex only when needed
                     let mut graph = threadgraph.lock().unwrap();
-                    graph.add_post(thread.clone());
+                    graph.add_post(thread);
                 }
             }
         });
     });
-    let threadgraph = threadgraph.lock().unwrap();
+    let mut threadgraph = threadgraph.lock().unwrap();
 
-    threadgraph.traverse()
+    std::mem::take(&mut *threadgraph)
+        .into_documents(&TraversalOptions::default())
+        .into_iter()
+        .map(Into::into)
+        .collect()
     // println!("Time taken for traverse: {:.2?}", traverse_time.elapsed());
     // threads
 }
 
+/// Build the reply graphs of `entries` in `shards` independent graphs
+///
+/// Unlike [get_threads](fn.get_threads.html), there is no lock shared by the readers: every post
+/// is routed by the hash of its `root_post_id` to the channel of a shard, and each shard builds its
+/// own graph on its own thread. A thread whose posts declare different roots may be split across
+/// shards, the same caveat as `--buckets`.
+///
+/// # Arguments
+///
+/// * `entries` - `&[PathBuf]` - The `jsonl` files to read
+/// * `shards` - `usize` - The number of graphs, at least one
+/// * `parser` - `&dyn InputParser` - Reads the lines of the files
+/// * `read` - `&ReadOptions` - How the lines are read
+/// * `duplicate_ids` - `DuplicateIds` - Which copy of a duplicate id each shard keeps
+///
+/// # Returns
+///
+/// * `Vec<ThreadGraph>` - One graph per shard
+pub fn build_sharded(
+    entries: &[PathBuf],
+    shards: usize,
+    parser: &dyn forum_thread::InputParser,
+    read: &ReadOptions,
+    duplicate_ids: DuplicateIds,
+) -> Vec<experimental::graph::ThreadGraph> {
+    let shards = shards.max(1);
+    let posts = utils::file::estimate_lines(entries) / shards;
+    let (senders, handles): (Vec<_>, Vec<_>) = (0..shards)
+        .map(|_| {
+            let (post_tx, post_rx) = unbounded::<(forum_thread::Post, u64)>();
+            let handle = std::thread::spawn(move || {
+                let mut threadgraph = experimental::graph::ThreadGraph::with_capacity(posts)
+                    .with_duplicate_ids(duplicate_ids);
+                while let Ok((post, position)) = post_rx.recv() {
+                    threadgraph.add_post_at(post, position);
+                }
+                threadgraph
+            });
+            (post_tx, handle)
+        })
+        .unzip();
+
+    experimental::sender::for_each_post(entries, parser, read, |post, position| {
+        let shard = shard_of(&post.root_post_id, shards);
+        senders[shard].send((post, position)).unwrap();
+    });
+    drop(senders);
+
+    handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect()
+}
+
+/// The shard of the posts of `root_post_id`, out of `shards`
+pub fn shard_of(root_post_id: &str, shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    root_post_id.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+
+/// Same as [get_threads](fn.get_threads.html) with the graph built in shards, see
+/// [build_sharded](fn.build_sharded.html)
+pub fn get_threads_sharded<P: AsRef<Path>>(path: P, shards: usize) -> Vec<(String, Vec<String>)> {
+    let entries = utils::file::single_folder(path);
+    build_sharded(
+        &entries,
+        shards,
+        &forum_thread::JsonlParser,
+        &ReadOptions::default(),
+        DuplicateIds::default(),
+    )
+    .into_par_iter()
+    .flat_map_iter(|mut threadgraph| {
+        threadgraph.break_cycles();
+        threadgraph.traverse()
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forum_thread::input_line;
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_get_threads_sharded() {
+        let temp_dir = TempDir::new().unwrap();
+        let line = |id: &str, parent: &str, root: &str| input_line(id, parent, root, id);
+        let first = [
+            line("1", "1", "1"),
+            line("2", "1", "1"),
+            line("10", "10", "10"),
+        ];
+        let second = [
+            line("3", "1", "1"),
+            line("11", "10", "10"),
+            line("20", "20", "20"),
+        ];
+        std::fs::write(temp_dir.path().join("a.jsonl"), first.join("\n")).unwrap();
+        std::fs::write(temp_dir.path().join("b.jsonl"), second.join("\n")).unwrap();
+
+        for shards in [1, 3] {
+            let mut threads = get_threads_sharded(temp_dir.path(), shards);
+            threads.sort();
+            assert_eq!(
+                threads,
+                vec![
+                    (
+                        "1".to_string(),
+                        vec!["1".to_string(), "2".into(), "3".into()]
+                    ),
+                    ("10".to_string(), vec!["10".to_string(), "11".into()]),
+                    ("20".to_string(), vec!["20".to_string()]),
+                ]
+            );
+        }
+    }
+}
+
 // Kept for reference
 // fn rayon_scope () {
 
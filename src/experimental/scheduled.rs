@@ -0,0 +1,488 @@
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, Receiver, Sender};
+use rayon::ScopeFifo;
+
+use crate::config::{Config, ReadOptions};
+use crate::error;
+use crate::experimental;
+use crate::experimental::graph::ThreadGraph;
+use crate::forum_thread::{self, InputParser, Post};
+use crate::utils;
+use crate::utils::writer::{Route, ThreadPost};
+
+/// Posts a reader keeps per shard before adding them to the graph, so the lock is taken once for
+/// all of them
+const BATCH: usize = 1024;
+
+/// A folder processed by [process_folders](fn.process_folders.html)
+#[derive(Debug)]
+pub struct Finished<'a> {
+    pub folder: &'a Path,
+    /// The size given for the folder
+    pub size: u64,
+    /// From the first file read to the last record handed over
+    pub elapsed: Duration,
+    /// A file or a shard of the folder panicked, or the folder could not be read, its records
+    /// are not built
+    pub failed: bool,
+    /// Time spent reading the files and building the threads, summed over the workers
+    pub get_threads: Duration,
+    /// Time spent building the records, summed over the workers
+    pub create_posts: Duration,
+}
+
+/// What every task of a run shares
+struct Shared<'a, F, D> {
+    config: &'a Config,
+    parser: &'a dyn InputParser,
+    emit: F,
+    finished: D,
+    /// A slot taken for every folder being built, with a read pool
+    building: Option<Receiver<()>>,
+}
+
+/// A folder in progress: its files are read into the shards, then the shards are built once
+/// the last file is read
+struct Work<'a> {
+    folder: &'a Path,
+    size: u64,
+    entries: Vec<PathBuf>,
+    shards: Vec<Mutex<ThreadGraph>>,
+    files_left: AtomicUsize,
+    shards_left: AtomicUsize,
+    failed: AtomicBool,
+    /// The documents that may repost a link of another shard
+    reposts: utils::links::Reposts,
+    start: Instant,
+    /// Nanoseconds of each stage
+    get_threads: AtomicU64,
+    create_posts: AtomicU64,
+    _reservation: utils::memory::Reservation<'static>,
+}
+
+/// Process `folders` with the files of every folder in a single work queue
+///
+/// The folders are started in the given order, each one once its `sizes` fit in
+/// `config.max_memory`, and every file is a task of its own on the global pool. The posts of a
+/// file are routed by the hash of their `root_post_id` to the shards of the folder, see
+/// [shard_of](../parallel/fn.shard_of.html), so the threads do not depend on which worker read
+/// which file. When the last file of a folder is read, each shard is a task that builds its
+/// threads and hands the records to `emit`, then `finished` is called once for the folder.
+///
+/// A folder is built in a single graph, so its threads are the same as with one folder per
+/// worker, or in `config.graph_shards`. As with `--graph-shards`, a thread whose posts declare
+/// different roots may then be split across shards.
+///
+/// With `config.read_threads` the files are read on a pool of their own, and the folders whose
+/// files are all read are built on the global pool, so the next folders are read while the
+/// previous ones are built. The two pools are connected by bounded channels: at most one folder
+/// per worker of the global pool is being built and as many wait for it, then the readers wait.
+///
+/// The calling thread only dispatches and waits, it must not be a worker of the global pool. No
+/// folder is started after a shutdown is [requested](../../utils/shutdown/fn.requested.html).
+pub fn process_folders<F, D>(
+    folders: &[PathBuf],
+    sizes: &[u64],
+    config: &Config,
+    emit: F,
+    finished: D,
+) where
+    F: Fn(Route, ThreadPost) + Sync,
+    D: Fn(Finished) + Sync,
+{
+    let depth = rayon::current_num_threads();
+    let (building_tx, building_rx) = bounded(depth);
+    let mut shared = Shared {
+        config,
+        parser: config.stages.parser(),
+        emit,
+        finished,
+        building: None,
+    };
+    let Some(read_threads) = config.read_threads else {
+        let shared = &shared;
+        rayon::in_place_scope_fifo(|scope| {
+            start_folders(folders, sizes, shared, |work| {
+                read_files(scope, shared, work, None)
+            })
+        });
+        return;
+    };
+    let read_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(read_threads)
+        .thread_name(|i| format!("read-{}", i))
+        .build()
+        .expect("Unable to create the read thread pool");
+    shared.building = Some(building_rx);
+    let shared = &shared;
+    let (ready_tx, ready_rx) = bounded(depth);
+    rayon::in_place_scope_fifo(|build| {
+        thread::scope(|threads| {
+            threads.spawn(|| {
+                for work in ready_rx {
+                    building_tx.send(()).unwrap();
+                    build_shards(build, shared, work);
+                }
+            });
+            read_pool.in_place_scope_fifo(|read| {
+                start_folders(folders, sizes, shared, |work| {
+                    read_files(read, shared, work, Some(&ready_tx))
+                })
+            });
+            drop(ready_tx);
+        });
+    });
+}
+
+/// Start the folders in order, each one once it fits in the memory budget
+fn start_folders<'a, F, D, S>(
+    folders: &'a [PathBuf],
+    sizes: &[u64],
+    shared: &Shared<F, D>,
+    mut start_reading: S,
+) where
+    D: Fn(Finished),
+    S: FnMut(Arc<Work<'a>>),
+{
+    let config = shared.config;
+    for (folder, &size) in folders.iter().zip(sizes) {
+        if utils::shutdown::requested() {
+            return;
+        }
+        let reservation = utils::memory::IN_FLIGHT.reserve(size, config.max_memory);
+        if utils::shutdown::requested() {
+            return;
+        }
+        let start = Instant::now();
+        let mut entries = match utils::file::folder_files(folder) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error::report(e);
+                (shared.finished)(Finished {
+                    folder,
+                    size,
+                    elapsed: start.elapsed(),
+                    failed: true,
+                    get_threads: Duration::ZERO,
+                    create_posts: Duration::ZERO,
+                });
+                continue;
+            }
+        };
+        entries.sort();
+        let shards = config.graph_shards.unwrap_or(1).max(1);
+        let posts = utils::file::estimate_lines(&entries) / shards;
+        start_reading(Arc::new(Work {
+            folder,
+            size,
+            files_left: AtomicUsize::new(entries.len()),
+            entries,
+            shards: (0..shards)
+                .map(|_| {
+                    let threadgraph = ThreadGraph::with_capacity(posts);
+                    Mutex::new(threadgraph.with_duplicate_ids(config.duplicate_ids))
+                })
+                .collect(),
+            shards_left: AtomicUsize::new(shards),
+            failed: AtomicBool::new(false),
+            reposts: utils::links::Reposts::new(config.traversal.link_reposts),
+            start,
+            get_threads: AtomicU64::new(0),
+            create_posts: AtomicU64::new(0),
+            _reservation: reservation,
+        }));
+    }
+}
+
+/// Read every file of a folder on a task of its own, once the last one is read the folder is
+/// built, or sent to `ready` to be built on another pool
+fn read_files<'s, 'a: 's, F, D>(
+    scope: &ScopeFifo<'s>,
+    shared: &'s Shared<'a, F, D>,
+    work: Arc<Work<'a>>,
+    ready: Option<&'s Sender<Arc<Work<'a>>>>,
+) where
+    F: Fn(Route, ThreadPost) + Sync,
+    D: Fn(Finished) + Sync,
+{
+    let done = move |scope: &ScopeFifo<'s>, work| match ready {
+        Some(ready) => ready.send(work).unwrap(),
+        None => build_shards(scope, shared, work),
+    };
+    if work.entries.is_empty() {
+        done(scope, work);
+        return;
+    }
+    for file in 0..work.entries.len() {
+        let work = work.clone();
+        scope.spawn_fifo(move |scope| {
+            work.run(|| {
+                read_file(&work, file, shared.parser, &shared.config.read);
+                Ok(())
+            });
+            if work.files_left.fetch_sub(1, Ordering::SeqCst) == 1 {
+                done(scope, work);
+            }
+        });
+    }
+}
+
+impl Work<'_> {
+    /// Run a task of the folder, an error or a panic is reported and fails the folder
+    fn run<G: FnOnce() -> error::Result<()>>(&self, task: G) {
+        if let Err(e) = error::catch_panic(self.folder, task) {
+            error::report(e);
+            self.failed.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// A graph left by a panicked task is still taken, the folder is failed and not built anyway
+    fn shard(&self, shard: usize) -> MutexGuard<'_, ThreadGraph> {
+        self.shards[shard]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Read a file of the folder into its shards
+fn read_file(work: &Work, file: usize, parser: &dyn InputParser, read: &ReadOptions) {
+    let start = Instant::now();
+    let mut batches: Vec<Vec<(Post, u64)>> = vec![Vec::new(); work.shards.len()];
+    let add = |shard: usize, batch: &mut Vec<(Post, u64)>| {
+        let mut threadgraph = work.shard(shard);
+        for (post, position) in batch.drain(..) {
+            threadgraph.add_post_at(post, position);
+        }
+    };
+    let entry = &work.entries[file];
+    experimental::sender::for_each_post_in(entry, file, parser, read, |post, position| {
+        let shard = experimental::parallel::shard_of(&post.root_post_id, work.shards.len());
+        batches[shard].push((post, position));
+        if batches[shard].len() >= BATCH {
+            add(shard, &mut batches[shard]);
+        }
+    });
+    for (shard, batch) in batches.iter_mut().enumerate() {
+        add(shard, batch);
+    }
+    work.get_threads
+        .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Build the threads of every shard of a folder whose files are read, on tasks of their own
+fn build_shards<'s, 'a: 's, F, D>(
+    scope: &ScopeFifo<'s>,
+    shared: &'s Shared<'a, F, D>,
+    work: Arc<Work<'a>>,
+) where
+    F: Fn(Route, ThreadPost) + Sync,
+    D: Fn(Finished) + Sync,
+{
+    for shard in 0..work.shards.len() {
+        let work = work.clone();
+        scope.spawn_fifo(move |_| {
+            if !work.failed.load(Ordering::SeqCst) {
+                work.run(|| build_shard(&work, shard, shared));
+            }
+            if work.shards_left.fetch_sub(1, Ordering::SeqCst) == 1 {
+                if !work.failed.load(Ordering::SeqCst) {
+                    work.run(|| {
+                        emit_threads(&work, work.reposts.release(), shared);
+                        Ok(())
+                    });
+                }
+                if let Some(building) = &shared.building {
+                    building.recv().unwrap();
+                }
+                (shared.finished)(Finished {
+                    folder: work.folder,
+                    size: work.size,
+                    elapsed: work.start.elapsed(),
+                    failed: work.failed.load(Ordering::SeqCst),
+                    get_threads: Duration::from_nanos(work.get_threads.load(Ordering::Relaxed)),
+                    create_posts: Duration::from_nanos(work.create_posts.load(Ordering::Relaxed)),
+                });
+            }
+        });
+    }
+}
+
+/// Build the threads of a shard and hand its records to `emit`
+fn build_shard<F, D>(work: &Work, shard: usize, shared: &Shared<F, D>) -> error::Result<()>
+where
+    F: Fn(Route, ThreadPost) + Sync,
+{
+    let _span = tracing::info_span!("folder", folder = %work.folder.display()).entered();
+    let start = Instant::now();
+    let threadgraph = mem::take(&mut *work.shard(shard));
+    let threads = experimental::sender::finish_graph(threadgraph, work.folder, shared.config)?;
+    work.get_threads
+        .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    emit_threads(work, work.reposts.hold(threads), shared);
+    Ok(())
+}
+
+/// Hand the records of threads of the folder to `emit`
+fn emit_threads<F, D>(work: &Work, threads: Vec<forum_thread::Thread>, shared: &Shared<F, D>)
+where
+    F: Fn(Route, ThreadPost) + Sync,
+{
+    let start = Instant::now();
+    forum_thread::for_each_kept_threadpost(threads, work.folder, shared.config, &shared.emit);
+    work.create_posts
+        .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TraversalOptions;
+    use crate::experimental::parallel;
+    use crate::forum_thread::input_line;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_process_folders() {
+        crate::globals::init_regex();
+        let temp_dir = TempDir::new().unwrap();
+        let line = |id: &str, parent: &str, root: &str| {
+            input_line(id, parent, root, &format!("post {id}"))
+        };
+        // The replies are in another file than their root
+        let files = [
+            (
+                "a",
+                "1.jsonl",
+                vec![line("1", "1", "1"), line("10", "10", "10")],
+            ),
+            (
+                "a",
+                "2.jsonl",
+                vec![line("2", "1", "1"), line("11", "10", "10")],
+            ),
+            ("a", "3.jsonl", vec![line("3", "2", "1")]),
+            (
+                "b",
+                "1.jsonl",
+                vec![line("20", "20", "20"), line("21", "20", "20")],
+            ),
+        ];
+        for (folder, file, lines) in files {
+            let folder = temp_dir.path().join(folder);
+            fs::create_dir_all(&folder).unwrap();
+            fs::write(folder.join(file), lines.join("\n")).unwrap();
+        }
+        let folders = vec![
+            temp_dir.path().join("a"),
+            temp_dir.path().join("b"),
+            temp_dir.path().join("missing"),
+        ];
+        for (graph_shards, read_threads) in [(None, None), (Some(3), None), (None, Some(2))] {
+            let config = Config {
+                graph_shards,
+                read_threads,
+                ..Default::default()
+            };
+            let records = Mutex::new(Vec::new());
+            let done = Mutex::new(Vec::new());
+            process_folders(
+                &folders,
+                &[0, 0, 0],
+                &config,
+                |_, threadpost| {
+                    let record = (threadpost.thread_id, threadpost.raw_content);
+                    records
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .push(record);
+                },
+                |finished| {
+                    let folder = finished.folder.file_name().unwrap().to_owned();
+                    done.lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .push((folder, finished.failed));
+                },
+            );
+            let mut records = records.into_inner().unwrap_or_else(PoisonError::into_inner);
+            records.sort();
+            let ids: Vec<&str> = records.iter().map(|(id, _)| id.as_str()).collect();
+            assert_eq!(ids, vec!["1", "10", "20"]);
+            assert!(records[0].1.contains("post 3"));
+            let mut done = done.into_inner().unwrap_or_else(PoisonError::into_inner);
+            done.sort();
+            assert_eq!(
+                done,
+                vec![
+                    ("a".into(), false),
+                    ("b".into(), false),
+                    ("missing".into(), true)
+                ]
+            );
+        }
+
+        // A large folder is still built in a single graph, a reply that declares another root stays
+        // in the thread of its parent
+        let folder = temp_dir.path().join("c");
+        fs::create_dir_all(&folder).unwrap();
+        let lines = [line("30", "30", "30"), line("31", "30", "99")];
+        fs::write(folder.join("1.jsonl"), lines.join("\n")).unwrap();
+        let records = Mutex::new(Vec::new());
+        process_folders(
+            &[folder],
+            &[1 << 40],
+            &Config::default(),
+            |_, threadpost| {
+                records
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push(threadpost.raw_content);
+            },
+            |_| {},
+        );
+        let records = records.into_inner().unwrap_or_else(PoisonError::into_inner);
+        assert_eq!(records.len(), 1);
+        assert!(records[0].contains("post 31"));
+
+        // Two threads with the same link in different shards, the repost is still left out
+        let folder = temp_dir.path().join("d");
+        fs::create_dir_all(&folder).unwrap();
+        let repost = (50..)
+            .map(|id| id.to_string())
+            .find(|id| parallel::shard_of(id, 4) != parallel::shard_of("40", 4))
+            .unwrap();
+        let link = |id: &str| input_line(id, id, id, "https://example.com/a");
+        let lines = [link("40"), line("41", "40", "40"), link(&repost)];
+        fs::write(folder.join("1.jsonl"), lines.join("\n")).unwrap();
+        let config = Config {
+            graph_shards: Some(4),
+            traversal: TraversalOptions {
+                link_reposts: Some(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let ids = Mutex::new(Vec::new());
+        process_folders(
+            &[folder],
+            &[0],
+            &config,
+            |_, threadpost| {
+                ids.lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push(threadpost.thread_id);
+            },
+            |_| {},
+        );
+        let ids = ids.into_inner().unwrap_or_else(PoisonError::into_inner);
+        assert_eq!(ids, vec!["40".to_string()]);
+        error::take_failures();
+    }
+}
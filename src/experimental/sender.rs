@@ -1,8 +1,13 @@
 use crossbeam_channel::{unbounded, Receiver, Sender};
 use rayon::prelude::*;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
 
+use crate::config::{Config, DuplicateIds, GraphExport, GraphFormat, ReadOptions, RootSource};
+use crate::error;
 use crate::experimental;
 use crate::forum_thread;
 use crate::utils;
@@ -26,8 +31,9 @@ Needs to be launched by a thread.
 
 # Arguments
 
-* `rx` - The receiver channel
-* `threadgraph` - The thread graph
+* `rx` - The receiver channel of the posts with their reading position
+* `posts` - The estimated number of posts
+* `duplicate_ids` - Which copy of a duplicate id is kept
 
 # Returns
 
@@ -59,16 +65,184 @@ drop(post_tx);
 
 ```
 */
-fn process_graph(rx: Receiver<forum_thread::Post>) -> experimental::graph::ThreadGraph {
-    let mut threadgraph = experimental::graph::ThreadGraph::new();
-    while let Ok(thread) = rx.recv() {
-        threadgraph.add_post(thread);
+fn process_graph(
+    rx: Receiver<(forum_thread::Post, u64)>,
+    posts: usize,
+    duplicate_ids: DuplicateIds,
+) -> experimental::graph::ThreadGraph {
+    let mut threadgraph =
+        experimental::graph::ThreadGraph::with_capacity(posts).with_duplicate_ids(duplicate_ids);
+    while let Ok((thread, position)) = rx.recv() {
+        threadgraph.add_post_at(thread, position);
     }
     threadgraph
 }
 
-pub fn get_threads(path: &str) -> Vec<(String, Vec<String>)> {
-    let entries = utils::file::single_folder(path);
+/// Number of reply cycles broken over all the folders, see
+/// [break_cycles](../graph/struct.ThreadGraph.html#method.break_cycles)
+static CYCLES_BROKEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of reply cycles found and broken so far
+pub fn cycles_broken() -> usize {
+    CYCLES_BROKEN.load(Ordering::Relaxed)
+}
+
+/// Folders with posts whose `root_post_id` disagrees with the reply graph, with the number of posts
+static ROOT_MISMATCHES: Mutex<Vec<(PathBuf, usize)>> = Mutex::new(Vec::new());
+
+/// Folders where `--validate-roots` found mismatches so far, sorted by folder
+pub fn root_mismatches() -> Vec<(PathBuf, usize)> {
+    // A folder built in shards has one entry per shard
+    let mut folders: BTreeMap<PathBuf, usize> = BTreeMap::new();
+    for (folder, count) in ROOT_MISMATCHES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+    {
+        *folders.entry(folder.clone()).or_default() += count;
+    }
+    folders.into_iter().collect()
+}
+
+/// Folders with post ids read more than once, with the number of extra copies
+static DUPLICATE_IDS: Mutex<Vec<(PathBuf, usize)>> = Mutex::new(Vec::new());
+
+/// Folders where duplicate post ids were found so far, sorted by folder
+pub fn duplicate_ids() -> Vec<(PathBuf, usize)> {
+    let mut folders: BTreeMap<PathBuf, usize> = BTreeMap::new();
+    for (folder, count) in DUPLICATE_IDS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+    {
+        *folders.entry(folder.clone()).or_default() += count;
+    }
+    folders.into_iter().collect()
+}
+
+/// Write the graphs of the selected threads found in this folder
+///
+/// The files are named `<folder>_<thread id>.dot` (or `.graphml`), errors are reported but do not
+/// stop the processing.
+fn export_graphs(
+    threadgraph: &experimental::graph::ThreadGraph,
+    path: &Path,
+    export: &GraphExport,
+) {
+    let folder = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    for thread_id in &export.thread_ids {
+        let (graph, extension) = match export.format {
+            GraphFormat::Dot => (threadgraph.to_dot(thread_id), "dot"),
+            GraphFormat::Graphml => (threadgraph.to_graphml(thread_id), "graphml"),
+        };
+        let Some(graph) = graph else {
+            continue;
+        };
+        let safe_id: String = thread_id
+            .chars()
+            .map(|c| if c.is_alphanumeric() { c } else { '_' })
+            .collect();
+        let file = export
+            .dir
+            .join(format!("{}_{}.{}", folder, safe_id, extension));
+        if let Err(e) = fs::create_dir_all(&export.dir).and_then(|_| fs::write(&file, graph)) {
+            tracing::warn!(file = %file.display(), error = %e, "Unable to export the graph");
+        }
+    }
+}
+
+pub fn get_threads<P: AsRef<Path>>(
+    path: P,
+    config: &Config,
+) -> error::Result<Vec<forum_thread::Thread>> {
+    let entries = utils::file::single_folder(&path);
+    threads_from_files(&entries, path.as_ref(), config)
+}
+
+/// Same as [get_threads](fn.get_threads.html) for a given list of `jsonl` files
+///
+/// # Arguments
+///
+/// * `entries` - `&[PathBuf]` - The files to read
+/// * `path` - `&Path` - The folder they belong to, used to name the exported graphs
+/// * `config` - `&Config` - The processing options
+///
+/// # Errors
+///
+/// Returns [Error::DuplicateIds](../../error/enum.Error.html) if a post id is read more than once
+/// with `DuplicateIds::Error`.
+pub fn threads_from_files(
+    entries: &[PathBuf],
+    path: &Path,
+    config: &Config,
+) -> error::Result<Vec<forum_thread::Thread>> {
+    let parser = config.stages.parser();
+    if let Some(shards) = config.graph_shards {
+        let shards = experimental::parallel::build_sharded(
+            entries,
+            shards,
+            parser,
+            &config.read,
+            config.duplicate_ids,
+        )
+        .into_par_iter()
+        .map(|threadgraph| finish_graph(threadgraph, path, config))
+        .collect::<error::Result<Vec<_>>>()?;
+        return Ok(shards.into_iter().flatten().collect());
+    }
+
+    let threadgraph = build_graph(entries, parser, &config.read, config.duplicate_ids);
+    finish_graph(threadgraph, path, config)
+}
+
+/// Same as [threads_from_files](fn.threads_from_files.html) for lines already in memory, read on
+/// the current thread
+///
+/// Used where there are no threads to spawn, for wasm32. Lines that cannot be parsed are skipped.
+///
+/// # Example
+///
+/// ```no_run
+/// # use forum_rs::experimental::sender::threads_from_lines;
+/// # use forum_rs::Config;
+/// let jsonl = std::fs::read_to_string("main_folder/sub1/posts.jsonl")?;
+/// let threads = threads_from_lines(&jsonl, &Config::default())?;
+/// # Ok::<(), forum_rs::Error>(())
+/// ```
+pub fn threads_from_lines(text: &str, config: &Config) -> error::Result<Vec<forum_thread::Thread>> {
+    let threadgraph = graph_from_lines(text, config.stages.parser(), config.duplicate_ids);
+    finish_graph(threadgraph, Path::new(""), config)
+}
+
+/// Same as [build_graph](fn.build_graph.html) for lines already in memory, read on the current
+/// thread
+pub fn graph_from_lines(
+    text: &str,
+    parser: &dyn forum_thread::InputParser,
+    duplicate_ids: DuplicateIds,
+) -> experimental::graph::ThreadGraph {
+    let mut threadgraph = experimental::graph::ThreadGraph::new().with_duplicate_ids(duplicate_ids);
+    let text = text.trim_start_matches(utils::json::BOM);
+    for (line, text) in text.lines().enumerate() {
+        if let Ok(Some(post)) = parser.parse(text) {
+            // The position of the first file of `for_each_post`
+            threadgraph.add_post_at(post, line as u64);
+        }
+    }
+    threadgraph
+}
+
+/// Read the posts of `entries` into a single graph, as is, the missing parents and the cycles
+/// are left to the caller
+pub fn build_graph(
+    entries: &[PathBuf],
+    parser: &dyn forum_thread::InputParser,
+    read: &ReadOptions,
+    duplicate_ids: DuplicateIds,
+) -> experimental::graph::ThreadGraph {
     let (post_tx, post_rx) = unbounded();
     // let (string_tx, string_rx) = bounded(1000);
 
@@ -76,31 +250,397 @@ pub fn get_threads(path: &str) -> Vec<(String, Vec<String>)> {
     //     process_line(string_rx, post_tx);
     // });
 
-    let graph_handle = std::thread::spawn(move || process_graph(post_rx));
+    let posts = utils::file::estimate_lines(entries);
+    let graph_handle = std::thread::spawn(move || process_graph(post_rx, posts, duplicate_ids));
     // let threadgraph = Arc::new(Mutex::new(graph::ThreadGraph::new()));
     // let comments = Arc::new(Mutex::new(Vec::with_capacity(10000)));
     // this shouldn't be parallelized for safety
-    entries.par_iter().for_each(|entry| {
-        let fp = File::open(entry).unwrap();
-        let reader = BufReader::new(fp);
-
-        reader
-            .lines()
-            .map_while(Result::ok)
-            .filter_map(|line| {
-                serde_json::from_str::<forum_thread::JsonStruct>(&line)
-                    .ok()
-                    .and_then(forum_thread::Post::from_json_struct)
-            })
-            .for_each(|post| {
-                post_tx.send(post).unwrap();
-            });
+    for_each_post(entries, parser, read, |post, position| {
+        post_tx.send((post, position)).unwrap();
     });
 
     // Drop the sender to signal the end of the stream
     drop(post_tx);
 
     // Wait for the graph processing to complete
-    let threadgraph = graph_handle.join().unwrap();
-    threadgraph.traverse()
+    graph_handle.join().unwrap()
+}
+
+/// Read the posts of `entries` in parallel with `parser` and call `f` with each post and its
+/// reading position, the lines are read with `read`
+///
+/// The files are sorted and the position is made of the file and the line, so a graph built with
+/// [add_post_at](../graph/struct.ThreadGraph.html#method.add_post_at) does not depend on the order
+/// the posts arrive in. Lines that cannot be parsed are skipped and [rejected](../../utils/rejects/fn.invalid_line.html),
+/// the files that cannot be opened are skipped, see [read_lines](../../utils/file/fn.read_lines.html).
+pub fn for_each_post<F>(
+    entries: &[PathBuf],
+    parser: &dyn forum_thread::InputParser,
+    read: &ReadOptions,
+    f: F,
+) where
+    F: Fn(forum_thread::Post, u64) + Sync,
+{
+    let mut entries = entries.to_vec();
+    entries.sort();
+    entries
+        .par_iter()
+        .enumerate()
+        .for_each(|(file, entry)| for_each_post_in(entry, file, parser, read, &f));
+}
+
+/// Same as [for_each_post](fn.for_each_post.html) for a single file, `file` is its index in the
+/// sorted files of the graph
+pub fn for_each_post_in<F>(
+    entry: &Path,
+    file: usize,
+    parser: &dyn forum_thread::InputParser,
+    read: &ReadOptions,
+    mut f: F,
+) where
+    F: FnMut(forum_thread::Post, u64),
+{
+    let _timer = utils::timing::start(utils::timing::Stage::Read);
+    let Some(lines) = utils::file::read_lines(entry, read) else {
+        return;
+    };
+    let (mut posts, mut bytes) = (0, 0);
+    lines
+        .enumerate()
+        .filter_map(|(line, text)| {
+            bytes += text.len() as u64 + 1;
+            parser
+                .parse(&text)
+                .inspect_err(|e| utils::rejects::invalid_line(entry, &text, e.as_ref()))
+                .ok()
+                .flatten()
+                .map(|post| (post, ((file as u64) << 32) | line as u64))
+        })
+        .for_each(|(post, position)| {
+            posts += 1;
+            f(post, position)
+        });
+    utils::file::record_read(posts, bytes);
+}
+
+/// Prepare a built graph and flatten it into threads
+///
+/// Counts the duplicate post ids, fills the missing parents, exports the selected graphs, breaks
+/// the reply cycles and checks the roots if requested.
+pub(crate) fn finish_graph(
+    mut threadgraph: experimental::graph::ThreadGraph,
+    path: &Path,
+    config: &Config,
+) -> error::Result<Vec<forum_thread::Thread>> {
+    let _timer = utils::timing::start(utils::timing::Stage::GetThreads);
+    let duplicates = threadgraph.duplicates();
+    if duplicates > 0 {
+        DUPLICATE_IDS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((path.to_path_buf(), duplicates));
+        if config.duplicate_ids == DuplicateIds::Error {
+            return Err(error::Error::DuplicateIds {
+                path: path.to_path_buf(),
+                count: duplicates,
+            });
+        }
+    }
+    threadgraph.fill_missing_parents(config.traversal.missing_parent);
+    if let Some(export) = &config.export_graph {
+        export_graphs(&threadgraph, path, export);
+    }
+    CYCLES_BROKEN.fetch_add(threadgraph.break_cycles(), Ordering::Relaxed);
+    if let Some(prefer) = config.validate_roots {
+        let mismatches = threadgraph.root_mismatches();
+        if mismatches > 0 {
+            ROOT_MISMATCHES
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push((path.to_path_buf(), mismatches));
+        }
+        if mismatches > 0 && prefer == RootSource::Declared {
+            let groups = experimental::grouped::group(threadgraph.into_posts());
+            let hook = config.stages.thread_hook.as_deref();
+            return Ok(experimental::grouped::documents(
+                groups,
+                &config.traversal,
+                hook,
+            ));
+        }
+    }
+    if let Some(hook) = &config.stages.thread_hook {
+        // The documents are rebuilt from the posts the hook returns
+        let threads = experimental::iter::thread_posts(threadgraph, &config.traversal);
+        return Ok(experimental::grouped::documents(
+            threads,
+            &config.traversal,
+            Some(hook.as_ref()),
+        ));
+    }
+    Ok(threadgraph.into_documents(&config.traversal))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::InvalidUtf8;
+    use crate::forum_thread::input_line;
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_threads_from_lines() {
+        let line = |id: &str, parent: &str, root: &str| {
+            input_line(id, parent, root, &format!("post {id}"))
+        };
+        let jsonl = [
+            line("1", "1", "1"),
+            line("3", "1", "1"),
+            "not json".to_string(),
+            line("2", "1", "1"),
+            line("4", "9", "9"),
+        ]
+        .join("\n");
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("a.jsonl");
+        fs::write(&file, &jsonl).unwrap();
+
+        let config = Config::default();
+        let from_lines = threads_from_lines(&jsonl, &config).unwrap();
+        let mut from_files = threads_from_files(&[file], Path::new(""), &config).unwrap();
+        from_files.sort_by(|a, b| a.id.cmp(&b.id));
+        let mut sorted = from_lines.clone();
+        sorted.sort_by(|a, b| a.id.cmp(&b.id));
+        assert_eq!(sorted, from_files);
+        assert_eq!(sorted[0].posts, vec!["post 1", "post 3", "post 2"]);
+    }
+    #[test]
+    fn test_duplicate_ids() {
+        let line = |id: &str, parent: &str, text: &str| input_line(id, parent, "1", text);
+        let temp_dir = TempDir::new().unwrap();
+        let files = [
+            temp_dir.path().join("a.jsonl"),
+            temp_dir.path().join("b.jsonl"),
+        ];
+        fs::write(
+            &files[0],
+            [line("1", "1", "root"), line("2", "1", "retry")].join("\n"),
+        )
+        .unwrap();
+        // The scraper retried the reply, the second file overlaps the first
+        fs::write(
+            &files[1],
+            [line("2", "1", "reply"), line("3", "2", "answer")].join("\n"),
+        )
+        .unwrap();
+        let path = Path::new("test_duplicate_ids");
+        for (duplicate_ids, posts) in [
+            (DuplicateIds::FirstWins, vec!["root", "retry", "answer"]),
+            (DuplicateIds::LastWins, vec!["root", "reply", "answer"]),
+        ] {
+            let config = Config {
+                duplicate_ids,
+                ..Default::default()
+            };
+            let threads = threads_from_files(&files, path, &config).unwrap();
+            assert_eq!(threads.len(), 1);
+            assert_eq!(threads[0].posts, posts);
+        }
+        // Counted once per run of the folder
+        assert!(duplicate_ids().contains(&(path.to_path_buf(), 2)));
+
+        let config = Config {
+            duplicate_ids: DuplicateIds::Error,
+            ..Default::default()
+        };
+        let error = threads_from_files(&files, Path::new(""), &config).unwrap_err();
+        assert!(matches!(error, error::Error::DuplicateIds { count: 1, .. }));
+    }
+    #[test]
+    fn test_invalid_utf8() {
+        let line = |id: &str, text: &str| input_line(id, "1", "1", text);
+        let mut bytes = [line("1", "root"), line("2", "bad ")]
+            .join("\r\n")
+            .into_bytes();
+        // A lone continuation byte in the text of the second post
+        bytes.insert(bytes.len() - 3, 0x80);
+        bytes.extend(format!("\n{}\n", line("3", "after")).into_bytes());
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("a.jsonl");
+        fs::write(&file, bytes).unwrap();
+        for (invalid_utf8, posts) in [
+            // The replacement character is removed by the cleaning
+            (InvalidUtf8::Lossy, vec!["root", "bad ", "after"]),
+            (InvalidUtf8::SkipLine, vec!["root", "after"]),
+            (InvalidUtf8::Error, vec!["root"]),
+        ] {
+            let config = Config {
+                read: ReadOptions {
+                    invalid_utf8,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let threads =
+                threads_from_files(std::slice::from_ref(&file), Path::new(""), &config).unwrap();
+            assert_eq!(threads[0].posts, posts);
+        }
+    }
+    #[test]
+    fn test_max_line_bytes() {
+        let line = |id: &str, text: &str| input_line(id, "1", "1", text);
+        let long = "x".repeat(100_000);
+        let lines = [line("1", "root"), line("2", &long), line("3", "after")];
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("a.jsonl");
+        fs::write(&file, lines.join("\n")).unwrap();
+        // Exactly as long as the last line, which has no newline
+        let max_line_bytes = lines[2].len();
+        for (max_line_bytes, posts) in [
+            (None, 3),
+            (Some(max_line_bytes), 2),
+            (Some(max_line_bytes - 1), 1),
+        ] {
+            let config = Config {
+                read: ReadOptions {
+                    max_line_bytes,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let threads =
+                threads_from_files(std::slice::from_ref(&file), Path::new(""), &config).unwrap();
+            assert_eq!(threads[0].posts.len(), posts);
+        }
+        assert!(utils::rejects::counts()
+            .iter()
+            .any(|&(reason, count)| reason == "line_too_long" && count >= 3));
+    }
+    #[test]
+    fn test_compression() {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let line = |id: &str, parent: &str| input_line(id, parent, "1", &format!("post {id}"));
+        let temp_dir = TempDir::new().unwrap();
+        // The extensions do not match the content
+        let plain = temp_dir.path().join("a.jsonl.gz");
+        fs::write(&plain, line("1", "1")).unwrap();
+        let gzip = |lines: &[String]| {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(lines.join("\n").as_bytes()).unwrap();
+            encoder.finish().unwrap()
+        };
+        let compressed = temp_dir.path().join("b.jsonl");
+        // Two gzip members, as with `cat b1.gz b2.gz`
+        let mut members = gzip(&[line("2", "1")]);
+        members.extend(gzip(&[String::new(), line("3", "2")]));
+        fs::write(&compressed, members).unwrap();
+        let bzip2 = temp_dir.path().join("c.jsonl");
+        fs::write(&bzip2, b"BZh91AY&SY").unwrap();
+
+        let files = [plain, compressed, bzip2.clone()];
+        let threads = threads_from_files(&files, Path::new(""), &Config::default()).unwrap();
+        assert_eq!(threads[0].posts, vec!["post 1", "post 2", "post 3"]);
+        // Reported and skipped
+        let reader = Box::new(std::io::BufReader::new(fs::File::open(&bzip2).unwrap()));
+        let error = utils::file::Compression::Bzip2
+            .decoder(reader)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+    }
+    #[test]
+    fn test_thread_hook() {
+        let line = |id: &str, parent: &str, root: &str| {
+            input_line(id, parent, root, &format!("post {id}"))
+        };
+        let jsonl = [
+            line("1", "1", "1"),
+            line("3", "1", "1"),
+            line("2", "1", "1"),
+            line("5", "3", "1"),
+            line("4", "9", "9"),
+        ]
+        .join("\n");
+        let sorted = |mut threads: Vec<forum_thread::Thread>| {
+            threads.sort_by(|a, b| a.id.cmp(&b.id));
+            threads
+        };
+
+        // The documents are the same when the hook keeps every post as is
+        let mut config = Config::default();
+        let expected = sorted(threads_from_lines(&jsonl, &config).unwrap());
+        config.stages.thread_hook = Some(std::sync::Arc::new(|_, posts| Some(posts)));
+        assert_eq!(
+            sorted(threads_from_lines(&jsonl, &config).unwrap()),
+            expected
+        );
+
+        config.stages.thread_hook = Some(std::sync::Arc::new(|root_id, mut posts| {
+            if root_id == "9" {
+                return None;
+            }
+            posts.retain(|post| post.id != "3");
+            for post in posts.iter_mut() {
+                post.pagetext = post.pagetext.to_uppercase();
+            }
+            Some(posts)
+        }));
+        let threads = threads_from_lines(&jsonl, &config).unwrap();
+        assert_eq!(threads.len(), 1);
+        // The reply to the removed post starts again from the top
+        assert_eq!(threads[0].posts, vec!["POST 1", "POST 2", "POST 5"]);
+    }
+    #[test]
+    fn test_parser() {
+        /// `id<TAB>parent<TAB>text`, the root is the id of the first post
+        struct TsvParser;
+
+        impl forum_thread::InputParser for TsvParser {
+            fn parse(
+                &self,
+                line: &str,
+            ) -> Result<Option<forum_thread::Post>, Box<dyn std::error::Error + Send + Sync>>
+            {
+                if line.starts_with('#') {
+                    return Ok(None);
+                }
+                let [id, parent, text] = line.split('\t').collect::<Vec<_>>()[..] else {
+                    return Err("expected 3 columns".into());
+                };
+                Ok(Some(forum_thread::Post::new(
+                    id,
+                    id == parent,
+                    text,
+                    parent,
+                    "",
+                )))
+            }
+        }
+
+        // The byte order mark is not given to the parser
+        let tsv = "\u{feff}1\t1\ttop\n# id parent text\n2\t1\treply\nbroken\n3\t2\tanswer";
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("a.tsv");
+        fs::write(&file, tsv).unwrap();
+        let mut config = Config::default();
+        config.stages.parser = Some(std::sync::Arc::new(TsvParser));
+        for config in [
+            config.clone(),
+            Config {
+                graph_shards: Some(2),
+                ..config.clone()
+            },
+        ] {
+            assert_eq!(
+                threads_from_lines(tsv, &config).unwrap()[0].posts,
+                vec!["top", "reply", "answer"]
+            );
+            let threads =
+                threads_from_files(std::slice::from_ref(&file), Path::new(""), &config).unwrap();
+            assert_eq!(threads[0].posts, vec!["top", "reply", "answer"]);
+        }
+    }
 }
@@ -0,0 +1,1002 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::config::{
+    DeepReplies, ExtraPosts, Extract, FilterScope, Metadata, ReplyOrder, Traversal,
+    TraversalOptions,
+};
+use crate::forum_thread::{Payload, PostMetadata, RootLink, Thread};
+use crate::utils::links;
+use crate::utils::writer::ThreadStats;
+
+/// Posts a task of the parallel traversal is given at least, when its roots are small
+const POSTS_PER_TASK: usize = 4096;
+/// Tasks per worker the roots are split into at least, so a few large threads still spread
+const TASKS_PER_WORKER: usize = 4;
+
+/// Number of posts, or threads with `FilterScope::Threads`, left out by the date range so far
+static DATE_FILTERED: AtomicUsize = AtomicUsize::new(0);
+/// Same as `DATE_FILTERED` for the score
+static SCORE_FILTERED: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of posts, or threads with `FilterScope::Threads`, left out by `--after` and `--before`
+pub fn date_filtered() -> usize {
+    DATE_FILTERED.load(Ordering::Relaxed)
+}
+
+/// Number of posts, or threads with `FilterScope::Threads`, left out by `--min-score`
+pub fn score_filtered() -> usize {
+    SCORE_FILTERED.load(Ordering::Relaxed)
+}
+
+/// Roots per task when the threads of `roots` roots and `posts` posts are traversed in parallel
+///
+/// Many small threads are grouped until a task has about 4096 posts, but the roots are always
+/// split in at least 4 tasks per worker, so a folder of a few huge threads gets one per task.
+/// `options.roots_per_task` is used as given when it is set.
+pub fn roots_per_task(roots: usize, posts: usize, options: &TraversalOptions) -> usize {
+    if let Some(roots_per_task) = options.roots_per_task {
+        return roots_per_task.max(1);
+    }
+    let average = (posts / roots.max(1)).max(1);
+    let tasks = rayon::current_num_threads() * TASKS_PER_WORKER;
+    (POSTS_PER_TASK / average).min(roots / tasks).max(1)
+}
+
+/// Sort key of a reply, `None` keeps the reply after the ones that have a key
+fn reply_key<P: Payload>(post: &P, order: ReplyOrder) -> Option<f64> {
+    match order {
+        ReplyOrder::Insertion => None,
+        ReplyOrder::Time => post.created_utc(),
+        // Negated so that the ascending sort puts the highest score first
+        ReplyOrder::Score => post.score().map(|score| -score),
+    }
+}
+
+/// Sort replies given in insertion order according to `order`
+///
+/// The sort is stable, replies with the same key keep the insertion order.
+///
+/// # Arguments
+///
+/// * `replies` - `&mut [usize]` - Indices of the replies
+/// * `post` - `Fn(usize) -> &P` - Look up the post of an index
+/// * `order` - `ReplyOrder` - The requested order
+pub fn sort_replies<'a, P, F>(replies: &mut [usize], post: F, order: ReplyOrder)
+where
+    P: Payload + 'a,
+    F: Fn(usize) -> &'a P,
+{
+    if order == ReplyOrder::Insertion {
+        return;
+    }
+    replies.sort_by(
+        |a, b| match (reply_key(post(*a), order), reply_key(post(*b), order)) {
+            (Some(a), Some(b)) => a.total_cmp(&b),
+            (a, b) => b.is_some().cmp(&a.is_some()),
+        },
+    );
+}
+
+/// Mark every reply below `node` as visited and return how many there are
+fn hide_replies<F>(node: usize, children: &F, visited: &mut HashSet<usize>) -> usize
+where
+    F: Fn(usize) -> Vec<usize>,
+{
+    let mut hidden = 0;
+    let mut stack = children(node);
+    while let Some(node) = stack.pop() {
+        if visited.insert(node) {
+            hidden += 1;
+            stack.extend(children(node));
+        }
+    }
+    hidden
+}
+
+/// Visit the thread starting at `start`
+///
+/// Posts that are already in `visited` are skipped, so a post in a cycle is only visited once and
+/// the same set can be shared by several starts.
+///
+/// # Arguments
+///
+/// * `start` - `usize` - Index of the first post
+/// * `children` - `Fn(usize) -> Vec<usize>` - Replies to a post, already in the requested order
+/// * `options` - `&TraversalOptions` - Depth first or breadth first and the depth limit
+/// * `visited` - `&mut HashSet<usize>` - Posts that were visited
+///
+/// # Returns
+///
+/// * `Vec<(usize, usize)>` - The post indices in visiting order, each with the number of replies
+///   below it that were left out because of `max_depth`
+pub fn walk<F>(
+    start: usize,
+    children: F,
+    options: &TraversalOptions,
+    visited: &mut HashSet<usize>,
+) -> Vec<(usize, usize)>
+where
+    F: Fn(usize) -> Vec<usize>,
+{
+    let mut order: Vec<(usize, usize)> = Vec::new();
+    let at_max_depth = |depth: usize| options.max_depth.is_some_and(|max| depth >= max);
+    match options.strategy {
+        Traversal::Dfs => {
+            let mut stack = vec![(start, 0)];
+            while let Some((node, depth)) = stack.pop() {
+                if !visited.insert(node) {
+                    continue;
+                }
+                if at_max_depth(depth) {
+                    order.push((node, hide_replies(node, &children, visited)));
+                    continue;
+                }
+                order.push((node, 0));
+                stack.extend(
+                    children(node)
+                        .into_iter()
+                        .rev()
+                        .map(|child| (child, depth + 1)),
+                );
+            }
+        }
+        Traversal::Bfs => {
+            if !visited.insert(start) {
+                return order;
+            }
+            let mut queue = VecDeque::from([(start, 0)]);
+            while let Some((node, depth)) = queue.pop_front() {
+                if at_max_depth(depth) {
+                    order.push((node, hide_replies(node, &children, visited)));
+                    continue;
+                }
+                order.push((node, 0));
+                for child in children(node) {
+                    if visited.insert(child) {
+                        queue.push_back((child, depth + 1));
+                    }
+                }
+            }
+        }
+    }
+    order
+}
+
+/// Every path from `start` down to a post without replies
+///
+/// Posts at `max_depth` end their path. Posts that are already in `visited` are skipped so a
+/// cycle cannot loop forever.
+///
+/// # Arguments
+///
+/// * `start` - `usize` - Index of the first post
+/// * `children` - `Fn(usize) -> Vec<usize>` - Replies to a post, already in the requested order
+/// * `options` - `&TraversalOptions` - Only `max_depth` is used
+/// * `visited` - `&mut HashSet<usize>` - Posts that were visited
+///
+/// # Returns
+///
+/// * `Vec<Vec<usize>>` - The post indices of every path, from the root to the leaf
+pub fn paths<F>(
+    start: usize,
+    children: F,
+    options: &TraversalOptions,
+    visited: &mut HashSet<usize>,
+) -> Vec<Vec<usize>>
+where
+    F: Fn(usize) -> Vec<usize>,
+{
+    collect_paths(start, children, options, visited, false)
+}
+
+/// Same as [paths](fn.paths.html), with the path down to every reply below `start`
+///
+/// The paths are in depth first order, `start` alone is not a path since it has no ancestors.
+pub fn ancestor_paths<F>(
+    start: usize,
+    children: F,
+    options: &TraversalOptions,
+    visited: &mut HashSet<usize>,
+) -> Vec<Vec<usize>>
+where
+    F: Fn(usize) -> Vec<usize>,
+{
+    collect_paths(start, children, options, visited, true)
+}
+
+/// Depth first walk that keeps the path to every leaf, or to every reply with `every_reply`
+fn collect_paths<F>(
+    start: usize,
+    children: F,
+    options: &TraversalOptions,
+    visited: &mut HashSet<usize>,
+    every_reply: bool,
+) -> Vec<Vec<usize>>
+where
+    F: Fn(usize) -> Vec<usize>,
+{
+    let mut paths: Vec<Vec<usize>> = Vec::new();
+    let mut path: Vec<usize> = Vec::new();
+    let mut stack = vec![(start, 0)];
+    while let Some((node, depth)) = stack.pop() {
+        if !visited.insert(node) {
+            continue;
+        }
+        path.truncate(depth);
+        path.push(node);
+        let replies: Vec<usize> = match options.max_depth {
+            Some(max) if depth >= max => Vec::new(),
+            _ => children(node)
+                .into_iter()
+                .filter(|child| !visited.contains(child))
+                .collect(),
+        };
+        let keep = match every_reply {
+            true => depth > 0,
+            false => replies.is_empty(),
+        };
+        if keep {
+            paths.push(path.clone());
+        }
+        stack.extend(replies.into_iter().rev().map(|child| (child, depth + 1)));
+    }
+    paths
+}
+
+/// Turn the result of [walk](fn.walk.html) into documents
+///
+/// The texts are collected in visiting order, with a `[N more replies]` line for the hidden replies
+/// if requested. With `max_posts` the posts are either truncated or split into continuation
+/// documents with the id `<root>-<part>`. With `options.metadata` or `options.composition` the
+/// metadata of the posts is collected along, the `[N more replies]` lines have none.
+///
+/// # Arguments
+///
+/// * `root_id` - `String` - The id of the thread
+/// * `visits` - `Vec<(usize, usize)>` - The output of `walk`
+/// * `pagetext` - `FnMut(usize) -> String` - The text of a post
+/// * `metadata` - `Fn(usize) -> PostMetadata` - The metadata of a post
+/// * `options` - `&TraversalOptions` - The traversal options
+pub fn into_parts<F, M>(
+    root_id: String,
+    mut visits: Vec<(usize, usize)>,
+    mut pagetext: F,
+    metadata: M,
+    options: &TraversalOptions,
+) -> Vec<Thread>
+where
+    F: FnMut(usize) -> String,
+    M: Fn(usize) -> PostMetadata,
+{
+    let collect = options.metadata != Metadata::None || options.composition;
+    let part_size = options.max_posts.unwrap_or(visits.len()).max(1);
+    if options.extra_posts == ExtraPosts::Drop {
+        visits.truncate(part_size);
+    }
+    visits
+        .chunks(part_size)
+        .enumerate()
+        .map(|(part, posts)| {
+            let mut vec_string: Vec<String> = Vec::with_capacity(posts.len());
+            let mut post_metadata: Vec<PostMetadata> = Vec::new();
+            for &(post, hidden) in posts {
+                vec_string.push(pagetext(post));
+                if collect {
+                    post_metadata.push(metadata(post));
+                }
+                if hidden > 0 && options.deep_replies == DeepReplies::Count {
+                    vec_string.push(format!("[{} more replies]", hidden));
+                    if collect {
+                        post_metadata.push(PostMetadata::default());
+                    }
+                }
+            }
+            let id = match part {
+                0 => root_id.clone(),
+                part => format!("{}-{}", root_id, part),
+            };
+            Thread {
+                metadata: collect.then_some(post_metadata),
+                ..Thread::from((id, vec_string))
+            }
+        })
+        .collect()
+}
+
+/// The text of a post for [documents_with](fn.documents_with.html), moved out of `text` when
+/// `extract` writes every post once and copied otherwise
+pub fn take_text(text: &mut String, extract: Extract) -> String {
+    match extract {
+        Extract::Tree => std::mem::take(text),
+        Extract::Paths | Extract::Context => text.clone(),
+    }
+}
+
+/// Structural statistics of the thread below `starts`
+///
+/// The whole thread is measured, regardless of `max_depth` and `max_posts`.
+pub fn stats<'a, T, C, P>(starts: &[usize], children: C, post: P) -> ThreadStats
+where
+    T: Payload + 'a,
+    C: Fn(usize) -> Vec<usize>,
+    P: Fn(usize) -> &'a T,
+{
+    let mut visited: HashSet<usize> = HashSet::new();
+    let mut authors: HashSet<&str> = HashSet::new();
+    let mut stats = ThreadStats::default();
+    let mut parents = 0;
+    let mut replies = 0;
+    for &start in starts {
+        let mut stack = vec![(start, 0)];
+        while let Some((node, depth)) = stack.pop() {
+            if !visited.insert(node) {
+                continue;
+            }
+            stats.num_posts += 1;
+            stats.depth = stats.depth.max(depth);
+            if let Some(author) = post(node).author() {
+                authors.insert(author);
+            }
+            let children: Vec<usize> = children(node)
+                .into_iter()
+                .filter(|child| !visited.contains(child))
+                .collect();
+            if !children.is_empty() {
+                parents += 1;
+                replies += children.len();
+                stats.max_replies = stats.max_replies.max(children.len());
+            }
+            stack.extend(children.into_iter().map(|child| (child, depth + 1)));
+        }
+    }
+    if parents > 0 {
+        stats.branching_factor = replies as f64 / parents as f64;
+    }
+    if !authors.is_empty() {
+        stats.unique_authors = Some(authors.len());
+    }
+    stats
+}
+
+/// The reply level of every post below `starts`, a start is at depth 0
+///
+/// Like the [stats](fn.stats.html), the whole thread is measured regardless of `max_depth`.
+pub fn depths<C>(starts: &[usize], children: C) -> HashMap<usize, usize>
+where
+    C: Fn(usize) -> Vec<usize>,
+{
+    let mut depths: HashMap<usize, usize> = HashMap::new();
+    for &start in starts {
+        let mut stack = vec![(start, 0)];
+        while let Some((node, depth)) = stack.pop() {
+            if depths.contains_key(&node) {
+                continue;
+            }
+            depths.insert(node, depth);
+            stack.extend(children(node).into_iter().map(|child| (child, depth + 1)));
+        }
+    }
+    depths
+}
+
+/// Flatten a thread into documents according to `options.extract`
+///
+/// With [Extract::Tree](../../config/enum.Extract.html) the whole thread is a single document, the
+/// starts are walked one after the other. With `Extract::Paths` every root to leaf path is a
+/// document with the id `<root>/<leaf>`. With `Extract::Context` every reply is a document with its
+/// ancestors and the id `<root>/<reply>`, `max_posts` then keeps the reply and its closest
+/// ancestors. If `options.stats` is set, every document gets the [stats](fn.stats.html) of the
+/// thread. With `options.composition` the metadata of the posts has their
+/// [depths](fn.depths.html) in the whole thread. With `options.hash_ids` the ids of the documents
+/// and the ids and authors of the posts are hashed. With `options.link_reposts` every document
+/// gets the [link](../../utils/links/index.html) of the thread root.
+///
+/// The posts outside `options.dates` or below `options.scores` are left out of the documents, the
+/// replies below them are kept. A context whose reply is left out is not written. With
+/// `FilterScope::Threads` there are no documents at all when the root post does not pass.
+///
+/// # Arguments
+///
+/// * `root_id` - `String` - The id of the thread
+/// * `starts` - `Vec<usize>` - The posts to start from, the root first
+/// * `children` - `Fn(usize) -> Vec<usize>` - Replies to a post, already in the requested order
+/// * `post` - `Fn(usize) -> &T` - Look up the post of an index
+/// * `post_id` - `Fn(usize) -> String` - The id of a post
+/// * `options` - `&TraversalOptions` - The traversal options
+pub fn documents<'a, T, C, P, I>(
+    root_id: String,
+    starts: Vec<usize>,
+    children: C,
+    post: P,
+    post_id: I,
+    options: &TraversalOptions,
+) -> Vec<Thread>
+where
+    T: Payload + 'a,
+    C: Fn(usize) -> Vec<usize>,
+    P: Fn(usize) -> &'a T,
+    I: Fn(usize) -> String,
+{
+    let pagetext = |i: usize| post(i).text().to_string();
+    documents_with(root_id, starts, children, &post, post_id, pagetext, options)
+}
+
+/// Same as [documents](fn.documents.html) with the texts given by `pagetext`
+///
+/// The texts are asked for in writing order, so `pagetext` can move them out of their owner
+/// instead of copying them, see [take_text](fn.take_text.html). `post` is still used for the
+/// reply order and the stats.
+pub fn documents_with<'a, T, C, P, I, F>(
+    root_id: String,
+    starts: Vec<usize>,
+    children: C,
+    post: P,
+    post_id: I,
+    mut pagetext: F,
+    options: &TraversalOptions,
+) -> Vec<Thread>
+where
+    T: Payload + 'a,
+    C: Fn(usize) -> Vec<usize>,
+    P: Fn(usize) -> &'a T,
+    I: Fn(usize) -> String,
+    F: FnMut(usize) -> String,
+{
+    let (dates, scores) = (options.dates, options.scores);
+    let in_range = |i: usize| dates.contains(post(i).created_utc());
+    let scored = |i: usize| scores.passes(post(i).score());
+    let filters = [
+        (
+            dates.is_set(),
+            dates.scope,
+            &in_range as &dyn Fn(usize) -> bool,
+            &DATE_FILTERED,
+        ),
+        (scores.min.is_some(), scores.scope, &scored, &SCORE_FILTERED),
+    ];
+    let mut left_out = false;
+    for (set, scope, passes, counter) in filters {
+        if set && scope == FilterScope::Threads && starts.first().is_some_and(|&root| !passes(root))
+        {
+            counter.fetch_add(1, Ordering::Relaxed);
+            left_out = true;
+        }
+    }
+    if left_out {
+        return Vec::new();
+    }
+    let post_filters: Vec<_> = filters
+        .into_iter()
+        .filter(|(set, scope, _, _)| *set && *scope == FilterScope::Posts)
+        .map(|(_, _, passes, counter)| (passes, counter))
+        .collect();
+    let keep = |i: usize| post_filters.iter().all(|(passes, _)| passes(i));
+    let thread_stats = options.stats.then(|| stats(&starts, &children, &post));
+    // The texts may already be moved out of the posts, the root text is asked for first and kept
+    // until the root is written, also when the filters leave the root out
+    let root = starts
+        .first()
+        .copied()
+        .filter(|_| options.link_reposts.is_some());
+    let mut root_text = root.map(&mut pagetext);
+    let root_link = root_text.as_deref().and_then(links::root_link);
+    let root_engagement = root.map(|root| {
+        let posts = depths(&starts, &children).len();
+        (root_id.clone(), posts, post(root).score())
+    });
+    let mut pagetext = |i: usize| match root_text.take() {
+        Some(text) if root == Some(i) => text,
+        text => {
+            root_text = text;
+            pagetext(i)
+        }
+    };
+    let depths = match options.composition {
+        true => depths(&starts, &children),
+        false => HashMap::new(),
+    };
+    let hide = |id: String| match options.hash_ids {
+        Some(key) => key.hash(&id),
+        None => id,
+    };
+    let root_id = hide(root_id);
+    let post_id = |i: usize| hide(post_id(i));
+    let metadata = |i: usize| {
+        let metadata = PostMetadata::of(post(i));
+        PostMetadata {
+            id: metadata.id.map(hide),
+            depth: depths.get(&i).copied(),
+            author: metadata.author.map(hide),
+            ..metadata
+        }
+    };
+    let mut visited: HashSet<usize> = HashSet::new();
+    let mut documents: Vec<Thread> = match options.extract {
+        Extract::Tree => {
+            let mut visits: Vec<(usize, usize)> = Vec::new();
+            for start in starts {
+                if !visited.contains(&start) {
+                    visits.extend(walk(start, &children, options, &mut visited));
+                }
+            }
+            visits.retain(|&(post, _)| keep(post));
+            into_parts(root_id, visits, pagetext, metadata, options)
+        }
+        Extract::Paths => {
+            let mut documents: Vec<Thread> = Vec::new();
+            for start in starts {
+                for mut path in paths(start, &children, options, &mut visited) {
+                    path.retain(|&post| keep(post));
+                    let Some(&leaf) = path.last() else {
+                        continue;
+                    };
+                    let id = format!("{}/{}", root_id, post_id(leaf));
+                    let visits = path.into_iter().map(|post| (post, 0)).collect();
+                    documents.extend(into_parts(id, visits, &mut pagetext, metadata, options));
+                }
+            }
+            documents
+        }
+        Extract::Context => {
+            let mut documents: Vec<Thread> = Vec::new();
+            for start in starts {
+                for mut path in ancestor_paths(start, &children, options, &mut visited) {
+                    let reply = *path.last().unwrap();
+                    if !keep(reply) {
+                        continue;
+                    }
+                    path.retain(|&post| keep(post));
+                    if let Some(max) = options.max_posts {
+                        path.drain(..path.len().saturating_sub(max.max(1)));
+                    }
+                    let id = format!("{}/{}", root_id, post_id(reply));
+                    let post_metadata = (options.metadata != Metadata::None || options.composition)
+                        .then(|| path.iter().map(|&post| metadata(post)).collect());
+                    let posts = path.into_iter().map(&mut pagetext).collect();
+                    documents.push(Thread {
+                        metadata: post_metadata,
+                        ..Thread::from((id, posts))
+                    });
+                }
+            }
+            documents
+        }
+    };
+    for (passes, counter) in &post_filters {
+        let left_out = visited.iter().filter(|&&post| !passes(post)).count();
+        counter.fetch_add(left_out, Ordering::Relaxed);
+    }
+    let link = root_engagement
+        .zip(root_link)
+        .map(|((root, posts, score), (url, words))| RootLink {
+            root,
+            url,
+            words,
+            posts,
+            score,
+        });
+    if thread_stats.is_some() || link.is_some() {
+        for document in documents.iter_mut() {
+            document.stats = thread_stats.clone();
+            document.link = link.clone();
+        }
+    }
+    documents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DateRange, HashKey, ScoreFilter};
+    use crate::forum_thread::Post;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_roots_per_task() {
+        let options = TraversalOptions::default();
+        let tasks = rayon::current_num_threads() * TASKS_PER_WORKER;
+        // A few huge threads, one per task
+        assert_eq!(roots_per_task(3, 3_000_000, &options), 1);
+        // Many tiny threads, grouped up to the posts of a task
+        assert_eq!(
+            roots_per_task(1_000_000 * tasks, 2_000_000 * tasks, &options),
+            POSTS_PER_TASK / 2
+        );
+        // But still split over the workers
+        assert_eq!(roots_per_task(tasks * 10, tasks * 10, &options), 10);
+        assert_eq!(roots_per_task(0, 0, &options), 1);
+        let options = TraversalOptions {
+            roots_per_task: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(roots_per_task(3, 3_000_000, &options), 100);
+    }
+
+    #[test]
+    fn test_paths() {
+        // 0 -> 1 -> 3, 0 -> 2, 3 -> 0 is a cycle back to the root
+        let tree: Vec<Vec<usize>> = vec![vec![1, 2], vec![3], vec![], vec![0]];
+        let children = |node: usize| tree[node].clone();
+        let options = TraversalOptions::default();
+        let mut visited = HashSet::new();
+        assert_eq!(
+            paths(0, children, &options, &mut visited),
+            vec![vec![0, 1, 3], vec![0, 2]]
+        );
+
+        let posts: Vec<Post> = (0..4)
+            .map(|i| {
+                let id = i.to_string();
+                Post::new(id.clone(), false, format!("text {}", i), id.clone(), id)
+                    .with_author(["a", "b"][i % 2])
+            })
+            .collect();
+        let options = TraversalOptions {
+            max_depth: Some(1),
+            extract: Extract::Paths,
+            stats: true,
+            ..Default::default()
+        };
+        let documents = super::documents(
+            "r".to_string(),
+            vec![0],
+            children,
+            |post| &posts[post],
+            |post| post.to_string(),
+            &options,
+        );
+        let ids: Vec<&str> = documents.iter().map(|thread| thread.id.as_str()).collect();
+        assert_eq!(ids, vec!["r/1", "r/2"]);
+        assert_eq!(documents[0].posts, vec!["text 0", "text 1"]);
+        assert_eq!(
+            ancestor_paths(
+                0,
+                children,
+                &TraversalOptions::default(),
+                &mut HashSet::new()
+            ),
+            vec![vec![0, 1], vec![0, 1, 3], vec![0, 2]]
+        );
+        assert_eq!(
+            documents[0].stats,
+            Some(ThreadStats {
+                depth: 2,
+                num_posts: 4,
+                branching_factor: 1.5,
+                max_replies: 2,
+                unique_authors: Some(2),
+            })
+        );
+
+        let options = TraversalOptions {
+            extract: Extract::Context,
+            max_posts: Some(2),
+            ..Default::default()
+        };
+        let documents = super::documents(
+            "r".to_string(),
+            vec![0],
+            children,
+            |post| &posts[post],
+            |post| post.to_string(),
+            &options,
+        );
+        let ids: Vec<&str> = documents.iter().map(|thread| thread.id.as_str()).collect();
+        assert_eq!(ids, vec!["r/1", "r/3", "r/2"]);
+        assert_eq!(documents[1].posts, vec!["text 1", "text 3"]);
+        assert_eq!(documents[2].posts, vec!["text 0", "text 2"]);
+    }
+
+    #[test]
+    fn test_metadata() {
+        // 0 -> 1 -> 3, 0 -> 2, 3 is below max_depth
+        let tree: Vec<Vec<usize>> = vec![vec![1, 2], vec![3], vec![], vec![]];
+        let posts: Vec<Post> = (0..4)
+            .map(|i| {
+                let id = i.to_string();
+                let post = Post::new(id.clone(), false, id.clone(), id.clone(), id);
+                match i {
+                    2 => post,
+                    i => post.with_author("a").with_created_utc(i as f64),
+                }
+            })
+            .collect();
+        let options = TraversalOptions {
+            max_depth: Some(1),
+            deep_replies: DeepReplies::Count,
+            metadata: Metadata::Posts,
+            ..Default::default()
+        };
+        let documents = super::documents(
+            "r".to_string(),
+            vec![0],
+            |node| tree[node].clone(),
+            |post| &posts[post],
+            |post| post.to_string(),
+            &options,
+        );
+        assert_eq!(documents[0].posts, vec!["0", "1", "[1 more replies]", "2"]);
+        let metadata = |id: &str, author: Option<&str>, created_utc: Option<f64>| PostMetadata {
+            id: Some(id.to_string()),
+            author: author.map(str::to_string),
+            created_utc,
+            ..Default::default()
+        };
+        assert_eq!(
+            documents[0].metadata,
+            Some(vec![
+                metadata("0", Some("a"), Some(0.0)),
+                metadata("1", Some("a"), Some(1.0)),
+                PostMetadata::default(),
+                metadata("2", None, None),
+            ])
+        );
+
+        // The depths are those of the whole thread
+        let options = TraversalOptions {
+            composition: true,
+            extract: Extract::Paths,
+            ..Default::default()
+        };
+        let documents = super::documents(
+            "r".to_string(),
+            vec![0],
+            |node| tree[node].clone(),
+            |post| &posts[post],
+            |post| post.to_string(),
+            &options,
+        );
+        let depths: Vec<Vec<Option<usize>>> = documents
+            .iter()
+            .map(|document| {
+                document
+                    .metadata
+                    .iter()
+                    .flatten()
+                    .map(|m| m.depth)
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            depths,
+            vec![vec![Some(0), Some(1), Some(2)], vec![Some(0), Some(1)]]
+        );
+
+        let documents = super::documents(
+            "r".to_string(),
+            vec![0],
+            |node| tree[node].clone(),
+            |post| &posts[post],
+            |post| post.to_string(),
+            &TraversalOptions::default(),
+        );
+        assert_eq!(documents[0].metadata, None);
+    }
+
+    #[test]
+    fn test_hash_ids() {
+        // 0 -> 1, 0 -> 2
+        let tree: Vec<Vec<usize>> = vec![vec![1, 2], vec![], vec![]];
+        let posts: Vec<Post> = (0..3)
+            .map(|i| {
+                let id = i.to_string();
+                Post::new(
+                    id.clone(),
+                    false,
+                    id.clone(),
+                    "0".to_string(),
+                    "0".to_string(),
+                )
+                .with_author("a")
+            })
+            .collect();
+        let documents = |key: &str| {
+            let options = TraversalOptions {
+                extract: Extract::Paths,
+                metadata: Metadata::Posts,
+                hash_ids: Some(HashKey::new(key)),
+                ..Default::default()
+            };
+            super::documents(
+                "0".to_string(),
+                vec![0],
+                |node| tree[node].clone(),
+                |post| &posts[post],
+                |post| post.to_string(),
+                &options,
+            )
+        };
+        let key = HashKey::new("secret");
+        let hashed = documents("secret");
+        assert_eq!(hashed[0].id, format!("{}/{}", key.hash("0"), key.hash("1")));
+        let metadata = hashed[1].metadata.as_ref().unwrap();
+        assert_eq!(metadata[0].id, Some(key.hash("0")));
+        assert_eq!(metadata[1].id, Some(key.hash("2")));
+        assert_eq!(metadata[1].author, Some(key.hash("a")));
+        // The texts are left as they are
+        assert_eq!(hashed[1].posts, vec!["0", "2"]);
+        // Another key gives other hashes
+        assert_ne!(documents("other")[0].id, hashed[0].id);
+        assert_eq!(format!("{:?}", key), "HashKey(..)");
+    }
+
+    #[test]
+    fn test_root_link() {
+        // 0 -> 1 -> 2, the texts are moved out as they are written
+        let tree: Vec<Vec<usize>> = vec![vec![1], vec![2], vec![]];
+        let texts = [
+            "see https://example.com/a".to_string(),
+            "b".to_string(),
+            "c".to_string(),
+        ];
+        let posts: Vec<Post> = (0..3)
+            .map(|i| Post::new(i.to_string(), false, String::new(), "0".into(), "0".into()))
+            .collect();
+        let options = TraversalOptions {
+            link_reposts: Some(2),
+            max_depth: Some(1),
+            ..Default::default()
+        };
+        let documents = |posts: &[Post], options: &TraversalOptions| {
+            let mut texts = texts.clone();
+            documents_with(
+                "0".to_string(),
+                vec![0],
+                |node| tree[node].clone(),
+                |post| &posts[post],
+                |post| post.to_string(),
+                |post| std::mem::take(&mut texts[post]),
+                options,
+            )
+        };
+        let link = Some(RootLink {
+            root: "0".to_string(),
+            url: "example.com/a".to_string(),
+            words: 1,
+            posts: 3,
+            score: None,
+        });
+        let written = documents(&posts, &options);
+        assert_eq!(written[0].link, link);
+        assert_eq!(written[0].posts, vec!["see https://example.com/a", "b"]);
+
+        // A root left out by the dates still gives the link of the thread
+        let mut posts = posts;
+        posts[0] = posts[0].clone().with_created_utc(0.0);
+        let options = TraversalOptions {
+            dates: DateRange {
+                after: Some(5),
+                ..Default::default()
+            },
+            ..options
+        };
+        let written = documents(&posts, &options);
+        assert_eq!(written[0].link, link);
+        assert_eq!(written[0].posts, vec!["b"]);
+    }
+
+    #[test]
+    fn test_date_range() {
+        // 0 -> 1 -> 3, 0 -> 2, post i is created at 10 * i, 3 has no time
+        let tree: Vec<Vec<usize>> = vec![vec![1, 2], vec![3], vec![], vec![]];
+        let posts: Vec<Post> = (0..4)
+            .map(|i| {
+                let id = i.to_string();
+                let post = Post::new(id.clone(), false, id.clone(), id.clone(), id);
+                match i {
+                    3 => post,
+                    i => post.with_created_utc(10.0 * i as f64),
+                }
+            })
+            .collect();
+        let documents = |extract: Extract, dates: DateRange| {
+            let options = TraversalOptions {
+                extract,
+                dates,
+                ..Default::default()
+            };
+            super::documents(
+                "r".to_string(),
+                vec![0],
+                |node| tree[node].clone(),
+                |post| &posts[post],
+                |post| post.to_string(),
+                &options,
+            )
+            .into_iter()
+            .map(|thread| (thread.id, thread.posts))
+            .collect::<Vec<_>>()
+        };
+        let texts = |texts: &[&str]| texts.iter().map(|text| text.to_string()).collect();
+        let dates = DateRange {
+            after: Some(5),
+            before: Some(20),
+            ..Default::default()
+        };
+        let before = date_filtered();
+        assert_eq!(
+            documents(Extract::Tree, dates),
+            vec![("r".to_string(), texts(&["1", "3"]))]
+        );
+        assert!(date_filtered() >= before + 2);
+        assert_eq!(
+            documents(Extract::Context, dates),
+            vec![
+                ("r/1".to_string(), texts(&["1"])),
+                ("r/3".to_string(), texts(&["1", "3"]))
+            ]
+        );
+
+        let dates = DateRange {
+            scope: FilterScope::Threads,
+            ..dates
+        };
+        assert_eq!(documents(Extract::Tree, dates), vec![]);
+        let dates = DateRange {
+            after: Some(0),
+            ..dates
+        };
+        assert_eq!(
+            documents(Extract::Tree, dates),
+            vec![("r".to_string(), texts(&["0", "1", "3", "2"]))]
+        );
+    }
+
+    #[test]
+    fn test_min_score() {
+        // 0 -> 1 -> 3, 0 -> 2, scores 5, -2, 1 and none
+        let tree: Vec<Vec<usize>> = vec![vec![1, 2], vec![3], vec![], vec![]];
+        let posts: Vec<Post> = [Some(5.0), Some(-2.0), Some(1.0), None]
+            .into_iter()
+            .enumerate()
+            .map(|(i, score)| {
+                let id = i.to_string();
+                let post = Post::new(id.clone(), false, id.clone(), id.clone(), id);
+                match score {
+                    Some(score) => post.with_score(score),
+                    None => post,
+                }
+            })
+            .collect();
+        let texts = |scores: ScoreFilter| {
+            let options = TraversalOptions {
+                scores,
+                ..Default::default()
+            };
+            super::documents(
+                "r".to_string(),
+                vec![0],
+                |node| tree[node].clone(),
+                |post| &posts[post],
+                |post| post.to_string(),
+                &options,
+            )
+            .into_iter()
+            .flat_map(|thread| thread.posts)
+            .collect::<Vec<_>>()
+        };
+        let before = score_filtered();
+        let scores = ScoreFilter {
+            min: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(texts(scores), vec!["0", "3", "2"]);
+        assert!(score_filtered() > before);
+        let scores = ScoreFilter {
+            min: Some(-2),
+            ..scores
+        };
+        assert_eq!(texts(scores), vec!["0", "1", "3", "2"]);
+        let scores = ScoreFilter {
+            min: Some(6),
+            scope: FilterScope::Threads,
+        };
+        assert!(texts(scores).is_empty());
+        let scores = ScoreFilter {
+            min: Some(2),
+            scope: FilterScope::Threads,
+        };
+        assert_eq!(texts(scores), vec!["0", "1", "3", "2"]);
+    }
+}
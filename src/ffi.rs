@@ -0,0 +1,119 @@
+//! C bindings of the thread reconstruction, with the `ffi` feature
+//!
+//! The declarations are in `include/forum_rs.h`, link against the `cdylib` built with
+//! `cargo build --release --features ffi`. The threads are rebuilt exactly as for a folder holding
+//! the lines in a single file, with the default [TraversalOptions](../config/struct.TraversalOptions.html).
+//!
+//! ```c
+//! char *threads = forum_rs_reconstruct_threads(buffer, length);
+//! if (threads != NULL) {
+//!     puts(threads);
+//!     forum_rs_free_string(threads);
+//! }
+//! ```
+
+use std::ffi::{c_char, CString};
+use std::panic::catch_unwind;
+
+use serde::Serialize;
+
+use crate::config::{DuplicateIds, TraversalOptions};
+use crate::experimental;
+use crate::forum_thread::JsonlParser;
+
+/// A line of the result of [forum_rs_reconstruct_threads](fn.forum_rs_reconstruct_threads.html)
+#[derive(Serialize)]
+struct ThreadIds {
+    root_id: String,
+    /// The ids of the posts in traversal order, empty for a parent missing from the buffer
+    post_ids: Vec<String>,
+}
+
+/// The threads of `len` bytes of JSONL at `jsonl`, one JSON line per thread
+///
+/// Every line is `{"root_id": "...", "post_ids": [...]}`, with the posts in traversal order. Lines
+/// that cannot be parsed are skipped. Returns `NULL` if the buffer is not UTF-8, the string must be
+/// freed with [forum_rs_free_string](fn.forum_rs_free_string.html).
+///
+/// # Safety
+///
+/// `jsonl` must point to `len` readable bytes, it may be `NULL` when `len` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn forum_rs_reconstruct_threads(jsonl: *const u8, len: usize) -> *mut c_char {
+    let bytes = match jsonl.is_null() {
+        true => &[][..],
+        false => std::slice::from_raw_parts(jsonl, len),
+    };
+    let Ok(text) = std::str::from_utf8(bytes) else {
+        return std::ptr::null_mut();
+    };
+    // A panic must not unwind into C
+    let lines = catch_unwind(|| {
+        let threadgraph =
+            experimental::sender::graph_from_lines(text, &JsonlParser, DuplicateIds::default());
+        experimental::iter::into_threads(threadgraph, &TraversalOptions::default())
+            .into_iter()
+            .map(|(root_id, posts)| {
+                let thread = ThreadIds {
+                    root_id,
+                    post_ids: posts
+                        .into_iter()
+                        .map(|post| post.id.into_string())
+                        .collect(),
+                };
+                serde_json::to_string(&thread).unwrap()
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    });
+    match lines.map(CString::new) {
+        Ok(Ok(lines)) => lines.into_raw(),
+        _ => std::ptr::null_mut(),
+    }
+}
+
+/// Free a string returned by this library, `NULL` is ignored
+///
+/// # Safety
+///
+/// `string` must come from this library and must not be used or freed again.
+#[no_mangle]
+pub unsafe extern "C" fn forum_rs_free_string(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::ffi::CStr;
+
+    #[test]
+    fn test_reconstruct_threads() {
+        let jsonl = [
+            r#"{"id":"1","is_thread":"Y","pagetext":"a","parent_post_id":"1","root_post_id":"1"}"#,
+            r#"{"id":"3","is_thread":"N","pagetext":"c","parent_post_id":"1","root_post_id":"1"}"#,
+            "not json",
+            r#"{"id":"2","is_thread":"N","pagetext":"b","parent_post_id":"1","root_post_id":"1"}"#,
+        ]
+        .join("\n");
+        unsafe {
+            let threads = forum_rs_reconstruct_threads(jsonl.as_ptr(), jsonl.len());
+            assert_eq!(
+                CStr::from_ptr(threads).to_str().unwrap(),
+                r#"{"root_id":"1","post_ids":["1","3","2"]}"#
+            );
+            forum_rs_free_string(threads);
+
+            let empty = forum_rs_reconstruct_threads(std::ptr::null(), 0);
+            assert_eq!(CStr::from_ptr(empty).to_str().unwrap(), "");
+            forum_rs_free_string(empty);
+
+            let invalid = [0xff, 0xfe];
+            assert!(forum_rs_reconstruct_threads(invalid.as_ptr(), invalid.len()).is_null());
+            forum_rs_free_string(std::ptr::null_mut());
+        }
+    }
+}
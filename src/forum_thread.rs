@@ -1,31 +1,111 @@
+pub use compact_str::CompactString;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
+use std::path::Path;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
+use crate::config::{Config, OutputContent};
+use crate::globals;
 use crate::graph;
 use crate::utils;
+use crate::utils::rejects::Reason;
+use crate::utils::writer::{Batch, Batcher, Route, ThreadPost, ThreadStats};
 
+/// A line of the dumps, the strings are borrowed from the line unless they have escapes
 #[derive(Clone, Deserialize, Serialize, Debug)]
-pub struct JsonStruct {
-    id: String,
-    is_thread: String,
-    pagetext: String,
-    parent_post_id: String,
-    root_post_id: String,
+pub struct JsonStruct<'a> {
+    #[serde(borrow)]
+    id: Cow<'a, str>,
+    #[serde(borrow)]
+    is_thread: Cow<'a, str>,
+    #[serde(borrow)]
+    pagetext: Cow<'a, str>,
+    #[serde(borrow)]
+    parent_post_id: Cow<'a, str>,
+    #[serde(borrow)]
+    root_post_id: Cow<'a, str>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_number",
+        skip_serializing_if = "Option::is_none"
+    )]
+    created_utc: Option<f64>,
+    /// Only used without `created_utc`, a line may have both keys
+    #[serde(default, deserialize_with = "deserialize_number", skip_serializing)]
+    timestamp: Option<f64>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_number",
+        skip_serializing_if = "Option::is_none"
+    )]
+    score: Option<f64>,
+    /// Only used without `score`
+    #[serde(default, deserialize_with = "deserialize_number", skip_serializing)]
+    upvotes: Option<f64>,
+    #[serde(
+        borrow,
+        default,
+        deserialize_with = "deserialize_optional",
+        skip_serializing_if = "Option::is_none"
+    )]
+    author: Option<Cow<'a, str>>,
+    /// Only used without `author`
+    #[serde(
+        borrow,
+        default,
+        deserialize_with = "deserialize_optional",
+        skip_serializing
+    )]
+    username: Option<Cow<'a, str>>,
 }
+
+/// serde only borrows a `Cow` that is not wrapped, so the string goes through a newtype
+fn deserialize_optional<'de: 'a, 'a, D>(deserializer: D) -> Result<Option<Cow<'a, str>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Borrowed<'a>(#[serde(borrow)] Cow<'a, str>);
+    Ok(Option::<Borrowed>::deserialize(deserializer)?.map(|text| text.0))
+}
+
+/// Accept a number or a numeric string, anything else is treated as missing
+fn deserialize_number<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = serde_json::Value::deserialize(deserializer)?;
+    Ok(match value {
+        serde_json::Value::Number(number) => number.as_f64(),
+        serde_json::Value::String(text) => text.trim().parse().ok(),
+        _ => None,
+    })
+}
+
+/// A post of the dump
+///
+/// The ids are [CompactString](https://docs.rs/compact_str)s, an id of up to 24 bytes is stored
+/// inline without an allocation of its own.
 #[derive(Clone, Debug, Default)]
 pub struct Post {
-    pub id: String,
+    pub id: CompactString,
     pub is_thread: bool,
     pub pagetext: String,
-    pub parent_post_id: String,
-    pub root_post_id: String,
+    pub parent_post_id: CompactString,
+    pub root_post_id: CompactString,
+    /// Creation time in seconds since the epoch, from `created_utc` or `timestamp`
+    pub created_utc: Option<f64>,
+    /// Score of the post, from `score` or `upvotes`
+    pub score: Option<f64>,
+    /// Author of the post, from `author` or `username`
+    pub author: Option<String>,
 }
 
 impl Post {
-    pub fn new<I: Into<String>>(
+    pub fn new<I: Into<String> + AsRef<str>>(
         id: I,
         is_thread: bool,
         pagetext: I,
@@ -33,54 +113,330 @@ impl Post {
         root_post_id: I,
     ) -> Self {
         Post {
-            id: id.into(),
+            id: CompactString::new(id),
             is_thread,
             pagetext: pagetext.into(),
-            parent_post_id: parent_post_id.into(),
-            root_post_id: root_post_id.into(),
+            parent_post_id: CompactString::new(parent_post_id),
+            root_post_id: CompactString::new(root_post_id),
+            created_utc: None,
+            score: None,
+            author: None,
         }
     }
 
-    pub fn placeholder(id: String) -> Self {
+    /// Set the creation time of the post
+    pub fn with_created_utc(mut self, created_utc: f64) -> Self {
+        self.created_utc = Some(created_utc);
+        self
+    }
+
+    /// Set the score of the post
+    pub fn with_score(mut self, score: f64) -> Self {
+        self.score = Some(score);
+        self
+    }
+
+    /// Set the author of the post
+    pub fn with_author<I: Into<String>>(mut self, author: I) -> Self {
+        self.author = Some(author.into());
+        self
+    }
+
+    pub fn placeholder(id: CompactString) -> Self {
         Post {
             id: id.clone(),
             is_thread: true,
             pagetext: "".to_string(),
             parent_post_id: id.clone(),
             root_post_id: id,
+            created_utc: None,
+            score: None,
+            author: None,
         }
     }
-    pub fn from_json_struct(json: JsonStruct) -> Option<Self> {
+    /// The post of a line, its strings are copied out of the line
+    pub fn from_json_struct(json: JsonStruct<'_>) -> Option<Self> {
         Some(Post {
-            id: json.id,
+            id: CompactString::new(json.id),
             is_thread: json.is_thread == "Y",
-            pagetext: json.pagetext,
-            parent_post_id: json.parent_post_id,
-            root_post_id: json.root_post_id,
+            pagetext: json.pagetext.into_owned(),
+            parent_post_id: CompactString::new(json.parent_post_id),
+            root_post_id: CompactString::new(json.root_post_id),
+            created_utc: json.created_utc.or(json.timestamp),
+            score: json.score.or(json.upvotes),
+            author: json.author.or(json.username).map(Cow::into_owned),
         })
     }
 }
 
+/// Reads the posts of the input files, one line at a time
+///
+/// [JsonlParser](struct.JsonlParser.html) reads the schema of the dumps, an implementation of its
+/// own lets a pipeline read a dump whose fields are named or shaped differently. Set it with
+/// [PipelineBuilder::parser](../builder/struct.PipelineBuilder.html#method.parser).
+///
+/// # Example
+///
+/// ```
+/// # use std::error::Error;
+/// # use forum_rs::forum_thread::{InputParser, Post};
+/// struct TsvParser;
+///
+/// impl InputParser for TsvParser {
+///     fn parse(&self, line: &str) -> Result<Option<Post>, Box<dyn Error + Send + Sync>> {
+///         let [id, parent, root, text] = line.split('\t').collect::<Vec<_>>()[..] else {
+///             return Err("expected 4 columns".into());
+///         };
+///         Ok(Some(Post::new(id, id == root, text, parent, root)))
+///     }
+/// }
+/// ```
+pub trait InputParser: Send + Sync {
+    /// The post of `line`, `Ok(None)` skips the line
+    ///
+    /// # Errors
+    ///
+    /// The line is not a post, it is skipped and written to the rejects with `--rejects`.
+    fn parse(&self, line: &str) -> Result<Option<Post>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+/// The built-in parser, one JSON object per line, see [JsonStruct](struct.JsonStruct.html)
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonlParser;
+
+impl InputParser for JsonlParser {
+    fn parse(&self, line: &str) -> Result<Option<Post>, Box<dyn std::error::Error + Send + Sync>> {
+        #[cfg(feature = "simd-json")]
+        if let Some(post) = utils::json::simd_line(line, |bytes, buffers| {
+            let json = simd_json::serde::from_slice_with_buffers::<JsonStruct>(bytes, buffers);
+            json.ok().map(Post::from_json_struct)
+        }) {
+            return Ok(post);
+        }
+        let json = utils::json::from_line::<JsonStruct>(line)?;
+        Ok(Post::from_json_struct(json))
+    }
+}
+
+/// A line of a post for [JsonlParser](struct.JsonlParser.html), the input of the tests
+#[cfg(test)]
+pub(crate) fn input_line(id: &str, parent: &str, root: &str, text: &str) -> String {
+    format!(
+        r#"{{"id":"{id}","is_thread":"N","pagetext":"{text}","parent_post_id":"{parent}","root_post_id":"{root}"}}"#
+    )
+}
+
+/// What a [ThreadGraph](../experimental/graph/struct.ThreadGraph.html) needs from the posts it links
+///
+/// Only the ids are required, the other methods have defaults so a lighter payload, like an index
+/// into another table, can be linked without its text. `Default` is used for the placeholder of a
+/// parent that was never added and must have an empty `id`.
+pub trait Payload: Default + Send + Sync {
+    fn id(&self) -> &str;
+    fn parent_id(&self) -> &str;
+    /// Root of the thread as declared in the dump, `None` if unknown
+    fn root_id(&self) -> Option<&str> {
+        None
+    }
+    /// Text written in the documents
+    fn text(&self) -> &str {
+        ""
+    }
+    /// Move the text out of the post once it is no longer needed, a copy by default
+    fn take_text(&mut self) -> String {
+        self.text().to_string()
+    }
+    fn created_utc(&self) -> Option<f64> {
+        None
+    }
+    fn score(&self) -> Option<f64> {
+        None
+    }
+    fn author(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl Payload for Post {
+    fn id(&self) -> &str {
+        &self.id
+    }
+    fn parent_id(&self) -> &str {
+        &self.parent_post_id
+    }
+    fn root_id(&self) -> Option<&str> {
+        Some(&self.root_post_id)
+    }
+    fn text(&self) -> &str {
+        &self.pagetext
+    }
+    fn take_text(&mut self) -> String {
+        std::mem::take(&mut self.pagetext)
+    }
+    fn created_utc(&self) -> Option<f64> {
+        self.created_utc
+    }
+    fn score(&self) -> Option<f64> {
+        self.score
+    }
+    fn author(&self) -> Option<&str> {
+        self.author.as_deref()
+    }
+}
+
+/// Author, creation time and score of a post, for the `metadata` of the records, with its id and
+/// depth for the `composition`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PostMetadata {
+    /// `None` for a line that is not a post of the dump
+    pub id: Option<String>,
+    /// Reply level below the start of the thread, only set with `composition`
+    pub depth: Option<usize>,
+    pub author: Option<String>,
+    pub created_utc: Option<f64>,
+    pub score: Option<f64>,
+}
+
+impl PostMetadata {
+    /// The metadata of `post`, empty for a payload without any
+    pub fn of<P: Payload>(post: &P) -> Self {
+        PostMetadata {
+            id: Some(post.id().to_string()).filter(|id| !id.is_empty()),
+            depth: None,
+            author: post.author().map(str::to_string),
+            created_utc: post.created_utc(),
+            score: post.score(),
+        }
+    }
+}
+
+/// The link a thread root shares and how much the thread was engaged with, see
+/// [links](../utils/links/index.html)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RootLink {
+    pub root: String,
+    /// The first link of the root post, normalized
+    pub url: String,
+    /// Words of the root post besides its links
+    pub words: usize,
+    /// Posts of the whole thread
+    pub posts: usize,
+    pub score: Option<f64>,
+}
+
+/// A flattened thread, ready to be processed into a [ThreadPost](../utils/writer/struct.ThreadPost.html)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Thread {
+    /// The root post id, with a suffix for continuation documents and paths
+    pub id: String,
+    /// The texts of the posts in traversal order
+    pub posts: Vec<String>,
+    /// Structure of the whole thread, only computed when requested
+    pub stats: Option<ThreadStats>,
+    /// Metadata of every post in the order of `posts`, only collected when requested
+    pub metadata: Option<Vec<PostMetadata>>,
+    /// Link of the root post, only collected with `link_reposts`
+    pub link: Option<RootLink>,
+}
+
+impl From<(String, Vec<String>)> for Thread {
+    fn from((id, posts): (String, Vec<String>)) -> Self {
+        Thread {
+            id,
+            posts,
+            stats: None,
+            metadata: None,
+            link: None,
+        }
+    }
+}
+
+impl From<Thread> for (String, Vec<String>) {
+    fn from(thread: Thread) -> Self {
+        (thread.id, thread.posts)
+    }
+}
+
 pub fn sender_thread_posts(
-    threads: Vec<(String, Vec<String>)>,
-    use_sentencepiece: bool,
-    forum_name: String,
-    sender_rx: crossbeam_channel::Sender<String>,
+    threads: Vec<Thread>,
+    folder: &Path,
+    config: &Config,
+    sender_rx: crossbeam_channel::Sender<Batch>,
 ) {
+    let batcher = Batcher::new(sender_rx, config.channel_batch);
+    for_each_threadpost(threads, folder, config, |route, threadpost| {
+        send_threadpost(&batcher, route, threadpost)
+    });
+}
+
+/// Serialize a record into the batches for the writer
+pub(crate) fn send_threadpost(batcher: &Batcher, route: Route, threadpost: ThreadPost) {
+    batcher.serialize(route, &threadpost);
+}
+
+/// Same as [sender_thread_posts](fn.sender_thread_posts.html), every record that passes the
+/// filters is handed to `f` with its route instead of being serialized and sent
+pub fn for_each_threadpost<F>(mut threads: Vec<Thread>, folder: &Path, config: &Config, f: F)
+where
+    F: Fn(Route, ThreadPost) + Sync,
+{
+    if let Some(max_words) = config.traversal.link_reposts {
+        utils::links::drop_reposts(&mut threads, max_words);
+    }
+    for_each_kept_threadpost(threads, folder, config, f)
+}
+
+/// Same as [for_each_threadpost](fn.for_each_threadpost.html) for threads whose link reposts are
+/// already left out, by a [Reposts](../utils/links/struct.Reposts.html) of the folder
+pub(crate) fn for_each_kept_threadpost<F>(
+    threads: Vec<Thread>,
+    folder: &Path,
+    config: &Config,
+    f: F,
+) where
+    F: Fn(Route, ThreadPost) + Sync,
+{
+    let source = config.source_of(folder);
     // Parallel processing for large number of threads
+    // Threads are processed in batches so the tokenizer can encode them together
     threads
-        .par_iter()
-        .with_min_len(50)
-        .for_each(|(thread_id, content)| {
-            let threadpost = utils::processing::process(
-                thread_id.to_string(),
-                content.to_vec(),
-                forum_name.to_string(),
-                use_sentencepiece,
-            );
-            sender_rx
-                .send(serde_json::to_string(&threadpost).unwrap())
-                .unwrap();
+        .par_chunks(config.batch_size.max(1))
+        .for_each(|batch| {
+            let _timer = utils::timing::start(utils::timing::Stage::CreatePosts);
+            utils::processing::process_batch_with_source(batch.to_vec(), config, &source)
+                .into_iter()
+                .filter(|threadpost| {
+                    let rule =
+                        utils::quality::failed_rule(&threadpost.raw_content, &config.quality);
+                    if let Some(rule) = rule {
+                        utils::rejects::quality(folder, rule, threadpost);
+                    }
+                    rule.is_none()
+                })
+                .filter_map(|threadpost| utils::blocklist::check(threadpost, folder, config))
+                .filter(|threadpost| {
+                    let first = utils::dedup::first_seen(&threadpost.raw_content, config);
+                    if !first {
+                        utils::rejects::filtered(folder, Reason::Duplicate, threadpost);
+                    }
+                    first
+                })
+                .flat_map(|threadpost| utils::processing::apply_token_budget(threadpost, config))
+                .for_each(|mut threadpost| {
+                    if !utils::processing::keep(&threadpost, config) {
+                        utils::rejects::filtered(folder, Reason::Perplexity, &threadpost);
+                        return;
+                    }
+                    let Some(route) = utils::processing::route(&threadpost, config) else {
+                        utils::rejects::filtered(folder, Reason::Oversized, &threadpost);
+                        return;
+                    };
+                    globals::record_length(threadpost.length);
+                    if config.output_content == OutputContent::Ids {
+                        threadpost.raw_content.clear();
+                    }
+                    f(route, threadpost);
+                });
         });
 }
 
@@ -88,40 +444,35 @@ pub fn sender_thread_posts(
 pub fn create_thread_posts(
     _forum_id: &str,
     threads: Vec<(String, Vec<String>)>,
-    use_sentencepiece: bool,
-    forum_name: String,
+    config: &Config,
 ) -> (Vec<String>, usize) {
     let byte_counter = AtomicUsize::new(0);
     let posts = if threads.len() > 5000 {
         // Parallel processing for large number of threads
-        let mut posts: Vec<String> = Vec::with_capacity(threads.len());
         threads
             .par_iter()
-            .map(|(thread_id, content)| {
-                let threadpost = utils::processing::process(
-                    thread_id.to_string(),
-                    content.to_vec(),
-                    forum_name.to_string(),
-                    use_sentencepiece,
-                );
+            .filter_map(|(thread_id, content)| {
+                let threadpost =
+                    utils::processing::process(thread_id.to_string(), content.to_vec(), config);
+                if !utils::processing::keep(&threadpost, config) {
+                    return None;
+                }
                 byte_counter.fetch_add(threadpost.raw_content.len(), Ordering::Relaxed);
-                serde_json::to_string(&threadpost).unwrap()
+                Some(serde_json::to_string(&threadpost).unwrap())
             })
-            .collect_into_vec(&mut posts);
-        posts
+            .collect()
     } else {
         // Sequential processing for smaller number of threads
         let posts: Vec<String> = threads
             .iter()
-            .map(|(thread_id, content)| {
-                let threadpost = utils::processing::process(
-                    thread_id.to_string(),
-                    content.to_vec(),
-                    forum_name.to_string(),
-                    use_sentencepiece,
-                );
+            .filter_map(|(thread_id, content)| {
+                let threadpost =
+                    utils::processing::process(thread_id.to_string(), content.to_vec(), config);
+                if !utils::processing::keep(&threadpost, config) {
+                    return None;
+                }
                 byte_counter.fetch_add(threadpost.raw_content.len(), Ordering::Relaxed);
-                serde_json::to_string(&threadpost).unwrap()
+                Some(serde_json::to_string(&threadpost).unwrap())
             })
             .collect();
         posts
@@ -134,7 +485,7 @@ pub fn create_thread_posts(
 fn get_threads(path: &str) -> Vec<(String, Vec<String>)> {
     let entries = utils::file::single_folder(path);
     let mut threadgraph = graph::ThreadGraph::new();
-    let mut comments: Vec<Post> = Vec::with_capacity(10000);
+    let mut comments: Vec<(CompactString, CompactString)> = Vec::with_capacity(10000);
 
     // let loop_start = std::time::Instant::now();
     // this shouldn't be parallelized for safety
@@ -153,10 +504,12 @@ fn get_threads(path: &str) -> Vec<(String, Vec<String>)> {
             .collect();
 
         for thread in threads {
-            let thread_node = threadgraph.add_node(thread.clone());
-            match thread.is_thread {
-                true => threadgraph.add_threads(thread_node),
-                false => comments.push(thread),
+            let edge =
+                (!thread.is_thread).then(|| (thread.parent_post_id.clone(), thread.id.clone()));
+            let thread_node = threadgraph.add_node(thread);
+            match edge {
+                None => threadgraph.add_threads(thread_node),
+                Some(edge) => comments.push(edge),
             }
         }
     });
@@ -166,12 +519,95 @@ fn get_threads(path: &str) -> Vec<(String, Vec<String>)> {
     // let comments = comments.lock().unwrap();
     // let mut threadgraph = threadgraph.lock().unwrap();
     // let comment_time = std::time::Instant::now();
-    // for comment in comments.iter() {
-    //     threadgraph.add_edge(&comment.parent_post_id, &comment.id);
+    // for (parent_post_id, id) in comments.iter() {
+    //     threadgraph.add_edge(parent_post_id, id);
     // }
     // println!("Time taken for comments: {:.2?}", comment_time.elapsed());
     // let traverse_time = std::time::Instant::now();
-    threadgraph.traverse()
+    threadgraph.into_traverse()
     // println!("Time taken for traverse: {:.2?}", traverse_time.elapsed());
     // threads
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_created_utc() {
+        let parse = |line: &str| {
+            Post::from_json_struct(serde_json::from_str::<JsonStruct>(line).unwrap())
+                .unwrap()
+                .created_utc
+        };
+        let base =
+            r#""id":"1","is_thread":"Y","pagetext":"a","parent_post_id":"1","root_post_id":"1""#;
+        assert_eq!(parse(&format!("{{{}}}", base)), None);
+        assert_eq!(
+            parse(&format!("{{{},\"created_utc\":12}}", base)),
+            Some(12.0)
+        );
+        assert_eq!(
+            parse(&format!("{{{},\"timestamp\":\"12.5\"}}", base)),
+            Some(12.5)
+        );
+        assert_eq!(parse(&format!("{{{},\"created_utc\":null}}", base)), None);
+        // Both keys, as in some Reddit dumps, `created_utc` wins
+        assert_eq!(
+            parse(&format!("{{{},\"created_utc\":12,\"timestamp\":99}}", base)),
+            Some(12.0)
+        );
+        assert_eq!(
+            parse(&format!(
+                "{{{},\"created_utc\":null,\"timestamp\":99}}",
+                base
+            )),
+            Some(99.0)
+        );
+    }
+
+    #[test]
+    fn test_score() {
+        let parse = |line: &str| {
+            Post::from_json_struct(serde_json::from_str::<JsonStruct>(line).unwrap())
+                .unwrap()
+                .score
+        };
+        let base =
+            r#""id":"1","is_thread":"Y","pagetext":"a","parent_post_id":"1","root_post_id":"1""#;
+        assert_eq!(parse(&format!("{{{}}}", base)), None);
+        assert_eq!(parse(&format!("{{{},\"score\":-3}}", base)), Some(-3.0));
+        assert_eq!(
+            parse(&format!("{{{},\"upvotes\":\"41\"}}", base)),
+            Some(41.0)
+        );
+        // Both keys, `score` wins
+        assert_eq!(
+            parse(&format!("{{{},\"score\":5,\"upvotes\":41}}", base)),
+            Some(5.0)
+        );
+        assert_eq!(
+            parse(&format!("{{{},\"upvotes\":41,\"score\":\"n/a\"}}", base)),
+            Some(41.0)
+        );
+
+        let author = |line: &str| JsonlParser.parse(line).unwrap().unwrap().author;
+        let line = format!("{{{},\"author\":\"a\",\"username\":\"u\"}}", base);
+        assert_eq!(author(&line).as_deref(), Some("a"));
+        let line = format!("{{{},\"username\":\"u\"}}", base);
+        assert_eq!(author(&line).as_deref(), Some("u"));
+    }
+
+    #[test]
+    fn test_borrowed_fields() {
+        let line = r#"{"id":"2","is_thread":"N","pagetext":"say \"hi\"","parent_post_id":"1","root_post_id":"1","author":"a"}"#;
+        let json = serde_json::from_str::<JsonStruct>(line).unwrap();
+        // Only the strings with escapes are copied out of the line
+        assert!(matches!(json.id, Cow::Borrowed(_)));
+        assert!(matches!(json.author, Some(Cow::Borrowed(_))));
+        assert!(matches!(json.pagetext, Cow::Owned(_)));
+        let post = JsonlParser.parse(line).unwrap().unwrap();
+        assert_eq!(post.pagetext, "say \"hi\"");
+        assert_eq!(post.author.as_deref(), Some("a"));
+    }
+}
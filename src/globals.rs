@@ -1,23 +1,93 @@
 // src/globals.rs
+use std::collections::HashSet;
+use std::io::IsTerminal;
+use std::path::PathBuf;
 use std::sync::OnceLock;
-use tokenizers;
 
-/// Tokenizer object
+use crate::config::LogFormat;
+use crate::error;
+use crate::utils::histogram::LengthHistogram;
+use crate::utils::kenlm::NGramModel;
+#[cfg(feature = "tokenizers")]
+use crate::utils::sentencepiece;
+
+#[cfg(feature = "tokenizers")]
+pub use tokenizers::Encoding;
+
+/// Tokenizer objects
 ///
-/// This is a `OnceLock<tokenizers::Tokenizer>` that will be initialized when called with
-/// `get_or_init` and a closure that returns a `tokenizers::Tokenizer`
+/// This is a `OnceLock` of the labelled tokenizers that will be initialized with
+/// `init_tokenizer` or `init_tokenizers`, after the names they were loaded from. The first
+/// tokenizer is the main one.
 ///
 /// # Example
 ///
-/// ```
+/// ```ignore
 /// pub mod globals;
 ///
-/// globals::TOKENIZER.get_or_init(|| {
-///    tokenizers::Tokenizer::from_pretrained("openai-community/gpt2", None).unwrap()
-/// });
+/// globals::init_tokenizer(&"openai-community/gpt2".to_string());
 ///
 /// ```
-static TOKENIZER: OnceLock<tokenizers::Tokenizer> = OnceLock::new();
+#[cfg(feature = "tokenizers")]
+static TOKENIZER: OnceLock<(Vec<String>, Vec<Labelled>)> = OnceLock::new();
+
+/// A tokenizer and its label
+#[cfg(feature = "tokenizers")]
+type Labelled = (String, tokenizers::Tokenizer);
+
+/// Dedicated thread pool for batch tokenization
+///
+/// Initialized with `init_tokenizer_threads`, after its number of threads, if it is never
+/// initialized `encode_batch` runs on the global rayon pool that is also used for the folders.
+static TOKENIZER_POOL: OnceLock<(usize, rayon::ThreadPool)> = OnceLock::new();
+
+/// KenLM model used for perplexity scoring
+///
+/// Initialized with `init_kenlm`, after its path, if it is never initialized no perplexity is
+/// computed.
+static KENLM: OnceLock<(String, NGramModel)> = OnceLock::new();
+
+/// Histogram of the token lengths of the written documents
+///
+/// Initialized with `init_histogram` when a tokenizer is used, after its bounds, if it is never
+/// initialized nothing is recorded.
+static HISTOGRAM: OnceLock<(Vec<usize>, LengthHistogram)> = OnceLock::new();
+
+/// The value of `lock`, set with `init` the first time
+///
+/// A process can run the pipeline more than once, the models are then loaded by the first run
+/// and kept for the next ones as long as they ask for the same `key`.
+///
+/// # Errors
+///
+/// `Error::Usage` if `lock` was set for another `key`, the error of `init` otherwise.
+fn init_once<'a, K, T, F>(
+    lock: &'a OnceLock<(K, T)>,
+    what: &str,
+    key: K,
+    init: F,
+) -> error::Result<&'a T>
+where
+    K: PartialEq + Clone + std::fmt::Debug,
+    F: FnOnce() -> error::Result<T>,
+{
+    let (loaded, value) = match lock.get() {
+        Some(set) => set,
+        None => {
+            let value = init()?;
+            // Set by another thread in the meantime, its key is checked below
+            let _ = lock.set((key.clone(), value));
+            lock.get().expect("set above")
+        }
+    };
+    if *loaded != key {
+        return Err(error::Error::Usage(format!(
+            "The {} {:?} is already loaded, a process cannot load {:?}",
+            what, loaded, key
+        )));
+    }
+    Ok(value)
+}
 
 /// Main regex
 ///
@@ -71,7 +141,7 @@ static SPACE_REGEX: OnceLock<regex::Regex> = OnceLock::new();
 /// This should be called at the beginning of the program
 ///
 /// # Example
-/// ```
+/// ```ignore
 /// pub mod globals;
 /// globals::init_regex();
 ///
@@ -98,7 +168,7 @@ pub fn init_regex() {
 ///
 /// # Example
 ///
-/// ```
+/// ```ignore
 /// pub mod globals;
 ///
 /// globals::init_regex();
@@ -122,34 +192,221 @@ pub fn clean_content(content: &str) -> String {
         .replace_all(&cleaned_text, " ")
         .into()
 }
+/// Options for loading tokenizers from the huggingface hub
+///
+/// # Example
+///
+/// ```ignore
+/// let hub = HubOptions {
+///     offline: true,
+///     cache_dir: Some("/models/huggingface/hub".into()),
+/// };
+/// globals::init_tokenizers(&["openai-community/gpt2".to_string()], &hub);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct HubOptions {
+    /// Never connect to the hub, the tokenizer must already be in the cache.
+    /// Also enabled by setting `HF_HUB_OFFLINE=1`.
+    pub offline: bool,
+    /// The hub cache, defaults to `$HF_HOME/hub` or `~/.cache/huggingface/hub`
+    pub cache_dir: Option<PathBuf>,
+}
+
+#[cfg(feature = "tokenizers")]
+impl HubOptions {
+    fn is_offline(&self) -> bool {
+        self.offline || std::env::var("HF_HUB_OFFLINE").is_ok_and(|value| value == "1")
+    }
+
+    fn cache(&self) -> hf_hub::Cache {
+        match &self.cache_dir {
+            Some(cache_dir) => hf_hub::Cache::new(cache_dir.clone()),
+            None => hf_hub::Cache::default(),
+        }
+    }
+}
+
+/// Resolve the `tokenizer.json` of a huggingface model
+///
+/// In offline mode only the local cache is checked, otherwise the file is downloaded into the cache if needed.
+///
+/// # Panics
+///
+/// This function will panic with the reason if the tokenizer cannot be found or downloaded
+#[cfg(feature = "tokenizers")]
+fn hub_tokenizer_path(tokenizer_name: &str, hub: &HubOptions) -> PathBuf {
+    let cache = hub.cache();
+    if hub.is_offline() {
+        return cache
+            .model(tokenizer_name.to_string())
+            .get("tokenizer.json")
+            .unwrap_or_else(|| {
+                panic!(
+                    "Tokenizer `{}` is not in the cache at `{}` and offline mode is enabled. \
+                    Download it first or pass a path to tokenizer.json",
+                    tokenizer_name,
+                    cache.path().display()
+                )
+            });
+    }
+    let mut builder = hf_hub::api::sync::ApiBuilder::from_cache(cache);
+    if let Ok(token) = std::env::var("HF_TOKEN") {
+        builder = builder.with_token(Some(token));
+    }
+    builder
+        .build()
+        .and_then(|api| api.model(tokenizer_name.to_string()).get("tokenizer.json"))
+        .unwrap_or_else(|e| {
+            panic!(
+                "Unable to download tokenizer `{}`: {}. \
+                Use --offline with a cached tokenizer or pass a path to tokenizer.json",
+                tokenizer_name, e
+            )
+        })
+}
+
+/// Load a single tokenizer from a huggingface name, a tokenizer.json or a SentencePiece `.model` file
+#[cfg(feature = "tokenizers")]
+fn load_tokenizer(tokenizer_name: &str, hub: &HubOptions) -> tokenizers::Tokenizer {
+    if tokenizer_name.ends_with(".json") {
+        tracing::info!(path = tokenizer_name, "Loading tokenizer from file");
+        tokenizers::Tokenizer::from_file(tokenizer_name).unwrap()
+    } else if tokenizer_name.ends_with(".model") {
+        tracing::info!(
+            path = tokenizer_name,
+            "Loading SentencePiece model from file"
+        );
+        sentencepiece::load(tokenizer_name).unwrap()
+    } else {
+        tracing::info!(name = tokenizer_name, "Loading tokenizer from the hub");
+        tokenizers::Tokenizer::from_file(hub_tokenizer_path(tokenizer_name, hub)).unwrap()
+    }
+}
+
+/// Split a tokenizer argument into its label and name
+///
+/// The label is used for the `length_<label>` fields. It can be given explicitly as `label=name`,
+/// otherwise it is taken from the name, e.g. `openai-community/gpt2` becomes `gpt2` and
+/// `llama3/tokenizer.json` becomes `llama3`.
+fn tokenizer_label(tokenizer_arg: &str) -> (String, &str) {
+    let (label, name) = match tokenizer_arg.split_once('=') {
+        Some((label, name)) => (label.to_string(), name),
+        None => {
+            let path = std::path::Path::new(tokenizer_arg);
+            let mut label = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or("");
+            if label == "tokenizer" {
+                label = path
+                    .parent()
+                    .and_then(|parent| parent.file_name())
+                    .and_then(|parent| parent.to_str())
+                    .unwrap_or(label);
+            }
+            (label.to_string(), tokenizer_arg)
+        }
+    };
+    let label = label
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_lowercase(),
+            false => '_',
+        })
+        .collect();
+    (label, name)
+}
+
+/// The first label given to more than one tokenizer, their `length_<label>` fields would collide
+pub fn duplicate_tokenizer_label(tokenizer_names: &[String]) -> Option<String> {
+    let mut labels = HashSet::new();
+    tokenizer_names
+        .iter()
+        .map(|tokenizer_arg| tokenizer_label(tokenizer_arg).0)
+        .find(|label| !labels.insert(label.clone()))
+}
+
 /// Helper function to initialize the tokenizer
 ///
 /// This may be called at the beginning of the program if choosing to use a specific tokenizer
 ///
 /// # Arguments
 ///
-/// * `tokenizer_name` - `&String` - The name of the tokenizer to use, this should be in the format of `huggingface <org>/<name>` or a path to a tokenizer.json file
+/// * `tokenizer_name` - `&String` - The name of the tokenizer to use, this should be in the format of `huggingface <org>/<name>`,
+///   a path to a tokenizer.json file or a path to a SentencePiece `.model` file
 ///
 /// # Example
 ///
-/// ```
+/// ```ignore
 /// pub mod globals;
 ///
-/// globals::init_tokenizer(&"openai-community/gpt2".to_string());
+/// globals::init_tokenizer(&"openai-community/gpt2".to_string())?;
 ///
 /// // Continue with the program
-pub fn init_tokenizer(tokenizer_name: &String) {
-    if tokenizer_name.ends_with(".json") {
-        println!("Loading tokenizer from file: {}", tokenizer_name);
-        TOKENIZER
-            .set(tokenizers::Tokenizer::from_file(tokenizer_name).unwrap())
-            .expect("Unable to load tokenizer");
-    } else {
-        println!("Loading tokenizer: {}", tokenizer_name);
-        TOKENIZER
-            .set(tokenizers::Tokenizer::from_pretrained(tokenizer_name, None).unwrap())
-            .expect("Unable to load tokenizer");
-    }
+/// ```
+pub fn init_tokenizer(tokenizer_name: &String) -> error::Result<()> {
+    init_tokenizers(std::slice::from_ref(tokenizer_name), &HubOptions::default())
+}
+
+/// Helper function to initialize several tokenizers
+///
+/// The first tokenizer is the main one, used by [tokenize](fn.tokenize.html) and
+/// [tokenize_batch](fn.tokenize_batch.html). The others are only used to count lengths with
+/// [tokenize_batch_with](fn.tokenize_batch_with.html).
+///
+/// # Arguments
+///
+/// * `tokenizer_names` - `&[String]` - The tokenizers, each in the same format as [init_tokenizer](fn.init_tokenizer.html),
+///   optionally prefixed with `label=`
+/// * `hub` - `&HubOptions` - How huggingface names are resolved
+///
+/// # Errors
+///
+/// `Error::Usage` if other tokenizers were already loaded by the process, the same ones are kept.
+///
+/// # Example
+///
+/// ```ignore
+/// pub mod globals;
+///
+/// globals::init_tokenizers(
+///     &["gpt2=openai-community/gpt2".to_string(), "llama.model".to_string()],
+///     &globals::HubOptions::default(),
+/// )?;
+/// ```
+#[cfg(feature = "tokenizers")]
+pub fn init_tokenizers(tokenizer_names: &[String], hub: &HubOptions) -> error::Result<()> {
+    init_once(&TOKENIZER, "tokenizers", tokenizer_names.to_vec(), || {
+        Ok(tokenizer_names
+            .iter()
+            .map(|tokenizer_arg| {
+                let (label, name) = tokenizer_label(tokenizer_arg);
+                (label, load_tokenizer(name, hub))
+            })
+            .collect())
+    })?;
+    Ok(())
+}
+
+/// Labels of the loaded tokenizers, in the order they were given
+///
+/// Returns an empty slice if no tokenizer has been initialized
+#[cfg(feature = "tokenizers")]
+pub fn tokenizer_labels() -> Vec<&'static str> {
+    TOKENIZER
+        .get()
+        .map(|(_, tokenizers)| tokenizers.iter().map(|(label, _)| label.as_str()).collect())
+        .unwrap_or_default()
+}
+
+/// Get the main tokenizer
+#[cfg(feature = "tokenizers")]
+fn main_tokenizer() -> &'static tokenizers::Tokenizer {
+    TOKENIZER
+        .get()
+        .and_then(|(_, tokenizers)| tokenizers.first())
+        .map(|(_, tokenizer)| tokenizer)
+        .expect("Tokenizer has not been initialized")
 }
 
 /// Helper function to tokenize directly
@@ -166,7 +423,7 @@ pub fn init_tokenizer(tokenizer_name: &String) {
 ///
 /// # Example
 ///
-/// ```
+/// ```ignore
 /// pub mod globals;
 ///
 ///
@@ -179,12 +436,270 @@ pub fn init_tokenizer(tokenizer_name: &String) {
 /// # Panics
 ///
 /// This function will panic if the tokenizer has not been initialized
-pub fn tokenize(content: &str) -> tokenizers::Encoding {
-    TOKENIZER
+#[cfg(feature = "tokenizers")]
+pub fn tokenize(content: &str) -> Encoding {
+    main_tokenizer().encode(content, false).unwrap()
+}
+
+/// Helper function to tokenize a batch of documents
+///
+/// Uses `encode_batch`, which parallelizes over the documents inside the tokenizer and is much faster
+/// than calling [tokenize](fn.tokenize.html) for each document.
+///
+/// # Arguments
+///
+/// * `contents` : `&[String]` - The documents to tokenize
+///
+/// # Returns
+///
+/// * `Vec<tokenizers::Encoding>` - The encodings, in the same order as `contents`
+///
+/// # Panics
+///
+/// This function will panic if the tokenizer has not been initialized
+pub fn tokenize_batch(contents: &[String]) -> Vec<Encoding> {
+    tokenize_batch_with(0, contents)
+}
+
+/// Same as [tokenize_batch](fn.tokenize_batch.html) but with the tokenizer at `index`
+///
+/// Runs on the dedicated tokenizer pool if one was set up with [init_tokenizer_threads](fn.init_tokenizer_threads.html).
+///
+/// # Panics
+///
+/// This function will panic if the tokenizer has not been initialized
+#[cfg(feature = "tokenizers")]
+pub fn tokenize_batch_with(index: usize, contents: &[String]) -> Vec<Encoding> {
+    let inputs: Vec<&str> = contents.iter().map(String::as_str).collect();
+    let (_, tokenizer) = TOKENIZER
         .get()
-        .expect("Tokenizer has not been initialized")
-        .encode(content, false)
-        .unwrap()
+        .and_then(|(_, tokenizers)| tokenizers.get(index))
+        .expect("Tokenizer has not been initialized");
+    match TOKENIZER_POOL.get() {
+        Some((_, pool)) => pool.install(|| tokenizer.encode_batch(inputs, false).unwrap()),
+        None => tokenizer.encode_batch(inputs, false).unwrap(),
+    }
+}
+
+/// Configure the parallelism inside the tokenizer
+///
+/// The tokenizers crate parallelizes `encode_batch` with rayon. By default this shares the global pool
+/// with the folder processing, which oversubscribes the cores when every worker tokenizes at once.
+///
+/// # Arguments
+///
+/// * `parallelism` - `Option<bool>` - Whether the tokenizer may parallelize internally, same as `TOKENIZERS_PARALLELISM`, left as it is if not set
+/// * `threads` - `Option<usize>` - If set, batch tokenization runs on its own pool of this many threads
+///
+/// # Errors
+///
+/// `Error::Usage` if a pool of another size was already set up by the process, an I/O error if the
+/// threads cannot be spawned.
+///
+/// # Example
+///
+/// ```ignore
+/// pub mod globals;
+///
+/// // Tokenize on 4 dedicated threads
+/// globals::init_tokenizer_threads(None, Some(4))?;
+/// ```
+pub fn init_tokenizer_threads(
+    parallelism: Option<bool>,
+    threads: Option<usize>,
+) -> error::Result<()> {
+    #[cfg(feature = "tokenizers")]
+    if let Some(parallelism) = parallelism {
+        tokenizers::utils::parallelism::set_parallelism(parallelism);
+    }
+    #[cfg(not(feature = "tokenizers"))]
+    let _ = parallelism;
+    if let Some(threads) = threads {
+        init_once(&TOKENIZER_POOL, "tokenizer pool of", threads, || {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .thread_name(|i| format!("tokenizer-{}", i))
+                .build()
+                .map_err(|e| std::io::Error::other(e).into())
+        })?;
+    }
+    Ok(())
+}
+
+/// Helper function to size the global rayon pool that processes the folders
+///
+/// Must be called before anything runs on the pool. Without it the pool follows
+/// `RAYON_NUM_THREADS`, or uses one thread per core.
+///
+/// # Arguments
+///
+/// * `threads` - `Option<usize>` - Number of worker threads, the rayon default if not set
+///
+/// # Example
+///
+/// ```ignore
+/// pub mod globals;
+///
+/// globals::init_threads(Some(8));
+/// ```
+pub fn init_threads(threads: Option<usize>) {
+    if let Some(threads) = threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .expect("Unable to create the thread pool");
+    }
+}
+
+/// Helper function to load the KenLM model
+///
+/// # Arguments
+///
+/// * `model_path` - `&String` - Path to a KenLM model in ARPA format
+///
+/// # Errors
+///
+/// `Error::ReadModel` if the model cannot be read, `Error::Usage` if another model was already
+/// loaded by the process.
+pub fn init_kenlm(model_path: &String) -> error::Result<()> {
+    init_once(&KENLM, "KenLM model", model_path.clone(), || {
+        tracing::info!(path = %model_path, "Loading KenLM model");
+        NGramModel::from_file(model_path).map_err(|source| error::Error::ReadModel {
+            path: PathBuf::from(model_path),
+            source,
+        })
+    })?;
+    Ok(())
+}
+
+/// Compute the perplexity of the content
+///
+/// # Returns
+///
+/// * `Option<f64>` - The perplexity, `None` if `init_kenlm` was not called
+pub fn perplexity(content: &str) -> Option<f64> {
+    KENLM.get().map(|(_, model)| model.perplexity(content))
+}
+
+/// Helper function to set up the log events
+///
+/// Events go to stderr, so they do not mix with the progress and reports on stdout. The level is
+/// `info` unless `RUST_LOG` says otherwise, for example `RUST_LOG=debug`. When `quiet`, only the
+/// warnings and the events of the `summary` target at the end of the run are kept.
+///
+/// # Arguments
+///
+/// * `format` - `LogFormat` - Text lines or one JSON object per event
+/// * `quiet` - `bool` - Leave out the informational events
+pub fn init_logging(format: LogFormat, quiet: bool) {
+    let default = match quiet {
+        true => "warn,summary=info",
+        false => "info",
+    };
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(default));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_writer(std::io::stderr);
+    // Ignore the error of a second call, the first subscriber is kept
+    let _ = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).try_init(),
+    };
+}
+
+/// Helper function to start recording the token length histogram
+///
+/// # Arguments
+///
+/// * `bounds` - `&[usize]` - Upper bounds of the buckets, an overflow bucket is added after the last one
+///
+/// # Errors
+///
+/// `Error::Usage` if the process already records a histogram with other bounds. With the same
+/// bounds the counts of the previous run are cleared.
+pub fn init_histogram(bounds: &[usize]) -> error::Result<()> {
+    let mut created = false;
+    let histogram = init_once(
+        &HISTOGRAM,
+        "histogram with the bounds",
+        bounds.to_vec(),
+        || {
+            created = true;
+            Ok(LengthHistogram::new(bounds))
+        },
+    )?;
+    if !created {
+        histogram.clear();
+    }
+    Ok(())
+}
+
+/// Add a document length to the histogram, does nothing if `init_histogram` was not called
+pub fn record_length(length: usize) {
+    if let Some((_, histogram)) = HISTOGRAM.get() {
+        histogram.record(length);
+    }
+}
+
+/// The token length histogram, `None` if `init_histogram` was not called
+pub fn histogram() -> Option<&'static LengthHistogram> {
+    HISTOGRAM.get().map(|(_, histogram)| histogram)
+}
+
+// Without the `tokenizers` feature, for wasm32, nothing can be tokenized and the lengths are
+// always counted in words
+
+/// Stand-in for `tokenizers::Encoding` without the `tokenizers` feature, none is ever made
+#[cfg(not(feature = "tokenizers"))]
+pub struct Encoding {
+    ids: Vec<u32>,
+    offsets: Vec<(usize, usize)>,
+}
+
+#[cfg(not(feature = "tokenizers"))]
+impl Encoding {
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    pub fn get_ids(&self) -> &[u32] {
+        &self.ids
+    }
+
+    pub fn get_offsets(&self) -> &[(usize, usize)] {
+        &self.offsets
+    }
+}
+
+/// # Errors
+///
+/// Always `Error::Usage`, there is no tokenizer without the `tokenizers` feature
+#[cfg(not(feature = "tokenizers"))]
+pub fn init_tokenizers(_tokenizer_names: &[String], _hub: &HubOptions) -> error::Result<()> {
+    Err(error::Error::Usage(
+        "Built without the `tokenizers` feature, no tokenizer can be loaded".to_string(),
+    ))
+}
+
+#[cfg(not(feature = "tokenizers"))]
+pub fn tokenizer_labels() -> Vec<&'static str> {
+    Vec::new()
+}
+
+#[cfg(not(feature = "tokenizers"))]
+pub fn tokenize(_content: &str) -> Encoding {
+    panic!("Tokenizer has not been initialized");
+}
+
+#[cfg(not(feature = "tokenizers"))]
+pub fn tokenize_batch_with(_index: usize, _contents: &[String]) -> Vec<Encoding> {
+    panic!("Tokenizer has not been initialized");
 }
 
 #[cfg(test)]
@@ -205,8 +720,9 @@ mod tokenizer_tests {
     }
 
     #[test]
+    #[cfg(feature = "tokenizers")]
     fn test_tokenizer() {
-        init_tokenizer(&"openai-community/gpt2".to_string());
+        init_tokenizer(&"openai-community/gpt2".to_string()).unwrap();
         let encoding = tokenize("Hello world");
         assert!(!encoding.get_tokens().is_empty());
     }
@@ -218,9 +734,72 @@ mod tokenizer_tests {
         tokenize("Hello world");
     }
 
+    #[test]
+    fn test_tokenizer_label() {
+        assert_eq!(
+            tokenizer_label("openai-community/gpt2"),
+            ("gpt2".to_string(), "openai-community/gpt2")
+        );
+        assert_eq!(
+            tokenizer_label("models/llama3/tokenizer.json"),
+            ("llama3".to_string(), "models/llama3/tokenizer.json")
+        );
+        assert_eq!(
+            tokenizer_label("Llama-2=tokenizer.model"),
+            ("llama_2".to_string(), "tokenizer.model")
+        );
+        let names = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            duplicate_tokenizer_label(&names(&["gpt2", "llama3/tokenizer.json"])),
+            None
+        );
+        assert_eq!(
+            duplicate_tokenizer_label(&names(&["openai-community/gpt2", "gpt2=models/gpt2.json"])),
+            Some("gpt2".to_string())
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "tokenizers")]
+    #[should_panic(expected = "offline mode is enabled")]
+    fn test_offline_missing_tokenizer() {
+        let cache_dir = tempfile::TempDir::new().unwrap();
+        let hub = HubOptions {
+            offline: true,
+            cache_dir: Some(cache_dir.path().to_path_buf()),
+        };
+        hub_tokenizer_path("openai-community/gpt2", &hub);
+    }
+
+    #[test]
+    fn test_init_once() {
+        static LOADED: OnceLock<(String, usize)> = OnceLock::new();
+        let mut loads = 0;
+        let mut init = |key: &str| {
+            init_once(&LOADED, "model", key.to_string(), || {
+                loads += 1;
+                Ok(key.len())
+            })
+            .copied()
+        };
+        assert_eq!(init("first").unwrap(), 5);
+        // The same key again is the model already loaded, another one is refused
+        assert_eq!(init("first").unwrap(), 5);
+        assert!(matches!(init("second"), Err(error::Error::Usage(_))));
+        assert_eq!(loads, 1);
+
+        let missing = init_kenlm(&"no/such/model.arpa".to_string());
+        assert!(matches!(missing, Err(error::Error::ReadModel { .. })));
+    }
+
     #[test]
     #[should_panic]
     fn test_invalid_huggingface_name() {
-        init_tokenizer(&"no_such_model".to_string());
+        let _ = init_tokenizer(&"no_such_model".to_string());
     }
 }
@@ -1,17 +1,18 @@
-use crate::forum_thread::Post;
+use crate::forum_thread::{CompactString, Post};
 use petgraph::graph::{DiGraph, NodeIndex};
 use petgraph::visit::Dfs;
 use petgraph::Graph;
 use rayon::prelude::*;
-use std::collections::HashMap;
+use rustc_hash::FxHashMap;
 
 ///
 /// ThreadGraph is a struct that represents a graph of threads and comments
 ///
 #[derive(Default)]
 pub struct ThreadGraph {
-    graph: Graph<String, ()>,
-    node_map: HashMap<String, NodeIndex>,
+    /// The nodes carry no weight, a node index is also the index of its post in `allthreads`
+    graph: Graph<(), ()>,
+    node_map: FxHashMap<CompactString, NodeIndex>,
     threads: Vec<NodeIndex>,
     allthreads: Vec<Post>,
 }
@@ -24,13 +25,13 @@ impl ThreadGraph {
     ///
     /// # Example
     ///
-    /// ```
+    /// ```ignore
     /// let threadgraph = ThreadGraph::new();
     /// ```
     pub fn new() -> Self {
         ThreadGraph {
             graph: DiGraph::new(),
-            node_map: HashMap::with_capacity(10000),
+            node_map: FxHashMap::with_capacity_and_hasher(10000, Default::default()),
             threads: Vec::with_capacity(10000),
             allthreads: Vec::with_capacity(10000),
         }
@@ -51,7 +52,7 @@ impl ThreadGraph {
     ///
     /// # Example
     ///
-    /// ```rust
+    /// ```ignore
     /// let post = Post::new("1", true, "1", "1", "1");
     /// let idx = threadgraph.add_node(post);
     ///
@@ -62,10 +63,10 @@ impl ThreadGraph {
         if let Some(&idx) = self.node_map.get(&id) {
             idx
         } else {
-            let idx = self.graph.add_node(id.clone());
+            let idx = self.graph.add_node(());
             self.allthreads.push(post);
             // self.id_set.insert(id.clone());
-            self.node_map.insert(id.to_string(), idx);
+            self.node_map.insert(id, idx);
             idx
         }
     }
@@ -75,11 +76,11 @@ impl ThreadGraph {
     /// If it doesn't exist, it will create a placeholder post and add it to the graph.
     ///
     /// The reason for the above implementation is due to some of the threads being detached from the main thread.
-    pub fn add_edge(&mut self, from_id: &String, to_id: &String) {
+    pub fn add_edge(&mut self, from_id: &str, to_id: &str) {
         // check if from_id is in map
         if !self.node_map.contains_key(from_id) {
             // This happens when the thread is detached, where the parent does not exist
-            let post = Post::placeholder(from_id.to_string());
+            let post = Post::placeholder(CompactString::new(from_id));
             let idx = self.add_node(post);
             self.add_threads(idx)
         }
@@ -128,60 +129,82 @@ impl ThreadGraph {
     ///
     /// # Example
     ///
-    /// ```
+    /// ```ignore
     /// let threads = threadgraph.traverse();
     /// threads[0].0 // root post id
     /// threads[0].1 // vector of pagetext
     /// ```
     pub fn traverse(&self) -> Vec<(String, Vec<String>)> {
-        self.show_roots();
-        // check for duplicates
-        // self.show_roots();
-        // let mut root_id: String = String::new();
-        // print number of nodes
-        //dbg!(self.graph.node_count());
+        self.thread_nodes()
+            .into_iter()
+            .map(|(root_id, nodes)| {
+                let vec_string: Vec<String> = nodes
+                    .iter()
+                    .map(|node| self.allthreads[*node].pagetext.clone())
+                    .collect();
+                (root_id, vec_string)
+            })
+            .collect()
+    }
+
+    /// Same as [traverse](#method.traverse), the texts are moved out of the graph
+    ///
+    /// A post visited from several threads is copied for all but the last one.
+    pub fn into_traverse(mut self) -> Vec<(String, Vec<String>)> {
+        let threads = self.thread_nodes();
+        let mut uses: Vec<u32> = vec![0; self.allthreads.len()];
+        for (_, nodes) in threads.iter() {
+            for &node in nodes {
+                uses[node] += 1;
+            }
+        }
+        threads
+            .into_iter()
+            .map(|(root_id, nodes)| {
+                let vec_string: Vec<String> = nodes
+                    .into_iter()
+                    .map(|node| {
+                        uses[node] -= 1;
+                        let pagetext = &mut self.allthreads[node].pagetext;
+                        match uses[node] {
+                            0 => std::mem::take(pagetext),
+                            _ => pagetext.clone(),
+                        }
+                    })
+                    .collect();
+                (root_id, vec_string)
+            })
+            .collect()
+    }
 
-        let mut final_threads: Vec<(String, Vec<String>)> = Vec::with_capacity(self.threads.len());
+    /// The root id and the nodes of every thread in depth first order
+    fn thread_nodes(&self) -> Vec<(String, Vec<usize>)> {
+        let mut final_threads: Vec<(String, Vec<usize>)> = Vec::with_capacity(self.threads.len());
         self.threads
             .par_iter()
             .with_min_len(100)
             .map(|start| {
-                // skip if not root
                 let mut dfs = Dfs::new(&self.graph, *start);
                 let mut threads: Vec<usize> = Vec::new();
 
                 while let Some(visited) = dfs.next(&self.graph) {
                     threads.push(visited.index());
                 }
-                let root_id = self.graph[*start].clone();
-                let vec_string: Vec<String> = threads
-                    .iter()
-                    // .with_min_len(100)
-                    .map(|thread| {
-                        // print!("{} ", thread);
-                        self.allthreads[*thread].pagetext.clone()
-                    })
-                    .collect();
-                // dbg!(vec_string.len());
-                // println!();
-                (root_id, vec_string)
+                (self.allthreads[start.index()].id.to_string(), threads)
             })
             .collect_into_vec(&mut final_threads);
-        // println!("Longest thread: {}", long_string);
-        // println!("Longest thread: {}", longest_thread);
-        // dbg!(roots);
         final_threads
     }
 
     pub fn show_threads(&self) {
         for node in self.graph.node_indices() {
-            println!("{:?}", self.graph[node]);
+            println!("{:?}", self.allthreads[node.index()].id);
         }
     }
     pub fn add_threads(&mut self, idx: NodeIndex) {
         self.threads.push(idx);
     }
-    pub fn is_in_map(&self, id: &String) -> bool {
+    pub fn is_in_map(&self, id: &str) -> bool {
         self.node_map.contains_key(id)
     }
 }
@@ -265,6 +288,8 @@ mod tests {
             assert_eq!(graph.threads.len(), 3);
 
             let mut threads = graph.traverse();
+            // Moving the texts out gives the same threads
+            assert_eq!(graph.into_traverse(), threads);
             threads.sort_by(|a, b| a.0.cmp(&b.0));
 
             assert_eq!(threads.len(), target.len());
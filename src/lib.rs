@@ -0,0 +1,59 @@
+#![doc = include_str!("../README.md")]
+
+/**
+
+# Struct for the command line arguments
+
+# Arguments
+
+* `input` - The input folder containing the forum data
+* `output` - The output folder where the processed data will be stored
+* `tokenizer` - The tokenizer to use for tokenization
+* `source` - The source of the data
+* `safe` - Whether to overwrite the output folder or not
+
+# Panics
+
+* If the input folder does not exist
+* If the output folder does not exist
+* If the tokenizer is not valid
+* If huggingface hub is not authorized
+
+
+*/
+pub mod args;
+pub mod builder;
+pub mod commands;
+pub mod config;
+pub mod error;
+
+/**
+
+# Module for the experimental functions
+
+This module contains functions that may not produce the best performance but are experimental
+*/
+pub mod experimental;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod forum_thread;
+pub mod globals;
+pub mod graph;
+pub mod pipeline;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "stream")]
+pub mod stream;
+pub mod utils;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use builder::{Pipeline, PipelineBuilder};
+pub use config::Config;
+pub use error::{Error, Exit, Result};
+pub use experimental::graph::ThreadGraph;
+pub use experimental::iter::ThreadIter;
+pub use forum_thread::{Post, Thread};
+pub use pipeline::{
+    process_files, process_folder, process_folder_with, process_folder_within, run,
+};
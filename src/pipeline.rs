@@ -0,0 +1,1076 @@
+//! The `process` pipeline, from the input folders to the output files
+//!
+//! [run](fn.run.html) is the entry point, the other functions process a single folder or a list
+//! of files and send the records to a writer.
+
+use crossbeam_channel::{unbounded, RecvTimeoutError, Sender};
+use std::collections::HashSet;
+use std::fs::{self};
+use std::io::{IsTerminal, Write};
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+};
+
+use std::time::{Duration, Instant};
+
+use crate::{config, error, experimental, forum_thread, globals, utils};
+
+/// Held while a folder sends its records with `--resume`, so the records of a folder are contiguous
+///
+/// Taken even when poisoned, like the other global locks, a folder that panics must not fail the
+/// folders after it.
+static CHECKPOINT_LOCK: Mutex<()> = Mutex::new(());
+
+/// Process the folder
+///
+/// What this function does:
+/// 1. Get the threads from the folder
+/// 2. Create the thread posts
+/// 3. Write the thread posts to a file
+///
+/// # Arguments
+///
+/// * `folder` - `&Path` - The folder containing list of `jsonl` files
+/// * `config` - `&Config` - The processing options, see [Config](../config/struct.Config.html)
+/// * `post_tx` - `Sender<Batch>` - The sender to send the records with the file they go to,
+///   `config.channel_batch` at a time
+///
+/// # Errors
+///
+/// Returns an error if the folder cannot be read or its buckets cannot be written, nothing of the
+/// folder is sent in that case. Files that cannot be opened are reported and skipped.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::path::Path;
+/// # use crossbeam_channel::unbounded;
+/// # use forum_rs::{process_folder, Config};
+///
+/// let folder = Path::new("main_folder/sub1/");
+/// let config = Config::default();
+/// let (data_tx, data_rx) = unbounded();
+/// process_folder(folder, &config, data_tx.clone())?;
+/// # Ok::<(), forum_rs::Error>(())
+/// ```
+pub fn process_folder(
+    folder: &Path,
+    config: &config::Config,
+    post_tx: Sender<utils::writer::Batch>,
+) -> error::Result<()> {
+    let entries = utils::file::folder_files(folder)?;
+    process_files(&entries, folder, config, post_tx)
+}
+
+/// Same as [process_folder](fn.process_folder.html), abandoned after `timeout`
+///
+/// The folder runs on a thread of its own and its records are forwarded as they come. Rust threads
+/// cannot be killed, an abandoned folder stops at its next record, or when the run exits. The
+/// records already forwarded stay in the output, with `--resume` a folder sends them all at once
+/// at the end, so there are none.
+///
+/// The caller only waits, it must not be a worker of the global pool: the folder needs those for
+/// its own parallel stages.
+pub fn process_folder_within(
+    folder: &Path,
+    config: &Arc<config::Config>,
+    post_tx: &Sender<utils::writer::Batch>,
+    timeout: Duration,
+) -> error::Result<()> {
+    let process = {
+        let folder = folder.to_path_buf();
+        let config = config.clone();
+        move |folder_tx| process_folder(&folder, &config, folder_tx)
+    };
+    forward_within(folder, post_tx, timeout, process, |(_, batch)| {
+        batch.len() as u64
+    })
+}
+
+/// Run `process` on a thread of its own and forward what it sends to `post_tx` until `timeout`
+///
+/// `size` is the share of the memory in flight of a message, released for the ones left behind.
+fn forward_within<T, F>(
+    folder: &Path,
+    post_tx: &Sender<T>,
+    timeout: Duration,
+    process: F,
+    size: fn(&T) -> u64,
+) -> error::Result<()>
+where
+    T: Send + 'static,
+    F: FnOnce(Sender<T>) -> error::Result<()> + Send + 'static,
+{
+    let deadline = Instant::now() + timeout;
+    let (folder_tx, folder_rx) = unbounded();
+    let handle = std::thread::spawn(move || process(folder_tx));
+    loop {
+        match folder_rx.recv_deadline(deadline) {
+            Ok(record) => post_tx.send(record).unwrap(),
+            Err(RecvTimeoutError::Disconnected) => {
+                return handle
+                    .join()
+                    .unwrap_or_else(|payload| std::panic::resume_unwind(payload))
+            }
+            Err(RecvTimeoutError::Timeout) => break,
+        }
+    }
+    // The records left behind are never written
+    for record in folder_rx.try_iter() {
+        utils::memory::IN_FLIGHT.sub(size(&record));
+    }
+    Err(error::Error::Timeout {
+        path: folder.to_path_buf(),
+        timeout,
+    })
+}
+
+/// Same as [process_folder](fn.process_folder.html) for a given list of `jsonl` files
+///
+/// `folder` is only used to name the temporary buckets and the exported graphs, with
+/// `--cross-folder` the files of every subfolder are processed together under the input folder.
+///
+/// With `--resume` the records are collected and sent at once, followed by a checkpoint for the
+/// folder, so the output of a folder is never mixed with the output of one that is interrupted.
+pub fn process_files(
+    entries: &[PathBuf],
+    folder: &Path,
+    config: &config::Config,
+    post_tx: Sender<utils::writer::Batch>,
+) -> error::Result<()> {
+    let _span = tracing::info_span!("folder", folder = %folder.display()).entered();
+    if !config.resume {
+        let batcher = utils::writer::Batcher::new(post_tx, config.channel_batch);
+        return send_threads(entries, folder, config, |route, threadpost| {
+            forum_thread::send_threadpost(&batcher, route, threadpost)
+        });
+    }
+    let (folder_tx, folder_rx) = unbounded();
+    let batcher = utils::writer::Batcher::new(folder_tx, config.channel_batch);
+    send_threads(entries, folder, config, |route, threadpost| {
+        forum_thread::send_threadpost(&batcher, route, threadpost)
+    })?;
+    drop(batcher);
+    let _lock = CHECKPOINT_LOCK
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    for batch in folder_rx.try_iter() {
+        post_tx.send(batch).unwrap();
+    }
+    let key = utils::resume::folder_key(folder);
+    post_tx
+        .send(utils::writer::single(
+            utils::writer::Route::Checkpoint,
+            &key,
+        ))
+        .unwrap();
+    Ok(())
+}
+
+/// Same as [process_folder](fn.process_folder.html), every record is handed to `f` with its route
+/// instead of being serialized and sent
+///
+/// `f` is called from the workers of the global pool, a slow `f` slows the folder down. There is
+/// no checkpoint, the records of a folder are handed over as they are built.
+pub fn process_folder_with<F>(folder: &Path, config: &config::Config, f: F) -> error::Result<()>
+where
+    F: Fn(utils::writer::Route, utils::writer::ThreadPost) + Sync,
+{
+    let entries = utils::file::folder_files(folder)?;
+    process_files_with(&entries, folder, config, f)
+}
+
+/// Same as [process_folder_with](fn.process_folder_with.html) for a given list of `jsonl` files
+fn process_files_with<F>(
+    entries: &[PathBuf],
+    folder: &Path,
+    config: &config::Config,
+    f: F,
+) -> error::Result<()>
+where
+    F: Fn(utils::writer::Route, utils::writer::ThreadPost) + Sync,
+{
+    let _span = tracing::info_span!("folder", folder = %folder.display()).entered();
+    send_threads(entries, folder, config, f)
+}
+
+/// Send a record to the writer thread of a custom sink
+fn send_post(
+    post_tx: &Sender<(utils::writer::Route, utils::writer::ThreadPost)>,
+    route: utils::writer::Route,
+    threadpost: utils::writer::ThreadPost,
+) {
+    utils::memory::IN_FLIGHT.add(threadpost.raw_content.len() as u64);
+    post_tx.send((route, threadpost)).unwrap();
+}
+
+/// How a folder went, for the table at the end of the run
+struct FolderOutcome {
+    folder: PathBuf,
+    elapsed: Duration,
+    failed: bool,
+}
+
+/// Process a folder with `f`, an error or a panic is reported and only fails this folder
+fn isolate<F: FnOnce() -> error::Result<()>>(folder: &Path, f: F) -> FolderOutcome {
+    let start = Instant::now();
+    let result = error::catch_panic(folder, f);
+    let failed = result.is_err();
+    if let Err(e) = result {
+        error::report(e);
+    }
+    FolderOutcome {
+        folder: folder.to_path_buf(),
+        elapsed: start.elapsed(),
+        failed,
+    }
+}
+
+/// Run `op` on the calling thread with a scope that spawns on `pool`, or on the current pool
+fn scope_fifo<'scope, F: FnOnce(&rayon::ScopeFifo<'scope>)>(
+    pool: Option<&rayon::ThreadPool>,
+    op: F,
+) {
+    match pool {
+        Some(pool) => pool.in_place_scope_fifo(op),
+        None => rayon::in_place_scope_fifo(op),
+    }
+}
+
+/// Print the folders of the run, the failed ones first
+fn print_outcomes(outcomes: &[FolderOutcome]) {
+    if outcomes.is_empty() {
+        return;
+    }
+    let failed = outcomes.iter().filter(|outcome| outcome.failed).count();
+    println!("{:>7} {:>10}  folder", "status", "seconds");
+    for outcome in outcomes.iter().filter(|outcome| outcome.failed) {
+        print_outcome(outcome);
+    }
+    for outcome in outcomes.iter().filter(|outcome| !outcome.failed) {
+        print_outcome(outcome);
+    }
+    println!(
+        "{} folders succeeded, {} failed",
+        outcomes.len() - failed,
+        failed
+    );
+}
+
+fn print_outcome(outcome: &FolderOutcome) {
+    println!(
+        "{:>7} {:>10.1}  {}",
+        if outcome.failed { "failed" } else { "ok" },
+        outcome.elapsed.as_secs_f64(),
+        outcome.folder.display()
+    );
+}
+
+/// Log the time a stage took for the current folder
+fn stage_finished(stage: &str, elapsed: Duration) {
+    tracing::info!(
+        stage,
+        elapsed_ms = elapsed.as_millis() as u64,
+        "Stage finished"
+    );
+}
+
+/// Whether the files of every folder go in one work queue, see
+/// [scheduled](../experimental/scheduled/index.html)
+///
+/// The buckets, the grouping by root, the checkpoints of `--resume` and the timeout all work on a
+/// whole folder at a time.
+fn file_schedule(config: &config::Config) -> bool {
+    config.schedule == config::Schedule::Files
+        && config.buckets.is_none()
+        && !config.group_by_root
+        && !config.resume
+        && !config.cross_folder
+        && config.folder_timeout.is_none()
+}
+
+/// Build the threads of the files and hand the records to `emit`
+fn send_threads<F>(
+    entries: &[PathBuf],
+    folder: &Path,
+    config: &config::Config,
+    emit: F,
+) -> error::Result<()>
+where
+    F: Fn(utils::writer::Route, utils::writer::ThreadPost) + Sync,
+{
+    if let Some(buckets) = &config.buckets {
+        let start = Instant::now();
+        let mut create_posts_time = Duration::ZERO;
+        // The best thread of a link is only known once every bucket is built
+        let reposts = utils::links::Reposts::new(config.traversal.link_reposts);
+        experimental::bucketed::process_buckets(entries, folder, config, buckets, |threads| {
+            let start = Instant::now();
+            forum_thread::for_each_kept_threadpost(reposts.hold(threads), folder, config, &emit);
+            create_posts_time += start.elapsed();
+        })?;
+        let get_threads_time = start.elapsed().saturating_sub(create_posts_time);
+        let start = Instant::now();
+        forum_thread::for_each_kept_threadpost(reposts.release(), folder, config, &emit);
+        create_posts_time += start.elapsed();
+        stage_finished("get_threads", get_threads_time);
+        stage_finished("create_posts", create_posts_time);
+        return Ok(());
+    }
+
+    let start = Instant::now();
+    let threads: Vec<forum_thread::Thread> = match config.group_by_root {
+        true => experimental::grouped::threads_from_files(entries, config),
+        false => experimental::sender::threads_from_files(entries, folder, config)?,
+    };
+    stage_finished("get_threads", start.elapsed());
+    tracing::debug!(threads = threads.len(), "Threads built");
+
+    let start = Instant::now();
+    forum_thread::for_each_threadpost(threads, folder, config, emit);
+    stage_finished("create_posts", start.elapsed());
+    Ok(())
+}
+
+/// Build the threads of every folder of `config.input` and write them to `config.output`, this is
+/// the `process` subcommand
+///
+/// The models of `config.models` are loaded into the [globals](../globals/index.html) by the first
+/// run of the process, the next runs reuse them and must ask for the same ones. Ctrl-C and SIGTERM
+/// stop the run after the folders in progress.
+///
+/// # Arguments
+///
+/// * `config` - `Config` - The run, see [Config](../config/struct.Config.html)
+///
+/// # Returns
+///
+/// * `error::Result<error::Exit>` - `Exit::Partial` if files or folders were skipped
+///
+/// # Errors
+///
+/// `Error::Usage` if the input folder does not exist or has no subfolder with files, or if the
+/// output folder is not empty with `safe`, or missing without `create_output`. Other errors stop
+/// the run.
+///
+/// The empty subfolders are skipped with a warning.
+///
+/// # Example
+///
+/// ```no_run
+/// # use std::path::PathBuf;
+/// # use forum_rs::Config;
+/// let config = Config {
+///     input: PathBuf::from("main_folder"),
+///     output: PathBuf::from("output"),
+///     ..Default::default()
+/// };
+/// let exit = forum_rs::run(config)?;
+/// # Ok::<(), forum_rs::Error>(())
+/// ```
+pub fn run(config: config::Config) -> error::Result<error::Exit> {
+    run_with(config, None)
+}
+
+/// Load the regexes and the models of `models` into the [globals](../globals/index.html)
+pub(crate) fn init_models(models: &config::Models) -> error::Result<()> {
+    if !models.tokenizers.is_empty() && !cfg!(feature = "tokenizers") {
+        return Err(error::Error::Usage(
+            "Built without the `tokenizers` feature, the lengths can only be counted in words"
+                .to_string(),
+        ));
+    }
+
+    if let Some(label) = globals::duplicate_tokenizer_label(&models.tokenizers) {
+        return Err(error::Error::Usage(format!(
+            "Several tokenizers are labelled `{}`, use `label=name` to tell them apart",
+            label
+        )));
+    }
+
+    // Initialize regex
+
+    globals::init_regex();
+    if !models.tokenizers.is_empty() {
+        globals::init_tokenizers(&models.tokenizers, &models.hub)?;
+        globals::init_tokenizer_threads(models.tokenizer_parallelism, models.tokenizer_threads)?;
+        globals::init_histogram(&models.histogram_buckets)?;
+    }
+    if let Some(kenlm_model) = &models.kenlm_model {
+        globals::init_kenlm(kenlm_model)?;
+    }
+    Ok(())
+}
+
+/// Same as [run](fn.run.html), the records go to `sink` instead of the output files when set
+///
+/// Without the output files there is nothing to resume from, and the output folder is only needed
+/// for the histogram of the token lengths, it is not checked when empty.
+pub(crate) fn run_with(
+    config: config::Config,
+    sink: Option<Box<dyn utils::writer::OutputSink>>,
+) -> error::Result<error::Exit> {
+    let config = Arc::new(config);
+    let folder = utils::file::long_path(&config.input);
+    let out_folder = utils::file::long_path(&config.output);
+    init_models(&config.models)?;
+    if !folder.is_dir() {
+        return Err(error::Error::Usage(format!(
+            "The input folder `{}` does not exist",
+            config.input.display()
+        )));
+    }
+    let custom_sink = sink.is_some();
+    if config.dedup_index.is_some() && config.dedup == config::Dedup::None {
+        return Err(error::Error::Usage(
+            "--dedup-index needs --dedup exact or --dedup bloom".to_string(),
+        ));
+    }
+    if let Some(max_tokens) = config.max_tokens.filter(|&max_tokens| max_tokens > 0) {
+        if config.chunk_overlap >= max_tokens {
+            return Err(error::Error::Usage(format!(
+                "--chunk-overlap {} must be less than --max-tokens {}",
+                config.chunk_overlap, max_tokens
+            )));
+        }
+    }
+    if let Some(pair) = config
+        .perplexity_buckets
+        .windows(2)
+        .find(|pair| pair[0] >= pair[1])
+    {
+        return Err(error::Error::Usage(format!(
+            "--perplexity-buckets must be increasing, {} is not below {}",
+            pair[0], pair[1]
+        )));
+    }
+    if custom_sink && config.resume {
+        return Err(error::Error::Usage(
+            "A custom sink cannot resume a run".to_string(),
+        ));
+    }
+
+    let mut all_folders: Vec<PathBuf> = utils::file::all_folders(&folder)?;
+    all_folders.retain(|folder| {
+        let empty = utils::file::is_empty_folder(folder);
+        if empty {
+            tracing::warn!(folder = %folder.display(), "Skipped an empty folder");
+        }
+        !empty
+    });
+    if all_folders.is_empty() {
+        return Err(error::Error::Usage(format!(
+            "The input folder `{}` has no subfolder with files, expected `{}`",
+            config.input.display(),
+            config.input.join("<subfolder>").join("*.jsonl").display()
+        )));
+    }
+
+    // Nothing is created before the input is known to have work
+    // For safety, the output folder is only created if asked, and must be empty
+    // Resuming needs the output of the previous run
+    if custom_sink && out_folder.as_os_str().is_empty() {
+        // Nothing is written to the output folder
+    } else if !config.safe || config.resume {
+        fs::create_dir_all(&out_folder)?;
+        tracing::info!(output = %config.output.display(), "Output folder created")
+    } else if !out_folder.is_dir() {
+        if !config.create_output {
+            return Err(error::Error::Usage(format!(
+                "The output folder `{}` does not exist, create it or run with `--create-output true`",
+                config.output.display()
+            )));
+        }
+        fs::create_dir_all(&out_folder)?;
+        tracing::info!(output = %config.output.display(), "Output folder created")
+    } else {
+        let entries = fs::read_dir(&out_folder)?
+            .map(|res| res.map(|e| e.path()))
+            .collect::<Result<Vec<_>, std::io::Error>>()?;
+        if !entries.is_empty() {
+            return Err(error::Error::Usage(
+                "Output folder is not empty, you can run with `--safe false` to overwrite the files."
+                    .to_string(),
+            ));
+        }
+    }
+
+    if let Some(path) = &config.dedup_index {
+        let documents =
+            utils::dedup::load(path, &config).map_err(|source| error::Error::ReadIndex {
+                path: path.clone(),
+                source,
+            })?;
+        tracing::info!(documents, path = %path.display(), "Loaded the dedup index");
+    }
+
+    if let Some(provenance) = &config.provenance {
+        if config.provenance_target != config::ProvenanceTarget::Records
+            && !out_folder.as_os_str().is_empty()
+        {
+            let path = provenance.save(&out_folder)?;
+            tracing::info!(path = %path.display(), "Saved the provenance");
+        }
+    }
+
+    // Reorder the largest size first
+    // This should speed up the parallel processing
+    let mut all_folders = match config.largest_first {
+        true => utils::file::reorder_by_size(all_folders),
+        false => {
+            let mut all_folders = all_folders;
+            all_folders.sort();
+            all_folders
+        }
+    };
+    let output_path = out_folder.as_path();
+    let completed: HashSet<String> = match config.resume {
+        true => {
+            let state = utils::resume::load(output_path)?;
+            utils::resume::restore(output_path, &state, &config)?;
+            tracing::info!(completed = state.completed.len(), "Resuming");
+            state.completed
+        }
+        false if custom_sink => HashSet::new(),
+        false => {
+            utils::resume::clear(output_path)?;
+            HashSet::new()
+        }
+    };
+    all_folders.retain(|folder| !completed.contains(&utils::resume::folder_key(folder)));
+    let total_folders = all_folders.len();
+    let folder_sizes: Vec<u64> = all_folders
+        .iter()
+        .map(|folder| utils::file::folder_size(folder).unwrap_or(0))
+        .collect();
+    let total_bytes: u64 = folder_sizes.iter().sum();
+
+    // Before the par_iter loop:
+    let counter = Arc::new(AtomicUsize::new(0));
+    let bytes_done = Arc::new(AtomicU64::new(0));
+    let bytes_done_clone = bytes_done.clone();
+    let running = Arc::new(AtomicBool::new(true));
+    let running_clone = running.clone();
+    let counter_clone = counter.clone();
+    let start_time_clone = Instant::now();
+
+    let (data_tx, data_rx) = unbounded();
+    let data_rx_clone = data_rx.clone();
+    // The records of a custom sink are not serialized, they have a channel of their own
+    let (posts_tx, posts_rx) = unbounded();
+    let posts_rx_clone = posts_rx.clone();
+    let progress_format = config.progress;
+    // Carriage returns only make sense on a terminal, in a log file the bar is printed once at the end
+    let redraw = std::io::stdout().is_terminal();
+    // Spawn progress display thread
+    let progress_thread = std::thread::spawn(move || {
+        let snapshot = || {
+            let counters = utils::progress::Counters {
+                posts_read: utils::file::posts_read(),
+                bytes_read: utils::file::bytes_read(),
+                bytes_written: utils::writer::bytes_written(),
+            };
+            let done = utils::progress::Work {
+                folders: counter_clone.load(Ordering::SeqCst),
+                bytes: bytes_done_clone.load(Ordering::SeqCst),
+            };
+            let total = utils::progress::Work {
+                folders: total_folders,
+                bytes: total_bytes,
+            };
+            utils::progress::Progress::new(
+                done,
+                total,
+                data_rx_clone.len() + posts_rx_clone.len(),
+                counters,
+                start_time_clone.elapsed(),
+            )
+        };
+        let show = |progress: &utils::progress::Progress| match progress_format {
+            config::ProgressFormat::Bar => {
+                print!("{}{}", if redraw { "\r" } else { "" }, progress.line());
+                std::io::stdout().flush().unwrap();
+            }
+            config::ProgressFormat::Json => {
+                eprintln!("{}", serde_json::to_string(&progress).unwrap());
+            }
+            config::ProgressFormat::None => {}
+        };
+        let interval = match progress_format {
+            config::ProgressFormat::Bar if !redraw => None,
+            config::ProgressFormat::Bar => Some(utils::progress::BAR_INTERVAL),
+            config::ProgressFormat::Json => Some(utils::progress::JSON_INTERVAL),
+            config::ProgressFormat::None => None,
+        };
+        let mut last_update: Option<Instant> = None;
+        let mut previous: Option<utils::progress::Progress> = None;
+        while running_clone.load(Ordering::SeqCst) {
+            let Some(interval) = interval else {
+                std::thread::sleep(Duration::from_millis(100));
+                continue;
+            };
+            if last_update.is_none_or(|last| last.elapsed() >= interval) {
+                let progress = snapshot().with_rates(previous.as_ref());
+                show(&progress);
+                previous = Some(progress);
+                last_update = Some(Instant::now());
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        // One final update after completion, with the rates of the whole run
+        show(&snapshot().with_rates(None));
+        if progress_format == config::ProgressFormat::Bar {
+            println!();
+        }
+    });
+    let writer_folder = out_folder.clone();
+    // The writer has its own thread, on the pool it would take a worker away from the folders
+    let resume = config.resume;
+    let write_buffer = config.write_buffer;
+    if config.rejects {
+        utils::rejects::init(data_tx.clone());
+    }
+    let writer_thread = std::thread::spawn(move || {
+        let written = match sink {
+            Some(mut sink) => utils::writer::write_sink_receiver(
+                posts_rx,
+                data_rx,
+                sink.as_mut(),
+                write_buffer.flush_interval,
+            ),
+            None => {
+                utils::writer::write_routed_receiver(data_rx, writer_folder, resume, write_buffer)
+            }
+        };
+        if let Err(e) = &written {
+            tracing::error!(error = %e, "Error writing JSONL");
+        }
+        written
+    });
+    utils::shutdown::install();
+    let mut outcomes: Vec<FolderOutcome> = Vec::new();
+    if config.cross_folder && completed.contains(&utils::resume::folder_key(&folder)) {
+        tracing::info!("All folders were completed by the previous run");
+    } else if config.cross_folder {
+        // A thread may span several folders, so all the files are read into the same threads
+        let mut entries: Vec<PathBuf> = Vec::new();
+        for folder in &all_folders {
+            match utils::file::folder_files(folder) {
+                Ok(files) => entries.extend(files),
+                Err(e) => error::report(e),
+            }
+        }
+        outcomes.push(isolate(&folder, || match custom_sink {
+            true => process_files_with(&entries, &folder, &config, |route, threadpost| {
+                send_post(&posts_tx, route, threadpost)
+            }),
+            false => process_files(&entries, &folder, &config, data_tx.clone()),
+        }));
+        counter.store(total_folders, Ordering::SeqCst);
+        bytes_done.store(total_bytes, Ordering::SeqCst);
+    } else if file_schedule(&config) {
+        let finished_outcomes = Mutex::new(Vec::new());
+        let finished = |finished: experimental::scheduled::Finished| {
+            let _span =
+                tracing::info_span!("folder", folder = %finished.folder.display()).entered();
+            stage_finished("get_threads", finished.get_threads);
+            stage_finished("create_posts", finished.create_posts);
+            counter.fetch_add(1, Ordering::SeqCst);
+            bytes_done.fetch_add(finished.size, Ordering::SeqCst);
+            finished_outcomes
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(FolderOutcome {
+                    folder: finished.folder.to_path_buf(),
+                    elapsed: finished.elapsed,
+                    failed: finished.failed,
+                });
+        };
+        match custom_sink {
+            true => experimental::scheduled::process_folders(
+                &all_folders,
+                &folder_sizes,
+                &config,
+                |route, threadpost| send_post(&posts_tx, route, threadpost),
+                finished,
+            ),
+            false => {
+                let batcher = utils::writer::Batcher::new(data_tx.clone(), config.channel_batch);
+                experimental::scheduled::process_folders(
+                    &all_folders,
+                    &folder_sizes,
+                    &config,
+                    |route, threadpost| forum_thread::send_threadpost(&batcher, route, threadpost),
+                    |done| {
+                        batcher.flush();
+                        finished(done)
+                    },
+                )
+            }
+        }
+        outcomes = finished_outcomes
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+    } else {
+        // The dispatchers wait on their folder, they get a pool of their own
+        let timeout_pool = match config.folder_timeout {
+            Some(_) => Some(
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(rayon::current_num_threads())
+                    .build()
+                    .map_err(std::io::Error::other)?,
+            ),
+            None => None,
+        };
+        let finished = Mutex::new(Vec::new());
+        {
+            let (config, data_tx, posts_tx) = (&config, &data_tx, &posts_tx);
+            let (counter, bytes_done, done) = (&counter, &bytes_done, &finished);
+            // The memory is reserved by the calling thread, a worker waiting for it could be the
+            // one that holds it, in a folder it stole the task from
+            scope_fifo(timeout_pool.as_ref(), |scope| {
+                for (index, (folder, &size)) in all_folders.iter().zip(&folder_sizes).enumerate() {
+                    if utils::shutdown::requested() {
+                        break;
+                    }
+                    let reservation = utils::memory::IN_FLIGHT.reserve(size, config.max_memory);
+                    if utils::shutdown::requested() {
+                        break;
+                    }
+                    scope.spawn_fifo(move |_| {
+                        let _reservation = reservation;
+                        let outcome =
+                            isolate(folder, || match (custom_sink, config.folder_timeout) {
+                                (false, Some(timeout)) => {
+                                    process_folder_within(folder, config, data_tx, timeout)
+                                }
+                                (false, None) => process_folder(folder, config, data_tx.clone()),
+                                (true, Some(timeout)) => {
+                                    let process = {
+                                        let folder = folder.clone();
+                                        let config = config.clone();
+                                        move |folder_tx| {
+                                            process_folder_with(
+                                                &folder,
+                                                &config,
+                                                |route, threadpost| {
+                                                    send_post(&folder_tx, route, threadpost)
+                                                },
+                                            )
+                                        }
+                                    };
+                                    forward_within(
+                                        folder,
+                                        posts_tx,
+                                        timeout,
+                                        process,
+                                        |(_, post)| post.raw_content.len() as u64,
+                                    )
+                                }
+                                (true, None) => {
+                                    process_folder_with(folder, config, |route, threadpost| {
+                                        send_post(posts_tx, route, threadpost)
+                                    })
+                                }
+                            });
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        bytes_done.fetch_add(size, Ordering::SeqCst);
+                        done.lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .push((index, outcome));
+                    });
+                }
+            });
+        }
+        let mut finished = finished
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+        finished.sort_by_key(|(index, _)| *index);
+        outcomes = finished.into_iter().map(|(_, outcome)| outcome).collect();
+    }
+    let interrupted = utils::shutdown::requested();
+    if interrupted {
+        tracing::warn!(
+            target: "summary",
+            not_started = total_folders - outcomes.len(),
+            "Stopped, run again with `--resume true` to process the folders that were not started"
+        );
+        let failed = outcomes.iter().any(|outcome| outcome.failed);
+        if !config.resume && !config.cross_folder && failed {
+            // The records a failed folder sent before failing are mixed with the others, there is
+            // no offset where only complete folders are written
+            tracing::warn!(
+                target: "summary",
+                "A folder failed, no folder is recorded as completed and `--resume true` starts over"
+            );
+        } else if !config.resume && !config.cross_folder {
+            // Every folder that started has finished, so its records are ahead of the checkpoints
+            for outcome in outcomes.iter().filter(|outcome| !outcome.failed) {
+                let key = utils::resume::folder_key(&outcome.folder);
+                data_tx
+                    .send(utils::writer::single(
+                        utils::writer::Route::Checkpoint,
+                        &key,
+                    ))
+                    .unwrap();
+            }
+        }
+    }
+    drop(data_tx);
+    drop(posts_tx);
+    utils::rejects::close();
+    if let Some(buckets) = &config.buckets {
+        // Every folder removed its own buckets, only the empty parent is left
+        let _ = fs::remove_dir(&buckets.dir);
+    }
+    // Wait for the receiver to finish
+    let written = writer_thread.join().unwrap();
+    tracing::info!("Completed processing all folders");
+
+    // After the loop completes, stop the progress thread
+    running.store(false, Ordering::SeqCst);
+    progress_thread.join().unwrap();
+
+    if progress_format == config::ProgressFormat::Bar {
+        println!();
+    }
+    // The busy and CPU times are summed over the workers, the wall time is while one was busy
+    for time in utils::timing::report() {
+        tracing::info!(
+            target: "summary",
+            stage = time.stage,
+            wall_ms = time.wall_ms,
+            busy_ms = time.busy_ms,
+            cpu_ms = time.cpu_ms,
+            "Total time taken"
+        );
+    }
+    let cycles = experimental::sender::cycles_broken();
+    if cycles > 0 {
+        tracing::info!(target: "summary", cycles, "Reply cycles found and broken");
+    }
+    let left_out = experimental::traversal::date_filtered();
+    if left_out > 0 {
+        let scope = config.traversal.dates.scope;
+        tracing::info!(target: "summary", left_out, ?scope, "Left out by the date range");
+    }
+    let left_out = experimental::traversal::score_filtered();
+    if left_out > 0 {
+        let scope = config.traversal.scores.scope;
+        tracing::info!(target: "summary", left_out, ?scope, "Left out by the minimum score");
+    }
+    let left_out = utils::links::reposts();
+    if left_out > 0 {
+        tracing::info!(target: "summary", left_out, "Documents left out as reposts of a link");
+    }
+    for (rule, lines) in utils::quality::lines_removed() {
+        tracing::info!(target: "summary", rule, lines, "Lines removed by a quality rule");
+    }
+    if let Some((lines, characters, share)) = utils::quality::repeats() {
+        let percent = format!("{:.2}", share * 100.0);
+        tracing::info!(
+            target: "summary",
+            lines,
+            characters,
+            percent,
+            "Repeated lines removed, with the percent of the characters of the documents"
+        );
+    }
+    let flagged = utils::blocklist::flagged();
+    if flagged > 0 {
+        tracing::info!(target: "summary", flagged, "Documents flagged with a term of the blocklist");
+    }
+    for (rule, documents) in utils::quality::dropped() {
+        tracing::info!(target: "summary", rule, documents, "Documents dropped by a quality rule");
+    }
+    for (folder, posts) in experimental::sender::root_mismatches() {
+        tracing::warn!(
+            target: "summary",
+            folder = %folder.display(),
+            posts,
+            "Posts with a root_post_id different from the root of their thread"
+        );
+    }
+
+    if let Some(bloom) = utils::dedup::bloom() {
+        tracing::info!(
+            target: "summary",
+            documents = bloom.inserted(),
+            capacity = bloom.capacity(),
+            false_positive_rate = bloom.false_positive_rate(),
+            "Documents in the Bloom filter of --dedup"
+        );
+        if bloom.inserted() > bloom.capacity() {
+            tracing::warn!(
+                target: "summary",
+                "The Bloom filter is over its capacity, raise --bloom-memory-mb to drop fewer new documents"
+            );
+        }
+    }
+
+    for (folder, posts) in experimental::sender::duplicate_ids() {
+        tracing::warn!(
+            target: "summary",
+            folder = %folder.display(),
+            posts,
+            "Posts with an id that was already read"
+        );
+    }
+
+    for (reason, count) in utils::rejects::counts() {
+        tracing::info!(target: "summary", reason, count, "Rejected records");
+    }
+
+    print_outcomes(&outcomes);
+
+    let failures = error::take_failures();
+    let exit = match (interrupted, failures.is_empty()) {
+        (true, _) => error::Exit::Interrupted,
+        (false, true) => error::Exit::Success,
+        (false, false) => error::Exit::Partial,
+    };
+    if !failures.is_empty() {
+        tracing::warn!(target: "summary", count = failures.len(), "Files or folders were skipped");
+        for failure in failures {
+            tracing::warn!(target: "summary", error = %failure, "Skipped");
+        }
+    }
+
+    if let Some(histogram) = globals::histogram() {
+        println!("Token lengths of the written documents:");
+        print!("{}", histogram);
+        if !out_folder.as_os_str().is_empty() {
+            let histogram_path = out_folder.join("token_lengths.json");
+            histogram.save(&histogram_path)?;
+            tracing::info!(target: "summary", path = %histogram_path.display(), "Saved the histogram");
+        }
+    }
+
+    if let Some(path) = &config.dedup_index {
+        let documents =
+            utils::dedup::save(path, &config).map_err(|source| error::Error::Write {
+                path: path.clone(),
+                source,
+            })?;
+        tracing::info!(target: "summary", documents, path = %path.display(), "Saved the dedup index");
+    }
+
+    written.map_err(|source| error::Error::Write {
+        path: out_folder,
+        source,
+    })?;
+    Ok(exit)
+}
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use pretty_assertions::assert_eq;
+    #[test]
+    fn test_path() {
+        let initial_path = Path::new("forum_folder/output/something.jsonl");
+        let folder = initial_path.parent().unwrap();
+        let stem = initial_path.file_stem().unwrap().to_string_lossy();
+        let extension = initial_path.extension().unwrap().to_string_lossy();
+        let new_file = folder.join(format!("{}_new.{}", stem, extension));
+        assert_eq!(
+            new_file,
+            Path::new("forum_folder")
+                .join("output")
+                .join("something_new.jsonl")
+        );
+    }
+
+    #[test]
+    fn test_run_usage() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let input = temp_dir.path().join("input");
+        fs::create_dir_all(&input).unwrap();
+        let config = config::Config {
+            input: input.clone(),
+            output: temp_dir.path().join("output"),
+            progress: config::ProgressFormat::None,
+            ..Default::default()
+        };
+        let usage = |config: &config::Config| match run(config.clone()) {
+            Err(error::Error::Usage(message)) => message,
+            other => panic!("expected a usage error, got {:?}", other),
+        };
+        let overlap = config::Config {
+            max_tokens: Some(4),
+            chunk_overlap: 4,
+            ..config.clone()
+        };
+        assert_eq!(
+            usage(&overlap),
+            "--chunk-overlap 4 must be less than --max-tokens 4"
+        );
+        let buckets = config::Config {
+            perplexity_buckets: vec![500.0, 100.0],
+            ..config.clone()
+        };
+        assert_eq!(
+            usage(&buckets),
+            "--perplexity-buckets must be increasing, 500 is not below 100"
+        );
+
+        // The input has no subfolder yet, the output is not created for a run that cannot start
+        let create = config::Config {
+            create_output: true,
+            ..config.clone()
+        };
+        assert!(usage(&create).contains("no subfolder"));
+        assert!(!config.output.exists());
+
+        // The empty subfolders are skipped
+        fs::create_dir_all(input.join("empty")).unwrap();
+        assert!(usage(&create).contains("no subfolder"));
+        assert!(!config.output.exists());
+
+        // The output folder is only created if asked
+        fs::create_dir_all(input.join("sub1")).unwrap();
+        fs::write(input.join("sub1").join("posts.jsonl"), "").unwrap();
+        assert!(usage(&config).contains("does not exist"));
+    }
+
+    // TODO: Add the test for this integration test
+    #[test]
+    #[ignore]
+    fn test_threads_integration() {
+        // this needs to have a folder with jsonl files
+        let folder = "test_data/";
+        // Skip test if file is not found
+        if !Path::new(folder).exists() {
+            return;
+        }
+        globals::init_regex();
+        let folder = String::from(folder);
+        let threads: Vec<(String, Vec<String>)> = experimental::parallel::get_threads(&folder);
+        let previous_implementation = experimental::parallel::_get_threads(&folder);
+        let sender_threads: Vec<forum_thread::Thread> =
+            experimental::sender::get_threads(&folder, &config::Config::default()).unwrap();
+
+        assert_eq!(threads.len(), 42);
+        assert_eq!(previous_implementation.len(), 42);
+        assert_eq!(sender_threads.len(), 42);
+
+        // check if roots are same
+        let mut sender_roots: HashSet<String> = HashSet::new();
+        let mut parallel_roots: HashSet<String> = HashSet::new();
+
+        for (root, _) in threads.iter() {
+            sender_roots.insert(root.clone());
+        }
+
+        for (root, _) in previous_implementation.iter() {
+            parallel_roots.insert(root.clone());
+        }
+
+        assert_eq!(sender_roots, parallel_roots);
+    }
+}
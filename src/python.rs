@@ -0,0 +1,131 @@
+//! The `forum_rs` Python module, with the `python` feature
+//!
+//! Built with [maturin](https://www.maturin.rs/) from the root of the repository:
+//!
+//! ```bash
+//! maturin develop --release
+//! python -c "import forum_rs; print(forum_rs.clean_text('hello--world'))"
+//! ```
+//!
+//! The functions return plain lists and dicts, the records have the same keys as the lines of the
+//! output files. The work runs without the GIL.
+
+use std::path::Path;
+
+use crossbeam_channel::unbounded;
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+
+use crate::config::Config;
+use crate::error::Error;
+use crate::forum_thread::Post;
+use crate::utils::writer::{self, Route};
+use crate::{globals, pipeline, ThreadIter};
+
+impl From<Error> for PyErr {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Usage(message) => PyValueError::new_err(message),
+            error => PyIOError::new_err(error.to_string()),
+        }
+    }
+}
+
+/// Clean the text of a post the way the pipeline does
+#[pyfunction]
+fn clean_text(py: Python<'_>, text: &str) -> String {
+    py.detach(|| {
+        globals::init_regex();
+        globals::clean_content(text).trim().to_string()
+    })
+}
+
+/// The threads of a folder as `{"root_id": ..., "posts": [...]}`, with the posts in traversal order
+#[pyfunction]
+fn reconstruct_threads<'py>(py: Python<'py>, path: &str) -> PyResult<Bound<'py, PyList>> {
+    let threads: Vec<(String, Vec<Post>)> =
+        py.detach(|| ThreadIter::new(Path::new(path), &Config::default()).map(Iterator::collect))?;
+    let list = PyList::empty(py);
+    for (root_id, posts) in threads {
+        let thread = PyDict::new(py);
+        thread.set_item("root_id", root_id)?;
+        let posts = posts
+            .into_iter()
+            .map(|post| post_dict(py, post))
+            .collect::<PyResult<Vec<_>>>()?;
+        thread.set_item("posts", posts)?;
+        list.append(thread)?;
+    }
+    Ok(list)
+}
+
+/// Build the records of a folder, as they would be written to the output files
+///
+/// `tokenizer` is a huggingface name or a `tokenizer.json` path, the lengths are words without it.
+/// The tokenizer is loaded once, the first one given is kept for the life of the process.
+#[pyfunction]
+#[pyo3(signature = (path, source = String::new(), tokenizer = None))]
+fn process_folder<'py>(
+    py: Python<'py>,
+    path: &str,
+    source: String,
+    tokenizer: Option<String>,
+) -> PyResult<Bound<'py, PyList>> {
+    let records: Vec<String> = py.detach(|| {
+        globals::init_regex();
+        if let Some(tokenizer) = &tokenizer {
+            if globals::tokenizer_labels().is_empty() {
+                globals::init_tokenizers(
+                    std::slice::from_ref(tokenizer),
+                    &globals::HubOptions::default(),
+                )?;
+            }
+        }
+        let config = Config {
+            source,
+            use_sentencepiece: tokenizer.is_some(),
+            ..Default::default()
+        };
+        let (records_tx, records_rx) = unbounded();
+        pipeline::process_folder(Path::new(path), &config, records_tx)?;
+        let records = records_rx
+            .into_iter()
+            .filter(|(route, _)| !matches!(route, Route::Checkpoint | Route::Rejects(_)))
+            .flat_map(|(_, batch)| {
+                crate::utils::memory::IN_FLIGHT.sub(batch.len() as u64);
+                writer::records(&batch)
+                    .map(str::to_string)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        Ok::<_, Error>(records)
+    })?;
+    let loads = py.import("json")?.getattr("loads")?;
+    let list = PyList::empty(py);
+    for record in records {
+        list.append(loads.call1((record,))?)?;
+    }
+    Ok(list)
+}
+
+fn post_dict(py: Python<'_>, post: Post) -> PyResult<Bound<'_, PyDict>> {
+    let dict = PyDict::new(py);
+    dict.set_item("id", post.id.as_str())?;
+    dict.set_item("is_thread", post.is_thread)?;
+    dict.set_item("pagetext", post.pagetext)?;
+    dict.set_item("parent_post_id", post.parent_post_id.as_str())?;
+    dict.set_item("root_post_id", post.root_post_id.as_str())?;
+    dict.set_item("created_utc", post.created_utc)?;
+    dict.set_item("score", post.score)?;
+    dict.set_item("author", post.author)?;
+    Ok(dict)
+}
+
+#[pymodule]
+fn forum_rs(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_function(wrap_pyfunction!(clean_text, module)?)?;
+    module.add_function(wrap_pyfunction!(reconstruct_threads, module)?)?;
+    module.add_function(wrap_pyfunction!(process_folder, module)?)?;
+    Ok(())
+}
@@ -0,0 +1,141 @@
+//! The records of an input folder as a tokio `Stream`, with the `stream` feature
+//!
+//! The folders are processed on a thread of their own, one after the other, with the workers of
+//! the global pool for the stages of a folder, as in [run](../pipeline/fn.run.html). The records
+//! go through a bounded channel: when the consumer falls behind, the workers wait for room instead
+//! of piling up records in memory.
+//!
+//! ```ignore
+//! use futures::StreamExt;
+//!
+//! let config = Config {
+//!     input: PathBuf::from("main_folder"),
+//!     ..Default::default()
+//! };
+//! let mut records = forum_rs::stream::stream(config, 1024)?;
+//! while let Some(record) = records.next().await {
+//!     println!("{} {}", record.thread_id, record.length);
+//! }
+//! ```
+
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use tokio::sync::mpsc;
+
+use crate::config::Config;
+use crate::error;
+use crate::pipeline;
+use crate::utils;
+use crate::utils::writer::{Route, ThreadPost};
+
+/// The records of a run, built by [stream](fn.stream.html)
+///
+/// Dropping the stream stops the run after the folder in progress.
+pub struct ThreadPostStream {
+    receiver: mpsc::Receiver<ThreadPost>,
+}
+
+impl Stream for ThreadPostStream {
+    type Item = ThreadPost;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ThreadPost>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// Process every folder of `config.input` and stream the records instead of writing them
+///
+/// At most `capacity` records wait for the consumer. The records are the ones that would be
+/// written to the output files, the rejected ones are left out. A folder that fails is reported
+/// and skipped, as with [run](../pipeline/fn.run.html). The models are loaded into the
+/// [globals](../globals/index.html) as for a run, the same ones are reused by the next streams.
+///
+/// `config.output`, `resume` and `cross_folder` are not used, every folder is processed on its
+/// own and nothing is written.
+///
+/// # Errors
+///
+/// `Error::Usage` if the input folder does not exist or the models cannot be used, an IO error if
+/// it cannot be read.
+pub fn stream(config: Config, capacity: usize) -> error::Result<ThreadPostStream> {
+    pipeline::init_models(&config.models)?;
+    if !config.input.is_dir() {
+        return Err(error::Error::Usage(format!(
+            "The input folder `{}` does not exist",
+            config.input.display()
+        )));
+    }
+    let mut folders: Vec<PathBuf> = utils::file::all_folders(&config.input)?;
+    folders = match config.largest_first {
+        true => utils::file::reorder_by_size(folders),
+        false => {
+            folders.sort();
+            folders
+        }
+    };
+    let (sender, receiver) = mpsc::channel(capacity.max(1));
+    std::thread::spawn(move || {
+        let closed = AtomicBool::new(false);
+        for folder in &folders {
+            if closed.load(Ordering::SeqCst) {
+                break;
+            }
+            let send = |_: Route, threadpost: ThreadPost| {
+                if sender.blocking_send(threadpost).is_err() {
+                    closed.store(true, Ordering::SeqCst);
+                }
+            };
+            let processed = error::catch_panic(folder, || {
+                pipeline::process_folder_with(folder, &config, send)
+            });
+            if let Err(e) = processed {
+                error::report(e);
+            }
+        }
+    });
+    Ok(ThreadPostStream { receiver })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forum_thread::input_line;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_stream() {
+        let temp_dir = TempDir::new().unwrap();
+        let line = |id: &str, parent: &str| input_line(id, parent, parent, &format!("post {id}"));
+        for (folder, lines) in [
+            ("a", [line("1", "1"), line("2", "1")]),
+            ("b", [line("3", "3"), line("4", "3")]),
+        ] {
+            let folder = temp_dir.path().join(folder);
+            fs::create_dir_all(&folder).unwrap();
+            fs::write(folder.join("posts.jsonl"), lines.join("\n")).unwrap();
+        }
+        let config = Config {
+            input: temp_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        // A capacity of one keeps the folders waiting on the consumer
+        let mut records = stream(config, 1).unwrap();
+        let mut thread_ids = Vec::new();
+        while let Some(record) = records.receiver.blocking_recv() {
+            thread_ids.push(record.thread_id);
+        }
+        assert_eq!(thread_ids, vec!["1", "3"]);
+
+        let config = Config {
+            input: temp_dir.path().join("missing"),
+            ..Default::default()
+        };
+        assert!(matches!(stream(config, 1), Err(error::Error::Usage(_))));
+    }
+}
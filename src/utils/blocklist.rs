@@ -0,0 +1,286 @@
+//! Documents with a prohibited term, dropped or flagged with `--blocklist`, and documents without
+//! a required pattern, dropped with `--require-pattern`
+//!
+//! A blocklist file has a term per line, the blank lines and the lines starting with `#` are
+//! skipped. A line starting with `re:` is a regular expression, the others are matched as they
+//! are, as whole words, without regard to the case of ASCII letters. The terms are searched in a
+//! single pass with an Aho-Corasick automaton and the regular expressions with a `RegexSet`, both
+//! built once and shared by all the workers.
+//!
+//! The required patterns are regular expressions, also case insensitive, a document is kept when
+//! it matches any of them. They are compiled one at a time when the arguments are parsed, so a
+//! long list cannot go over the size limit of a single set. It is checked before the blocklist.
+//!
+//! The terms and patterns are looked for in the text as it would be written, after the cleaning
+//! and the line rules of [quality](../quality/index.html).
+
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use aho_corasick::AhoCorasick;
+use regex::{Regex, RegexBuilder, RegexSet, RegexSetBuilder};
+
+use crate::config::{BlocklistAction, Config};
+use crate::utils::rejects;
+use crate::utils::writer::ThreadPost;
+
+/// Prefix of the regular expressions of a blocklist file
+const REGEX_PREFIX: &str = "re:";
+
+/// Documents flagged so far
+static FLAGGED: AtomicU64 = AtomicU64::new(0);
+
+/// The terms and regular expressions of a blocklist file
+#[derive(Clone)]
+pub struct Blocklist {
+    terms: Vec<String>,
+    /// The lines of the regular expressions, with their prefix
+    regexes: Vec<String>,
+    automaton: AhoCorasick,
+    set: RegexSet,
+}
+
+impl Blocklist {
+    /// Build a blocklist from the lines of a file
+    ///
+    /// # Errors
+    ///
+    /// Returns the first regular expression that does not compile.
+    pub fn new(lines: &str) -> Result<Blocklist, String> {
+        let (regexes, terms): (Vec<String>, Vec<String>) = lines
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .partition(|line| line.starts_with(REGEX_PREFIX));
+        let automaton = AhoCorasick::builder()
+            .ascii_case_insensitive(true)
+            .build(&terms)
+            .map_err(|e| e.to_string())?;
+        let set = RegexSetBuilder::new(regexes.iter().map(|line| &line[REGEX_PREFIX.len()..]))
+            .case_insensitive(true)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Blocklist {
+            terms,
+            regexes,
+            automaton,
+            set,
+        })
+    }
+
+    /// Read a blocklist file
+    pub fn from_file(path: &Path) -> Result<Blocklist, String> {
+        let lines = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read the blocklist {}: {}", path.display(), e))?;
+        Blocklist::new(&lines).map_err(|e| format!("Invalid blocklist {}: {}", path.display(), e))
+    }
+
+    /// The terms and regular expressions found in `text`, as they are in the file
+    pub fn matches(&self, text: &str) -> Vec<String> {
+        let mut found = vec![false; self.terms.len()];
+        for term in self.automaton.find_overlapping_iter(text) {
+            let pattern = term.pattern().as_usize();
+            if !found[pattern] && is_word(text, term.start(), term.end()) {
+                found[pattern] = true;
+            }
+        }
+        let terms = found
+            .iter()
+            .zip(&self.terms)
+            .filter(|(found, _)| **found)
+            .map(|(_, term)| term.clone());
+        let regexes = self
+            .set
+            .matches(text)
+            .into_iter()
+            .map(|regex| self.regexes[regex].clone());
+        terms.chain(regexes).collect()
+    }
+}
+
+/// Whether the match at `start..end` is not part of a longer word
+///
+/// A term that starts or ends with a symbol can be next to anything on that side.
+fn is_word(text: &str, start: usize, end: usize) -> bool {
+    let alphanumeric = |c: Option<char>| c.is_some_and(char::is_alphanumeric);
+    let term = &text[start..end];
+    let before =
+        alphanumeric(term.chars().next()) && alphanumeric(text[..start].chars().next_back());
+    let after = alphanumeric(term.chars().next_back()) && alphanumeric(text[end..].chars().next());
+    !before && !after
+}
+
+impl std::fmt::Debug for Blocklist {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Blocklist")
+            .field("terms", &self.terms)
+            .field("regexes", &self.regexes)
+            .finish()
+    }
+}
+
+impl PartialEq for Blocklist {
+    fn eq(&self, other: &Self) -> bool {
+        self.terms == other.terms && self.regexes == other.regexes
+    }
+}
+
+/// The regular expressions of `--require-pattern`
+#[derive(Clone)]
+pub struct Allowlist {
+    regexes: Vec<Regex>,
+}
+
+impl Allowlist {
+    /// # Errors
+    ///
+    /// Returns the first pattern that does not compile.
+    pub fn new(patterns: &[String]) -> Result<Allowlist, String> {
+        let regexes = patterns
+            .iter()
+            .map(|pattern| required_pattern(pattern))
+            .collect::<Result<_, _>>()?;
+        Ok(Allowlist { regexes })
+    }
+
+    /// Whether `text` matches one of the patterns
+    pub fn is_match(&self, text: &str) -> bool {
+        self.regexes.iter().any(|regex| regex.is_match(text))
+    }
+}
+
+/// The patterns compiled by [required_pattern](fn.required_pattern.html)
+impl From<Vec<Regex>> for Allowlist {
+    fn from(regexes: Vec<Regex>) -> Self {
+        Allowlist { regexes }
+    }
+}
+
+/// Compile a pattern of `--require-pattern`, without regard to case
+pub fn required_pattern(pattern: &str) -> Result<Regex, String> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+impl std::fmt::Debug for Allowlist {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let patterns: Vec<&str> = self.regexes.iter().map(Regex::as_str).collect();
+        f.debug_tuple("Allowlist").field(&patterns).finish()
+    }
+}
+
+impl PartialEq for Allowlist {
+    fn eq(&self, other: &Self) -> bool {
+        self.regexes
+            .iter()
+            .map(Regex::as_str)
+            .eq(other.regexes.iter().map(Regex::as_str))
+    }
+}
+
+/// The document if it has a required pattern, flagged when it has a term of the blocklist of
+/// `config`, `None` if it is dropped
+///
+/// A document without a required pattern is a reject with the reason `missing_pattern`. A document
+/// dropped by the blocklist is a reject with the reason `blocklist` and the terms found as the
+/// error.
+pub fn check(mut threadpost: ThreadPost, folder: &Path, config: &Config) -> Option<ThreadPost> {
+    if let Some(required) = &config.quality.required {
+        if !required.is_match(&threadpost.raw_content) {
+            rejects::filtered(folder, rejects::Reason::MissingPattern, &threadpost);
+            return None;
+        }
+    }
+    let Some(blocklist) = &config.quality.blocklist else {
+        return Some(threadpost);
+    };
+    let terms = blocklist.matches(&threadpost.raw_content);
+    if terms.is_empty() {
+        return Some(threadpost);
+    }
+    match config.quality.blocklist_action {
+        BlocklistAction::Drop => {
+            rejects::blocklisted(folder, &terms, &threadpost);
+            None
+        }
+        BlocklistAction::Flag => {
+            FLAGGED.fetch_add(1, Ordering::Relaxed);
+            threadpost.blocklist = Some(terms);
+            Some(threadpost)
+        }
+    }
+}
+
+/// Number of documents flagged with `BlocklistAction::Flag`
+pub fn flagged() -> u64 {
+    FLAGGED.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_matches() {
+        let blocklist = Blocklist::new(
+            "# spam\n\nfoo\nBar Baz\n$$$\n  re:buy\\s+\\w+\\s+now  \nre:\\d{3}-\\d{4}\n",
+        )
+        .unwrap();
+        assert_eq!(blocklist.terms, vec!["foo", "Bar Baz", "$$$"]);
+        assert_eq!(
+            blocklist.matches("nothing to see here"),
+            Vec::<String>::new()
+        );
+        // Whole words only, the case of ASCII letters does not matter
+        assert_eq!(blocklist.matches("food and foobar"), Vec::<String>::new());
+        assert_eq!(
+            blocklist.matches("FOO! then bar baz, and foo again"),
+            vec!["foo", "Bar Baz"]
+        );
+        assert_eq!(blocklist.matches("win100$$$"), vec!["$$$"]);
+        assert_eq!(
+            blocklist.matches("Buy pills NOW, call 555-1234"),
+            vec!["re:buy\\s+\\w+\\s+now", "re:\\d{3}-\\d{4}"]
+        );
+        assert!(Blocklist::new("re:(unclosed").is_err());
+
+        let patterns = [
+            "rust(acean)?".to_string(),
+            r"\bborrow checker\b".to_string(),
+        ];
+        let required = Allowlist::new(&patterns).unwrap();
+        assert!(required.is_match("Fighting the Borrow Checker again"));
+        assert!(required.is_match("a fellow Rustacean"));
+        assert!(!required.is_match("python and go"));
+        assert!(Allowlist::new(&["(".to_string()]).is_err());
+        assert_eq!(
+            Blocklist::new("").unwrap().matches("anything"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let mut config = Config::default();
+        config.quality.required = Some(Allowlist::new(&["rust".to_string()]).unwrap());
+        let threadpost = |text: &str| ThreadPost {
+            raw_content: text.to_string(),
+            ..Default::default()
+        };
+        let missing = || {
+            rejects::counts()
+                .into_iter()
+                .find(|(reason, _)| *reason == "missing_pattern")
+                .map_or(0, |(_, count)| count)
+        };
+        let folder = Path::new("sub1");
+        assert!(check(threadpost("Rust at work"), folder, &config).is_some());
+        let before = missing();
+        assert!(check(threadpost("python at work"), folder, &config).is_none());
+        assert!(missing() > before);
+    }
+}
@@ -0,0 +1,377 @@
+//! The documents already written in the run, to drop their copies with `--dedup`
+//!
+//! A document is known by a 128 bit hash of its cleaned text, before it is cut with
+//! `--max-tokens`. With `Dedup::Exact` the hashes are kept in a set shared by all the folders,
+//! split in shards behind their own lock so the workers rarely wait for each other. With
+//! `Dedup::Bloom` they go into a [Bloom](struct.Bloom.html) filter of a fixed size instead, a few
+//! documents that were never written are then taken for copies.
+//!
+//! The hashes can be [saved](fn.save.html) at the end of a run and [loaded](fn.load.html) at the
+//! start of the next one, so the documents of the previous outputs are not written again.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock, PoisonError};
+
+use rustc_hash::FxBuildHasher;
+
+use crate::config::{BloomOptions, Config, Dedup};
+
+/// Number of locks the set is split in
+const SHARDS: usize = 64;
+/// Most bits set per document, more only slow the filter down
+const MAX_HASHES: u32 = 16;
+/// Start of a dedup index file, followed by the version and the kind of index
+const INDEX_MAGIC: &[u8; 8] = b"FRSDEDUP";
+const INDEX_VERSION: u8 = 1;
+
+/// The hashes are already uniform, they are not hashed again
+///
+/// A shard poisoned by a panicked folder is still used, a hash is inserted or not at all.
+static SEEN: [Mutex<HashSet<u128, FxBuildHasher>>; SHARDS] =
+    [const { Mutex::new(HashSet::with_hasher(FxBuildHasher)) }; SHARDS];
+
+/// Built on the first document with the options of its config
+static BLOOM: OnceLock<Bloom> = OnceLock::new();
+
+/// The hash a text is known by
+pub fn text_hash(text: &str) -> u128 {
+    let hash = blake3::hash(text.as_bytes());
+    u128::from_le_bytes(hash.as_bytes()[..16].try_into().unwrap())
+}
+
+/// Whether `text` is written for the first time in the run, it is remembered if so
+///
+/// Always true with `Dedup::None`.
+pub fn first_seen(text: &str, config: &Config) -> bool {
+    match config.dedup {
+        Dedup::None => true,
+        Dedup::Exact => {
+            let hash = text_hash(text);
+            SEEN[hash as usize % SHARDS]
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(hash)
+        }
+        Dedup::Bloom => BLOOM
+            .get_or_init(|| Bloom::new(&config.bloom))
+            .insert(text_hash(text)),
+    }
+}
+
+/// The Bloom filter of the run, if `Dedup::Bloom` was used
+pub fn bloom() -> Option<&'static Bloom> {
+    BLOOM.get()
+}
+
+/// A Bloom filter of document hashes, shared by the workers without a lock
+///
+/// Every document sets `hashes` bits out of `bits`, picked by double hashing. Two copies inserted
+/// at the same time may both be taken for new.
+#[derive(Debug)]
+pub struct Bloom {
+    words: Vec<AtomicU64>,
+    hashes: u32,
+    inserted: AtomicU64,
+}
+
+impl Bloom {
+    /// A filter of `options.bytes` with the number of hashes that gives
+    /// `options.false_positive_rate` when it is full
+    pub fn new(options: &BloomOptions) -> Self {
+        let words = (options.bytes / 8).max(1) as usize;
+        let hashes = (-options.false_positive_rate.log2()).round() as u32;
+        Bloom {
+            words: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            hashes: hashes.clamp(1, MAX_HASHES),
+            inserted: AtomicU64::new(0),
+        }
+    }
+
+    fn bits(&self) -> u64 {
+        self.words.len() as u64 * 64
+    }
+
+    /// Add the document `hash`, returns false if it was already in the filter
+    pub fn insert(&self, hash: u128) -> bool {
+        let bits = self.bits();
+        let (first, step) = (hash as u64, (hash >> 64) as u64 | 1);
+        let mut new = false;
+        for i in 0..self.hashes as u64 {
+            let bit = first.wrapping_add(i.wrapping_mul(step)) % bits;
+            let mask = 1 << (bit % 64);
+            let word = self.words[(bit / 64) as usize].fetch_or(mask, Ordering::Relaxed);
+            new |= word & mask == 0;
+        }
+        if new {
+            self.inserted.fetch_add(1, Ordering::Relaxed);
+        }
+        new
+    }
+
+    /// Documents in the filter
+    pub fn inserted(&self) -> u64 {
+        self.inserted.load(Ordering::Relaxed)
+    }
+
+    /// Documents the filter holds at the rate it was built for
+    pub fn capacity(&self) -> u64 {
+        let rate = 0.5_f64.powi(self.hashes as i32);
+        (self.bits() as f64 * std::f64::consts::LN_2.powi(2) / -rate.ln()) as u64
+    }
+
+    /// Chance that a new document is now taken for a copy
+    pub fn false_positive_rate(&self) -> f64 {
+        let filled = -(self.hashes as f64 * self.inserted() as f64 / self.bits() as f64);
+        (1.0 - filled.exp()).powi(self.hashes as i32)
+    }
+}
+
+/// The hashes of a dedup index file
+#[derive(Debug)]
+enum Index {
+    Exact(Vec<u128>),
+    Bloom(Bloom),
+}
+
+impl Index {
+    fn kind(&self) -> Dedup {
+        match self {
+            Index::Exact(_) => Dedup::Exact,
+            Index::Bloom(_) => Dedup::Bloom,
+        }
+    }
+}
+
+/// Add the hashes saved at `path` by a previous run, returns the number of documents
+///
+/// Nothing is loaded if the file does not exist yet. With `Dedup::Bloom` the filter of the file
+/// is used as it is, whatever `config.bloom` asks for.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read, or was saved with another `--dedup`.
+pub fn load(path: &Path, config: &Config) -> io::Result<u64> {
+    if !path.exists() {
+        return Ok(0);
+    }
+    let index = read_index(BufReader::new(File::open(path)?))?;
+    if index.kind() != config.dedup {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("the index was saved with --dedup {:?}", index.kind()).to_lowercase(),
+        ));
+    }
+    match index {
+        Index::Exact(hashes) => {
+            let documents = hashes.len() as u64;
+            for hash in hashes {
+                SEEN[hash as usize % SHARDS]
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .insert(hash);
+            }
+            Ok(documents)
+        }
+        Index::Bloom(bloom) => {
+            if bloom.words.len() as u64 * 8 != config.bloom.bytes {
+                tracing::warn!(
+                    bytes = bloom.words.len() * 8,
+                    "The Bloom filter keeps the size it was saved with"
+                );
+            }
+            let documents = bloom.inserted();
+            BLOOM.set(bloom).map_err(|_| {
+                io::Error::new(io::ErrorKind::AlreadyExists, "the Bloom filter is in use")
+            })?;
+            Ok(documents)
+        }
+    }
+}
+
+/// Save the hashes of the run, with those loaded, to `path`, returns the number of documents
+///
+/// The file is written next to `path` first and then renamed, an interrupted save leaves the
+/// previous index as it was.
+pub fn save(path: &Path, config: &Config) -> io::Result<u64> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    let temporary = path.with_file_name(name);
+    let mut writer = BufWriter::new(File::create(&temporary)?);
+    let documents = match config.dedup {
+        Dedup::None => 0,
+        Dedup::Exact => {
+            let shards: Vec<_> = SEEN
+                .iter()
+                .map(|shard| shard.lock().unwrap_or_else(PoisonError::into_inner))
+                .collect();
+            let count = shards.iter().map(|shard| shard.len()).sum::<usize>();
+            let hashes = shards.iter().flat_map(|shard| shard.iter().copied());
+            write_exact(&mut writer, count as u64, hashes)?
+        }
+        Dedup::Bloom => {
+            let bloom = BLOOM.get_or_init(|| Bloom::new(&config.bloom));
+            write_bloom(&mut writer, bloom)?;
+            bloom.inserted()
+        }
+    };
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    fs::rename(&temporary, path)?;
+    Ok(documents)
+}
+
+fn write_header<W: Write>(writer: &mut W, kind: Dedup) -> io::Result<()> {
+    writer.write_all(INDEX_MAGIC)?;
+    writer.write_all(&[INDEX_VERSION, kind as u8])
+}
+
+/// The number of hashes, then the `count` hashes
+fn write_exact<W, H>(writer: &mut W, count: u64, hashes: H) -> io::Result<u64>
+where
+    W: Write,
+    H: Iterator<Item = u128>,
+{
+    write_header(writer, Dedup::Exact)?;
+    writer.write_all(&count.to_le_bytes())?;
+    for hash in hashes {
+        writer.write_all(&hash.to_le_bytes())?;
+    }
+    Ok(count)
+}
+
+/// The number of hashes per document, of documents and of words, then the words
+fn write_bloom<W: Write>(writer: &mut W, bloom: &Bloom) -> io::Result<()> {
+    write_header(writer, Dedup::Bloom)?;
+    writer.write_all(&bloom.hashes.to_le_bytes())?;
+    writer.write_all(&bloom.inserted().to_le_bytes())?;
+    writer.write_all(&(bloom.words.len() as u64).to_le_bytes())?;
+    for word in &bloom.words {
+        writer.write_all(&word.load(Ordering::Relaxed).to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_index<R: Read>(mut reader: R) -> io::Result<Index> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let mut header = [0; 10];
+    reader.read_exact(&mut header)?;
+    if &header[..8] != INDEX_MAGIC || header[8] != INDEX_VERSION {
+        return Err(invalid("not a dedup index of this version"));
+    }
+    let mut u64_bytes = [0; 8];
+    let mut read_u64 = |reader: &mut R| {
+        reader.read_exact(&mut u64_bytes)?;
+        Ok::<_, io::Error>(u64::from_le_bytes(u64_bytes))
+    };
+    match header[9] {
+        kind if kind == Dedup::Exact as u8 => {
+            let count = read_u64(&mut reader)?;
+            let mut hashes = Vec::with_capacity(count.min(1 << 24) as usize);
+            let mut hash = [0; 16];
+            for _ in 0..count {
+                reader.read_exact(&mut hash)?;
+                hashes.push(u128::from_le_bytes(hash));
+            }
+            Ok(Index::Exact(hashes))
+        }
+        kind if kind == Dedup::Bloom as u8 => {
+            let mut hashes = [0; 4];
+            reader.read_exact(&mut hashes)?;
+            let inserted = read_u64(&mut reader)?;
+            let count = read_u64(&mut reader)?;
+            let mut words = Vec::with_capacity(count.min(1 << 24) as usize);
+            for _ in 0..count {
+                words.push(AtomicU64::new(read_u64(&mut reader)?));
+            }
+            if words.is_empty() {
+                return Err(invalid("empty Bloom filter"));
+            }
+            Ok(Index::Bloom(Bloom {
+                words,
+                hashes: u32::from_le_bytes(hashes).clamp(1, MAX_HASHES),
+                inserted: AtomicU64::new(inserted),
+            }))
+        }
+        _ => Err(invalid("unknown kind of dedup index")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_seen() {
+        let config = Config {
+            dedup: Dedup::Exact,
+            ..Default::default()
+        };
+        let text = "a thread that is only in the dedup test";
+        assert!(first_seen(text, &config));
+        assert!(!first_seen(text, &config));
+        assert!(first_seen(&format!("{text}!"), &config));
+        assert!(first_seen(text, &Config::default()));
+        assert_ne!(text_hash("a"), text_hash("b"));
+    }
+
+    #[test]
+    fn test_bloom() {
+        let bloom = Bloom::new(&BloomOptions {
+            bytes: 1024,
+            false_positive_rate: 0.01,
+        });
+        assert_eq!(bloom.hashes, 7);
+        // 8192 bits hold about 850 documents at 1%
+        assert!((800..900).contains(&bloom.capacity()));
+        let texts: Vec<String> = (0..500).map(|i| format!("document {i}")).collect();
+        let new = texts
+            .iter()
+            .filter(|text| bloom.insert(text_hash(text)))
+            .count();
+        // A few may already be taken for copies
+        assert!(new > 495);
+        assert_eq!(bloom.inserted(), new as u64);
+        assert!(texts.iter().all(|text| !bloom.insert(text_hash(text))));
+        assert!(bloom.false_positive_rate() < 0.01);
+
+        let mut bytes = Vec::new();
+        write_bloom(&mut bytes, &bloom).unwrap();
+        let Index::Bloom(loaded) = read_index(&bytes[..]).unwrap() else {
+            panic!("not a Bloom filter");
+        };
+        assert_eq!(loaded.hashes, bloom.hashes);
+        assert_eq!(loaded.inserted(), bloom.inserted());
+        assert!(texts.iter().all(|text| !loaded.insert(text_hash(text))));
+    }
+
+    #[test]
+    fn test_index() {
+        let hashes = [text_hash("a"), text_hash("b")];
+        let mut bytes = Vec::new();
+        assert_eq!(write_exact(&mut bytes, 2, hashes.into_iter()).unwrap(), 2);
+        match read_index(&bytes[..]).unwrap() {
+            Index::Exact(loaded) => assert_eq!(loaded, hashes),
+            index => panic!("unexpected index {:?}", index),
+        }
+        // Cut short
+        assert!(read_index(&bytes[..bytes.len() - 1]).is_err());
+        assert!(read_index(&b"not an index"[..]).is_err());
+
+        // Saved with the other kind
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("index");
+        std::fs::write(&path, &bytes).unwrap();
+        let config = Config {
+            dedup: Dedup::Bloom,
+            ..Default::default()
+        };
+        assert!(load(&path, &config).is_err());
+        assert_eq!(load(&temp_dir.path().join("missing"), &config).unwrap(), 0);
+    }
+}
@@ -1,6 +1,33 @@
-use std::fs;
-use std::io;
-use std::path::PathBuf;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config::{InvalidUtf8, ReadOptions};
+use crate::error::{self, Error};
+use crate::utils::{json, rejects, resume};
+
+/// Posts parsed from the input so far
+static POSTS_READ: AtomicU64 = AtomicU64::new(0);
+
+/// Bytes of the input lines parsed so far
+static BYTES_READ: AtomicU64 = AtomicU64::new(0);
+
+/// Count the posts and bytes of a file once its lines are parsed, for the progress
+pub fn record_read(posts: u64, bytes: u64) {
+    POSTS_READ.fetch_add(posts, Ordering::Relaxed);
+    BYTES_READ.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Posts parsed so far, see [record_read](fn.record_read.html)
+pub fn posts_read() -> u64 {
+    POSTS_READ.load(Ordering::Relaxed)
+}
+
+/// Bytes of input parsed so far, see [record_read](fn.record_read.html)
+pub fn bytes_read() -> u64 {
+    BYTES_READ.load(Ordering::Relaxed)
+}
 
 /// This returns the subfolders in a specified folder, do not use this direct output for
 /// the main function, as it does not provide a `Vec<jsonl path>`.
@@ -14,23 +41,76 @@ use std::path::PathBuf;
 ///|   |-- 11.jsonl
 ///|   |-- 12.jsonl
 ///
-/// * `forum_folder` - A string reference to the main folder
+/// * `forum_folder` - `P: AsRef<Path>` - The main folder
 ///
 /// # Example
 ///
-/// ```
+/// ```ignore
 /// let all_folders = all_folders("forum_folder");
 /// ```
 ///
-pub fn all_folders(forum_folder: &str) -> Result<Vec<PathBuf>, io::Error> {
+pub fn all_folders<P: AsRef<Path>>(forum_folder: P) -> Result<Vec<PathBuf>, io::Error> {
     let subfolders = fs::read_dir(forum_folder)?
         .map(|res| res.map(|e| e.path()))
         .collect::<Result<Vec<_>, io::Error>>()?;
     // Print total subfolders
-    println!("Total subfolders: {}", &subfolders.len());
+    tracing::info!(subfolders = subfolders.len(), "Found the subfolders");
     Ok(subfolders)
 }
 
+/// `path` made absolute with the `\\?\` prefix on Windows, so that the files below it are not
+/// limited to 260 characters
+///
+/// The prefix also turns off the handling of `/` and `..`, the path is made absolute first and the
+/// files must then be joined with `Path::join`. Elsewhere, or if the path cannot be made
+/// absolute, it is returned as is.
+///
+/// # Example
+///
+/// ```
+/// let input = long_path(Path::new("forum_folder"));
+/// let folders = all_folders(&input)?;
+/// ```
+#[cfg(windows)]
+pub fn long_path(path: &Path) -> PathBuf {
+    use std::ffi::OsString;
+    use std::path::{Component, Prefix};
+
+    let Ok(absolute) = std::path::absolute(path) else {
+        return path.to_path_buf();
+    };
+    let mut components = absolute.components();
+    let mut long = match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(_) => OsString::from(r"\\?\"),
+            Prefix::UNC(server, share) => {
+                let mut long = OsString::from(r"\\?\UNC\");
+                long.push(server);
+                long.push(r"\");
+                long.push(share);
+                long.push(components.as_path());
+                return PathBuf::from(long);
+            }
+            // Already verbatim, or a device
+            _ => return absolute,
+        },
+        _ => return absolute,
+    };
+    long.push(absolute.as_os_str());
+    PathBuf::from(long)
+}
+
+#[cfg(not(windows))]
+pub fn long_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+/// Whether `folder` is a folder without any entry, a folder that cannot be read is not empty so
+/// that it is reported when it is processed
+pub fn is_empty_folder(folder: &Path) -> bool {
+    fs::read_dir(folder).is_ok_and(|mut entries| entries.next().is_none())
+}
+
 /// Get all files in a forum subfolder
 ///
 /// The folder should contain JSONL files for the downstream tasks
@@ -41,15 +121,307 @@ pub fn all_folders(forum_folder: &str) -> Result<Vec<PathBuf>, io::Error> {
 ///
 /// # Example
 ///
-/// ```
+/// ```ignore
 /// let entries = single_folder("forum/subforum");
 /// ```
-pub fn single_folder(folder: &str) -> Vec<PathBuf> {
+pub fn single_folder<P: AsRef<Path>>(folder: P) -> Vec<PathBuf> {
+    folder_files(folder.as_ref()).unwrap()
+}
+
+/// Same as [single_folder](fn.single_folder.html), but a folder that cannot be read is an error
+pub fn folder_files(folder: &Path) -> error::Result<Vec<PathBuf>> {
+    let read_folder = |source| Error::ReadFolder {
+        path: folder.to_path_buf(),
+        source,
+    };
     fs::read_dir(folder)
-        .unwrap()
+        .map_err(read_folder)?
         .map(|res| res.map(|e| e.path()))
         .collect::<Result<Vec<_>, io::Error>>()
-        .unwrap()
+        .map_err(read_folder)
+}
+
+/// Open a `jsonl` file for reading
+///
+/// A file that cannot be opened is [reported](../../error/fn.report.html) and `None` is returned,
+/// so the rest of the folder is still processed. With the `io-uring` feature on Linux the file is
+/// read with a [UringReader](../uring/struct.UringReader.html), or with blocking reads if the
+/// kernel does not allow io_uring.
+///
+/// A compressed file is decompressed whatever its extension, see [Compression](enum.Compression.html).
+///
+/// # Example
+///
+/// ```ignore
+/// let Some(reader) = open_reader(&entry) else {
+///     return;
+/// };
+/// ```
+pub fn open_reader(path: &Path) -> Option<Box<dyn BufRead + Send>> {
+    let report = |source| {
+        error::report(Error::ReadFile {
+            path: path.to_path_buf(),
+            source,
+        })
+    };
+    let mut reader = match File::open(path) {
+        Ok(file) => file_reader(file),
+        Err(source) => {
+            report(source);
+            return None;
+        }
+    };
+    let compression = match reader.fill_buf() {
+        Ok(header) => Compression::detect(header),
+        Err(source) => {
+            report(source);
+            return None;
+        }
+    };
+    match compression.decoder(reader) {
+        Ok(reader) => Some(reader),
+        Err(source) => {
+            report(source);
+            None
+        }
+    }
+}
+
+/// The reader of the bytes of a file as stored
+fn file_reader(file: File) -> Box<dyn BufRead + Send> {
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    match crate::utils::uring::ring() {
+        Ok(ring) => return Box::new(crate::utils::uring::UringReader::new(file, ring)),
+        Err(e) => {
+            static WARNED: std::sync::Once = std::sync::Once::new();
+            WARNED.call_once(|| {
+                tracing::warn!(error = %e, "io_uring is not available, reading the files with blocking reads")
+            });
+        }
+    }
+    Box::new(BufReader::new(file))
+}
+
+/// How an input file is compressed, detected from its first bytes
+///
+/// The folders of an archive may mix plain and compressed files, with extensions that do not
+/// match, so the extension is not looked at. gzip is always read, zstd with the `zstd` feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Bzip2,
+    Xz,
+}
+
+impl Compression {
+    /// The compression whose magic bytes start `header`, the first bytes of a file
+    pub fn detect(header: &[u8]) -> Compression {
+        match header {
+            [0x1f, 0x8b, ..] => Compression::Gzip,
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Compression::Zstd,
+            [b'B', b'Z', b'h', ..] => Compression::Bzip2,
+            [0xfd, b'7', b'z', b'X', b'Z', 0x00, ..] => Compression::Xz,
+            _ => Compression::None,
+        }
+    }
+
+    /// The reader of the decompressed bytes of `reader`
+    ///
+    /// # Errors
+    ///
+    /// Returns an `Unsupported` error for a compression this build cannot read.
+    pub fn decoder(self, reader: Box<dyn BufRead + Send>) -> io::Result<Box<dyn BufRead + Send>> {
+        match self {
+            Compression::None => Ok(reader),
+            // Concatenated members, as written by `cat a.gz b.gz` or pigz, are all read
+            Compression::Gzip => Ok(Box::new(BufReader::new(
+                flate2::bufread::MultiGzDecoder::new(reader),
+            ))),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(Box::new(BufReader::new(
+                zstd::stream::read::Decoder::with_buffer(reader)?,
+            ))),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "the file is compressed with {:?}, which this build cannot read",
+                    self
+                ),
+            )),
+        }
+    }
+}
+
+/// The lines of a file opened with [open_reader](fn.open_reader.html), without their line ending
+///
+/// The byte order mark at the start of the file is removed. A line that is not UTF-8 is handled
+/// according to `options.invalid_utf8`. A line longer than
+/// `options.max_line_bytes` is logged, [rejected](../rejects/fn.line_too_long.html) and skipped, at
+/// most that many bytes of it are kept in memory. A file that cannot be
+/// opened is reported and `None` is returned, a read error or a line that is not UTF-8 with
+/// `InvalidUtf8::Error` is reported and ends the lines of the file.
+///
+/// # Example
+///
+/// ```ignore
+/// let Some(lines) = read_lines(&entry, &config.read) else {
+///     return;
+/// };
+/// let posts = lines.filter_map(|line| parser.parse(&line).ok().flatten()).count();
+/// ```
+pub fn read_lines<'a>(path: &'a Path, options: &ReadOptions) -> Option<Lines<'a>> {
+    Some(Lines {
+        path,
+        reader: open_reader(path)?,
+        options: *options,
+        line: 0,
+        done: false,
+    })
+}
+
+/// Iterator of [read_lines](fn.read_lines.html)
+pub struct Lines<'a> {
+    path: &'a Path,
+    reader: Box<dyn BufRead + Send>,
+    options: ReadOptions,
+    /// Lines read so far, for the errors
+    line: usize,
+    done: bool,
+}
+
+impl Lines<'_> {
+    /// Report the file and stop reading it
+    fn fail(&mut self, source: io::Error) -> Option<String> {
+        error::report(Error::ReadFile {
+            path: self.path.to_path_buf(),
+            source,
+        });
+        self.done = true;
+        None
+    }
+}
+
+impl Iterator for Lines<'_> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        while !self.done {
+            let mut bytes = Vec::new();
+            let max = self.options.max_line_bytes;
+            let length = match read_line(&mut self.reader, &mut bytes, max.unwrap_or(usize::MAX)) {
+                Ok(Some(length)) => length,
+                Ok(None) => {
+                    self.done = true;
+                    continue;
+                }
+                Err(source) => return self.fail(source),
+            };
+            self.line += 1;
+            if max.is_some_and(|max| length > max) {
+                tracing::warn!(
+                    file = %self.path.display(),
+                    line = self.line,
+                    bytes = length,
+                    "Skipped a line over --max-line-bytes"
+                );
+                rejects::line_too_long(self.path, &bytes, length);
+                continue;
+            }
+            // The same line endings as `BufRead::lines`
+            if bytes.last() == Some(&b'\r') {
+                bytes.pop();
+            }
+            if self.line == 1 && bytes.starts_with(json::BOM.as_bytes()) {
+                bytes.drain(..json::BOM.len());
+            }
+            let e = match String::from_utf8(bytes) {
+                Ok(line) => return Some(line),
+                Err(e) => e,
+            };
+            match self.options.invalid_utf8 {
+                InvalidUtf8::Lossy => {
+                    return Some(String::from_utf8_lossy(e.as_bytes()).into_owned())
+                }
+                InvalidUtf8::SkipLine => {
+                    rejects::invalid_utf8(self.path, e.as_bytes(), &e.utf8_error())
+                }
+                InvalidUtf8::Error => {
+                    let message = format!("line {}: {}", self.line, e.utf8_error());
+                    return self.fail(io::Error::new(io::ErrorKind::InvalidData, message));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Read a line into `line` without its newline, as `read_until`, only its first `max` bytes are
+/// kept
+///
+/// Returns the length of the whole line without the newline, `None` at the end of the file.
+fn read_line(
+    reader: &mut dyn BufRead,
+    line: &mut Vec<u8>,
+    max: usize,
+) -> io::Result<Option<usize>> {
+    let mut length = 0;
+    loop {
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if available.is_empty() {
+            return Ok((length > 0).then_some(length));
+        }
+        let newline = available.iter().position(|&byte| byte == b'\n');
+        let end = newline.unwrap_or(available.len());
+        let keep = end.min(max.saturating_sub(line.len()));
+        line.extend_from_slice(&available[..keep]);
+        length += end;
+        match newline {
+            Some(_) => {
+                reader.consume(end + 1);
+                return Ok(Some(length));
+            }
+            None => reader.consume(end),
+        }
+    }
+}
+
+/// Bytes read from the start of a file to estimate the length of its lines
+const SAMPLE_BYTES: u64 = 64 * 1024;
+
+/// An estimate of the number of lines of `entries`, from their size and the lines at the start of
+/// the first file that is not empty
+///
+/// Used to size a graph before it is read, a wrong estimate only costs a resize. Files that
+/// cannot be read count as empty, they are reported when they are read.
+///
+/// # Example
+///
+/// ```ignore
+/// let threadgraph = ThreadGraph::with_capacity(estimate_lines(&entries));
+/// ```
+pub fn estimate_lines(entries: &[PathBuf]) -> usize {
+    let total: u64 = entries
+        .iter()
+        .filter_map(|entry| fs::metadata(entry).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    for entry in entries {
+        let mut sample = Vec::new();
+        let read =
+            File::open(entry).and_then(|file| file.take(SAMPLE_BYTES).read_to_end(&mut sample));
+        if read.is_err() || sample.is_empty() {
+            continue;
+        }
+        let lines = sample.iter().filter(|&&byte| byte == b'\n').count().max(1);
+        return (total as f64 * lines as f64 / sample.len() as f64) as usize;
+    }
+    0
 }
 
 /// Get the size of a folder
@@ -57,10 +429,10 @@ pub fn single_folder(folder: &str) -> Vec<PathBuf> {
 ///
 /// # Example
 ///
-/// ```
+/// ```ignore
 /// let size = folder_size(&PathBuf::from("forum_folder")).unwrap();
 /// ```
-fn folder_size(folder: &PathBuf) -> Result<u64, io::Error> {
+pub fn folder_size(folder: &PathBuf) -> Result<u64, io::Error> {
     let mut size: u64 = 0;
 
     for entry in fs::read_dir(folder)? {
@@ -93,7 +465,7 @@ fn folder_size(folder: &PathBuf) -> Result<u64, io::Error> {
 ///
 /// # Example
 ///
-/// ```
+/// ```ignore
 /// let sorted_folder = reorder_by_size(folder);
 /// ```
 pub fn reorder_by_size(mut folder: Vec<PathBuf>) -> Vec<PathBuf> {
@@ -107,3 +479,59 @@ pub fn reorder_by_size(mut folder: Vec<PathBuf>) -> Vec<PathBuf> {
 
     folder
 }
+
+/// The `jsonl` files of an output folder, in every subfolder, without the resume state and the
+/// rejected records
+///
+/// Unlike [single_folder](fn.single_folder.html) this recurses, the output of a run may be split
+/// into subfolders. The files are sorted so they are always read in the same order.
+///
+/// # Example
+///
+/// ```ignore
+/// let files = output_files(Path::new("output")).unwrap();
+/// ```
+pub fn output_files(folder: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(folder)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if path
+                .file_name()
+                .is_some_and(|name| name == rejects::REJECTS_DIR)
+            {
+                continue;
+            }
+            files.extend(output_files(&path)?);
+        } else if path
+            .extension()
+            .is_some_and(|extension| extension == "jsonl")
+            && path
+                .file_name()
+                .is_some_and(|name| name != resume::STATE_FILE)
+        {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_long_path() {
+        let path = Path::new("forum_folder").join("sub1");
+        let long = long_path(&path);
+        match cfg!(windows) {
+            true => {
+                assert!(long.to_string_lossy().starts_with(r"\\?\"));
+                assert!(long.ends_with(&path));
+            }
+            false => assert_eq!(long, path),
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use serde::Serialize;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Default upper bounds of the buckets, common context lengths
+pub const DEFAULT_BUCKETS: [usize; 8] = [512, 1024, 2048, 4096, 8192, 16384, 32768, 65536];
+
+/// Histogram of document token lengths
+///
+/// Each bucket counts the documents with a length up to and including its bound, documents longer
+/// than the last bound go to an extra overflow bucket. Counts are atomic so the histogram can be
+/// shared by all the processing threads.
+///
+/// # Example
+///
+/// ```
+/// # use forum_rs::utils::histogram::LengthHistogram;
+/// let histogram = LengthHistogram::new(&[512, 1024]);
+/// histogram.record(100);
+/// histogram.record(2000);
+/// println!("{}", histogram);
+/// ```
+#[derive(Debug)]
+pub struct LengthHistogram {
+    bounds: Vec<usize>,
+    counts: Vec<AtomicU64>,
+}
+
+/// One bucket of the histogram as it is saved, `max` is `None` for the overflow bucket
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Bucket {
+    pub max: Option<usize>,
+    pub count: u64,
+}
+
+impl LengthHistogram {
+    /// Create an empty histogram, the bounds are sorted and deduplicated
+    pub fn new(bounds: &[usize]) -> Self {
+        let mut bounds = bounds.to_vec();
+        bounds.sort_unstable();
+        bounds.dedup();
+        let counts = (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect();
+        LengthHistogram { bounds, counts }
+    }
+
+    /// Add a document of `length` tokens
+    pub fn record(&self, length: usize) {
+        let index = self.bounds.partition_point(|&bound| bound < length);
+        self.counts[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Set every count back to 0, for the next run of the process
+    pub fn clear(&self) {
+        for count in &self.counts {
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Snapshot of the buckets, the overflow bucket is last
+    pub fn buckets(&self) -> Vec<Bucket> {
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(i, count)| Bucket {
+                max: self.bounds.get(i).copied(),
+                count: count.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Save the buckets as JSON
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, &self.buckets())?;
+        writeln!(writer)?;
+        writer.flush()
+    }
+}
+
+impl fmt::Display for LengthHistogram {
+    /// One line per bucket with the count, its share and the cumulative share of documents
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let buckets = self.buckets();
+        let total: u64 = buckets.iter().map(|bucket| bucket.count).sum();
+        let mut cumulative = 0;
+        for bucket in buckets {
+            cumulative += bucket.count;
+            let label = match bucket.max {
+                Some(max) => format!("<= {}", max),
+                None => format!("> {}", self.bounds.last().copied().unwrap_or(0)),
+            };
+            let share = |count: u64| match total {
+                0 => 0.0,
+                _ => count as f64 * 100.0 / total as f64,
+            };
+            writeln!(
+                f,
+                "{:>10}: {:>10} ({:6.2}%, cumulative {:6.2}%)",
+                label,
+                bucket.count,
+                share(bucket.count),
+                share(cumulative)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_record() {
+        let histogram = LengthHistogram::new(&[1024, 512, 512]);
+        for length in [0, 512, 513, 1024, 5000] {
+            histogram.record(length);
+        }
+        assert_eq!(
+            histogram.buckets(),
+            vec![
+                Bucket {
+                    max: Some(512),
+                    count: 2
+                },
+                Bucket {
+                    max: Some(1024),
+                    count: 2
+                },
+                Bucket {
+                    max: None,
+                    count: 1
+                },
+            ]
+        );
+        let report = histogram.to_string();
+        assert!(report.contains("> 1024"));
+        assert!(report.contains("cumulative 100.00%"));
+    }
+
+    #[test]
+    fn test_save() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("token_lengths.json");
+        let histogram = LengthHistogram::new(&[10]);
+        histogram.record(3);
+        histogram.save(&path).unwrap();
+        let saved: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(saved[0]["max"], 10);
+        assert_eq!(saved[0]["count"], 1);
+        assert!(saved[1]["max"].is_null());
+    }
+}
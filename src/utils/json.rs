@@ -0,0 +1,104 @@
+//! Deserialization of the input lines
+//!
+//! The strings of a line are borrowed from it where they have no escapes, only the fields kept in
+//! the posts are copied. With the `simd-json` feature the lines are first parsed with
+//! [simd-json](https://docs.rs/simd-json), see [simd_line](fn.simd_line.html). For a line it
+//! rejects [serde_json](https://docs.rs/serde_json) is used, so the errors written to the rejects
+//! are the same either way.
+//!
+//! A line may have a UTF-8 byte order mark and whitespace around its object, as written by some
+//! Windows tools, see [trim](fn.trim.html).
+//!
+//! Which one is faster depends on the dump and the CPU, on the generated lines of
+//! `benches/parse.rs` they are close. Measure on a file of the dump before turning it on:
+//!
+//! ```bash
+//! FORUM_RS_BENCH_JSONL=main_folder/sub1/posts.jsonl cargo bench --bench parse --features simd-json
+//! ```
+
+use serde::Deserialize;
+
+/// The byte order mark some tools write at the start of a UTF-8 file
+pub const BOM: &str = "\u{feff}";
+
+/// `line` without a byte order mark and the whitespace around the JSON
+///
+/// serde_json only allows ASCII whitespace around a value, this also removes the Unicode
+/// whitespace, such as the no-break spaces of a copy and paste.
+pub fn trim(line: &str) -> &str {
+    line.trim_start_matches(BOM).trim()
+}
+
+/// Deserialize a line of JSON with serde_json, the strings of `T` may borrow from `line`
+///
+/// # Example
+///
+/// ```ignore
+/// let json: JsonStruct = utils::json::from_line(&line)?;
+/// let post = Post::from_json_struct(json);
+/// ```
+pub fn from_line<'a, T: Deserialize<'a>>(line: &'a str) -> serde_json::Result<T> {
+    serde_json::from_str(trim(line))
+}
+
+#[cfg(feature = "simd-json")]
+thread_local! {
+    /// The copy of the line and the buffers of simd-json, reused from one line to the next
+    static SIMD_BUFFERS: std::cell::RefCell<(Vec<u8>, simd_json::Buffers)> =
+        std::cell::RefCell::new((Vec::new(), simd_json::Buffers::default()));
+}
+
+/// Parse a copy of `line` with simd-json, `None` if it is rejected
+///
+/// simd-json parses in place and the line is not ours to change, so `parse` gets a copy of the
+/// line and the buffers of this thread. What is deserialized borrows from the copy and must be
+/// turned into owned values before `parse` returns.
+///
+/// # Example
+///
+/// ```ignore
+/// let post = utils::json::simd_line(&line, |bytes, buffers| {
+///     let json = simd_json::serde::from_slice_with_buffers::<JsonStruct>(bytes, buffers).ok()?;
+///     Post::from_json_struct(json)
+/// });
+/// ```
+#[cfg(feature = "simd-json")]
+pub fn simd_line<R>(
+    line: &str,
+    parse: impl FnOnce(&mut [u8], &mut simd_json::Buffers) -> Option<R>,
+) -> Option<R> {
+    SIMD_BUFFERS.with_borrow_mut(|(bytes, buffers)| {
+        bytes.clear();
+        bytes.extend_from_slice(trim(line).as_bytes());
+        parse(bytes, buffers)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forum_thread::{JsonStruct, Post};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_from_line() {
+        let line = r#"{"id":"2","is_thread":"N","pagetext":"café \"quoted\"","parent_post_id":"1","root_post_id":"1","created_utc":"1700000000","score":3,"username":"a"}"#;
+        let post = Post::from_json_struct(from_line::<JsonStruct>(line).unwrap()).unwrap();
+        assert_eq!(post.pagetext, "café \"quoted\"");
+        assert_eq!(post.created_utc, Some(1_700_000_000.0));
+        assert_eq!(post.score, Some(3.0));
+        assert_eq!(post.author.as_deref(), Some("a"));
+
+        let error = from_line::<JsonStruct>(r#"{"id":"2""#).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            serde_json::from_str::<JsonStruct>(r#"{"id":"2""#)
+                .unwrap_err()
+                .to_string()
+        );
+
+        let padded = format!("{}\u{a0} {}\t\r", BOM, line);
+        let post = Post::from_json_struct(from_line::<JsonStruct>(&padded).unwrap()).unwrap();
+        assert_eq!(post.id, "2");
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+/// Log10 probability KenLM assigns to `<unk>` when the model does not contain it
+const DEFAULT_UNK_LOG_PROB: f32 = -100.0;
+
+/// Backoff n-gram language model loaded from an ARPA file
+///
+/// This is the text format produced by KenLM's `lmplz`, so the same model used by
+/// CCNet-style pipelines can be loaded directly. Scoring follows KenLM: log10 probabilities,
+/// `<s>`/`</s>` sentence markers and backoff weights for unseen contexts.
+///
+/// Words are split by whitespace, so the model should be trained on text cleaned the same way
+/// as the pipeline output.
+///
+/// # Example
+///
+/// ```ignore
+/// let model = NGramModel::from_file("model.arpa").unwrap();
+/// let perplexity = model.perplexity("hello world\nsecond post");
+/// ```
+pub struct NGramModel {
+    order: usize,
+    /// One map per order, keyed by the space-joined n-gram, value is (log10 prob, log10 backoff)
+    ngrams: Vec<HashMap<String, (f32, f32)>>,
+    unk: f32,
+}
+
+impl NGramModel {
+    /// Load a model from an ARPA file
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - `P: AsRef<Path>` - The path to the `.arpa` file
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<NGramModel>` - The loaded model, or an error if the file is not valid ARPA
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        Self::from_reader(reader)
+    }
+
+    /// Load a model from any reader containing ARPA text
+    pub fn from_reader<R: BufRead>(reader: R) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut ngrams: Vec<HashMap<String, (f32, f32)>> = Vec::new();
+        // 0 while in the header, otherwise the order of the current section
+        let mut current_order: usize = 0;
+
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line == "\\end\\" {
+                break;
+            }
+            if line == "\\data\\" {
+                continue;
+            }
+            if let Some(counts) = line.strip_prefix("ngram ") {
+                // ngram N=count, used to size the maps
+                let (order, count) = counts
+                    .split_once('=')
+                    .ok_or_else(|| invalid(format!("Invalid ngram count line: {}", line)))?;
+                let order: usize = order.trim().parse().map_err(|_| invalid(line.into()))?;
+                let count: usize = count.trim().parse().map_err(|_| invalid(line.into()))?;
+                if ngrams.len() < order {
+                    ngrams.resize_with(order, HashMap::new);
+                }
+                ngrams[order - 1].reserve(count);
+                continue;
+            }
+            if let Some(section) = line.strip_prefix('\\') {
+                current_order = section
+                    .strip_suffix("-grams:")
+                    .and_then(|order| order.parse().ok())
+                    .ok_or_else(|| invalid(format!("Invalid section header: {}", line)))?;
+                if ngrams.len() < current_order {
+                    ngrams.resize_with(current_order, HashMap::new);
+                }
+                continue;
+            }
+            if current_order == 0 {
+                return Err(invalid(format!(
+                    "Unexpected line before n-gram section: {}",
+                    line
+                )));
+            }
+
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() < current_order + 1 {
+                return Err(invalid(format!(
+                    "Invalid {}-gram line: {}",
+                    current_order, line
+                )));
+            }
+            let prob: f32 = parts[0].parse().map_err(|_| invalid(line.into()))?;
+            let backoff: f32 = match parts.get(current_order + 1) {
+                Some(backoff) => backoff.parse().map_err(|_| invalid(line.into()))?,
+                None => 0.0,
+            };
+            let key = parts[1..=current_order].join(" ");
+            ngrams[current_order - 1].insert(key, (prob, backoff));
+        }
+
+        if ngrams.is_empty() || ngrams[0].is_empty() {
+            return Err(invalid("ARPA file does not contain any unigrams".into()));
+        }
+        let unk = ngrams[0]
+            .get("<unk>")
+            .map(|(prob, _)| *prob)
+            .unwrap_or(DEFAULT_UNK_LOG_PROB);
+        Ok(NGramModel {
+            order: ngrams.len(),
+            ngrams,
+            unk,
+        })
+    }
+
+    /// Log10 probability of `word` given the preceding `context`, applying backoff weights
+    fn log_prob(&self, context: &[&str], word: &str) -> f32 {
+        for start in 0..=context.len() {
+            let mut key = context[start..].join(" ");
+            if !key.is_empty() {
+                key.push(' ');
+            }
+            key.push_str(word);
+            let order = context.len() - start + 1;
+            let prob = match self.ngrams[order - 1].get(&key) {
+                Some((prob, _)) => *prob,
+                // Unknown words still pay the backoff of their context
+                None if order == 1 => self.unk,
+                None => continue,
+            };
+            // Add the backoff of every longer context that was not found
+            let backoff: f32 = (0..start)
+                .filter_map(|s| {
+                    let ctx = context[s..].join(" ");
+                    self.ngrams[context.len() - s - 1]
+                        .get(&ctx)
+                        .map(|(_, b)| *b)
+                })
+                .sum();
+            return prob + backoff;
+        }
+        self.unk
+    }
+
+    /// Score a single sentence, returning the total log10 probability and the number of
+    /// predicted tokens (words plus `</s>`)
+    pub fn score(&self, sentence: &str) -> (f64, usize) {
+        let mut words: Vec<&str> = Vec::with_capacity(16);
+        words.push("<s>");
+        words.extend(sentence.split_whitespace());
+        words.push("</s>");
+
+        let total: f64 = (1..words.len())
+            .map(|i| {
+                let start = i.saturating_sub(self.order - 1);
+                self.log_prob(&words[start..i], words[i]) as f64
+            })
+            .sum();
+        (total, words.len() - 1)
+    }
+
+    /// Perplexity of a document
+    ///
+    /// Like CCNet, each line is scored as a separate sentence and the log probabilities are
+    /// pooled over the whole document.
+    ///
+    /// # Returns
+    ///
+    /// * `f64` - The document perplexity, lower is more fluent
+    pub fn perplexity(&self, document: &str) -> f64 {
+        let (log_prob, length) = document
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| self.score(line))
+            .fold((0.0, 0), |acc, (score, len)| (acc.0 + score, acc.1 + len));
+        if length == 0 {
+            return 0.0;
+        }
+        10f64.powf(-log_prob / length as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ARPA: &str = "
+\\data\\
+ngram 1=5
+ngram 2=3
+
+\\1-grams:
+-1.0\t<unk>\t0
+-99\t<s>\t-0.5
+-0.5\t</s>\t0
+-0.7\thello\t-0.2
+-0.9\tworld\t-0.3
+
+\\2-grams:
+-0.1\t<s> hello
+-0.2\thello world
+-0.3\tworld </s>
+
+\\end\\
+";
+
+    #[test]
+    fn test_score() {
+        let model = NGramModel::from_reader(ARPA.as_bytes()).unwrap();
+        assert_eq!(model.order, 2);
+
+        // All bigrams are present
+        let (score, len) = model.score("hello world");
+        assert_eq!(len, 3);
+        assert!((score - (-0.1 - 0.2 - 0.3)).abs() < 1e-6);
+
+        // "world hello" is missing, backoff(world) + p(hello)
+        let (score, _) = model.score("world hello");
+        let expected = (-0.5 - 0.9) + (-0.3 - 0.7) + (-0.2 - 0.5);
+        assert!((score - expected).abs() < 1e-6);
+
+        // Unknown word uses <unk>
+        let (score, _) = model.score("foo");
+        assert!((score - (-0.5 - 1.0 + -0.5)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_perplexity() {
+        let model = NGramModel::from_reader(ARPA.as_bytes()).unwrap();
+        let fluent = model.perplexity("hello world\nhello world");
+        let garbled = model.perplexity("world hello\nfoo bar");
+        assert!(fluent < garbled);
+        assert_eq!(model.perplexity(""), 0.0);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(NGramModel::from_reader("not an arpa file".as_bytes()).is_err());
+    }
+}
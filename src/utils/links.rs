@@ -0,0 +1,249 @@
+//! Threads that only repost a link another thread already shares, left out with
+//! `--drop-link-reposts`
+//!
+//! The first `http(s)://` link of a thread root is the link of the thread. Among the threads of a
+//! folder that share a link, the one with the most posts, then the highest root score, is kept.
+//! The others are left out when their root has no more than a few words besides the links, a
+//! thread with its own commentary is kept even if it shares the link.
+//!
+//! A folder built in parts, its buckets or its graph shards, holds the threads that may be reposts
+//! in [Reposts](struct.Reposts.html) until its last part is built, the best thread of a link is only
+//! known then. With `--cross-folder` all the folders are built together.
+
+use std::collections::HashMap;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock, PoisonError};
+
+use crate::forum_thread::{RootLink, Thread};
+
+static URL_REGEX: OnceLock<regex::Regex> = OnceLock::new();
+
+/// Number of documents left out as reposts so far
+static REPOSTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of documents left out by `--drop-link-reposts`
+pub fn reposts() -> usize {
+    REPOSTS.load(Ordering::Relaxed)
+}
+
+fn url_regex() -> &'static regex::Regex {
+    URL_REGEX.get_or_init(|| regex::Regex::new(r"https?://[^\s<>\[\]()]+").unwrap())
+}
+
+/// The link of a root post and the number of words of its text without the links
+pub fn root_link(text: &str) -> Option<(String, usize)> {
+    let url = url_regex().find(text)?;
+    let words = url_regex()
+        .split(text)
+        .flat_map(str::split_whitespace)
+        .count();
+    Some((normalize_url(url.as_str()), words))
+}
+
+/// The same page is linked as the same string
+///
+/// The scheme, a `www.` prefix, the fragment, the `utm_` parameters and a trailing slash are left
+/// out, and the host is lowercased. The path keeps its case, it matters on most sites.
+pub fn normalize_url(url: &str) -> String {
+    let url = url.trim_end_matches(['.', ',', ';', ':', '!', '?', '"', '\'']);
+    let url = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let url = url.split('#').next().unwrap_or_default();
+    let (address, query) = url.split_once('?').unwrap_or((url, ""));
+    let (host, path) = address.split_once('/').unwrap_or((address, ""));
+    let host = host.to_lowercase();
+    let host = host.strip_prefix("www.").unwrap_or(&host);
+    let mut normalized = format!("{}/{}", host, path.trim_end_matches('/'));
+    let query: Vec<&str> = query
+        .split('&')
+        .filter(|parameter| !parameter.is_empty() && !parameter.starts_with("utm_"))
+        .collect();
+    if !query.is_empty() {
+        normalized.push('?');
+        normalized.push_str(&query.join("&"));
+    }
+    normalized
+}
+
+/// Whether the thread of `link` has more posts, or the same and a higher root score, than the one
+/// of `kept`, the smallest root id wins a tie so the choice does not depend on the order
+fn more_engaged(link: &RootLink, kept: &RootLink) -> bool {
+    let score = |link: &RootLink| link.score.unwrap_or(f64::MIN);
+    link.posts
+        .cmp(&kept.posts)
+        .then(score(link).total_cmp(&score(kept)))
+        .then(kept.root.cmp(&link.root))
+        .is_gt()
+}
+
+/// Leave out the documents of the threads that repost the link of a better thread
+///
+/// # Arguments
+///
+/// * `threads` - `&mut Vec<Thread>` - All the documents of a folder, with their `link`
+/// * `max_words` - `usize` - Most words next to the link of a repost
+pub fn drop_reposts(threads: &mut Vec<Thread>, max_words: usize) {
+    let reposts = Reposts::new(Some(max_words));
+    reposts.record(threads);
+    let best = reposts.best.lock().unwrap_or_else(PoisonError::into_inner);
+    let before = threads.len();
+    threads.retain(|thread| {
+        let Some(link) = &thread.link else {
+            return true;
+        };
+        link.words > max_words || best[&link.url].root == link.root
+    });
+    REPOSTS.fetch_add(before - threads.len(), Ordering::Relaxed);
+}
+
+/// The documents of a folder built in parts that may be reposts, held until the folder is built
+///
+/// Every document with a link counts for the best thread of its link, the ones whose root has at
+/// most `max_words` other words are held, the others can be written at once. Without `max_words`
+/// nothing is held.
+///
+/// # Example
+///
+/// ```ignore
+/// let reposts = Reposts::new(config.traversal.link_reposts);
+/// for threads in parts {
+///     write(reposts.hold(threads));
+/// }
+/// write(reposts.release());
+/// ```
+#[derive(Debug, Default)]
+pub struct Reposts {
+    max_words: Option<usize>,
+    /// The best thread of every link so far
+    best: Mutex<HashMap<String, RootLink>>,
+    held: Mutex<Vec<Thread>>,
+}
+
+impl Reposts {
+    pub fn new(max_words: Option<usize>) -> Self {
+        Reposts {
+            max_words,
+            ..Default::default()
+        }
+    }
+
+    /// The documents of `threads` that are not reposts whatever the other parts hold, the others
+    /// are held
+    pub fn hold(&self, threads: Vec<Thread>) -> Vec<Thread> {
+        let Some(max_words) = self.max_words else {
+            return threads;
+        };
+        self.record(&threads);
+        let (held, kept): (Vec<Thread>, Vec<Thread>) = threads.into_iter().partition(|thread| {
+            thread
+                .link
+                .as_ref()
+                .is_some_and(|link| link.words <= max_words)
+        });
+        self.held
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .extend(held);
+        kept
+    }
+
+    /// Count the links of `threads` for the best thread of every link
+    fn record(&self, threads: &[Thread]) {
+        let mut best = self.best.lock().unwrap_or_else(PoisonError::into_inner);
+        for link in threads.iter().filter_map(|thread| thread.link.as_ref()) {
+            match best.get_mut(&link.url) {
+                Some(kept) if more_engaged(link, kept) => *kept = link.clone(),
+                Some(_) => {}
+                None => {
+                    best.insert(link.url.clone(), link.clone());
+                }
+            }
+        }
+    }
+
+    /// The held documents of the best thread of their link, the others are counted as reposts
+    pub fn release(&self) -> Vec<Thread> {
+        let best = self.best.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut held = mem::take(&mut *self.held.lock().unwrap_or_else(PoisonError::into_inner));
+        let before = held.len();
+        held.retain(|thread| {
+            thread
+                .link
+                .as_ref()
+                .is_some_and(|link| best[&link.url].root == link.root)
+        });
+        REPOSTS.fetch_add(before - held.len(), Ordering::Relaxed);
+        held
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_normalize_url() {
+        assert_eq!(
+            normalize_url("https://WWW.Example.com/Watch/?v=Ab1&utm_source=x#top"),
+            "example.com/Watch?v=Ab1"
+        );
+        assert_eq!(normalize_url("http://example.com/a/."), "example.com/a");
+        assert_eq!(normalize_url("http://example.com"), "example.com/");
+        assert_eq!(
+            root_link("look at this https://example.com/a, wow"),
+            Some(("example.com/a".to_string(), 4))
+        );
+        assert_eq!(root_link("no link here"), None);
+    }
+
+    #[test]
+    fn test_drop_reposts() {
+        let thread = |root: &str, text: &str, posts: usize, score: Option<f64>| {
+            let link = root_link(text).map(|(url, words)| RootLink {
+                root: root.to_string(),
+                url,
+                words,
+                posts,
+                score,
+            });
+            Thread {
+                id: root.to_string(),
+                link,
+                ..Default::default()
+            }
+        };
+        let mut threads = vec![
+            thread("1", "https://example.com/a", 2, Some(10.0)),
+            thread("2", "https://www.example.com/a/", 5, Some(1.0)),
+            // Same posts, higher score
+            thread("3", "http://example.com/b", 3, Some(2.0)),
+            thread("4", "http://example.com/b", 3, Some(7.0)),
+            // Commentary of its own
+            thread(
+                "5",
+                "A long review of https://example.com/a that is kept",
+                1,
+                None,
+            ),
+            thread("6", "https://example.com/c", 1, None),
+            thread("7", "no link", 1, None),
+        ];
+        let parts = vec![threads[3..].to_vec(), threads[..3].to_vec()];
+        drop_reposts(&mut threads, 2);
+        let ids: Vec<&str> = threads.iter().map(|thread| thread.id.as_str()).collect();
+        assert_eq!(ids, vec!["2", "4", "5", "6", "7"]);
+
+        // The same documents built in two parts, the better thread of a link in the second one
+        let reposts = Reposts::new(Some(2));
+        let mut kept: Vec<Thread> = parts
+            .into_iter()
+            .flat_map(|part| reposts.hold(part))
+            .collect();
+        kept.extend(reposts.release());
+        let mut ids: Vec<&str> = kept.iter().map(|thread| thread.id.as_str()).collect();
+        ids.sort();
+        assert_eq!(ids, vec!["2", "4", "5", "6", "7"]);
+        assert!(Reposts::new(None).hold(kept).len() == 5);
+    }
+}
@@ -0,0 +1,141 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, PoisonError};
+
+/// Approximate bytes held by the run, see [IN_FLIGHT](static.IN_FLIGHT.html)
+///
+/// Counts the folders being processed, by the size of their files, and the records waiting for the
+/// writer. It is an estimate, the real usage also depends on the allocator and the tokenizer.
+///
+/// The folders waiting for memory sleep on `freed` until bytes are removed, instead of checking
+/// again at intervals.
+#[derive(Debug, Default)]
+pub struct InFlight {
+    bytes: AtomicU64,
+    /// Held by a folder from its check to its wait, so a removal between the two is not missed
+    waiting: Mutex<()>,
+    freed: Condvar,
+}
+
+/// Released when dropped, see [reserve](struct.InFlight.html#method.reserve)
+#[derive(Debug)]
+pub struct Reservation<'a> {
+    in_flight: &'a InFlight,
+    bytes: u64,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.in_flight.sub(self.bytes);
+    }
+}
+
+impl InFlight {
+    pub const fn new() -> Self {
+        InFlight {
+            bytes: AtomicU64::new(0),
+            waiting: Mutex::new(()),
+            freed: Condvar::new(),
+        }
+    }
+
+    pub fn add(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Remove `bytes`, never below zero
+    pub fn sub(&self, bytes: u64) {
+        let _ = self
+            .bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+                Some(current.saturating_sub(bytes))
+            });
+        drop(self.waiting.lock().unwrap_or_else(PoisonError::into_inner));
+        self.freed.notify_all();
+    }
+
+    pub fn get(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Wait until `bytes` fit in `limit`, then count them until the reservation is dropped
+    ///
+    /// Without a limit the bytes are counted right away. When nothing else is in flight the bytes
+    /// are always counted, so a folder larger than the limit still runs, alone.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let _reservation = IN_FLIGHT.reserve(folder_size, config.max_memory);
+    /// process_folder(folder, &config, data_tx.clone());
+    /// ```
+    pub fn reserve(&self, bytes: u64, limit: Option<u64>) -> Reservation<'_> {
+        let mut waited = false;
+        let mut waiting = self.waiting.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            let current = self.get();
+            let fits = match limit {
+                Some(limit) => current == 0 || current + bytes <= limit,
+                None => true,
+            };
+            if fits {
+                let swapped = self.bytes.compare_exchange(
+                    current,
+                    current + bytes,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                );
+                if swapped.is_ok() {
+                    return Reservation {
+                        in_flight: self,
+                        bytes,
+                    };
+                }
+                continue;
+            }
+            if !waited {
+                tracing::debug!(in_flight = current, bytes, "Waiting for memory");
+                waited = true;
+            }
+            waiting = self
+                .freed
+                .wait(waiting)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+/// Bytes in flight for the whole run, the records add to it when they are sent and the writer
+/// removes them when they are written
+pub static IN_FLIGHT: InFlight = InFlight::new();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn test_reserve() {
+        let in_flight = InFlight::new();
+        {
+            // Larger than the limit, but nothing else is in flight
+            let _first = in_flight.reserve(200, Some(100));
+            assert_eq!(in_flight.get(), 200);
+        }
+        assert_eq!(in_flight.get(), 0);
+
+        let first = in_flight.reserve(60, Some(100));
+        let done = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let _second = in_flight.reserve(60, Some(100));
+                done.store(true, Ordering::SeqCst);
+            });
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            assert!(!done.load(Ordering::SeqCst));
+            drop(first);
+        });
+        assert!(done.load(Ordering::SeqCst));
+        assert_eq!(in_flight.get(), 0);
+    }
+}
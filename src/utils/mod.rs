@@ -1,3 +1,20 @@
+pub mod blocklist;
+pub mod dedup;
 pub mod file;
+pub mod histogram;
+pub mod json;
+pub mod kenlm;
+pub mod links;
+pub mod memory;
 pub mod processing;
+pub mod progress;
+pub mod quality;
+pub mod rejects;
+pub mod resume;
+#[cfg(feature = "tokenizers")]
+pub mod sentencepiece;
+pub mod shutdown;
+pub mod timing;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
 pub mod writer;
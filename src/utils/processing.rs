@@ -1,5 +1,12 @@
+use std::collections::BTreeMap;
+
+use crate::config::{Chunking, Config, Metadata, OutputContent, OversizedPolicy, ProvenanceTarget};
+use crate::forum_thread::{PostMetadata, Thread};
 use crate::globals;
 use crate::utils;
+use crate::utils::writer::{
+    Composition, PostsMetadata, RecordMetadata, Route, Split, ThreadMetadata, ThreadStats,
+};
 
 /// Text cleaning function
 ///
@@ -15,7 +22,7 @@ use crate::utils;
 ///
 /// # Example
 ///
-/// ```
+/// ```ignore
 /// let text = "hello--world".to_string();
 /// let cleaned_text = clean_text(&text);
 /// assert_eq!(cleaned_text, "hello world");
@@ -24,18 +31,108 @@ fn clean_text(text: String) -> String {
     let cleaned_text = globals::clean_content(&text);
     cleaned_text.trim().to_string()
 }
+
+/// The `metadata` object of a record from the metadata of its posts
+///
+/// # Arguments
+///
+/// * `posts` - `Vec<PostMetadata>` - The metadata of every post of the record, in order
+/// * `metadata` - `Metadata` - Arrays per field or an aggregate, `Metadata::None` gives `None`
+pub fn record_metadata(posts: Vec<PostMetadata>, metadata: Metadata) -> Option<RecordMetadata> {
+    match metadata {
+        Metadata::None => None,
+        Metadata::Posts => {
+            let mut arrays = PostsMetadata::default();
+            for post in posts {
+                arrays.author.push(post.author);
+                arrays.created_utc.push(post.created_utc);
+                arrays.score.push(post.score);
+            }
+            Some(RecordMetadata::Posts(arrays))
+        }
+        Metadata::Thread => {
+            let mut thread = ThreadMetadata::default();
+            for post in posts {
+                if let Some(created_utc) = post.created_utc {
+                    thread.first_created_utc = Some(
+                        thread
+                            .first_created_utc
+                            .map_or(created_utc, |t| t.min(created_utc)),
+                    );
+                    thread.last_created_utc = Some(
+                        thread
+                            .last_created_utc
+                            .map_or(created_utc, |t| t.max(created_utc)),
+                    );
+                }
+                if let Some(score) = post.score {
+                    thread.total_score = Some(thread.total_score.unwrap_or(0.0) + score);
+                    thread.max_score = Some(thread.max_score.map_or(score, |s| s.max(score)));
+                }
+                if let Some(author) = post.author {
+                    if !thread.authors.contains(&author) {
+                        thread.authors.push(author);
+                    }
+                }
+            }
+            Some(RecordMetadata::Thread(thread))
+        }
+    }
+}
+
+/// The `composition` object of a record from the metadata of its posts and their cleaned texts
+///
+/// The offsets are those of the texts joined by newlines, as in `raw_content`.
+///
+/// # Arguments
+///
+/// * `posts` - `&[PostMetadata]` - The metadata of every post of the record, in order
+/// * `texts` - `&[String]` - The cleaned text of every post, in the same order
+pub fn composition(posts: &[PostMetadata], texts: &[String]) -> Composition {
+    let mut composition = Composition::default();
+    let mut start = 0;
+    for (post, text) in posts.iter().zip(texts) {
+        composition.post_ids.push(post.id.clone());
+        composition.depths.push(post.depth);
+        composition.offsets.push((start, start + text.len()));
+        start += text.len() + 1;
+    }
+    composition
+}
+
+/// Count the length of each text
+///
+/// Uses the tokenizer stage if there is one, then the tokenizer if `use_sentencepiece` is true,
+/// otherwise counts the words split by whitespace. The texts are tokenized in a single batch.
+fn count_lengths(texts: &[String], config: &Config) -> Vec<usize> {
+    if let Some(tokenizer) = &config.stages.tokenizer {
+        return tokenizer(texts).iter().map(Vec::len).collect();
+    }
+    match config.use_sentencepiece {
+        true => globals::tokenize_batch(texts)
+            .iter()
+            .map(|encoding| encoding.len())
+            .collect(),
+        false => texts
+            .iter()
+            .map(|text| text.split_whitespace().count())
+            .collect(),
+    }
+}
+
 /// Process the thread content
 ///
-/// This function processes the thread content and returns a `ThreadPost` struct
+/// This function processes the thread content and returns a `ThreadPost` struct.
+/// It is a shorthand for [process_batch](fn.process_batch.html) with a single thread.
 ///
 /// # Arguments
 ///
 /// * `thread_id` - `String` - The thread id. This is the root of the thread.
 /// * `content` - `Vec<String>` - The content of the thread. This is the output from the DFS or BFS traversal,
-///     where each element represents a String that is a content of either a thread or a comment. For more info, check the output of the [traverse](../../graph/struct.ThreadGraph.html#method.traverse) function.
-/// * `forum_name` - `String` - The name of the forum. Used for tagging.
-/// * `use_sentencepiece` - `bool` - Whether to use a tokenizer for counting the number of tokens. If this is set to false,
-///     the function will count the number of words split by whitespace.
+///   where each element represents a String that is a content of either a thread or a comment. For more info, check the output of the [traverse](../../graph/struct.ThreadGraph.html#method.traverse) function.
+/// * `config` - `&Config` - The processing options. `use_sentencepiece` selects between the tokenizer and
+///   whitespace-split words for counting, `source` is used for tagging. If `post_lengths` is set,
+///   the length of each post is also recorded in the same unit.
 ///
 /// # Returns
 ///
@@ -43,34 +140,342 @@ fn clean_text(text: String) -> String {
 ///
 /// # Example
 ///
-/// ```
+/// ```ignore
 /// let thread_id = "1234".to_string();
 /// let content = vec!["thread root".to_string(), "comment".to_string()];
-/// let forum_name = "reddit".to_string();
-/// let use_sentencepiece = true;
-///
+/// let config = Config::default();
+/// let threadpost = process(thread_id, content, &config);
 /// ```
 pub fn process(
     thread_id: String,
     content: Vec<String>,
-    forum_name: String,
-    use_sentencepiece: bool,
+    config: &Config,
 ) -> utils::writer::ThreadPost {
-    let content: Vec<String> = content
+    process_batch(vec![(thread_id, content)], config)
+        .pop()
+        .expect("One thread in, one thread out")
+}
+
+/// Process a batch of threads
+///
+/// Same as [process](fn.process.html), but the documents of the whole batch are tokenized with a single
+/// `encode_batch` call, which lets the tokenizer parallelize internally.
+///
+/// The cleaner and tokenizer of `config.stages` replace the built-in ones when set.
+///
+/// # Arguments
+///
+/// * `threads` - `Vec<T: Into<Thread>>` - The threads, or tuples of the thread id and its content
+/// * `config` - `&Config` - The processing options
+///
+/// # Returns
+///
+/// * `Vec<ThreadPost>` - The processed threads, in the same order as the input
+pub fn process_batch<T: Into<Thread>>(
+    threads: Vec<T>,
+    config: &Config,
+) -> Vec<utils::writer::ThreadPost> {
+    process_batch_with_source(threads, config, &config.source)
+}
+
+/// Same as [process_batch](fn.process_batch.html), the records are labelled with `source` instead
+/// of `config.source`
+pub fn process_batch_with_source<T: Into<Thread>>(
+    threads: Vec<T>,
+    config: &Config,
+    source: &str,
+) -> Vec<utils::writer::ThreadPost> {
+    let mut thread_ids: Vec<String> = Vec::with_capacity(threads.len());
+    let mut posts: Vec<Vec<String>> = Vec::with_capacity(threads.len());
+    let mut stats: Vec<Option<ThreadStats>> = Vec::with_capacity(threads.len());
+    let mut metadata: Vec<Option<RecordMetadata>> = Vec::with_capacity(threads.len());
+    let mut compositions: Vec<Option<Composition>> = Vec::with_capacity(threads.len());
+    for thread in threads {
+        let thread: Thread = thread.into();
+        thread_ids.push(thread.id);
+        let mut texts: Vec<String> = match &config.stages.cleaner {
+            Some(cleaner) => thread.posts.iter().map(|post| cleaner(post)).collect(),
+            None => thread.posts.into_iter().map(clean_text).collect(),
+        };
+        let mut post_metadata = thread.metadata;
+        utils::quality::remove_lines(&mut texts, post_metadata.as_mut(), &config.quality);
+        posts.push(texts);
+        stats.push(thread.stats);
+        compositions.push(
+            post_metadata
+                .as_ref()
+                .filter(|_| config.traversal.composition)
+                .map(|post_metadata| composition(post_metadata, posts.last().unwrap())),
+        );
+        metadata.push(
+            post_metadata.and_then(|posts| record_metadata(posts, config.traversal.metadata)),
+        );
+    }
+    let contents: Vec<String> = posts.iter().map(|content| content.join("\n")).collect();
+    // Keep the token ids from the counting pass instead of tokenizing twice
+    let token_ids: Option<Vec<Vec<u32>>> = config
+        .stages
+        .tokenizer
+        .as_ref()
+        .map(|tokenizer| tokenizer(&contents));
+    let encodings = match config.use_sentencepiece && token_ids.is_none() {
+        true => Some(globals::tokenize_batch(&contents)),
+        false => None,
+    };
+    let lengths: Vec<usize> = match (&token_ids, &encodings) {
+        (Some(token_ids), _) => token_ids.iter().map(Vec::len).collect(),
+        (None, Some(encodings)) => encodings.iter().map(|encoding| encoding.len()).collect(),
+        (None, None) => count_lengths(&contents, config),
+    };
+
+    let mut threadposts: Vec<utils::writer::ThreadPost> = thread_ids
         .into_iter()
-        // .with_min_len(1000)
-        .map(clean_text)
+        .zip(contents)
+        .zip(lengths)
+        .zip(stats)
+        .zip(metadata)
+        .zip(compositions)
+        .map(
+            |(((((thread_id, content), length), stats), metadata), composition)| {
+                utils::writer::ThreadPost {
+                    length,
+                    perplexity: globals::perplexity(&content),
+                    raw_content: content,
+                    thread_id,
+                    source: source.to_string(),
+                    stats,
+                    metadata,
+                    composition,
+                    provenance: config
+                        .provenance
+                        .clone()
+                        .filter(|_| config.provenance_target != ProvenanceTarget::Manifest),
+                    ..Default::default()
+                }
+            },
+        )
         .collect();
-    let content = content.join("\n");
-    let length: usize = match use_sentencepiece {
-        true => globals::tokenize(&content).len(),
-        false => content.split_whitespace().count(),
+
+    if let (Some(max_tokens), Some(encodings)) = (config.max_tokens, &encodings) {
+        // The documents over the budget are cut on these offsets
+        for (threadpost, encoding) in threadposts.iter_mut().zip(encodings) {
+            if threadpost.length > max_tokens {
+                threadpost.token_offsets = Some(encoding.get_offsets().to_vec());
+            }
+        }
+    }
+
+    if config.output_content != OutputContent::Text {
+        if let Some(token_ids) = token_ids {
+            for (threadpost, ids) in threadposts.iter_mut().zip(token_ids) {
+                threadpost.input_ids = Some(ids);
+            }
+        } else if let Some(encodings) = encodings {
+            for (threadpost, encoding) in threadposts.iter_mut().zip(encodings) {
+                threadpost.input_ids = Some(encoding.get_ids().to_vec());
+            }
+        }
+    }
+
+    // With several tokenizers, every one of them gets its own length field
+    let labels = globals::tokenizer_labels();
+    if config.use_sentencepiece && config.stages.tokenizer.is_none() && labels.len() > 1 {
+        let contents: Vec<String> = threadposts
+            .iter()
+            .map(|threadpost| threadpost.raw_content.clone())
+            .collect();
+        for (index, label) in labels.iter().enumerate() {
+            let counts: Vec<usize> = match index {
+                0 => threadposts
+                    .iter()
+                    .map(|threadpost| threadpost.length)
+                    .collect(),
+                _ => globals::tokenize_batch_with(index, &contents)
+                    .iter()
+                    .map(|encoding| encoding.len())
+                    .collect(),
+            };
+            for (threadpost, count) in threadposts.iter_mut().zip(counts) {
+                threadpost
+                    .tokenizer_lengths
+                    .insert(format!("length_{}", label), count);
+            }
+        }
+    }
+
+    if config.post_lengths {
+        // Tokenize every post of the batch at once, then split the counts back per thread
+        let counts: Vec<usize> = posts.iter().map(Vec::len).collect();
+        let flat: Vec<String> = posts.into_iter().flatten().collect();
+        let mut flat_lengths = count_lengths(&flat, config).into_iter();
+        for (threadpost, count) in threadposts.iter_mut().zip(counts) {
+            threadpost.post_lengths = Some(flat_lengths.by_ref().take(count).collect());
+        }
+    }
+
+    threadposts
+}
+
+/// Byte spans of each token in the text
+///
+/// Uses the tokenizer offsets if `use_sentencepiece` is true, otherwise the words split by whitespace.
+/// The offsets of a record from [process_batch](fn.process_batch.html) are already kept in
+/// `token_offsets`, this is for the records built otherwise.
+fn token_spans(text: &str, use_sentencepiece: bool) -> Vec<(usize, usize)> {
+    match use_sentencepiece {
+        true => globals::tokenize(text).get_offsets().to_vec(),
+        false => text
+            .split_whitespace()
+            .map(|word| {
+                let start = word.as_ptr() as usize - text.as_ptr() as usize;
+                (start, start + word.len())
+            })
+            .collect(),
+    }
+}
+
+/// Apply the token budget to a processed thread
+///
+/// If `max_tokens` is set and the thread is longer, it is either truncated at a token boundary or
+/// split into several records, each starting `chunk_overlap` tokens before the end of the previous one.
+/// Per-post lengths and the lengths of other tokenizers no longer line up with the chunks, so they are
+/// dropped from cut records. The `metadata` of every chunk is the one of the whole document, its
+/// `composition` only has the posts in the chunk, with the offsets into the chunk.
+///
+/// # Arguments
+///
+/// * `threadpost` - `ThreadPost` - The output of [process](fn.process.html)
+/// * `config` - `&Config` - The processing options with `max_tokens`, `chunking` and `chunk_overlap`
+///
+/// # Returns
+///
+/// * `Vec<ThreadPost>` - The records to write, a single record if the thread fits the budget
+pub fn apply_token_budget(
+    mut threadpost: utils::writer::ThreadPost,
+    config: &Config,
+) -> Vec<utils::writer::ThreadPost> {
+    let max_tokens = match config.max_tokens {
+        Some(max_tokens) if threadpost.length > max_tokens && max_tokens > 0 => max_tokens,
+        _ => return vec![threadpost],
     };
-    utils::writer::ThreadPost {
-        length,
-        raw_content: content,
-        thread_id,
-        source: forum_name,
+    let spans = match threadpost.token_offsets.take() {
+        Some(spans) => spans,
+        None => token_spans(&threadpost.raw_content, config.use_sentencepiece),
+    };
+    // `pipeline::run` rejects an overlap of `max_tokens` or more, the step stays positive for
+    // the other callers
+    let step = max_tokens.saturating_sub(config.chunk_overlap).max(1);
+
+    let mut chunks: Vec<utils::writer::ThreadPost> = Vec::new();
+    let mut start = 0;
+    while start < spans.len() {
+        let end = (start + max_tokens).min(spans.len());
+        let (from, to) = (spans[start].0, spans[end - 1].1);
+        let raw_content = threadpost.raw_content[from..to].to_string();
+        let chunk = match config.chunking {
+            Chunking::Truncate => None,
+            Chunking::Split => Some(chunks.len()),
+        };
+        chunks.push(utils::writer::ThreadPost {
+            length: end - start,
+            raw_content,
+            post_lengths: None,
+            tokenizer_lengths: BTreeMap::new(),
+            chunk,
+            composition: threadpost
+                .composition
+                .as_ref()
+                .map(|composition| composition.slice(from, to)),
+            input_ids: threadpost
+                .input_ids
+                .as_ref()
+                .map(|ids| ids[start..end].to_vec()),
+            ..threadpost.clone()
+        });
+        if config.chunking == Chunking::Truncate || end == spans.len() {
+            break;
+        }
+        start += step;
+    }
+    chunks
+}
+
+/// Check if a processed thread should be written
+///
+/// # Arguments
+///
+/// * `threadpost` - `&ThreadPost` - The output of [process](fn.process.html)
+/// * `config` - `&Config` - The processing options with the filter thresholds
+///
+/// # Returns
+///
+/// * `bool` - `true` if the thread passes every configured filter
+pub fn keep(threadpost: &utils::writer::ThreadPost, config: &Config) -> bool {
+    if let (Some(max), Some(perplexity)) = (config.max_perplexity, threadpost.perplexity) {
+        if perplexity > max {
+            return false;
+        }
+    }
+    true
+}
+
+/// Decide which output file a processed thread goes to
+///
+/// # Arguments
+///
+/// * `threadpost` - `&ThreadPost` - A record that passed [keep](fn.keep.html)
+/// * `config` - `&Config` - The processing options with `oversized_tokens`, `oversized`,
+///   `perplexity_buckets` and `val_fraction`
+///
+/// # Returns
+///
+/// * `Option<Route>` - The route to write to, `None` if the record is dropped
+pub fn route(threadpost: &utils::writer::ThreadPost, config: &Config) -> Option<Route> {
+    let route = match config.oversized_tokens {
+        Some(max) if threadpost.length > max => match config.oversized {
+            OversizedPolicy::Route => Route::Oversized,
+            OversizedPolicy::Drop => return None,
+        },
+        _ => Route::Main,
+    };
+    // The bucket is the number of thresholds the perplexity is at or over
+    let route = match threadpost.perplexity {
+        Some(perplexity) if !config.perplexity_buckets.is_empty() => {
+            let bucket = config
+                .perplexity_buckets
+                .iter()
+                .take_while(|&&threshold| perplexity >= threshold)
+                .count();
+            Route::Perplexity(bucket, Box::new(route))
+        }
+        _ => route,
+    };
+    match config.val_fraction {
+        Some(fraction) => Some(Route::Split(
+            split_of(&threadpost.thread_id, fraction),
+            Box::new(route),
+        )),
+        None => Some(route),
+    }
+}
+
+/// Train or validation split of a thread
+///
+/// The suffixes of continuation documents (`-1`) and paths (`/reply`) are removed first, so every
+/// record of a thread is in the same split. The id is hashed with FNV-1a, which does not change
+/// between runs, builds or platforms.
+pub fn split_of(thread_id: &str, val_fraction: f64) -> Split {
+    let root = thread_id.split('/').next().unwrap_or(thread_id);
+    let root = match root.rsplit_once('-') {
+        Some((root, part)) if part.bytes().all(|b| b.is_ascii_digit()) => root,
+        _ => root,
+    };
+    let hash = root.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    match (hash as f64 / u64::MAX as f64) < val_fraction {
+        true => Split::Val,
+        false => Split::Train,
     }
 }
 
@@ -135,4 +540,325 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_post_lengths() {
+        globals::init_regex();
+        let content = vec!["thread root".to_string(), "a  reply here".to_string()];
+        let config = Config {
+            post_lengths: true,
+            ..Default::default()
+        };
+        let threadpost = process("1".to_string(), content.clone(), &config);
+        assert_eq!(threadpost.length, 5);
+        assert_eq!(threadpost.post_lengths, Some(vec![2, 3]));
+
+        let threadpost = process("1".to_string(), content, &Config::default());
+        assert_eq!(threadpost.post_lengths, None);
+    }
+
+    #[test]
+    fn test_process_batch() {
+        globals::init_regex();
+        let threads = vec![
+            (
+                "1".to_string(),
+                vec!["one two".to_string(), "three".to_string()],
+            ),
+            ("2".to_string(), vec!["four".to_string()]),
+        ];
+        let config = Config {
+            post_lengths: true,
+            ..Default::default()
+        };
+        let threadposts = process_batch(threads, &config);
+        assert_eq!(threadposts.len(), 2);
+        assert_eq!(threadposts[0].thread_id, "1");
+        assert_eq!(threadposts[0].raw_content, "one two\nthree");
+        assert_eq!(threadposts[0].post_lengths, Some(vec![2, 1]));
+        assert_eq!(threadposts[1].thread_id, "2");
+        assert_eq!(threadposts[1].post_lengths, Some(vec![1]));
+    }
+
+    #[test]
+    fn test_record_metadata() {
+        let posts = vec![
+            PostMetadata {
+                author: Some("a".to_string()),
+                created_utc: Some(20.0),
+                score: Some(3.0),
+                ..Default::default()
+            },
+            PostMetadata::default(),
+            PostMetadata {
+                author: Some("b".to_string()),
+                created_utc: Some(10.0),
+                score: Some(-1.0),
+                ..Default::default()
+            },
+            PostMetadata {
+                author: Some("a".to_string()),
+                created_utc: None,
+                score: None,
+                ..Default::default()
+            },
+        ];
+        assert_eq!(record_metadata(posts.clone(), Metadata::None), None);
+        assert_eq!(
+            record_metadata(posts.clone(), Metadata::Thread),
+            Some(RecordMetadata::Thread(ThreadMetadata {
+                first_created_utc: Some(10.0),
+                last_created_utc: Some(20.0),
+                total_score: Some(2.0),
+                max_score: Some(3.0),
+                authors: vec!["a".to_string(), "b".to_string()],
+            }))
+        );
+        let threadpost = utils::writer::ThreadPost {
+            metadata: record_metadata(posts, Metadata::Posts),
+            ..Default::default()
+        };
+        let record: serde_json::Value = serde_json::to_value(&threadpost).unwrap();
+        assert_eq!(
+            record["metadata"],
+            serde_json::json!({
+                "author": ["a", null, "b", "a"],
+                "created_utc": [20.0, null, 10.0, null],
+                "score": [3.0, null, -1.0, null],
+            })
+        );
+        assert_eq!(
+            record_metadata(Vec::new(), Metadata::Thread),
+            Some(RecordMetadata::Thread(ThreadMetadata::default()))
+        );
+    }
+
+    #[test]
+    fn test_composition() {
+        let post = |id: Option<&str>, depth: Option<usize>| PostMetadata {
+            id: id.map(str::to_string),
+            depth,
+            ..Default::default()
+        };
+        let thread = Thread {
+            id: "1".to_string(),
+            posts: vec!["One two".to_string(), "".to_string(), "three".to_string()],
+            metadata: Some(vec![
+                post(Some("1"), Some(0)),
+                post(None, None),
+                post(Some("3"), Some(1)),
+            ]),
+            ..Default::default()
+        };
+        let mut config = Config {
+            stages: crate::config::Stages {
+                cleaner: Some(std::sync::Arc::new(|text: &str| text.to_string())),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            process_batch(vec![thread.clone()], &config)[0].composition,
+            None
+        );
+
+        config.traversal.composition = true;
+        let threadpost = process_batch(vec![thread], &config).pop().unwrap();
+        let composition = threadpost.composition.clone().unwrap();
+        assert_eq!(
+            composition.post_ids,
+            vec![Some("1".to_string()), None, Some("3".to_string())]
+        );
+        assert_eq!(composition.depths, vec![Some(0), None, Some(1)]);
+        assert_eq!(composition.offsets, vec![(0, 7), (8, 8), (9, 14)]);
+        for (i, &(start, end)) in composition.offsets.iter().enumerate() {
+            assert_eq!(
+                &threadpost.raw_content[start..end],
+                ["One two", "", "three"][i]
+            );
+        }
+
+        // Every chunk has the posts it holds, with offsets into the chunk
+        config.max_tokens = Some(1);
+        config.chunking = Chunking::Split;
+        let chunks = apply_token_budget(threadpost, &config);
+        let offsets: Vec<_> = chunks
+            .iter()
+            .map(|chunk| chunk.composition.clone().unwrap())
+            .map(|composition| (composition.post_ids, composition.offsets))
+            .collect();
+        assert_eq!(
+            offsets,
+            vec![
+                (vec![Some("1".to_string())], vec![(0, 3)]),
+                (vec![Some("1".to_string())], vec![(0, 3)]),
+                (vec![Some("3".to_string())], vec![(0, 5)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stages() {
+        let threads = vec![(
+            "1".to_string(),
+            vec!["One two".to_string(), "three".to_string()],
+        )];
+        let config = Config {
+            post_lengths: true,
+            output_content: OutputContent::Both,
+            stages: crate::config::Stages {
+                cleaner: Some(std::sync::Arc::new(|text: &str| text.to_lowercase())),
+                tokenizer: Some(std::sync::Arc::new(|texts: &[String]| {
+                    texts
+                        .iter()
+                        .map(|text| text.bytes().map(u32::from).collect())
+                        .collect()
+                })),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let threadposts = process_batch(threads, &config);
+        assert_eq!(threadposts[0].raw_content, "one two\nthree");
+        assert_eq!(threadposts[0].length, 13);
+        assert_eq!(threadposts[0].post_lengths, Some(vec![7, 5]));
+        assert_eq!(
+            threadposts[0].input_ids.as_ref().unwrap()[0],
+            u32::from(b'o')
+        );
+    }
+
+    #[test]
+    fn test_token_budget() {
+        let threadpost = utils::writer::ThreadPost {
+            length: 5,
+            raw_content: "one two\nthree four five".to_string(),
+            thread_id: "1".to_string(),
+            ..Default::default()
+        };
+        // Fits the budget
+        let config = Config {
+            max_tokens: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(apply_token_budget(threadpost.clone(), &config).len(), 1);
+
+        let config = Config {
+            max_tokens: Some(2),
+            ..Default::default()
+        };
+        let truncated = apply_token_budget(threadpost.clone(), &config);
+        assert_eq!(truncated.len(), 1);
+        assert_eq!(truncated[0].raw_content, "one two");
+        assert_eq!(truncated[0].chunk, None);
+
+        let config = Config {
+            max_tokens: Some(2),
+            chunking: Chunking::Split,
+            ..Default::default()
+        };
+        let chunks: Vec<String> = apply_token_budget(threadpost.clone(), &config)
+            .into_iter()
+            .map(|chunk| chunk.raw_content)
+            .collect();
+        assert_eq!(chunks, vec!["one two", "three four", "five"]);
+
+        let config = Config {
+            max_tokens: Some(3),
+            chunking: Chunking::Split,
+            chunk_overlap: 1,
+            ..Default::default()
+        };
+        let chunks = apply_token_budget(threadpost.clone(), &config);
+        assert_eq!(chunks[0].raw_content, "one two\nthree");
+        assert_eq!(chunks[1].raw_content, "three four five");
+        assert_eq!(chunks[1].chunk, Some(1));
+        assert_eq!(chunks[1].length, 3);
+
+        // The offsets of the batch are used as they are, the text is not split again
+        let threadpost = utils::writer::ThreadPost {
+            token_offsets: Some(vec![(0, 3), (3, 7), (7, 13), (13, 18), (18, 23)]),
+            ..threadpost
+        };
+        let chunks: Vec<String> = apply_token_budget(threadpost, &config)
+            .into_iter()
+            .map(|chunk| chunk.raw_content)
+            .collect();
+        assert_eq!(chunks, vec!["one two\nthree", "\nthree four five"]);
+    }
+
+    #[test]
+    fn test_route() {
+        let threadpost = utils::writer::ThreadPost {
+            length: 10,
+            ..Default::default()
+        };
+        assert_eq!(route(&threadpost, &Config::default()), Some(Route::Main));
+
+        let mut config = Config {
+            oversized_tokens: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(route(&threadpost, &config), Some(Route::Main));
+        config.oversized_tokens = Some(9);
+        assert_eq!(route(&threadpost, &config), Some(Route::Oversized));
+        config.oversized = OversizedPolicy::Drop;
+        assert_eq!(route(&threadpost, &config), None);
+
+        let config = Config {
+            perplexity_buckets: vec![100.0, 500.0],
+            ..Default::default()
+        };
+        // Without a score the record is not in a bucket
+        assert_eq!(route(&threadpost, &config), Some(Route::Main));
+        let bucket = |perplexity: f64| {
+            let threadpost = utils::writer::ThreadPost {
+                perplexity: Some(perplexity),
+                ..threadpost.clone()
+            };
+            match route(&threadpost, &config) {
+                Some(Route::Perplexity(bucket, route)) if *route == Route::Main => bucket,
+                other => panic!("expected a perplexity bucket, got {:?}", other),
+            }
+        };
+        assert_eq!((bucket(99.0), bucket(100.0), bucket(700.0)), (0, 1, 2));
+        let route = Route::Perplexity(2, Box::new(Route::Oversized));
+        assert_eq!(
+            route.path(),
+            std::path::Path::new("perplexity_2/oversized.jsonl")
+        );
+        let outputs = Route::outputs(&config);
+        assert_eq!(outputs.len(), 24);
+        assert!(outputs.contains(&Route::Split(Split::Val, Box::new(route))));
+    }
+
+    #[test]
+    fn test_split_of() {
+        assert_eq!(split_of("abc", 0.0), Split::Train);
+        assert_eq!(split_of("abc", 1.0), Split::Val);
+        let ids: Vec<String> = (0..1000).map(|i| i.to_string()).collect();
+        let val = ids
+            .iter()
+            .filter(|id| split_of(id, 0.1) == Split::Val)
+            .count();
+        assert!((50..150).contains(&val));
+        // The parts of a thread stay together
+        for id in &ids {
+            let split = split_of(id, 0.5);
+            assert_eq!(split_of(&format!("{}-2", id), 0.5), split);
+            assert_eq!(split_of(&format!("{}/x", id), 0.5), split);
+        }
+
+        let threadpost = utils::writer::ThreadPost {
+            thread_id: "abc".to_string(),
+            ..Default::default()
+        };
+        let config = Config {
+            val_fraction: Some(1.0),
+            ..Default::default()
+        };
+        let route = route(&threadpost, &config).unwrap();
+        assert_eq!(route, Route::Split(Split::Val, Box::new(Route::Main)));
+        assert_eq!(route.path(), std::path::Path::new("val/all.jsonl"));
+    }
 }
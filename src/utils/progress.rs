@@ -0,0 +1,223 @@
+use serde::Serialize;
+use std::time::Duration;
+
+/// Time between two updates of the progress line
+pub const BAR_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Time between two JSON progress lines, slower than the bar so the logs stay small
+pub const JSON_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Folders, and the bytes of their files, done or in total
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Work {
+    pub folders: usize,
+    pub bytes: u64,
+}
+
+/// Counters of the run at one point in time
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counters {
+    /// Posts parsed from the input
+    pub posts_read: u64,
+    /// Bytes of the input lines parsed
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+/// Throughput between two snapshots
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rates {
+    pub posts_per_sec: f64,
+    pub read_mb_per_sec: f64,
+    pub write_mb_per_sec: f64,
+}
+
+impl Rates {
+    /// Rates from `previous` to `current`, over `seconds`
+    fn between(previous: &Counters, current: &Counters, seconds: f64) -> Self {
+        if seconds <= 0.0 {
+            return Rates::default();
+        }
+        let per_sec = |before: u64, after: u64| after.saturating_sub(before) as f64 / seconds;
+        let mb = 1024_f64.powi(2);
+        Rates {
+            posts_per_sec: per_sec(previous.posts_read, current.posts_read),
+            read_mb_per_sec: per_sec(previous.bytes_read, current.bytes_read) / mb,
+            write_mb_per_sec: per_sec(previous.bytes_written, current.bytes_written) / mb,
+        }
+    }
+}
+
+/// Snapshot of a run, printed by the progress thread
+///
+/// # Example
+///
+/// ```ignore
+/// let progress = Progress::new(done, total, queue.len(), counters, start.elapsed())
+///     .with_rates(previous.as_ref());
+/// eprintln!("{}", serde_json::to_string(&progress).unwrap());
+/// ```
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Progress {
+    pub folders_done: usize,
+    pub folders_total: usize,
+    /// Size of the input files of the folders done
+    pub input_bytes_done: u64,
+    pub input_bytes_total: u64,
+    /// Messages waiting for the writer, a batch of records or a record of a custom sink
+    pub queue: usize,
+    #[serde(flatten)]
+    pub counters: Counters,
+    /// Since the previous snapshot, to see which stage is slowing down
+    #[serde(flatten)]
+    pub rates: Rates,
+    pub elapsed_secs: u64,
+    /// Seconds left at the average time per input byte so far, or per folder when the folders are
+    /// empty, `None` before the first folder is done
+    pub eta_secs: Option<u64>,
+    #[serde(skip)]
+    elapsed: Duration,
+}
+
+impl Progress {
+    pub fn new(
+        done: Work,
+        total: Work,
+        queue: usize,
+        counters: Counters,
+        elapsed: Duration,
+    ) -> Self {
+        // Folder sizes can vary by orders of magnitude, bytes give a far better estimate
+        let (done_units, total_units) = match (done.bytes, total.bytes) {
+            (_, 0) => (done.folders as f64, total.folders as f64),
+            (done, total) => (done as f64, total as f64),
+        };
+        let eta_secs = match (done.folders, done_units > 0.0) {
+            (0, _) | (_, false) => None,
+            _ => {
+                let left = (total_units - done_units).max(0.0);
+                Some((elapsed.as_secs_f64() / done_units * left).round() as u64)
+            }
+        };
+        Progress {
+            folders_done: done.folders,
+            folders_total: total.folders,
+            input_bytes_done: done.bytes,
+            input_bytes_total: total.bytes,
+            queue,
+            counters,
+            rates: Rates::default(),
+            elapsed_secs: elapsed.as_secs(),
+            eta_secs,
+            elapsed,
+        }
+    }
+
+    /// Fill the rates since the `previous` snapshot, or since the start without one
+    pub fn with_rates(mut self, previous: Option<&Progress>) -> Self {
+        self.rates = match previous {
+            Some(previous) => Rates::between(
+                &previous.counters,
+                &self.counters,
+                self.elapsed.saturating_sub(previous.elapsed).as_secs_f64(),
+            ),
+            None => Rates::between(
+                &Counters::default(),
+                &self.counters,
+                self.elapsed.as_secs_f64(),
+            ),
+        };
+        self
+    }
+
+    /// Share of the input done, by size
+    pub fn percent(&self) -> f64 {
+        match self.input_bytes_total {
+            0 => 100.0 * self.folders_done as f64 / self.folders_total.max(1) as f64,
+            total => 100.0 * self.input_bytes_done as f64 / total as f64,
+        }
+    }
+
+    /// The progress line, without the carriage return
+    pub fn line(&self) -> String {
+        let eta = match self.eta_secs {
+            Some(eta) => format!(" ETA {}m {:02}s.", eta / 60, eta % 60),
+            None => String::new(),
+        };
+        format!(
+            "Processed {}/{} folders ({:.1}%).{} Queue to write: {}. {:.0} posts/s, read {:.1} MB/s, written {:.1} MB/s. Current duration: {:2}m {:.2}s",
+            self.folders_done,
+            self.folders_total,
+            self.percent(),
+            eta,
+            self.queue,
+            self.rates.posts_per_sec,
+            self.rates.read_mb_per_sec,
+            self.rates.write_mb_per_sec,
+            self.elapsed_secs / 60,
+            self.elapsed_secs % 60
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_progress() {
+        let total = Work {
+            folders: 4,
+            bytes: 1000,
+        };
+        let none = Work::default();
+        let progress = Progress::new(
+            none,
+            total,
+            10,
+            Counters::default(),
+            Duration::from_secs(30),
+        );
+        assert_eq!(progress.eta_secs, None);
+
+        let counters = Counters {
+            posts_read: 6000,
+            bytes_read: 6 * 1024 * 1024,
+            bytes_written: 2048,
+        };
+        // The largest folder first, a tenth of the input in 90 seconds
+        let done = Work {
+            folders: 1,
+            bytes: 100,
+        };
+        let progress = Progress::new(done, total, 10, counters, Duration::from_secs(90))
+            .with_rates(Some(&progress));
+        assert_eq!(progress.eta_secs, Some(810));
+        assert_eq!(progress.rates.posts_per_sec, 100.0);
+        assert_eq!(progress.rates.read_mb_per_sec, 0.1);
+        assert_eq!(
+            progress.line(),
+            "Processed 1/4 folders (10.0%). ETA 13m 30s. Queue to write: 10. 100 posts/s, read 0.1 MB/s, written 0.0 MB/s. Current duration:  1m 30s"
+        );
+        let json: serde_json::Value = serde_json::to_value(&progress).unwrap();
+        assert_eq!(json["bytes_written"], 2048);
+        assert_eq!(json["folders_total"], 4);
+        assert_eq!(json["posts_per_sec"], 100.0);
+        assert_eq!(json["input_bytes_total"], 1000);
+        assert!(json.get("elapsed").is_none());
+
+        // Empty folders, the ETA is per folder
+        let total = Work {
+            folders: 4,
+            bytes: 0,
+        };
+        let done = Work {
+            folders: 1,
+            bytes: 0,
+        };
+        let progress = Progress::new(done, total, 0, counters, Duration::from_secs(90));
+        assert_eq!(progress.eta_secs, Some(270));
+        assert_eq!(progress.percent(), 25.0);
+    }
+}
@@ -0,0 +1,684 @@
+//! Heuristic quality filters on the cleaned text, before it is tokenized
+//!
+//! The [C4 rules](https://arxiv.org/abs/1910.10683) of `--c4` either remove lines of a document
+//! or drop the whole document. The cleaning joins the whitespace of a post, so a line is usually a
+//! post, a post left without lines is removed along with its metadata. The line rules run first,
+//! the document rules see what is left of the document. A document left without lines is dropped
+//! as `no_lines`.
+//!
+//! `--repeated-lines` removes the lines that repeat an earlier line of the same document, after
+//! the C4 line rules. A near repeat shares enough word trigrams with an earlier line, the
+//! candidates are found with a MinHash of the trigrams and then compared in full. The first copy
+//! is kept. The share of the characters removed is counted for the summary.
+//!
+//! The [Gopher rules](https://arxiv.org/abs/2112.11446) of `--gopher` then drop the documents
+//! whose words, symbols or lines do not look like prose, on the text left by the C4 rules.
+//!
+//! `--max-symbol-ratio` and `--max-non-alphanumeric` last drop the documents made mostly of
+//! symbols, the ASCII art, code dumps and emoticon floods the cleaning leaves. A symbol is a
+//! character that is neither a letter, a digit, whitespace nor
+//! [punctuation](constant.PUNCTUATION.html), emoji are symbols. The share of non alphanumeric
+//! characters leaves out the whitespace.
+//!
+//! The dropped documents are rejects with the reason `quality` and the rule as the error, the
+//! lines removed and the documents dropped by every rule are counted for the summary.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use rustc_hash::{FxBuildHasher, FxHasher};
+
+use crate::config::{C4Rule, GopherRule, QualityOptions, RepeatedLines};
+use crate::forum_thread::PostMetadata;
+
+/// A line must end with one of these, with `C4Rule::TerminalPunctuation`
+const END_MARKS: [char; 4] = ['.', '!', '?', '"'];
+
+const MIN_LINE_WORDS: usize = 5;
+
+const MIN_SENTENCES: usize = 3;
+
+/// A line with one of these is removed, with `C4Rule::Policy`
+const POLICY: [&str; 6] = [
+    "terms of use",
+    "privacy policy",
+    "cookie policy",
+    "uses cookies",
+    "use of cookies",
+    "use cookies",
+];
+
+const MIN_WORDS: usize = 50;
+
+const MAX_WORDS: usize = 100_000;
+
+const MIN_MEAN_WORD_LENGTH: f64 = 3.0;
+
+const MAX_MEAN_WORD_LENGTH: f64 = 10.0;
+
+/// Most `#` and ellipses per word, with `GopherRule::SymbolRatio`
+const MAX_SYMBOL_RATIO: f64 = 0.1;
+
+const MAX_BULLET_LINES: f64 = 0.9;
+
+const MAX_ELLIPSIS_LINES: f64 = 0.3;
+
+const MIN_ALPHABETIC_WORDS: f64 = 0.8;
+
+/// A line starting with one of these is a bullet point
+const BULLETS: [char; 7] = ['•', '●', '‣', '◦', '▪', '-', '*'];
+
+/// A document needs at least 2 of these, with `GopherRule::StopWords`
+const STOP_WORDS: [&str; 8] = ["the", "be", "to", "of", "and", "that", "have", "with"];
+
+/// Not symbols, for `max_symbol_ratio`
+pub const PUNCTUATION: [char; 12] = ['.', ',', '!', '?', ';', ':', '\'', '"', '-', '(', ')', '’'];
+
+/// Number of MinHash values of a line, in `BANDS` bands
+const SIGNATURE: usize = 32;
+
+/// Lines with the same values in one of the bands are compared, 8 bands of 4 values find
+/// nearly all the pairs over 0.8 similar
+const BANDS: usize = 8;
+
+/// Lines removed so far, by C4 rule
+static LINES: [AtomicU64; C4Rule::ALL.len()] = [const { AtomicU64::new(0) }; C4Rule::ALL.len()];
+
+/// Documents dropped so far, by C4 rule
+static DROPPED: [AtomicU64; C4Rule::ALL.len()] = [const { AtomicU64::new(0) }; C4Rule::ALL.len()];
+
+/// Documents dropped so far, by Gopher rule
+static GOPHER_DROPPED: [AtomicU64; GopherRule::ALL.len()] =
+    [const { AtomicU64::new(0) }; GopherRule::ALL.len()];
+
+/// Documents dropped so far because the line rules left nothing of them
+static NO_LINES: AtomicU64 = AtomicU64::new(0);
+
+/// Documents dropped so far by `max_symbol_ratio` and `max_non_alphanumeric`
+static SYMBOLS_DROPPED: AtomicU64 = AtomicU64::new(0);
+static NON_ALPHANUMERIC_DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Lines removed so far as repeats
+static REPEATED_LINES: AtomicU64 = AtomicU64::new(0);
+
+/// Characters of the lines removed as repeats so far, and of all the lines checked for repeats
+static REPEATED_CHARS: AtomicU64 = AtomicU64::new(0);
+static CHECKED_CHARS: AtomicU64 = AtomicU64::new(0);
+
+/// Remove the lines of the posts of a document that fail a line rule or repeat an earlier line
+///
+/// # Arguments
+///
+/// * `posts` - `&mut Vec<String>` - The cleaned texts of the posts, the posts left without lines
+///   are removed
+/// * `metadata` - `Option<&mut Vec<PostMetadata>>` - The metadata of the posts, kept in step
+/// * `quality` - `&QualityOptions` - The rules to apply
+pub fn remove_lines(
+    posts: &mut Vec<String>,
+    metadata: Option<&mut Vec<PostMetadata>>,
+    quality: &QualityOptions,
+) {
+    let rules: Vec<C4Rule> = C4Rule::ALL
+        .into_iter()
+        .filter(|rule| rule.removes_lines() && quality.c4.contains(rule))
+        .collect();
+    if rules.is_empty() && quality.repeated_lines == RepeatedLines::None {
+        return;
+    }
+    let mut repeats = Repeats::new(quality);
+    let mut kept = Vec::with_capacity(posts.len());
+    for post in posts.iter_mut() {
+        let mut removed = false;
+        let lines: Vec<&str> = post
+            .lines()
+            .filter(|line| {
+                let keep = match failed_line_rule(line, &rules) {
+                    Some(rule) => {
+                        LINES[rule as usize].fetch_add(1, Ordering::Relaxed);
+                        false
+                    }
+                    None => !repeats.is_repeat(line),
+                };
+                removed |= !keep;
+                keep
+            })
+            .collect();
+        if removed {
+            *post = lines.join("\n");
+        }
+        kept.push(!post.is_empty());
+    }
+    repeats.count();
+    let mut keep = kept.iter().copied();
+    posts.retain(|_| keep.next().unwrap());
+    if let Some(metadata) = metadata {
+        let mut keep = kept.iter().copied();
+        metadata.retain(|_| keep.next().unwrap_or(true));
+    }
+}
+
+/// The first of `rules` the line fails
+fn failed_line_rule(line: &str, rules: &[C4Rule]) -> Option<C4Rule> {
+    let line = line.trim();
+    let lowercase = || line.to_lowercase();
+    rules.iter().copied().find(|rule| match rule {
+        C4Rule::TerminalPunctuation => {
+            !line.ends_with(END_MARKS) || line.ends_with("...") || line.ends_with('…')
+        }
+        C4Rule::LineWords => line.split_whitespace().count() < MIN_LINE_WORDS,
+        C4Rule::Javascript => lowercase().contains("javascript"),
+        C4Rule::Policy => {
+            let line = lowercase();
+            POLICY.iter().any(|policy| line.contains(policy))
+        }
+        _ => false,
+    })
+}
+
+/// The lines of a document seen so far, to find the repeats
+struct Repeats {
+    mode: RepeatedLines,
+    similarity: f64,
+    hashes: HashSet<u64, FxBuildHasher>,
+    /// Word trigrams of every line kept, for the near repeats
+    trigrams: Vec<HashSet<u64, FxBuildHasher>>,
+    /// Lines kept by band and values of their MinHash in the band
+    bands: HashMap<(usize, u64), Vec<usize>, FxBuildHasher>,
+    lines: u64,
+    chars: u64,
+    removed: u64,
+}
+
+impl Repeats {
+    fn new(quality: &QualityOptions) -> Self {
+        Repeats {
+            mode: quality.repeated_lines,
+            similarity: quality.repeated_line_similarity,
+            hashes: HashSet::default(),
+            trigrams: Vec::new(),
+            bands: HashMap::default(),
+            lines: 0,
+            chars: 0,
+            removed: 0,
+        }
+    }
+
+    /// Whether the line repeats a line seen before, it is remembered if not
+    fn is_repeat(&mut self, line: &str) -> bool {
+        if self.mode == RepeatedLines::None {
+            return false;
+        }
+        let line = line.trim();
+        let chars = line.chars().count() as u64;
+        self.chars += chars;
+        let repeat = !self.hashes.insert(hash(line, 0))
+            || (self.mode == RepeatedLines::Near && self.is_near_repeat(line));
+        if repeat {
+            self.lines += 1;
+            self.removed += chars;
+        }
+        repeat
+    }
+
+    fn is_near_repeat(&mut self, line: &str) -> bool {
+        let words: Vec<String> = line
+            .split_whitespace()
+            .map(|word| {
+                word.trim_matches(|c: char| !c.is_alphanumeric())
+                    .to_lowercase()
+            })
+            .filter(|word| !word.is_empty())
+            .collect();
+        if words.is_empty() {
+            return false;
+        }
+        let trigrams: HashSet<u64, FxBuildHasher> = words
+            .windows(3.min(words.len()))
+            .map(|trigram| hash(trigram, 0))
+            .collect();
+        let signature = minhash(&trigrams);
+        let rows = SIGNATURE / BANDS;
+        let keys: Vec<(usize, u64)> = signature
+            .chunks(rows)
+            .enumerate()
+            .map(|(band, values)| (band, hash(values, 0)))
+            .collect();
+        let mut candidates: Vec<usize> = keys
+            .iter()
+            .filter_map(|key| self.bands.get(key))
+            .flatten()
+            .copied()
+            .collect();
+        candidates.sort_unstable();
+        candidates.dedup();
+        let repeat = candidates
+            .into_iter()
+            .any(|line| jaccard(&trigrams, &self.trigrams[line]) >= self.similarity);
+        if !repeat {
+            let line = self.trigrams.len();
+            for key in keys {
+                self.bands.entry(key).or_default().push(line);
+            }
+            self.trigrams.push(trigrams);
+        }
+        repeat
+    }
+
+    /// Add the repeats of the document to the counts of the run
+    fn count(&self) {
+        if self.mode == RepeatedLines::None {
+            return;
+        }
+        REPEATED_LINES.fetch_add(self.lines, Ordering::Relaxed);
+        REPEATED_CHARS.fetch_add(self.removed, Ordering::Relaxed);
+        CHECKED_CHARS.fetch_add(self.chars, Ordering::Relaxed);
+    }
+}
+
+fn hash<T: Hash + ?Sized>(value: &T, seed: u64) -> u64 {
+    let mut hasher = FxHasher::default();
+    seed.hash(&mut hasher);
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The smallest value of every permutation of the hashes, each permutation mixes the hash with
+/// its own constant
+fn minhash(hashes: &HashSet<u64, FxBuildHasher>) -> [u64; SIGNATURE] {
+    let mut signature = [u64::MAX; SIGNATURE];
+    for &value in hashes {
+        for (i, min) in signature.iter_mut().enumerate() {
+            *min = (*min).min(mix(value ^ (i as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)));
+        }
+    }
+    signature
+}
+
+/// The finalizer of splitmix64
+fn mix(mut value: u64) -> u64 {
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}
+
+fn jaccard(a: &HashSet<u64, FxBuildHasher>, b: &HashSet<u64, FxBuildHasher>) -> f64 {
+    let shared = a.intersection(b).count();
+    shared as f64 / (a.len() + b.len() - shared) as f64
+}
+
+/// Lines removed as repeats, the characters removed and the share of all the characters checked
+/// that they are, `None` before any line is checked
+pub fn repeats() -> Option<(u64, u64, f64)> {
+    let checked = CHECKED_CHARS.load(Ordering::Relaxed);
+    if checked == 0 {
+        return None;
+    }
+    let removed = REPEATED_CHARS.load(Ordering::Relaxed);
+    Some((
+        REPEATED_LINES.load(Ordering::Relaxed),
+        removed,
+        removed as f64 / checked as f64,
+    ))
+}
+
+/// The name of the rule that drops a document, `None` if it is kept
+///
+/// The drop is counted, the caller records the reject.
+pub fn failed_rule(text: &str, quality: &QualityOptions) -> Option<&'static str> {
+    if let Some(rule) = failed_c4_rule(text, &quality.c4) {
+        return Some(rule);
+    }
+    if let Some(rule) = failed_gopher_rule(text, &quality.gopher) {
+        GOPHER_DROPPED[rule as usize].fetch_add(1, Ordering::Relaxed);
+        return Some(rule.name());
+    }
+    failed_symbol_rule(text, quality)
+}
+
+fn failed_symbol_rule(text: &str, quality: &QualityOptions) -> Option<&'static str> {
+    if quality.max_symbol_ratio.is_none() && quality.max_non_alphanumeric.is_none() {
+        return None;
+    }
+    let (mut characters, mut alphanumeric, mut symbols) = (0, 0, 0);
+    for c in text.chars().filter(|c| !c.is_whitespace()) {
+        characters += 1;
+        if c.is_alphanumeric() {
+            alphanumeric += 1;
+        } else if !PUNCTUATION.contains(&c) {
+            symbols += 1;
+        }
+    }
+    let words = text.split_whitespace().count();
+    if let Some(max) = quality.max_symbol_ratio {
+        if symbols as f64 / words.max(1) as f64 > max {
+            SYMBOLS_DROPPED.fetch_add(1, Ordering::Relaxed);
+            return Some("symbols_per_word");
+        }
+    }
+    if let Some(max) = quality.max_non_alphanumeric {
+        if (characters - alphanumeric) as f64 / characters.max(1) as f64 > max {
+            NON_ALPHANUMERIC_DROPPED.fetch_add(1, Ordering::Relaxed);
+            return Some("non_alphanumeric");
+        }
+    }
+    None
+}
+
+fn failed_c4_rule(text: &str, rules: &[C4Rule]) -> Option<&'static str> {
+    if rules.is_empty() {
+        return None;
+    }
+    if text.is_empty() && rules.iter().any(|rule| rule.removes_lines()) {
+        NO_LINES.fetch_add(1, Ordering::Relaxed);
+        return Some("no_lines");
+    }
+    let rule = C4Rule::ALL
+        .into_iter()
+        .filter(|rule| rules.contains(rule))
+        .find(|rule| match rule {
+            C4Rule::LoremIpsum => text.to_lowercase().contains("lorem ipsum"),
+            C4Rule::CurlyBrace => text.contains('{'),
+            C4Rule::Sentences => sentences(text) < MIN_SENTENCES,
+            _ => false,
+        })?;
+    DROPPED[rule as usize].fetch_add(1, Ordering::Relaxed);
+    Some(rule.name())
+}
+
+/// The first of the Gopher `rules` a document fails
+fn failed_gopher_rule(text: &str, rules: &[GopherRule]) -> Option<GopherRule> {
+    if rules.is_empty() {
+        return None;
+    }
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let lines: Vec<&str> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect();
+    let share = |count: usize, total: usize| count as f64 / total.max(1) as f64;
+    let ellipses = |text: &str| text.matches("...").count() + text.matches('…').count();
+    GopherRule::ALL
+        .into_iter()
+        .filter(|rule| rules.contains(rule))
+        .find(|rule| match rule {
+            GopherRule::WordCount => !(MIN_WORDS..=MAX_WORDS).contains(&words.len()),
+            GopherRule::MeanWordLength => {
+                let characters = words.iter().map(|word| word.chars().count()).sum();
+                let mean = share(characters, words.len());
+                !(MIN_MEAN_WORD_LENGTH..=MAX_MEAN_WORD_LENGTH).contains(&mean)
+            }
+            GopherRule::SymbolRatio => {
+                let symbols = text.matches('#').count() + ellipses(text);
+                share(symbols, words.len()) > MAX_SYMBOL_RATIO
+            }
+            GopherRule::BulletLines => {
+                let bullets = lines.iter().filter(|line| line.starts_with(BULLETS));
+                share(bullets.count(), lines.len()) > MAX_BULLET_LINES
+            }
+            GopherRule::EllipsisLines => {
+                let ellipsis = lines
+                    .iter()
+                    .filter(|line| line.ends_with("...") || line.ends_with('…'));
+                share(ellipsis.count(), lines.len()) > MAX_ELLIPSIS_LINES
+            }
+            GopherRule::AlphabeticWords => {
+                let alphabetic = words
+                    .iter()
+                    .filter(|word| word.chars().any(char::is_alphabetic));
+                share(alphabetic.count(), words.len()) < MIN_ALPHABETIC_WORDS
+            }
+            GopherRule::StopWords => {
+                let stop_words = words.iter().filter(|word| {
+                    let word = word.trim_matches(|c: char| !c.is_alphanumeric());
+                    STOP_WORDS.contains(&word.to_lowercase().as_str())
+                });
+                stop_words.take(2).count() < 2
+            }
+        })
+}
+
+/// Number of sentences of a text
+///
+/// A sentence ends with `.`, `!` or `?` followed by a space, a closing quote or the end of the
+/// text, and must have a letter or a digit. Trailing words without punctuation are a sentence too.
+pub fn sentences(text: &str) -> usize {
+    let mut count = 0;
+    let mut words = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_alphanumeric() {
+            words = true;
+        } else if matches!(c, '.' | '!' | '?')
+            && words
+            && chars
+                .peek()
+                .is_none_or(|next| next.is_whitespace() || matches!(next, '"' | '\'' | ')'))
+        {
+            count += 1;
+            words = false;
+        }
+    }
+    count + usize::from(words)
+}
+
+/// Lines removed by every C4 rule, only the rules that removed some
+pub fn lines_removed() -> Vec<(&'static str, u64)> {
+    C4Rule::ALL
+        .iter()
+        .map(|rule| (rule.name(), LINES[*rule as usize].load(Ordering::Relaxed)))
+        .filter(|(_, count)| *count > 0)
+        .collect()
+}
+
+/// Documents dropped by every rule, only the rules that dropped some
+pub fn dropped() -> Vec<(&'static str, u64)> {
+    C4Rule::ALL
+        .iter()
+        .map(|rule| (rule.name(), DROPPED[*rule as usize].load(Ordering::Relaxed)))
+        .chain([("no_lines", NO_LINES.load(Ordering::Relaxed))])
+        .chain(GopherRule::ALL.iter().map(|rule| {
+            let count = GOPHER_DROPPED[*rule as usize].load(Ordering::Relaxed);
+            (rule.name(), count)
+        }))
+        .chain([
+            ("symbols_per_word", SYMBOLS_DROPPED.load(Ordering::Relaxed)),
+            (
+                "non_alphanumeric",
+                NON_ALPHANUMERIC_DROPPED.load(Ordering::Relaxed),
+            ),
+        ])
+        .filter(|(_, count)| *count > 0)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_remove_lines() {
+        let quality = QualityOptions {
+            c4: vec![C4Rule::TerminalPunctuation, C4Rule::LineWords],
+            ..Default::default()
+        };
+        let mut posts = vec![
+            "This post has enough words to stay.".to_string(),
+            "Too short.".to_string(),
+            "This one has no punctuation at the end".to_string(),
+            "First line is kept as it is.\nsecond line is not kept".to_string(),
+            "And this one trails off into nothing...".to_string(),
+        ];
+        let post = |id: &str| PostMetadata {
+            id: Some(id.to_string()),
+            ..Default::default()
+        };
+        let mut metadata = vec![post("1"), post("2"), post("3"), post("4"), post("5")];
+        remove_lines(&mut posts, Some(&mut metadata), &quality);
+        assert_eq!(
+            posts,
+            vec![
+                "This post has enough words to stay.",
+                "First line is kept as it is.",
+            ]
+        );
+        assert_eq!(metadata, vec![post("1"), post("4")]);
+
+        let quality = QualityOptions {
+            c4: vec![C4Rule::Javascript, C4Rule::Policy],
+            ..Default::default()
+        };
+        let mut posts = vec![
+            "Please enable JavaScript to view the comments.".to_string(),
+            "This site uses cookies.".to_string(),
+            "fine".to_string(),
+        ];
+        remove_lines(&mut posts, None, &quality);
+        assert_eq!(posts, vec!["fine"]);
+    }
+
+    #[test]
+    fn test_repeated_lines() {
+        let posts = || {
+            vec![
+                "Has anyone tried the new firmware on the older model yet?".to_string(),
+                "  Has anyone tried the new firmware on the older model yet?".to_string(),
+                "Has anyone tried the NEW firmware on the older model yet??\nYes, it works."
+                    .to_string(),
+                "Yes, it works.".to_string(),
+                "No, the firmware bricked mine.".to_string(),
+            ]
+        };
+        let quality = |repeated_lines| QualityOptions {
+            repeated_lines,
+            ..Default::default()
+        };
+        let mut exact = posts();
+        remove_lines(&mut exact, None, &quality(RepeatedLines::Exact));
+        assert_eq!(
+            exact,
+            vec![
+                "Has anyone tried the new firmware on the older model yet?",
+                "Has anyone tried the NEW firmware on the older model yet??\nYes, it works.",
+                "No, the firmware bricked mine.",
+            ]
+        );
+        let mut near = posts();
+        remove_lines(&mut near, None, &quality(RepeatedLines::Near));
+        assert_eq!(
+            near,
+            vec![
+                "Has anyone tried the new firmware on the older model yet?",
+                "Yes, it works.",
+                "No, the firmware bricked mine.",
+            ]
+        );
+        let mut kept = posts();
+        remove_lines(&mut kept, None, &quality(RepeatedLines::None));
+        assert_eq!(kept, posts());
+
+        let trigrams = |text: &str| {
+            let mut repeats = Repeats::new(&quality(RepeatedLines::Near));
+            repeats.is_near_repeat(text);
+            repeats.trigrams.pop().unwrap()
+        };
+        let a = trigrams("one two three four five six seven eight nine ten");
+        let b = trigrams("one two three four five six seven eight nine eleven");
+        assert_eq!(jaccard(&a, &b), 7.0 / 9.0);
+        let (lines, characters, share) = repeats().unwrap();
+        assert!(lines >= 4 && characters > 0 && share > 0.0);
+    }
+
+    #[test]
+    fn test_failed_rule() {
+        assert_eq!(sentences("One. Two! Three? Four"), 4);
+        assert_eq!(sentences("Wait... what? \"Yes.\" 3.5 is a number."), 4);
+        assert_eq!(sentences("..."), 0);
+
+        let text = "It works. It really does. Try it.";
+        assert_eq!(failed_rule(text, &QualityOptions::default()), None);
+        let quality = QualityOptions {
+            c4: C4Rule::ALL.to_vec(),
+            ..Default::default()
+        };
+        assert_eq!(failed_rule(text, &quality), None);
+        assert_eq!(failed_rule("", &quality), Some("no_lines"));
+        assert_eq!(
+            failed_rule("Two sentences. Only two.", &quality),
+            Some("sentences")
+        );
+        assert_eq!(
+            failed_rule("Lorem ipsum dolor. Sit amet. Consectetur.", &quality),
+            Some("lorem_ipsum")
+        );
+        assert_eq!(
+            failed_rule("Use this. fn main() { } works. Done.", &quality),
+            Some("curly_brace")
+        );
+        let quality = QualityOptions {
+            c4: vec![C4Rule::LineWords],
+            ..Default::default()
+        };
+        assert_eq!(failed_rule("Short.", &quality), None);
+    }
+
+    #[test]
+    fn test_symbols() {
+        let quality = QualityOptions {
+            max_symbol_ratio: Some(0.5),
+            max_non_alphanumeric: Some(0.3),
+            ..Default::default()
+        };
+        let prose = "Well, it's fine (mostly): the game runs at 60 fps!";
+        assert_eq!(failed_rule(prose, &quality), None);
+        assert_eq!(
+            failed_rule("look at my cat =^.^= <3 <3 ¯\\_(ツ)_/¯", &quality),
+            Some("symbols_per_word")
+        );
+        assert_eq!(
+            failed_rule("if (x) { y[0] = z; } else { y[1] = w; }", &quality),
+            Some("symbols_per_word")
+        );
+        assert_eq!(
+            failed_rule("... !!! ??? well, ok...", &quality),
+            Some("non_alphanumeric")
+        );
+        assert_eq!(failed_rule("", &quality), None);
+    }
+
+    #[test]
+    fn test_gopher() {
+        let prose = "The thread is about the best way to store bread, and most of the replies \
+            agree that a cloth bag keeps it fresh for a few days. Someone with a bakery says \
+            that the fridge dries it out, and that the freezer is better for more than a week. \
+            Others share their own tricks with paper, boxes and tea towels.";
+        let quality = QualityOptions {
+            gopher: GopherRule::ALL.to_vec(),
+            ..Default::default()
+        };
+        assert_eq!(failed_rule(prose, &quality), None);
+        let gopher = |text: &str| failed_gopher_rule(text, &GopherRule::ALL);
+        assert_eq!(gopher("The bread is fresh."), Some(GopherRule::WordCount));
+
+        let words = |word: &str, count: usize| vec![word; count].join(" ");
+        let long_words = format!("the of {}", words("incomprehensibilities", 60));
+        assert_eq!(gopher(&long_words), Some(GopherRule::MeanWordLength));
+        let hashtags = format!("the of {} # # # # # # # #", words("bread", 60));
+        assert_eq!(gopher(&hashtags), Some(GopherRule::SymbolRatio));
+        let bullets = format!("the of\n{}", ["- bread and butter"; 20].join("\n"));
+        assert_eq!(
+            failed_gopher_rule(&bullets, &[GopherRule::BulletLines]),
+            Some(GopherRule::BulletLines)
+        );
+        let trailing = format!("{}\nthe of", ["so then..."; 4].join("\n"));
+        assert_eq!(
+            failed_gopher_rule(&trailing, &[GopherRule::EllipsisLines]),
+            Some(GopherRule::EllipsisLines)
+        );
+        let numbers = format!("the of {} {}", words("bread", 30), words("1234", 30));
+        assert_eq!(gopher(&numbers), Some(GopherRule::AlphabeticWords));
+        assert_eq!(gopher(&words("bread", 60)), Some(GopherRule::StopWords));
+    }
+}
@@ -0,0 +1,271 @@
+use crossbeam_channel::Sender;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{PoisonError, RwLock};
+
+use crate::utils::writer::{self, Batch, Route, ThreadPost};
+
+/// Folder of the rejected records, relative to the output folder
+pub const REJECTS_DIR: &str = "rejects";
+
+/// Bytes kept of a line over `--max-line-bytes`
+const LONG_LINE_PREVIEW: usize = 1024;
+
+/// Why a line or a document was not written
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    /// The line is not a post, or not JSON at all
+    InvalidJson,
+    /// The line is not UTF-8, with `--invalid-utf8 skip-line`
+    InvalidUtf8,
+    /// The line is over `--max-line-bytes`
+    LineTooLong,
+    /// The document is over `--max-perplexity`
+    Perplexity,
+    /// The document is over `--oversized-tokens` with `--oversized drop`
+    Oversized,
+    /// The document was already written, with `--dedup`
+    Duplicate,
+    /// The document failed a quality rule, such as those of `--c4` or `--gopher`
+    Quality,
+    /// The document has a term of `--blocklist`
+    Blocklist,
+    /// The document has none of the patterns of `--require-pattern`
+    MissingPattern,
+}
+
+impl Reason {
+    const ALL: [Reason; 9] = [
+        Reason::InvalidJson,
+        Reason::InvalidUtf8,
+        Reason::LineTooLong,
+        Reason::Perplexity,
+        Reason::Oversized,
+        Reason::Duplicate,
+        Reason::Quality,
+        Reason::Blocklist,
+        Reason::MissingPattern,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Reason::InvalidJson => "invalid_json",
+            Reason::InvalidUtf8 => "invalid_utf8",
+            Reason::LineTooLong => "line_too_long",
+            Reason::Perplexity => "perplexity",
+            Reason::Oversized => "oversized",
+            Reason::Duplicate => "duplicate",
+            Reason::Quality => "quality",
+            Reason::Blocklist => "blocklist",
+            Reason::MissingPattern => "missing_pattern",
+        }
+    }
+}
+
+/// A line of `rejects/<folder>.jsonl`
+#[derive(Serialize)]
+struct Reject<'a> {
+    reason: Reason,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// The input file of an invalid line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<&'a Path>,
+    /// The line as read, or the document as it would have been written
+    record: &'a str,
+}
+
+/// Where the rejected records are sent, `None` unless `--rejects` is set
+static SINK: RwLock<Option<Sender<Batch>>> = RwLock::new(None);
+
+/// Rejected records of the run, by [Reason](enum.Reason.html)
+static COUNTS: [AtomicU64; Reason::ALL.len()] = [const { AtomicU64::new(0) }; Reason::ALL.len()];
+
+/// Send the rejected records to the writer from now on
+pub fn init(sender: Sender<Batch>) {
+    *SINK.write().unwrap_or_else(PoisonError::into_inner) = Some(sender);
+}
+
+/// Stop sending the rejected records, the writer finishes once the other senders are dropped too
+pub fn close() {
+    SINK.write().unwrap_or_else(PoisonError::into_inner).take();
+}
+
+/// Record an input line that could not be parsed, blank lines are ignored
+///
+/// # Arguments
+///
+/// * `file` - `&Path` - The file the line was read from, its folder names the rejects file
+/// * `line` - `&str` - The line as read
+/// * `error` - `&dyn Error` - Why the line is not a post
+pub fn invalid_line(file: &Path, line: &str, error: &dyn std::error::Error) {
+    if line.trim().is_empty() {
+        return;
+    }
+    invalid(file, Reason::InvalidJson, line, error.to_string());
+}
+
+/// Record an input line that is not UTF-8, written with the invalid bytes replaced
+pub fn invalid_utf8(file: &Path, line: &[u8], error: &std::str::Utf8Error) {
+    invalid(
+        file,
+        Reason::InvalidUtf8,
+        &String::from_utf8_lossy(line),
+        error.to_string(),
+    );
+}
+
+/// Record an input line over `--max-line-bytes`, at most its first KiB is written
+///
+/// # Arguments
+///
+/// * `file` - `&Path` - The file the line was read from
+/// * `start` - `&[u8]` - The start of the line, as much as was kept
+/// * `length` - `usize` - The length of the whole line in bytes
+pub fn line_too_long(file: &Path, start: &[u8], length: usize) {
+    let start = &start[..start.len().min(LONG_LINE_PREVIEW)];
+    invalid(
+        file,
+        Reason::LineTooLong,
+        &String::from_utf8_lossy(start),
+        format!("line of {} bytes", length),
+    );
+}
+
+fn invalid(file: &Path, reason: Reason, line: &str, error: String) {
+    let folder = file.parent().unwrap_or(Path::new(""));
+    reject(folder, reason, || {
+        let reject = Reject {
+            reason,
+            error: Some(error),
+            file: Some(file),
+            record: line,
+        };
+        serde_json::to_string(&reject).unwrap()
+    });
+}
+
+/// Record a document dropped by a filter
+///
+/// # Arguments
+///
+/// * `folder` - `&Path` - The folder the document was built from, names the rejects file
+/// * `reason` - `Reason` - The filter that dropped it
+/// * `threadpost` - `&ThreadPost` - The document as it would have been written
+pub fn filtered(folder: &Path, reason: Reason, threadpost: &ThreadPost) {
+    filtered_with(folder, reason, None, threadpost);
+}
+
+/// Record a document dropped by a quality rule, the rule is the `error` of the reject
+pub fn quality(folder: &Path, rule: &str, threadpost: &ThreadPost) {
+    filtered_with(folder, Reason::Quality, Some(rule), threadpost);
+}
+
+/// Record a document dropped for the terms of the blocklist it has, they are the `error`
+pub fn blocklisted(folder: &Path, terms: &[String], threadpost: &ThreadPost) {
+    filtered_with(
+        folder,
+        Reason::Blocklist,
+        Some(&terms.join(", ")),
+        threadpost,
+    );
+}
+
+fn filtered_with(folder: &Path, reason: Reason, error: Option<&str>, threadpost: &ThreadPost) {
+    reject(folder, reason, || {
+        let record = serde_json::to_string(threadpost).unwrap();
+        let reject = Reject {
+            reason,
+            error: error.map(str::to_string),
+            file: None,
+            record: &record,
+        };
+        serde_json::to_string(&reject).unwrap()
+    });
+}
+
+/// Count the reject, and send it when `--rejects` is set, `line` is only built then
+fn reject<F: FnOnce() -> String>(folder: &Path, reason: Reason, line: F) {
+    COUNTS[reason as usize].fetch_add(1, Ordering::Relaxed);
+    if let Some(sender) = SINK.read().unwrap_or_else(PoisonError::into_inner).as_ref() {
+        let name = folder
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "input".to_string());
+        sender
+            .send(writer::single(Route::Rejects(name), &line()))
+            .unwrap();
+    }
+}
+
+/// Number of rejected records by reason, only the reasons that happened
+pub fn counts() -> Vec<(&'static str, u64)> {
+    Reason::ALL
+        .iter()
+        .map(|reason| {
+            (
+                reason.name(),
+                COUNTS[*reason as usize].load(Ordering::Relaxed),
+            )
+        })
+        .filter(|(_, count)| *count > 0)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_reject() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        init(sender);
+        let file = Path::new("input/rejects_a/posts.jsonl");
+        let error = serde_json::from_str::<serde_json::Value>("cut").unwrap_err();
+        invalid_line(file, "cut", &error);
+        invalid_line(file, "  ", &error);
+        let threadpost = ThreadPost {
+            thread_id: "1".to_string(),
+            ..Default::default()
+        };
+        filtered(
+            Path::new("input/rejects_b"),
+            Reason::Perplexity,
+            &threadpost,
+        );
+        close();
+
+        // Other tests may reject lines while the sink is set
+        let rejects: Vec<(Route, String)> = receiver
+            .iter()
+            .filter(|(route, _)| match route {
+                Route::Rejects(name) => name.starts_with("rejects_"),
+                _ => false,
+            })
+            .flat_map(|(route, batch)| {
+                writer::records(&batch)
+                    .map(|line| (route.clone(), line.to_string()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        assert_eq!(rejects.len(), 2);
+        assert_eq!(rejects[0].0, Route::Rejects("rejects_a".to_string()));
+        assert_eq!(rejects[0].0.path(), Path::new("rejects/rejects_a.jsonl"));
+        let first: serde_json::Value = serde_json::from_str(&rejects[0].1).unwrap();
+        assert_eq!(first["reason"], "invalid_json");
+        assert_eq!(first["file"], "input/rejects_a/posts.jsonl");
+        assert_eq!(first["record"], "cut");
+        let second: serde_json::Value = serde_json::from_str(&rejects[1].1).unwrap();
+        assert_eq!(second["reason"], "perplexity");
+        let record: serde_json::Value =
+            serde_json::from_str(second["record"].as_str().unwrap()).unwrap();
+        assert_eq!(record["thread_id"], "1");
+        assert!(second.get("file").is_none());
+        assert!(counts()
+            .iter()
+            .any(|(reason, count)| *reason == "perplexity" && *count >= 1));
+    }
+}
@@ -0,0 +1,132 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use crate::config::Config;
+use crate::utils::writer::Route;
+
+/// File in the output folder where the completed folders are recorded
+pub const STATE_FILE: &str = "resume_state.jsonl";
+
+/// A folder whose records are all written, one line of the state file
+///
+/// `offsets` is the size of every output file right after the folder was written. Records of the
+/// folders are written one folder at a time when resuming is enabled, so everything past the last
+/// offsets belongs to a folder that did not finish.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Checkpoint {
+    pub folder: String,
+    pub offsets: BTreeMap<String, u64>,
+}
+
+/// The state of a previous run
+#[derive(Debug, Default, PartialEq)]
+pub struct ResumeState {
+    /// Folders that do not need to be processed again
+    pub completed: HashSet<String>,
+    /// Size of the output files at the last checkpoint
+    pub offsets: BTreeMap<String, u64>,
+}
+
+/// Append a checkpoint to the state file, the output files must be flushed before
+pub fn append_checkpoint(output_folder: &Path, checkpoint: &Checkpoint) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(output_folder.join(STATE_FILE))?;
+    writeln!(file, "{}", serde_json::to_string(checkpoint)?)?;
+    file.sync_data()
+}
+
+/// Read the state file of a previous run, empty if there is none
+///
+/// A last line that was cut by the interruption is ignored.
+pub fn load(output_folder: &Path) -> io::Result<ResumeState> {
+    let path = output_folder.join(STATE_FILE);
+    if !path.exists() {
+        return Ok(ResumeState::default());
+    }
+    let mut state = ResumeState::default();
+    let reader = BufReader::new(File::open(path)?);
+    for line in reader.lines() {
+        let Ok(checkpoint) = serde_json::from_str::<Checkpoint>(&line?) else {
+            continue;
+        };
+        state.completed.insert(checkpoint.folder);
+        state.offsets = checkpoint.offsets;
+    }
+    Ok(state)
+}
+
+/// Cut the output files back to the last checkpoint
+///
+/// Removes the records of the folders that were interrupted, they are written again when the
+/// folder is processed. Output files that did not exist at the last checkpoint are emptied.
+pub fn restore(output_folder: &Path, state: &ResumeState, config: &Config) -> io::Result<()> {
+    for route in Route::outputs(config) {
+        let name = route.path().display().to_string();
+        let path = output_folder.join(&name);
+        if !path.exists() {
+            continue;
+        }
+        let offset = state.offsets.get(&name).copied().unwrap_or(0);
+        let file = OpenOptions::new().write(true).open(&path)?;
+        if file.metadata()?.len() > offset {
+            file.set_len(offset)?;
+        }
+    }
+    Ok(())
+}
+
+/// Name of a folder in the state file
+pub fn folder_key(folder: &Path) -> String {
+    folder
+        .file_name()
+        .unwrap_or(folder.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Remove the state file, for a run that starts over
+pub fn clear(output_folder: &Path) -> io::Result<()> {
+    match fs::remove_file(output_folder.join(STATE_FILE)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_resume_state() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        assert_eq!(load(dir).unwrap(), ResumeState::default());
+
+        fs::write(dir.join("all.jsonl"), "a\nb\n").unwrap();
+        let checkpoint = Checkpoint {
+            folder: "sub1".to_string(),
+            offsets: BTreeMap::from([("all.jsonl".to_string(), 2)]),
+        };
+        append_checkpoint(dir, &checkpoint).unwrap();
+        // interrupted while writing the next line
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join(STATE_FILE))
+            .unwrap();
+        write!(file, "{{\"folder\":\"sub2\",\"off").unwrap();
+
+        let state = load(dir).unwrap();
+        assert_eq!(state.completed, HashSet::from(["sub1".to_string()]));
+        assert_eq!(state.offsets, checkpoint.offsets);
+        restore(dir, &state, &Config::default()).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("all.jsonl")).unwrap(), "a\n");
+        assert_eq!(folder_key(Path::new("main/sub1")), "sub1");
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use tokenizers::decoders::byte_fallback::ByteFallback;
+use tokenizers::models::bpe::BPE;
+use tokenizers::models::unigram::Unigram;
+use tokenizers::pre_tokenizers::metaspace::{Metaspace, PrependScheme};
+use tokenizers::Tokenizer;
+
+/// Piece type for unknown tokens in the SentencePiece proto
+const TYPE_UNKNOWN: u64 = 2;
+/// Model types in `TrainerSpec.model_type`
+const MODEL_UNIGRAM: u64 = 1;
+const MODEL_BPE: u64 = 2;
+
+#[derive(Debug, Default)]
+struct Piece {
+    piece: String,
+    score: f32,
+    kind: u64,
+}
+
+/// The parts of the SentencePiece `ModelProto` that are needed to rebuild the tokenizer
+#[derive(Debug)]
+struct ModelProto {
+    pieces: Vec<Piece>,
+    model_type: u64,
+    byte_fallback: bool,
+    add_dummy_prefix: bool,
+}
+
+/// Minimal protobuf wire format reader, only what `sentencepiece_model.proto` needs
+struct ProtoReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ProtoReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        ProtoReader { buf, pos: 0 }
+    }
+
+    fn varint(&mut self) -> io::Result<u64> {
+        let mut value: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.buf.get(self.pos).ok_or_else(truncated)?;
+            self.pos += 1;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid("varint is too long"))
+    }
+
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let end = self.pos.checked_add(len).ok_or_else(truncated)?;
+        let bytes = self.buf.get(self.pos..end).ok_or_else(truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Returns the next `(field number, wire type)` or `None` at the end of the message
+    fn key(&mut self) -> io::Result<Option<(u64, u64)>> {
+        if self.pos >= self.buf.len() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        Ok(Some((key >> 3, key & 0x7)))
+    }
+
+    fn skip(&mut self, wire_type: u64) -> io::Result<()> {
+        match wire_type {
+            0 => self.varint().map(|_| ()),
+            1 => self.bytes(8).map(|_| ()),
+            2 => {
+                let len = self.varint()? as usize;
+                self.bytes(len).map(|_| ())
+            }
+            5 => self.bytes(4).map(|_| ()),
+            _ => Err(invalid("unsupported wire type")),
+        }
+    }
+
+    fn message(&mut self) -> io::Result<ProtoReader<'a>> {
+        let len = self.varint()? as usize;
+        Ok(ProtoReader::new(self.bytes(len)?))
+    }
+
+    fn fixed32(&mut self) -> io::Result<[u8; 4]> {
+        let bytes = self.bytes(4)?;
+        Ok([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid SentencePiece model: {}", msg),
+    )
+}
+
+fn truncated() -> io::Error {
+    invalid("unexpected end of data")
+}
+
+fn parse_piece(mut reader: ProtoReader) -> io::Result<Piece> {
+    let mut piece = Piece {
+        kind: 1,
+        ..Default::default()
+    };
+    while let Some((field, wire_type)) = reader.key()? {
+        match (field, wire_type) {
+            (1, 2) => {
+                let len = reader.varint()? as usize;
+                piece.piece = String::from_utf8(reader.bytes(len)?.to_vec())
+                    .map_err(|_| invalid("piece is not valid UTF-8"))?;
+            }
+            (2, 5) => piece.score = f32::from_le_bytes(reader.fixed32()?),
+            (3, 0) => piece.kind = reader.varint()?,
+            _ => reader.skip(wire_type)?,
+        }
+    }
+    Ok(piece)
+}
+
+fn parse_model(buf: &[u8]) -> io::Result<ModelProto> {
+    let mut reader = ProtoReader::new(buf);
+    let mut model = ModelProto {
+        pieces: Vec::new(),
+        model_type: MODEL_UNIGRAM,
+        byte_fallback: false,
+        add_dummy_prefix: true,
+    };
+    while let Some((field, wire_type)) = reader.key()? {
+        match (field, wire_type) {
+            (1, 2) => model.pieces.push(parse_piece(reader.message()?)?),
+            // TrainerSpec
+            (2, 2) => {
+                let mut spec = reader.message()?;
+                while let Some((field, wire_type)) = spec.key()? {
+                    match (field, wire_type) {
+                        (3, 0) => model.model_type = spec.varint()?,
+                        (35, 0) => model.byte_fallback = spec.varint()? != 0,
+                        _ => spec.skip(wire_type)?,
+                    }
+                }
+            }
+            // NormalizerSpec
+            (3, 2) => {
+                let mut spec = reader.message()?;
+                while let Some((field, wire_type)) = spec.key()? {
+                    match (field, wire_type) {
+                        (3, 0) => model.add_dummy_prefix = spec.varint()? != 0,
+                        _ => spec.skip(wire_type)?,
+                    }
+                }
+            }
+            _ => reader.skip(wire_type)?,
+        }
+    }
+    if model.pieces.is_empty() {
+        return Err(invalid("no pieces found"));
+    }
+    Ok(model)
+}
+
+/// Recover BPE merges from the vocabulary, the same way `transformers` converts SentencePiece models
+///
+/// Every split of a piece into two pieces that are also in the vocabulary is a merge,
+/// ranked by the score of the merged piece.
+fn extract_merges(pieces: &[Piece], vocab: &HashMap<String, u32>) -> Vec<(String, String)> {
+    let mut merges: Vec<(String, String, f32)> = Vec::new();
+    for piece in pieces {
+        let mut local: Vec<(String, String, f32)> = piece
+            .piece
+            .char_indices()
+            .skip(1)
+            .filter_map(|(index, _)| {
+                let (left, right) = piece.piece.split_at(index);
+                (vocab.contains_key(left) && vocab.contains_key(right))
+                    .then(|| (left.to_string(), right.to_string(), piece.score))
+            })
+            .collect();
+        local.sort_by_key(|(left, right, _)| (vocab[left], vocab[right]));
+        merges.extend(local);
+    }
+    // Stable sort keeps the vocabulary order for equal scores
+    merges.sort_by(|a, b| b.2.total_cmp(&a.2));
+    merges
+        .into_iter()
+        .map(|(left, right, _)| (left, right))
+        .collect()
+}
+
+/// Load a SentencePiece `.model` file as a `tokenizers::Tokenizer`
+///
+/// The protobuf is decoded directly and converted to the equivalent Unigram or BPE model with
+/// a `Metaspace` pre-tokenizer, so the token counts match the original SentencePiece model.
+///
+/// # Arguments
+///
+/// * `path` - `P: AsRef<Path>` - The path to the `.model` file
+///
+/// # Returns
+///
+/// * `io::Result<Tokenizer>` - The converted tokenizer
+///
+/// # Example
+///
+/// ```ignore
+/// let tokenizer = sentencepiece::load("tokenizer.model").unwrap();
+/// let encoding = tokenizer.encode("Hello world", false).unwrap();
+/// ```
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Tokenizer> {
+    from_bytes(&fs::read(path)?)
+}
+
+/// Same as [load](fn.load.html) but from the raw bytes of the model
+pub fn from_bytes(buf: &[u8]) -> io::Result<Tokenizer> {
+    let model = parse_model(buf)?;
+    let to_io_error = |e: tokenizers::Error| io::Error::new(io::ErrorKind::InvalidData, e);
+
+    let mut tokenizer = match model.model_type {
+        MODEL_UNIGRAM => {
+            let unk_id = model.pieces.iter().position(|p| p.kind == TYPE_UNKNOWN);
+            let vocab: Vec<(String, f64)> = model
+                .pieces
+                .iter()
+                .map(|p| (p.piece.clone(), p.score as f64))
+                .collect();
+            Tokenizer::new(Unigram::from(vocab, unk_id, model.byte_fallback).map_err(to_io_error)?)
+        }
+        MODEL_BPE => {
+            let vocab: HashMap<String, u32> = model
+                .pieces
+                .iter()
+                .enumerate()
+                .map(|(id, p)| (p.piece.clone(), id as u32))
+                .collect();
+            let merges = extract_merges(&model.pieces, &vocab);
+            let mut builder = BPE::builder()
+                .vocab_and_merges(vocab, merges)
+                .byte_fallback(model.byte_fallback)
+                .fuse_unk(true);
+            if let Some(unk) = model.pieces.iter().find(|p| p.kind == TYPE_UNKNOWN) {
+                builder = builder.unk_token(unk.piece.clone());
+            }
+            Tokenizer::new(builder.build().map_err(to_io_error)?)
+        }
+        other => {
+            return Err(invalid(&format!(
+                "model type {} is not supported, only unigram and BPE",
+                other
+            )))
+        }
+    };
+
+    let prepend_scheme = match model.add_dummy_prefix {
+        true => PrependScheme::Always,
+        false => PrependScheme::Never,
+    };
+    tokenizer.with_pre_tokenizer(Some(Metaspace::new('▁', prepend_scheme, false)));
+    if model.byte_fallback {
+        tokenizer.with_decoder(Some(ByteFallback::new()));
+    }
+    Ok(tokenizer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(mut value: u64, out: &mut Vec<u8>) {
+        while value >= 0x80 {
+            out.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn field(number: u64, wire_type: u64, payload: &[u8], out: &mut Vec<u8>) {
+        varint((number << 3) | wire_type, out);
+        if wire_type == 2 {
+            varint(payload.len() as u64, out);
+        }
+        out.extend_from_slice(payload);
+    }
+
+    /// Encode a ModelProto with the given pieces and model type
+    fn encode(pieces: &[(&str, f32, u64)], model_type: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (piece, score, kind) in pieces {
+            let mut msg = Vec::new();
+            field(1, 2, piece.as_bytes(), &mut msg);
+            field(2, 5, &score.to_le_bytes(), &mut msg);
+            let mut kind_bytes = Vec::new();
+            varint(*kind, &mut kind_bytes);
+            field(3, 0, &kind_bytes, &mut msg);
+            field(1, 2, &msg, &mut out);
+        }
+        let mut spec = Vec::new();
+        let mut type_bytes = Vec::new();
+        varint(model_type, &mut type_bytes);
+        field(3, 0, &type_bytes, &mut spec);
+        field(2, 2, &spec, &mut out);
+        out
+    }
+
+    const PIECES: &[(&str, f32, u64)] = &[
+        ("<unk>", 0.0, 2),
+        ("▁", -1.0, 1),
+        ("h", -2.0, 1),
+        ("i", -2.0, 1),
+        ("▁h", -1.5, 1),
+        ("▁hi", -0.5, 1),
+    ];
+
+    #[test]
+    fn test_unigram() {
+        let tokenizer = from_bytes(&encode(PIECES, MODEL_UNIGRAM)).unwrap();
+        let encoding = tokenizer.encode("hi hi", false).unwrap();
+        assert_eq!(encoding.get_tokens(), &["▁hi", "▁hi"]);
+    }
+
+    #[test]
+    fn test_bpe() {
+        let tokenizer = from_bytes(&encode(PIECES, MODEL_BPE)).unwrap();
+        let encoding = tokenizer.encode("hi", false).unwrap();
+        assert_eq!(encoding.get_tokens(), &["▁hi"]);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(from_bytes(&[0xff, 0xff]).is_err());
+        assert!(from_bytes(&[]).is_err());
+    }
+}
@@ -0,0 +1,53 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of Ctrl-C (or SIGTERM) received so far
+static SIGNALS: AtomicUsize = AtomicUsize::new(0);
+
+/// Stop the run on Ctrl-C or SIGTERM, after the folders in progress
+///
+/// The first signal only stops new folders from starting, the folders in progress finish and the
+/// writer flushes the output. A second signal exits at once, the output may then be cut.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn install() {
+    let installed = ctrlc::set_handler(|| {
+        if request() {
+            tracing::warn!(
+                "Stopping after the folders in progress, press Ctrl-C again to exit immediately"
+            );
+        } else {
+            std::process::exit(crate::error::Exit::Interrupted as i32);
+        }
+    });
+    if let Err(e) = installed {
+        tracing::warn!(error = %e, "Unable to handle Ctrl-C, interrupting will cut the output");
+    }
+}
+
+/// There are no signals on wasm32
+#[cfg(target_arch = "wasm32")]
+pub fn install() {}
+
+/// Record a signal, `true` for the first one
+///
+/// A program that embeds the pipeline can call it to stop the run as a first Ctrl-C would.
+pub fn request() -> bool {
+    SIGNALS.fetch_add(1, Ordering::SeqCst) == 0
+}
+
+/// Whether the run should stop starting new folders
+pub fn requested() -> bool {
+    SIGNALS.load(Ordering::SeqCst) > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request() {
+        assert!(!requested());
+        assert!(request());
+        assert!(requested());
+        assert!(!request());
+    }
+}
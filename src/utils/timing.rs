@@ -0,0 +1,317 @@
+//! Time spent in each stage of a run, for the report at the end
+//!
+//! A stage is measured with a [Timer](struct.Timer.html) around work that runs on one thread, a
+//! file being read or a batch of documents being cleaned. Every stage has:
+//!
+//! * the wall time, while at least one worker is in the stage
+//! * the busy time, summed over the workers, `busy / wall` is the average number of busy workers
+//! * the CPU time of the threads in the stage, summed over the workers, on Unix only
+//!
+//! The work a timed task hands to other workers, such as the parallel traversal of a large graph,
+//! only counts in the wall time. The writer thread measures itself with a
+//! [Clock](struct.Clock.html). There is no clock on wasm32, nothing is measured there.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// A stage of the run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// Reading and parsing the input files
+    Read,
+    /// Building the threads of the parsed posts
+    GetThreads,
+    /// Cleaning, tokenizing and filtering the documents
+    CreatePosts,
+    /// Writing the records, on the writer thread
+    Write,
+    /// The writer waiting for records
+    QueueWait,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 5] = [
+        Stage::Read,
+        Stage::GetThreads,
+        Stage::CreatePosts,
+        Stage::Write,
+        Stage::QueueWait,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Read => "read",
+            Stage::GetThreads => "get_threads",
+            Stage::CreatePosts => "create_posts",
+            Stage::Write => "write",
+            Stage::QueueWait => "queue_wait",
+        }
+    }
+}
+
+/// The time of a stage so far
+#[derive(Debug)]
+struct Totals {
+    busy_ns: AtomicU64,
+    cpu_ns: AtomicU64,
+    wall: Mutex<Wall>,
+}
+
+#[derive(Debug)]
+struct Wall {
+    /// Timers running
+    active: usize,
+    /// When the first of the running timers started
+    since: Option<Instant>,
+    total: Duration,
+}
+
+impl Totals {
+    const fn new() -> Self {
+        Totals {
+            busy_ns: AtomicU64::new(0),
+            cpu_ns: AtomicU64::new(0),
+            wall: Mutex::new(Wall {
+                active: 0,
+                since: None,
+                total: Duration::ZERO,
+            }),
+        }
+    }
+
+    fn enter(&self, now: Instant) {
+        let mut wall = self.wall.lock().unwrap_or_else(PoisonError::into_inner);
+        if wall.active == 0 {
+            wall.since = Some(now);
+        }
+        wall.active += 1;
+    }
+
+    fn leave(&self, now: Instant, busy: Duration, cpu: Option<Duration>) {
+        self.add(busy, cpu);
+        let mut wall = self.wall.lock().unwrap_or_else(PoisonError::into_inner);
+        wall.active -= 1;
+        if wall.active == 0 {
+            if let Some(since) = wall.since.take() {
+                wall.total += now.saturating_duration_since(since);
+            }
+        }
+    }
+
+    fn add(&self, busy: Duration, cpu: Option<Duration>) {
+        self.busy_ns
+            .fetch_add(busy.as_nanos() as u64, Ordering::Relaxed);
+        if let Some(cpu) = cpu {
+            self.cpu_ns
+                .fetch_add(cpu.as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// The wall time up to `now`, with the timers still running
+    fn wall(&self, now: Instant) -> Duration {
+        let wall = self.wall.lock().unwrap_or_else(PoisonError::into_inner);
+        match wall.since {
+            Some(since) => wall.total + now.saturating_duration_since(since),
+            None => wall.total,
+        }
+    }
+}
+
+static TOTALS: [Totals; Stage::ALL.len()] = [const { Totals::new() }; Stage::ALL.len()];
+
+/// Measures a stage on the current thread until it is dropped
+///
+/// # Example
+///
+/// ```ignore
+/// let timer = timing::start(Stage::Read);
+/// read(file);
+/// let elapsed = timer.stop();
+/// ```
+#[derive(Debug)]
+pub struct Timer {
+    stage: Stage,
+    start: Option<Instant>,
+    cpu: Option<Duration>,
+}
+
+/// Start measuring `stage`
+pub fn start(stage: Stage) -> Timer {
+    let start = now();
+    if let Some(start) = start {
+        TOTALS[stage as usize].enter(start);
+    }
+    Timer {
+        stage,
+        start,
+        cpu: thread_cpu(),
+    }
+}
+
+impl Timer {
+    /// Stop measuring, returns the time since the start
+    pub fn stop(self) -> Duration {
+        self.start.map_or(Duration::ZERO, |start| start.elapsed())
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let (Some(start), Some(now)) = (self.start, now()) else {
+            return;
+        };
+        let cpu = self
+            .cpu
+            .zip(thread_cpu())
+            .map(|(start, end)| end.saturating_sub(start));
+        TOTALS[self.stage as usize].leave(now, now - start, cpu);
+    }
+}
+
+/// `Instant::now` panics on wasm32
+fn now() -> Option<Instant> {
+    match cfg!(target_arch = "wasm32") {
+        true => None,
+        false => Some(Instant::now()),
+    }
+}
+
+/// Splits the time of the writer thread between writing and waiting for records
+///
+/// The time between two waits goes to [Stage::Write](enum.Stage.html) with the CPU time of the
+/// thread, which includes the spinning before a wait, the waits go to
+/// [Stage::QueueWait](enum.Stage.html) with no CPU time. The writer is the only thread in
+/// these stages, its busy time is also their wall time. Counted when dropped.
+#[derive(Debug)]
+pub struct Clock {
+    last: Instant,
+    cpu: Option<Duration>,
+    write: Duration,
+    wait: Duration,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Clock {
+            last: Instant::now(),
+            cpu: thread_cpu(),
+            write: Duration::ZERO,
+            wait: Duration::ZERO,
+        }
+    }
+
+    /// Run `recv`, the time it takes is waiting
+    pub fn wait<T, F: FnOnce() -> T>(&mut self, recv: F) -> T {
+        let start = Instant::now();
+        self.write += start - self.last;
+        let received = recv();
+        self.last = Instant::now();
+        self.wait += self.last - start;
+        received
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock::new()
+    }
+}
+
+impl Drop for Clock {
+    fn drop(&mut self) {
+        let write = self.write + self.last.elapsed();
+        let cpu = self
+            .cpu
+            .zip(thread_cpu())
+            .map(|(start, end)| end.saturating_sub(start));
+        for (stage, busy, cpu) in [
+            (Stage::Write, write, cpu),
+            (Stage::QueueWait, self.wait, None),
+        ] {
+            let totals = &TOTALS[stage as usize];
+            totals.add(busy, cpu);
+            totals
+                .wall
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .total += busy;
+        }
+    }
+}
+
+/// The time of a stage, in milliseconds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StageTime {
+    pub stage: &'static str,
+    pub wall_ms: u64,
+    pub busy_ms: u64,
+    /// `None` where the CPU time of a thread is not available
+    pub cpu_ms: Option<u64>,
+}
+
+/// The time of every stage so far
+pub fn report() -> Vec<StageTime> {
+    let now = Instant::now();
+    Stage::ALL
+        .iter()
+        .map(|&stage| {
+            let totals = &TOTALS[stage as usize];
+            StageTime {
+                stage: stage.name(),
+                wall_ms: totals.wall(now).as_millis() as u64,
+                busy_ms: totals.busy_ns.load(Ordering::Relaxed) / 1_000_000,
+                cpu_ms: thread_cpu()
+                    .filter(|_| stage != Stage::QueueWait)
+                    .map(|_| totals.cpu_ns.load(Ordering::Relaxed) / 1_000_000),
+            }
+        })
+        .collect()
+}
+
+/// The CPU time of the current thread
+#[cfg(unix)]
+fn thread_cpu() -> Option<Duration> {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `time` is a valid timespec for the call to write to
+    let result = unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut time) };
+    (result == 0).then(|| Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+}
+
+#[cfg(not(unix))]
+fn thread_cpu() -> Option<Duration> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_totals() {
+        let totals = Totals::new();
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        // Two workers overlap for a second, then one runs alone
+        totals.enter(at(0));
+        totals.enter(at(1000));
+        totals.leave(at(2000), Duration::from_millis(2000), None);
+        totals.leave(at(3000), Duration::from_millis(2000), None);
+        totals.enter(at(5000));
+        totals.leave(
+            at(5500),
+            Duration::from_millis(500),
+            Some(Duration::from_millis(400)),
+        );
+        assert_eq!(totals.wall(at(6000)), Duration::from_millis(3500));
+        assert_eq!(totals.busy_ns.load(Ordering::Relaxed), 4_500_000_000);
+        assert_eq!(totals.cpu_ns.load(Ordering::Relaxed), 400_000_000);
+
+        totals.enter(at(7000));
+        assert_eq!(totals.wall(at(7250)), Duration::from_millis(3750));
+    }
+}
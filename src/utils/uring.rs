@@ -0,0 +1,234 @@
+//! Reading of the input files with io_uring, with the `io-uring` feature on Linux
+//!
+//! A [UringReader](struct.UringReader.html) keeps [DEPTH](constant.DEPTH.html) reads of the file
+//! in flight, the next chunks are read by the kernel while the current one is parsed. Every file
+//! read in parallel has its own ring, so a folder keeps the device busy with many reads at once
+//! instead of one blocking read per worker.
+//!
+//! [open_reader](../file/fn.open_reader.html) uses it when the feature is on, and falls back to
+//! blocking reads when the kernel refuses to set up a ring, as in some containers.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, Read};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+
+use io_uring::{opcode, types, IoUring};
+
+/// Reads in flight for every file
+pub const DEPTH: usize = 8;
+
+/// Bytes of every read
+const CHUNK: usize = 256 * 1024;
+
+/// A ring for a [UringReader](struct.UringReader.html)
+///
+/// # Errors
+///
+/// The kernel does not support io_uring or does not allow it.
+pub fn ring() -> io::Result<IoUring> {
+    IoUring::new(DEPTH as u32)
+}
+
+/// A file read in chunks of 256 KiB, with the next chunks read ahead
+pub struct UringReader {
+    ring: IoUring,
+    file: File,
+    /// The buffer of every read, each one is given to the kernel while its read is in flight
+    buffers: Vec<Box<[u8]>>,
+    /// Offset in the file of the read of every buffer
+    offsets: Vec<u64>,
+    /// Result of the read of every buffer, `None` while it is in flight
+    results: Vec<Option<i32>>,
+    /// Buffers in the order of the file
+    queue: VecDeque<usize>,
+    /// The buffer being consumed, its length and the bytes consumed
+    current: Option<(usize, usize, usize)>,
+    /// Offset of the next read
+    next: u64,
+    in_flight: usize,
+    started: bool,
+    eof: bool,
+}
+
+impl UringReader {
+    /// Read `file` with `ring`, the first reads are submitted on the first call to `fill_buf`
+    pub fn new(file: File, ring: IoUring) -> Self {
+        UringReader {
+            ring,
+            file,
+            buffers: (0..DEPTH)
+                .map(|_| vec![0; CHUNK].into_boxed_slice())
+                .collect(),
+            offsets: vec![0; DEPTH],
+            results: vec![None; DEPTH],
+            queue: VecDeque::with_capacity(DEPTH),
+            current: None,
+            next: 0,
+            in_flight: 0,
+            started: false,
+            eof: false,
+        }
+    }
+
+    /// Read the next chunk of the file into `buffer`
+    fn submit(&mut self, buffer: usize) -> io::Result<()> {
+        let target = &mut self.buffers[buffer];
+        let read = opcode::Read::new(
+            types::Fd(self.file.as_raw_fd()),
+            target.as_mut_ptr(),
+            target.len() as u32,
+        )
+        .offset(self.next)
+        .build()
+        .user_data(buffer as u64);
+        // The buffer is not touched again before its completion, and dropping the reader waits
+        // for the reads in flight
+        unsafe { self.ring.submission().push(&read) }.map_err(io::Error::other)?;
+        self.ring.submit()?;
+        self.offsets[buffer] = self.next;
+        self.results[buffer] = None;
+        self.queue.push_back(buffer);
+        self.next += CHUNK as u64;
+        self.in_flight += 1;
+        Ok(())
+    }
+
+    /// Wait for at least one read to complete and record the results
+    fn complete(&mut self) -> io::Result<()> {
+        match self.ring.submit_and_wait(1) {
+            Err(e) if e.kind() != io::ErrorKind::Interrupted => return Err(e),
+            _ => {}
+        }
+        for entry in self.ring.completion() {
+            self.results[entry.user_data() as usize] = Some(entry.result());
+            self.in_flight -= 1;
+        }
+        Ok(())
+    }
+
+    /// The next buffer of the file, waiting for its read
+    fn next_buffer(&mut self) -> io::Result<Option<(usize, usize)>> {
+        let Some(&buffer) = self.queue.front() else {
+            return Ok(None);
+        };
+        while self.results[buffer].is_none() {
+            self.complete()?;
+        }
+        self.queue.pop_front();
+        let result = self.results[buffer].unwrap();
+        if result < 0 {
+            // The reads after a failed one are dropped, the file ends at the error
+            self.eof = true;
+            self.queue.clear();
+            return Err(io::Error::from_raw_os_error(-result));
+        }
+        let mut len = result as usize;
+        // A short read before the end of the file, the rest of the chunk is read directly
+        while len > 0 && len < CHUNK && !self.eof {
+            let offset = self.offsets[buffer] + len as u64;
+            match self
+                .file
+                .read_at(&mut self.buffers[buffer][len..], offset)?
+            {
+                0 => self.eof = true,
+                read => len += read,
+            }
+        }
+        if len == 0 {
+            self.eof = true;
+            return Ok(None);
+        }
+        Ok(Some((buffer, len)))
+    }
+}
+
+impl BufRead for UringReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if !self.started {
+            self.started = true;
+            for buffer in 0..DEPTH {
+                self.submit(buffer)?;
+            }
+        }
+        if let Some((buffer, len, consumed)) = self.current {
+            if consumed < len {
+                return Ok(&self.buffers[buffer][consumed..len]);
+            }
+            self.current = None;
+            if !self.eof {
+                self.submit(buffer)?;
+            }
+        }
+        if self.eof && self.queue.is_empty() {
+            return Ok(&[]);
+        }
+        match self.next_buffer()? {
+            Some((buffer, len)) => {
+                self.current = Some((buffer, len, 0));
+                Ok(&self.buffers[buffer][..len])
+            }
+            None => Ok(&[]),
+        }
+    }
+
+    fn consume(&mut self, amount: usize) {
+        if let Some((_, len, consumed)) = &mut self.current {
+            *consumed = (*consumed + amount).min(*len);
+        }
+    }
+}
+
+impl Read for UringReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl Drop for UringReader {
+    fn drop(&mut self) {
+        while self.in_flight > 0 {
+            if self.complete().is_err() {
+                // The kernel may still write into the buffers, they must outlive the reads
+                std::mem::forget(std::mem::take(&mut self.buffers));
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::io::Write;
+
+    #[test]
+    fn test_uring_reader() {
+        let Ok(_) = ring() else {
+            eprintln!("io_uring is not available, skipping");
+            return;
+        };
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        // Several times the read ahead, and a last line without a newline
+        let lines: Vec<String> = (0..200_000).map(|i| format!("line {}", i)).collect();
+        file.write_all(lines.join("\n").as_bytes()).unwrap();
+        file.flush().unwrap();
+
+        let reader = UringReader::new(File::open(file.path()).unwrap(), ring().unwrap());
+        let read: Vec<String> = reader.lines().map(Result::unwrap).collect();
+        assert_eq!(read, lines);
+
+        let mut empty = UringReader::new(File::open("/dev/null").unwrap(), ring().unwrap());
+        assert_eq!(empty.fill_buf().unwrap(), b"");
+
+        // Dropped with reads in flight
+        let mut reader = UringReader::new(File::open(file.path()).unwrap(), ring().unwrap());
+        assert_eq!(&reader.fill_buf().unwrap()[..6], b"line 0");
+    }
+}
@@ -1,9 +1,188 @@
-use crossbeam_channel::Receiver;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
 use rayon::prelude::*;
-use serde::Serialize;
-use std::fs::File;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
 use std::io::{BufWriter, Write};
+use std::mem;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, WriteBuffer};
+use crate::utils::{memory, rejects, resume, timing};
+
+/// Bytes of records written by the routed writer so far
+static BYTES_WRITTEN: AtomicU64 = AtomicU64::new(0);
+
+/// Bytes of records written so far by [write_routed_receiver](fn.write_routed_receiver.html)
+pub fn bytes_written() -> u64 {
+    BYTES_WRITTEN.load(Ordering::Relaxed)
+}
+
+/// Default capacity of the `BufWriter` of each output file
+pub const WRITE_BUFFER_BYTES: usize = 1_048_576;
+/// Default number of records sent to the writer in a message, see [Batcher](struct.Batcher.html)
+pub const CHANNEL_BATCH: usize = 256;
+
+/// Records of a route sent to the writer in one message, as JSON lines that each end with a
+/// newline
+pub type Batch = (Route, Vec<u8>);
+
+/// A batch of a single record
+pub fn single(route: Route, record: &str) -> Batch {
+    let mut lines = Vec::with_capacity(record.len() + 1);
+    lines.extend_from_slice(record.as_bytes());
+    lines.push(b'\n');
+    (route, lines)
+}
+
+/// The records of a batch, without their newline
+pub fn records(lines: &[u8]) -> impl Iterator<Item = &str> {
+    lines
+        .strip_suffix(b"\n")
+        .into_iter()
+        .flat_map(|lines| lines.split(|&byte| byte == b'\n'))
+        .map(|line| std::str::from_utf8(line).expect("the records are written as UTF-8"))
+}
+
+/// Serializes the records straight into batches for the writer and sends them `size` records at a
+/// time, so the channel is not synchronized for every record
+///
+/// Every worker of the pool fills a buffer of its own, with a batch per route, and sends a batch
+/// once it holds `size` records. The next batch of the route starts with the capacity of the one
+/// sent. The records left are sent when the batcher is dropped, so a folder drops its batcher
+/// before its checkpoint, or flushed when it is shared by several folders.
+///
+/// # Example
+///
+/// ```ignore
+/// let batcher = Batcher::new(data_tx.clone(), config.channel_batch);
+/// threadposts.par_iter().for_each(|threadpost| batcher.serialize(Route::Main, threadpost));
+/// drop(batcher);
+/// ```
+pub struct Batcher {
+    sender: Sender<Batch>,
+    size: usize,
+    /// One per worker of the pool the batcher was created on, and one for the other threads
+    buffers: Vec<Mutex<Vec<Pending>>>,
+}
+
+/// The batch of a route being filled
+struct Pending {
+    route: Route,
+    lines: Vec<u8>,
+    records: usize,
+}
+
+impl Batcher {
+    pub fn new(sender: Sender<Batch>, size: usize) -> Self {
+        Batcher {
+            sender,
+            size: size.max(1),
+            buffers: (0..=rayon::current_num_threads())
+                .map(|_| Mutex::new(Vec::new()))
+                .collect(),
+        }
+    }
+
+    /// Serialize a record as a JSON line at the end of the batch of its route, the batch is sent
+    /// once it is full
+    ///
+    /// The line is counted in the memory [in flight](../memory/static.IN_FLIGHT.html) until the
+    /// writer has written it.
+    pub fn serialize<T: Serialize>(&self, route: Route, record: &T) {
+        let worker = rayon::current_thread_index().map_or(0, |index| index + 1);
+        let mut buffer = self.buffers[worker % self.buffers.len()]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let position = match buffer.iter().position(|pending| pending.route == route) {
+            Some(position) => position,
+            None => {
+                buffer.push(Pending {
+                    route,
+                    lines: Vec::new(),
+                    records: 0,
+                });
+                buffer.len() - 1
+            }
+        };
+        let pending = &mut buffer[position];
+        let start = pending.lines.len();
+        serde_json::to_writer(&mut pending.lines, record).unwrap();
+        pending.lines.push(b'\n');
+        pending.records += 1;
+        memory::IN_FLIGHT.add((pending.lines.len() - start) as u64);
+        if pending.records >= self.size {
+            let capacity = pending.lines.len();
+            let lines = mem::replace(&mut pending.lines, Vec::with_capacity(capacity));
+            pending.records = 0;
+            let route = pending.route.clone();
+            drop(buffer);
+            self.sender.send((route, lines)).unwrap();
+        }
+    }
+
+    /// Send the batches being filled, the records of a folder are then all on their way to the
+    /// writer and no longer hold the memory others wait for
+    pub fn flush(&self) {
+        for buffer in &self.buffers {
+            let pending = mem::take(&mut *buffer.lock().unwrap_or_else(PoisonError::into_inner));
+            for pending in pending.into_iter().filter(|pending| pending.records > 0) {
+                // The writer is only gone if it failed, which is reported on its own
+                let _ = self.sender.send((pending.route, pending.lines));
+            }
+        }
+    }
+}
+
+impl Drop for Batcher {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// When the writer last flushed its files, for `flush_interval`
+struct FlushTimer {
+    interval: Option<Duration>,
+    last: Instant,
+}
+
+impl FlushTimer {
+    fn new(interval: Option<Duration>) -> Self {
+        FlushTimer {
+            interval,
+            last: Instant::now(),
+        }
+    }
+
+    /// Whether the files should be flushed now, the interval starts again if so
+    fn due(&mut self) -> bool {
+        let due = self
+            .interval
+            .is_some_and(|interval| self.last.elapsed() >= interval);
+        if due {
+            self.last = Instant::now();
+        }
+        due
+    }
+
+    /// How long the writer can wait for a record before the next flush
+    fn timeout(&self) -> Option<Duration> {
+        self.interval
+            .map(|interval| interval.saturating_sub(self.last.elapsed()))
+    }
+
+    /// The next message, or a timeout when a flush is due first
+    fn recv<T>(&self, receiver: &Receiver<T>) -> Result<T, RecvTimeoutError> {
+        match self.timeout() {
+            Some(timeout) => receiver.recv_timeout(timeout),
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        }
+    }
+}
 
 #[doc(hidden)]
 const MAX_BYTES_PER_FILE: usize = 100 * 1024_usize.pow(2);
@@ -15,6 +194,158 @@ pub struct ThreadPost {
     pub raw_content: String,
     pub thread_id: String,
     pub source: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub perplexity: Option<f64>,
+    /// Length of each post in `raw_content`, in the same unit as `length`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_lengths: Option<Vec<usize>>,
+    /// Index of the chunk when a long thread is split into several records
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunk: Option<usize>,
+    /// Token ids of `raw_content`, only written when requested with a tokenizer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_ids: Option<Vec<u32>>,
+    /// Byte spans of the tokens of `raw_content`, kept from the tokenization of the batch for a
+    /// document over `max_tokens`
+    #[serde(skip)]
+    pub token_offsets: Option<Vec<(usize, usize)>>,
+    /// `length_<label>` for every tokenizer when more than one is used
+    #[serde(flatten)]
+    pub tokenizer_lengths: BTreeMap<String, usize>,
+    /// Structure of the thread the record comes from
+    #[serde(flatten)]
+    pub stats: Option<ThreadStats>,
+    /// Author, time and score of the posts of the record
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<RecordMetadata>,
+    /// The posts `raw_content` is made of, to trace a span of text back to its post
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub composition: Option<Composition>,
+    /// Where the record comes from, with `ProvenanceTarget::Records`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+    /// The terms of the blocklist found in `raw_content`, with `BlocklistAction::Flag`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blocklist: Option<Vec<String>>,
+}
+
+/// Name of the provenance of a run in the output folder
+pub const PROVENANCE_FILE: &str = "provenance.json";
+
+/// License and origin of the records of a run, for the dataset cards
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Provenance {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
+    /// Date the input was crawled, as given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crawl_date: Option<String>,
+    /// Version of forum-rs that wrote the records
+    pub pipeline_version: String,
+    /// See [config_hash](../../config/struct.Config.html#method.config_hash)
+    pub config_hash: String,
+}
+
+impl Provenance {
+    /// The provenance of a run with `config`
+    pub fn new(config: &Config, license: Option<String>, crawl_date: Option<String>) -> Self {
+        Provenance {
+            license,
+            crawl_date,
+            pipeline_version: env!("CARGO_PKG_VERSION").to_string(),
+            config_hash: config.config_hash(),
+        }
+    }
+
+    /// Save as [PROVENANCE_FILE](constant.PROVENANCE_FILE.html) in `folder`
+    pub fn save(&self, folder: &Path) -> std::io::Result<PathBuf> {
+        let path = folder.join(PROVENANCE_FILE);
+        let mut writer = BufWriter::new(File::create(&path)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writeln!(writer)?;
+        writer.flush()?;
+        Ok(path)
+    }
+}
+
+/// Structural statistics of a thread
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct ThreadStats {
+    /// Deepest reply level, the root post is at depth 0
+    pub depth: usize,
+    pub num_posts: usize,
+    /// Average number of replies of the posts that have replies
+    pub branching_factor: f64,
+    /// Most replies to a single post
+    pub max_replies: usize,
+    /// Number of distinct authors, only when the posts have an author
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unique_authors: Option<usize>,
+}
+
+/// The `metadata` object of a record, see [Metadata](../../config/enum.Metadata.html)
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum RecordMetadata {
+    Posts(PostsMetadata),
+    Thread(ThreadMetadata),
+}
+
+/// A value, or null, for every post of the record in the order of `raw_content`
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct PostsMetadata {
+    pub author: Vec<Option<String>>,
+    pub created_utc: Vec<Option<f64>>,
+    pub score: Vec<Option<f64>>,
+}
+
+/// The id, depth and place in `raw_content` of every post of a record, in order
+///
+/// The `[N more replies]` lines and the placeholders of missing parents have a null id.
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct Composition {
+    pub post_ids: Vec<Option<String>>,
+    /// Reply level of every post, the start of the thread is at depth 0
+    pub depths: Vec<Option<usize>>,
+    /// Byte range `[start, end)` of every post in `raw_content`
+    pub offsets: Vec<(usize, usize)>,
+}
+
+impl Composition {
+    /// The composition of the bytes `start..end` of `raw_content`, with the offsets moved to the
+    /// start of that span and cut to its end
+    pub fn slice(&self, start: usize, end: usize) -> Composition {
+        let mut composition = Composition::default();
+        for (i, &(post_start, post_end)) in self.offsets.iter().enumerate() {
+            let (from, to) = (post_start.max(start), post_end.min(end));
+            let empty_inside = post_start == post_end && (start..end).contains(&post_start);
+            if from < to || empty_inside {
+                composition.post_ids.push(self.post_ids[i].clone());
+                composition.depths.push(self.depths[i]);
+                composition
+                    .offsets
+                    .push((from - start, to.max(from) - start));
+            }
+        }
+        composition
+    }
+}
+
+/// The metadata of the posts of a record taken together, a field that no post has is left out
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct ThreadMetadata {
+    /// Oldest `created_utc` of the posts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_created_utc: Option<f64>,
+    /// Newest `created_utc` of the posts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_created_utc: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_score: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_score: Option<f64>,
+    /// Distinct authors, in the order of their first post
+    pub authors: Vec<String>,
 }
 #[doc(hidden)]
 fn get_chunk_size(bytes: usize, data: &[ThreadPost]) -> usize {
@@ -32,13 +363,13 @@ fn _write_jsonl(data: Vec<ThreadPost>, bytes: usize, file_path: PathBuf) -> std:
     // Note that the size of the input should be checked before entering here
     let chunk_size = get_chunk_size(bytes, &data);
 
-    let folder = file_path.parent().unwrap().to_str().unwrap();
-    let stem = file_path.file_stem().unwrap().to_str().unwrap();
-    let extension = file_path.extension().unwrap().to_str().unwrap();
+    let folder = file_path.parent().unwrap();
+    let stem = file_path.file_stem().unwrap().to_string_lossy();
+    let extension = file_path.extension().unwrap().to_string_lossy();
 
     if data.len() <= chunk_size {
         // TODO: Possible refactor for the naming convention
-        let file_path = Path::new(folder).join(format!("{}_0.{}", stem, extension));
+        let file_path = folder.join(format!("{}_0.{}", stem, extension));
         let file = File::create(file_path)?;
         let mut writer = BufWriter::new(file);
 
@@ -52,7 +383,7 @@ fn _write_jsonl(data: Vec<ThreadPost>, bytes: usize, file_path: PathBuf) -> std:
         data.par_chunks(chunk_size)
             .enumerate()
             .for_each(|(i, chunk)| {
-                let file_path = Path::new(folder).join(format!("{}_{}.{}", stem, i, extension));
+                let file_path = folder.join(format!("{}_{}.{}", stem, i, extension));
                 let file = File::create(file_path).unwrap();
                 let mut writer = BufWriter::new(file);
 
@@ -67,11 +398,11 @@ fn _write_jsonl(data: Vec<ThreadPost>, bytes: usize, file_path: PathBuf) -> std:
 #[doc(hidden)]
 #[allow(dead_code)]
 fn write_jsonl(data: Vec<String>, _bytes: usize, file_path: PathBuf) -> std::io::Result<()> {
-    let folder = file_path.parent().unwrap().to_str().unwrap();
-    let stem = file_path.file_stem().unwrap().to_str().unwrap();
-    let extension = file_path.extension().unwrap().to_str().unwrap();
+    let folder = file_path.parent().unwrap();
+    let stem = file_path.file_stem().unwrap().to_string_lossy();
+    let extension = file_path.extension().unwrap().to_string_lossy();
 
-    let file_path = Path::new(folder).join(format!("{}_0.{}", stem, extension));
+    let file_path = folder.join(format!("{}_0.{}", stem, extension));
     let file = File::create(file_path)?;
     let handle = std::thread::spawn(move || {
         let mut writer = BufWriter::new(file);
@@ -97,7 +428,7 @@ fn write_jsonl(data: Vec<String>, _bytes: usize, file_path: PathBuf) -> std::io:
 ///
 /// # Example
 ///
-/// ```
+/// ```ignore
 /// let (tx, rx) = bounded(1000); // This can be unbounded
 /// let write_handle = std::thread::spawn(move || {
 ///    write_jsonl_receiver(rx, output_folder)
@@ -115,15 +446,341 @@ pub fn write_jsonl_receiver(
 ) -> std::io::Result<()> {
     // Create a all.jsonl file
     let output_path = output_folder.join("all.jsonl");
-    let mut writer = BufWriter::with_capacity(1_048_576, File::create(output_path)?);
-    while let Ok(data) = receiver.recv() {
+    let mut writer = BufWriter::with_capacity(WRITE_BUFFER_BYTES, File::create(output_path)?);
+    let mut clock = timing::Clock::new();
+    while let Ok(data) = clock.wait(|| receiver.recv()) {
         writeln!(&mut writer, "{}", data)?;
     }
     writer.flush()?;
-    println!("Finished writing to all.jsonl");
+    tracing::info!("Finished writing to all.jsonl");
     Ok(())
 }
 
+/// The output file a record is written to
+///
+/// Records are sent together with their route, so a single writer can fill several files.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Route {
+    /// `all.jsonl`, the main output
+    Main,
+    /// `oversized.jsonl`, documents over the oversized token threshold
+    Oversized,
+    /// The file of another route in the `train/` or `val/` folder, with `--val-fraction`
+    Split(Split, Box<Route>),
+    /// The file of another route in the `perplexity_<bucket>/` folder, with
+    /// `--perplexity-buckets`
+    Perplexity(usize, Box<Route>),
+    /// `rejects/<folder>.jsonl`, the lines and documents of a folder that were not written, with
+    /// `--rejects`
+    Rejects(String),
+    /// Not a record, the name of a folder whose records were all sent, see
+    /// [resume](../resume/index.html)
+    Checkpoint,
+}
+
+/// Half of the train and validation split a record belongs to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Split {
+    Train,
+    Val,
+}
+
+impl Route {
+    /// Path of the file, relative to the output folder
+    pub fn path(&self) -> PathBuf {
+        match self {
+            Route::Main => PathBuf::from("all.jsonl"),
+            Route::Oversized => PathBuf::from("oversized.jsonl"),
+            Route::Split(Split::Train, route) => Path::new("train").join(route.path()),
+            Route::Split(Split::Val, route) => Path::new("val").join(route.path()),
+            Route::Perplexity(bucket, route) => {
+                Path::new(&format!("perplexity_{}", bucket)).join(route.path())
+            }
+            Route::Rejects(folder) => {
+                Path::new(rejects::REJECTS_DIR).join(format!("{}.jsonl", folder))
+            }
+            Route::Checkpoint => PathBuf::from(resume::STATE_FILE),
+        }
+    }
+
+    /// Every route that records are written to with `config`
+    pub fn outputs(config: &Config) -> Vec<Route> {
+        let files = [Route::Main, Route::Oversized];
+        let mut records = files.to_vec();
+        if !config.perplexity_buckets.is_empty() {
+            for bucket in 0..=config.perplexity_buckets.len() {
+                records.extend(
+                    files
+                        .iter()
+                        .map(|route| Route::Perplexity(bucket, Box::new(route.clone()))),
+                );
+            }
+        }
+        let mut outputs = records.clone();
+        for split in [Split::Train, Split::Val] {
+            outputs.extend(
+                records
+                    .iter()
+                    .map(|route| Route::Split(split, Box::new(route.clone()))),
+            );
+        }
+        outputs
+    }
+}
+
+/// # Routed JSONL Handler
+///
+/// Same as [write_jsonl_receiver](fn.write_jsonl_receiver.html), but each record comes with the
+/// [Route](enum.Route.html) of the file it belongs to. `all.jsonl` is always created, the other files
+/// only when the first record for them arrives.
+///
+/// A `Route::Checkpoint` message flushes the files and records the folders it carries as
+/// completed, with the size of every file, in the [resume](../resume/index.html) state file.
+///
+/// # Arguments
+///
+/// * `receiver` - `Receiver<Batch>` - The receiver channel that receives the records in batches,
+///   see [Batcher](struct.Batcher.html)
+/// * `output_folder` - `PathBuf` - The output folder where the JSONL files will be written
+/// * `append` - `bool` - Append to the existing files instead of replacing them, to resume a run
+/// * `buffer` - `WriteBuffer` - The buffer size of the files and how often they are flushed
+///
+/// # Example
+///
+/// ```ignore
+/// let (tx, rx) = unbounded();
+/// let write_handle = std::thread::spawn(move || {
+///    write_routed_receiver(rx, output_folder, false, WriteBuffer::default())
+/// });
+///
+/// tx.send(single(Route::Main, "Hello")).unwrap();
+/// tx.send(single(Route::Oversized, "World")).unwrap();
+///
+/// drop(tx);
+/// write_handle.join().unwrap().unwrap();
+/// ```
+pub fn write_routed_receiver(
+    receiver: Receiver<Batch>,
+    output_folder: PathBuf,
+    append: bool,
+    buffer: WriteBuffer,
+) -> std::io::Result<()> {
+    let open = |route: &Route| -> std::io::Result<BufWriter<File>> {
+        let output_path = output_folder.join(route.path());
+        if let Some(parent) = output_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = match append {
+            true => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(output_path)?,
+            false => File::create(output_path)?,
+        };
+        Ok(BufWriter::with_capacity(buffer.bytes, file))
+    };
+    let mut writers: HashMap<Route, BufWriter<File>> = HashMap::new();
+    writers.insert(Route::Main, open(&Route::Main)?);
+
+    let mut clock = timing::Clock::new();
+    let mut flush = FlushTimer::new(buffer.flush_interval);
+    loop {
+        if flush.due() {
+            for writer in writers.values_mut() {
+                writer.flush()?;
+            }
+        }
+        let (route, batch) = match clock.wait(|| flush.recv(&receiver)) {
+            Ok(message) => message,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if route == Route::Checkpoint {
+            let mut offsets = BTreeMap::new();
+            for (route, writer) in writers.iter_mut() {
+                writer.flush()?;
+                let size = writer.get_ref().metadata()?.len();
+                offsets.insert(route.path().display().to_string(), size);
+            }
+            for folder in records(&batch) {
+                let checkpoint = resume::Checkpoint {
+                    folder: folder.to_string(),
+                    offsets: offsets.clone(),
+                };
+                resume::append_checkpoint(&output_folder, &checkpoint)?;
+            }
+            continue;
+        }
+        let writer = match writers.entry(route) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let writer = open(entry.key())?;
+                entry.insert(writer)
+            }
+        };
+        writer.write_all(&batch)?;
+        BYTES_WRITTEN.fetch_add(batch.len() as u64, Ordering::Relaxed);
+        memory::IN_FLIGHT.sub(batch.len() as u64);
+    }
+    for writer in writers.values_mut() {
+        writer.flush()?;
+    }
+    tracing::info!(files = writers.len(), "Finished writing the output files");
+    Ok(())
+}
+
+/// Same as [write_routed_receiver](fn.write_routed_receiver.html), but every record goes to
+/// `writer` instead of the files
+///
+/// `writer` gets the route and the JSON line of every record, the checkpoints of `--resume` are
+/// not forwarded.
+///
+/// # Arguments
+///
+/// * `receiver` - `Receiver<Batch>` - The receiver channel that receives the records in batches
+/// * `writer` - `F` - Called with each record in the order they arrive
+pub fn write_with_receiver<F>(receiver: Receiver<Batch>, mut writer: F) -> std::io::Result<()>
+where
+    F: FnMut(&Route, &str) -> std::io::Result<()>,
+{
+    let mut clock = timing::Clock::new();
+    while let Ok((route, batch)) = clock.wait(|| receiver.recv()) {
+        if route == Route::Checkpoint {
+            continue;
+        }
+        for data in records(&batch) {
+            writer(&route, data)?;
+        }
+        BYTES_WRITTEN.fetch_add(batch.len() as u64, Ordering::Relaxed);
+        memory::IN_FLIGHT.sub(batch.len() as u64);
+    }
+    Ok(())
+}
+
+/// Where the records of a run go in place of the output files, see
+/// [PipelineBuilder::sink](../../builder/struct.PipelineBuilder.html#method.sink)
+///
+/// The sink runs on the writer thread, it gets the records one at a time in the order they arrive.
+/// [JsonlSink](struct.JsonlSink.html) writes them as JSON lines.
+///
+/// # Example
+///
+/// ```
+/// # use std::io;
+/// # use forum_rs::utils::writer::{OutputSink, Route, ThreadPost};
+/// struct Count(usize);
+///
+/// impl OutputSink for Count {
+///     fn write(&mut self, _: &Route, _: ThreadPost) -> io::Result<()> {
+///         self.0 += 1;
+///         Ok(())
+///     }
+/// }
+/// ```
+pub trait OutputSink: Send {
+    /// Write a record, `route` is the file it would go to
+    fn write(&mut self, route: &Route, threadpost: ThreadPost) -> std::io::Result<()>;
+
+    /// Write a rejected line or document with `--rejects`, they are dropped by default
+    fn reject(&mut self, _route: &Route, _record: &str) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Called once after the last record
+    fn close(&mut self) -> std::io::Result<()> {
+        self.flush()
+    }
+}
+
+/// Writes every record as a JSON line to `W`, whatever its route
+pub struct JsonlSink<W: Write> {
+    writer: BufWriter<W>,
+}
+
+impl<W: Write> JsonlSink<W> {
+    pub fn new(writer: W) -> Self {
+        JsonlSink {
+            writer: BufWriter::with_capacity(WRITE_BUFFER_BYTES, writer),
+        }
+    }
+}
+
+impl<W: Write + Send> OutputSink for JsonlSink<W> {
+    fn write(&mut self, _route: &Route, threadpost: ThreadPost) -> std::io::Result<()> {
+        serde_json::to_writer(&mut self.writer, &threadpost)?;
+        self.writer.write_all(b"\n")
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Same as [write_routed_receiver](fn.write_routed_receiver.html), the records of `posts` go to
+/// `sink` and the rejects of `lines` to its `reject`
+///
+/// Returns once both channels are closed, after closing the sink. The checkpoints are not
+/// forwarded. The sink is flushed every `flush_interval`.
+pub fn write_sink_receiver(
+    posts: Receiver<(Route, ThreadPost)>,
+    lines: Receiver<Batch>,
+    sink: &mut dyn OutputSink,
+    flush_interval: Option<Duration>,
+) -> std::io::Result<()> {
+    /// A message of either channel, `None` once it is closed
+    // Only one is ever alive, on the stack of the loop
+    #[allow(clippy::large_enum_variant)]
+    enum Message {
+        Post(Option<(Route, ThreadPost)>),
+        Line(Option<Batch>),
+        /// A flush is due
+        Timeout,
+    }
+    let (mut posts, mut lines) = (Some(posts), Some(lines));
+    let (no_posts, no_lines) = (crossbeam_channel::never(), crossbeam_channel::never());
+    let mut clock = timing::Clock::new();
+    let mut flush = FlushTimer::new(flush_interval);
+    while posts.is_some() || lines.is_some() {
+        if flush.due() {
+            sink.flush()?;
+        }
+        let timeout = match flush.timeout() {
+            Some(timeout) => crossbeam_channel::after(timeout),
+            None => crossbeam_channel::never(),
+        };
+        let message = clock.wait(|| {
+            crossbeam_channel::select! {
+                recv(posts.as_ref().unwrap_or(&no_posts)) -> message => Message::Post(message.ok()),
+                recv(lines.as_ref().unwrap_or(&no_lines)) -> message => Message::Line(message.ok()),
+                recv(timeout) -> _ => Message::Timeout,
+            }
+        });
+        match message {
+            Message::Post(Some((route, threadpost))) => {
+                // The records are not serialized here, their text stands for their size
+                let size = threadpost.raw_content.len() as u64;
+                sink.write(&route, threadpost)?;
+                BYTES_WRITTEN.fetch_add(size + 1, Ordering::Relaxed);
+                memory::IN_FLIGHT.sub(size);
+            }
+            Message::Post(None) => posts = None,
+            Message::Line(Some((Route::Checkpoint, _))) => {}
+            Message::Line(Some((route, batch))) => {
+                for data in records(&batch) {
+                    sink.reject(&route, data)?;
+                }
+            }
+            Message::Line(None) => lines = None,
+            Message::Timeout => {}
+        }
+    }
+    sink.close()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -162,4 +819,164 @@ mod tests {
 
         assert_eq!(contents, "Hello\nWorld\n");
     }
+
+    #[test]
+    fn test_routed_receiver() {
+        let temp_dir = TempDir::new().unwrap();
+        let output_folder = temp_dir.path().to_path_buf();
+        let output_folder_clone = output_folder.clone();
+        let (tx, rx) = bounded(1000);
+        let write_handle = std::thread::spawn(move || {
+            write_routed_receiver(rx, output_folder_clone, false, WriteBuffer::default())
+        });
+
+        tx.send(single(Route::Main, "Hello")).unwrap();
+        tx.send(single(Route::Oversized, "Big")).unwrap();
+        tx.send(single(Route::Main, "World")).unwrap();
+        drop(tx);
+
+        write_handle.join().unwrap().unwrap();
+
+        let contents = std::fs::read_to_string(output_folder.join("all.jsonl")).unwrap();
+        assert_eq!(contents, "Hello\nWorld\n");
+        let contents = std::fs::read_to_string(output_folder.join("oversized.jsonl")).unwrap();
+        assert_eq!(contents, "Big\n");
+    }
+
+    #[test]
+    fn test_batcher() {
+        let (tx, rx) = bounded(1000);
+        let batcher = Batcher::new(tx, 2);
+        for record in ["a", "b", "c"] {
+            batcher.serialize(Route::Main, &record);
+        }
+        batcher.serialize(Route::Oversized, &"big");
+        // Only the full batch is sent before the batcher is dropped
+        let sent: Vec<Batch> = rx.try_iter().collect();
+        assert_eq!(sent, vec![(Route::Main, b"\"a\"\n\"b\"\n".to_vec())]);
+        assert_eq!(
+            records(&sent[0].1).collect::<Vec<_>>(),
+            vec!["\"a\"", "\"b\""]
+        );
+        drop(batcher);
+        let mut rest: Vec<Batch> = rx.try_iter().collect();
+        rest.sort_by_key(|(route, _)| route.path());
+        assert_eq!(
+            rest,
+            vec![
+                (Route::Main, b"\"c\"\n".to_vec()),
+                (Route::Oversized, b"\"big\"\n".to_vec())
+            ]
+        );
+        assert_eq!(records(b"").count(), 0);
+    }
+
+    #[test]
+    fn test_flush_interval() {
+        let temp_dir = TempDir::new().unwrap();
+        let folder = temp_dir.path().to_path_buf();
+        let (tx, rx) = bounded(1000);
+        let buffer = WriteBuffer {
+            bytes: WRITE_BUFFER_BYTES,
+            flush_interval: Some(Duration::from_millis(10)),
+        };
+        let write_handle =
+            std::thread::spawn(move || write_routed_receiver(rx, folder, false, buffer));
+
+        tx.send(single(Route::Main, "Hello")).unwrap();
+        // Flushed while the channel is still open
+        let path = temp_dir.path().join("all.jsonl");
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while std::fs::read_to_string(&path).unwrap_or_default() != "Hello\n" {
+            assert!(Instant::now() < deadline, "the file was not flushed");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        drop(tx);
+        write_handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let temp_dir = TempDir::new().unwrap();
+        let output_folder = temp_dir.path().to_path_buf();
+        std::fs::write(output_folder.join("all.jsonl"), "Before\n").unwrap();
+        let (tx, rx) = bounded(1000);
+        let folder = output_folder.clone();
+        let write_handle = std::thread::spawn(move || {
+            write_routed_receiver(rx, folder, true, WriteBuffer::default())
+        });
+
+        tx.send(single(Route::Main, "Hello")).unwrap();
+        tx.send(single(Route::Checkpoint, "sub1")).unwrap();
+        tx.send(single(Route::Main, "World")).unwrap();
+        drop(tx);
+        write_handle.join().unwrap().unwrap();
+
+        let contents = std::fs::read_to_string(output_folder.join("all.jsonl")).unwrap();
+        assert_eq!(contents, "Before\nHello\nWorld\n");
+        let state = resume::load(&output_folder).unwrap();
+        assert!(state.completed.contains("sub1"));
+        assert_eq!(state.offsets["all.jsonl"], 13);
+    }
+    #[test]
+    fn test_sink_receiver() {
+        /// Keeps the routes and texts, the rejects, and whether it was closed
+        #[derive(Default)]
+        struct Collect {
+            records: Vec<(Route, String)>,
+            rejects: Vec<String>,
+            closed: bool,
+        }
+
+        impl OutputSink for Collect {
+            fn write(&mut self, route: &Route, threadpost: ThreadPost) -> std::io::Result<()> {
+                self.records.push((route.clone(), threadpost.raw_content));
+                Ok(())
+            }
+
+            fn reject(&mut self, _: &Route, record: &str) -> std::io::Result<()> {
+                self.rejects.push(record.to_string());
+                Ok(())
+            }
+
+            fn close(&mut self) -> std::io::Result<()> {
+                self.closed = true;
+                Ok(())
+            }
+        }
+
+        let post = |text: &str| ThreadPost {
+            raw_content: text.to_string(),
+            ..Default::default()
+        };
+        let (posts_tx, posts_rx) = bounded(1000);
+        let (lines_tx, lines_rx) = bounded(1000);
+        posts_tx.send((Route::Main, post("Hello"))).unwrap();
+        lines_tx
+            .send(single(Route::Rejects("sub1".to_string()), "Bad"))
+            .unwrap();
+        lines_tx.send(single(Route::Checkpoint, "sub1")).unwrap();
+        posts_tx.send((Route::Oversized, post("Big"))).unwrap();
+        drop((posts_tx, lines_tx));
+        let mut sink = Collect::default();
+        write_sink_receiver(posts_rx, lines_rx, &mut sink, None).unwrap();
+        assert_eq!(
+            sink.records,
+            vec![
+                (Route::Main, "Hello".to_string()),
+                (Route::Oversized, "Big".to_string())
+            ]
+        );
+        assert_eq!(sink.rejects, vec!["Bad"]);
+        assert!(sink.closed);
+
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("records.jsonl");
+        let mut sink = JsonlSink::new(File::create(&path).unwrap());
+        sink.write(&Route::Main, post("Hello")).unwrap();
+        sink.close().unwrap();
+        let record: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(record["raw_content"], "Hello");
+    }
 }
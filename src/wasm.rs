@@ -0,0 +1,53 @@
+//! Browser bindings, with the `wasm` feature
+//!
+//! Built without the default features, there is no tokenizer and the lengths are counted in words:
+//!
+//! ```bash
+//! wasm-pack build --target web --no-default-features --features wasm
+//! ```
+//!
+//! ```js
+//! import init, { preview } from "./pkg/forum_rs.js";
+//! await init();
+//! const records = preview(pastedLines);
+//! ```
+//!
+//! Everything runs on the calling thread, the folders and the output files of the pipeline are
+//! replaced by the pasted lines and the returned string.
+
+use std::path::Path;
+
+use crossbeam_channel::unbounded;
+use wasm_bindgen::prelude::*;
+
+use crate::config::Config;
+use crate::utils::writer::Route;
+use crate::{experimental, forum_thread, globals};
+
+/// The records the pipeline writes for `jsonl`, one JSON line each, with the default options
+///
+/// The threads are rebuilt and cleaned exactly as for a folder holding these lines in a single
+/// file. Lines that cannot be parsed are skipped.
+#[wasm_bindgen]
+pub fn preview(jsonl: &str) -> String {
+    globals::init_regex();
+    let config = Config::default();
+    // Only `DuplicateIds::Error` fails, it is not the default
+    let threads = experimental::sender::threads_from_lines(jsonl, &config).unwrap_or_default();
+    let (records_tx, records_rx) = unbounded();
+    forum_thread::sender_thread_posts(threads, Path::new(""), &config, records_tx);
+    let mut lines: Vec<u8> = records_rx
+        .into_iter()
+        .filter(|(route, _)| !matches!(route, Route::Rejects(_)))
+        .flat_map(|(_, batch)| batch)
+        .collect();
+    lines.pop();
+    String::from_utf8(lines).unwrap()
+}
+
+/// Clean the text of a post the way the pipeline does
+#[wasm_bindgen]
+pub fn clean_text(text: &str) -> String {
+    globals::init_regex();
+    globals::clean_content(text).trim().to_string()
+}
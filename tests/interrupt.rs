@@ -0,0 +1,102 @@
+//! A run stopped while a folder is in progress, then resumed
+//!
+//! The stop request is global to the process and stays once made, so the test has a binary of
+//! its own and the other runs are made with `clean-reddit`.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use forum_rs::config::{Config, ProgressFormat};
+use forum_rs::error::Exit;
+use forum_rs::utils::shutdown;
+use forum_rs::Pipeline;
+
+/// A folder of `threads` threads of two posts each, the text of the last reply is `last`
+fn write_folder(folder: &Path, threads: usize, last: &str) {
+    fs::create_dir_all(folder).unwrap();
+    let mut lines = String::new();
+    for thread in 0..threads {
+        let root = thread * 2;
+        let reply = if thread + 1 == threads {
+            last.to_string()
+        } else {
+            format!("reply {}", thread)
+        };
+        for (id, is_thread, text) in [
+            (root, "Y", format!("root post {}", thread)),
+            (root + 1, "N", reply),
+        ] {
+            let post = serde_json::json!({
+                "id": id.to_string(),
+                "is_thread": is_thread,
+                "pagetext": text,
+                "parent_post_id": root.to_string(),
+                "root_post_id": root.to_string(),
+            });
+            lines.push_str(&format!("{}\n", post));
+        }
+    }
+    fs::write(folder.join("posts.jsonl"), lines).unwrap();
+}
+
+fn sorted_lines(path: &Path) -> Vec<String> {
+    let mut lines: Vec<String> = fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(str::to_string)
+        .collect();
+    lines.sort();
+    lines
+}
+
+#[test]
+fn test_interrupted_then_resumed() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let input = temp_dir.path().join("input");
+    write_folder(&input.join("a"), 2, "last of a");
+    write_folder(&input.join("b"), 200, "stop here");
+    let output = temp_dir.path().join("output");
+    let clean_reddit = |output: &Path, resume: &str| {
+        let status = Command::new(env!("CARGO_BIN_EXE_clean-reddit"))
+            .args(["--safe", "false", "--quiet", "true", "--resume", resume])
+            .arg("-i")
+            .arg(&input)
+            .arg("-o")
+            .arg(output)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    };
+    let expected = temp_dir.path().join("expected");
+    clean_reddit(&expected, "false");
+    let expected = sorted_lines(&expected.join("all.jsonl"));
+
+    // Ctrl-C while `b` is cleaned, and `b` fails with some of its records written, `a` is
+    // small and done by then
+    let config = Config {
+        input: input.clone(),
+        output: output.clone(),
+        safe: false,
+        largest_first: false,
+        progress: ProgressFormat::None,
+        ..Default::default()
+    };
+    let exit = Pipeline::new()
+        .config(config)
+        .cleaner(|text: &str| {
+            if text == "stop here" {
+                shutdown::request();
+                panic!("stopped in the middle of the folder");
+            }
+            text.to_string()
+        })
+        .build()
+        .run()
+        .unwrap();
+    assert_eq!(exit, Exit::Interrupted);
+    assert!(!sorted_lines(&output.join("all.jsonl")).is_empty());
+
+    clean_reddit(&output, "true");
+    assert_eq!(sorted_lines(&output.join("all.jsonl")), expected);
+}